@@ -0,0 +1,178 @@
+//! Per-client topic access control for the MQTT listener
+//!
+//! Each entry names an authenticated identity (certificate Common Name or CONNECT
+//! username) and the topic filters it may publish to and subscribe to. The `*` entry
+//! applies to clients without their own entry, including anonymous ones; clients covered
+//! by no entry at all are unrestricted.
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Identity of the entry that applies to clients without their own
+pub const DEFAULT_ACL_IDENTITY: &str = "*";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientAcl {
+    pub identity: String,
+    /// Topic filters the client may publish to
+    #[serde(default)]
+    pub publish: Vec<String>,
+    /// Topic filters the client may subscribe to (or narrower filters)
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+}
+
+/// ACL entries by identity, shared between the listener and the web API
+#[derive(Default)]
+pub struct AclTable {
+    entries: RwLock<HashMap<String, ClientAcl>>,
+}
+
+impl AclTable {
+    pub fn new(acls: Vec<ClientAcl>) -> Self {
+        Self {
+            entries: RwLock::new(
+                acls.into_iter()
+                    .map(|acl| (acl.identity.clone(), acl))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// All entries, sorted by identity
+    pub fn list(&self) -> Vec<ClientAcl> {
+        let mut acls: Vec<ClientAcl> = self.entries.read().values().cloned().collect();
+        acls.sort_by(|a, b| a.identity.cmp(&b.identity));
+        acls
+    }
+
+    pub fn set(&self, acl: ClientAcl) {
+        self.entries.write().insert(acl.identity.clone(), acl);
+    }
+
+    pub fn remove(&self, identity: &str) -> bool {
+        self.entries.write().remove(identity).is_some()
+    }
+
+    /// Replace every entry, e.g. with ACLs fetched from a management service
+    pub fn replace_all(&self, acls: Vec<ClientAcl>) {
+        *self.entries.write() = acls
+            .into_iter()
+            .map(|acl| (acl.identity.clone(), acl))
+            .collect();
+    }
+
+    pub fn can_publish(&self, identity: Option<&str>, topic: &str) -> bool {
+        self.check(identity, |acl| {
+            acl.publish
+                .iter()
+                .any(|filter| filter_covers(filter, topic))
+        })
+    }
+
+    pub fn can_subscribe(&self, identity: Option<&str>, filter: &str) -> bool {
+        self.check(identity, |acl| {
+            acl.subscribe
+                .iter()
+                .any(|allowed| filter_covers(allowed, filter))
+        })
+    }
+
+    fn check(&self, identity: Option<&str>, allowed: impl Fn(&ClientAcl) -> bool) -> bool {
+        let entries = self.entries.read();
+        let acl = identity
+            .and_then(|identity| entries.get(identity))
+            .or_else(|| entries.get(DEFAULT_ACL_IDENTITY));
+        acl.is_none_or(allowed)
+    }
+}
+
+/// Whether `filter` is a well-formed MQTT topic filter
+pub fn is_valid_filter(filter: &str) -> bool {
+    let levels: Vec<&str> = filter.split('/').collect();
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(i, level)| match *level {
+            "#" => i == levels.len() - 1,
+            "+" => true,
+            level => !level.contains(['+', '#']),
+        })
+}
+
+/// Whether every topic matched by `requested` is also matched by `allowed`.
+/// A plain topic name is the narrowest filter, so this also checks publish topics.
+pub fn filter_covers(allowed: &str, requested: &str) -> bool {
+    let mut allowed = allowed.split('/');
+    let mut requested = requested.split('/');
+    loop {
+        match (allowed.next(), requested.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(level)) => {
+                if level == "#" {
+                    return false;
+                }
+            }
+            (Some(a), Some(r)) => {
+                if a != r {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_covers() {
+        assert!(filter_covers("sensors/#", "sensors/a/temp"));
+        assert!(filter_covers("sensors/#", "sensors/+/temp"));
+        assert!(filter_covers("sensors/+/temp", "sensors/a/temp"));
+        assert!(filter_covers("sensors/+/temp", "sensors/+/temp"));
+        assert!(!filter_covers("sensors/+/temp", "sensors/#"));
+        assert!(!filter_covers("sensors/a/temp", "sensors/+/temp"));
+        assert!(!filter_covers("sensors/+", "sensors/a/temp"));
+        assert!(!filter_covers("sensors/a", "sensors"));
+    }
+
+    #[test]
+    fn test_is_valid_filter() {
+        assert!(is_valid_filter("sensors/+/temp"));
+        assert!(is_valid_filter("#"));
+        assert!(!is_valid_filter("sensors/#/temp"));
+        assert!(!is_valid_filter("sensors/a+"));
+        assert!(!is_valid_filter(""));
+    }
+
+    #[test]
+    fn test_acl_table_uses_own_then_default_entry() {
+        let table = AclTable::new(vec![
+            ClientAcl {
+                identity: "device-1".into(),
+                publish: vec!["devices/device-1/#".into()],
+                subscribe: vec!["commands/device-1".into()],
+            },
+            ClientAcl {
+                identity: DEFAULT_ACL_IDENTITY.into(),
+                publish: vec![],
+                subscribe: vec!["public/#".into()],
+            },
+        ]);
+
+        assert!(table.can_publish(Some("device-1"), "devices/device-1/state"));
+        assert!(!table.can_publish(Some("device-1"), "devices/device-2/state"));
+        assert!(table.can_subscribe(Some("device-1"), "commands/device-1"));
+        assert!(!table.can_subscribe(Some("device-1"), "public/news"));
+
+        assert!(!table.can_publish(None, "devices/device-1/state"));
+        assert!(table.can_subscribe(Some("other"), "public/+"));
+
+        table.remove(DEFAULT_ACL_IDENTITY);
+        assert!(table.can_publish(None, "anything"));
+    }
+}
@@ -0,0 +1,680 @@
+//! Certificates for the HTTPS Web UI from an ACME CA (RFC 8555), e.g. Let's Encrypt
+//!
+//! With `[web_ui.acme]` the Web UI serves HTTPS with a certificate for `domains`, requested
+//! on first start and renewed `renew_before_days` before it expires. The CA checks control
+//! of each domain with an `http-01` challenge (answered by a plain HTTP server on
+//! `http_port`) or a `tls-alpn-01` challenge (answered on the Web UI port itself during
+//! the TLS handshake). The account key, the certificate and its key are kept in `dir`, so
+//! restarts reuse them instead of requesting new ones; a failed request is retried hourly
+//! while the old certificate, if any, is still served. Enabling ACME agrees to the CA's
+//! terms of service.
+
+use crate::config::{AcmeChallenge, AcmeConfig};
+use crate::http_client::{self, HttpResponse, HttpUrl};
+use crate::listener_tls::cert_not_after;
+use crate::self_signed::write_private;
+use anyhow::{anyhow, bail, Context, Result};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
+use rcgen::{Certificate, CertificateParams, CustomExtension, DnType};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// ALPN protocol of `tls-alpn-01` validation connections (RFC 8737)
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const ACCOUNT_KEY_FILE: &str = "account.pk8";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const JSON: &str = "application/json";
+const PEM_CHAIN: &str = "application/pem-certificate-chain";
+
+/// Time allowed for each request to the CA
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time between checks of an order or authorization the CA is working on
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+/// Time between checks whether the certificate is due for renewal
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+/// Time before a failed request is tried again
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    #[serde(default)]
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    identifier: Identifier,
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+/// Error document of a failed request (RFC 7807)
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// State of one certificate request
+struct Session {
+    directory: Directory,
+    nonce: Option<String>,
+    /// Account URL, once the account is registered
+    kid: Option<String>,
+}
+
+/// Picks the certificate for each TLS handshake: the issued one, or a challenge
+/// certificate for `tls-alpn-01` validation connections
+#[derive(Default)]
+struct CertResolver {
+    certificate: RwLock<Option<Arc<CertifiedKey>>>,
+    /// `tls-alpn-01` certificates by domain
+    alpn_challenges: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl std::fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("issued", &self.certificate.read().is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let validation = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+        if validation {
+            let domain = client_hello.server_name()?;
+            return self.alpn_challenges.lock().get(domain).cloned();
+        }
+        self.certificate.read().clone()
+    }
+}
+
+pub struct Acme {
+    config: AcmeConfig,
+    dir: PathBuf,
+    account_key: EcdsaKeyPair,
+    rng: SystemRandom,
+    resolver: Arc<CertResolver>,
+    /// Expiry of the installed certificate
+    expires: Mutex<Option<DateTime<Utc>>>,
+    /// `http-01` key authorizations by token
+    http_challenges: Mutex<HashMap<String, String>>,
+}
+
+impl Acme {
+    /// Load or create the account key, and install a previously issued certificate
+    pub fn new(config: AcmeConfig) -> Result<Self> {
+        anyhow::ensure!(!config.domains.is_empty(), "acme needs at least one domain");
+        let dir = PathBuf::from(&config.dir);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create directory: {:?}", dir))?;
+        let rng = SystemRandom::new();
+
+        let key_path = dir.join(ACCOUNT_KEY_FILE);
+        let pkcs8 = match std::fs::read(&key_path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| anyhow!("Failed to generate ACME account key"))?;
+                write_private(&key_path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", key_path)),
+        };
+        let account_key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| anyhow!("Invalid ACME account key {:?}: {}", key_path, e))?;
+
+        let acme = Self {
+            config,
+            dir,
+            account_key,
+            rng,
+            resolver: Arc::new(CertResolver::default()),
+            expires: Mutex::new(None),
+            http_challenges: Mutex::new(HashMap::new()),
+        };
+        let (cert_path, key_path) = (acme.dir.join(CERT_FILE), acme.dir.join(KEY_FILE));
+        if cert_path.exists() && key_path.exists() {
+            let cert_pem = std::fs::read(&cert_path)
+                .with_context(|| format!("Failed to read {:?}", cert_path))?;
+            let key_pem = std::fs::read(&key_path)
+                .with_context(|| format!("Failed to read {:?}", key_path))?;
+            match acme.install(&cert_pem, &key_pem) {
+                Ok(expires) => info!("Loaded ACME certificate valid until {}", expires),
+                Err(e) => warn!("Ignoring stored ACME certificate: {:#}", e),
+            }
+        }
+        Ok(acme)
+    }
+
+    /// TLS acceptor serving the current certificate (and `tls-alpn-01` challenges)
+    pub fn acceptor(&self) -> TlsAcceptor {
+        let mut server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&self.resolver) as Arc<dyn ResolvesServerCert>);
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        if self.config.challenge == AcmeChallenge::TlsAlpn01 {
+            server_config.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+        }
+        TlsAcceptor::from(Arc::new(server_config))
+    }
+
+    /// Keep the certificate current until `shutdown` is cancelled, answering `http-01`
+    /// challenges on `bind_address` if that is the challenge type
+    pub async fn run(self: Arc<Self>, bind_address: String, shutdown: CancellationToken) {
+        if self.config.challenge == AcmeChallenge::Http01 {
+            let acme = Arc::clone(&self);
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) = acme.serve_http_challenges(&bind_address, shutdown).await {
+                    error!("ACME http-01 challenge server failed: {}", e);
+                }
+            });
+        }
+        loop {
+            let wait = if self.needs_renewal() {
+                info!(
+                    "Requesting certificate for {} from {}",
+                    self.config.domains.join(", "),
+                    self.config.directory_url
+                );
+                let issued = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    issued = self.issue() => issued,
+                };
+                match issued {
+                    Ok(expires) => {
+                        info!("Installed ACME certificate valid until {}", expires);
+                        CHECK_INTERVAL
+                    }
+                    Err(e) => {
+                        warn!("ACME certificate request failed: {:#}", e);
+                        RETRY_INTERVAL
+                    }
+                }
+            } else {
+                CHECK_INTERVAL
+            };
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+
+    fn needs_renewal(&self) -> bool {
+        let renew_before = chrono::Duration::days(i64::from(self.config.renew_before_days));
+        let expires = *self.expires.lock();
+        expires.is_none_or(|expires| expires - renew_before <= Utc::now())
+    }
+
+    async fn serve_http_challenges(
+        self: Arc<Self>,
+        bind_address: &str,
+        shutdown: CancellationToken,
+    ) -> std::io::Result<()> {
+        let listener = TcpListener::bind((bind_address, self.config.http_port)).await?;
+        info!(
+            "Answering ACME http-01 challenges on port {}",
+            self.config.http_port
+        );
+        let app = Router::new()
+            .route("/.well-known/acme-challenge/:token", get(http_challenge))
+            .with_state(self);
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+    }
+
+    /// Run one order through to a stored and installed certificate
+    async fn issue(&self) -> Result<DateTime<Utc>> {
+        let directory = get_json(&self.config.directory_url).await?;
+        let mut session = Session {
+            directory,
+            nonce: None,
+            kid: None,
+        };
+
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &self.config.email {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account = session.directory.new_account.clone();
+        let response = self
+            .post(&mut session, &new_account, Some(&account), JSON)
+            .await?;
+        session.kid = Some(
+            response
+                .header("location")
+                .context("Account response has no Location")?
+                .to_string(),
+        );
+
+        let identifiers: Vec<Value> = self
+            .config
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let new_order = session.directory.new_order.clone();
+        let response = self
+            .post(
+                &mut session,
+                &new_order,
+                Some(&json!({ "identifiers": identifiers })),
+                JSON,
+            )
+            .await?;
+        let order_url = response
+            .header("location")
+            .context("Order response has no Location")?
+            .to_string();
+        let order: Order = parse(&response)?;
+        for authorization in &order.authorizations {
+            self.authorize(&mut session, authorization).await?;
+        }
+
+        let (csr, key_pem) = certificate_request(&self.config.domains)?;
+        self.post(
+            &mut session,
+            &order.finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+            JSON,
+        )
+        .await?;
+        let order: Order = self
+            .poll(&mut session, &order_url, |order: &Order| {
+                match order.status.as_str() {
+                    "valid" => Ok(true),
+                    "invalid" => bail!("The CA rejected the order"),
+                    _ => Ok(false),
+                }
+            })
+            .await?;
+        let certificate_url = order
+            .certificate
+            .context("Valid order has no certificate URL")?;
+        let cert_pem = self
+            .post(&mut session, &certificate_url, None, PEM_CHAIN)
+            .await?
+            .body;
+
+        let expires = self.install(&cert_pem, key_pem.as_bytes())?;
+        write_private(&self.dir.join(KEY_FILE), key_pem.as_bytes())?;
+        let cert_path = self.dir.join(CERT_FILE);
+        std::fs::write(&cert_path, &cert_pem)
+            .with_context(|| format!("Failed to write {:?}", cert_path))?;
+        Ok(expires)
+    }
+
+    /// Complete the challenge of one authorization of the order
+    async fn authorize(&self, session: &mut Session, url: &str) -> Result<()> {
+        let authorization: Authorization = parse(&self.post(session, url, None, JSON).await?)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let kind = match self.config.challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+        let domain = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == kind)
+            .with_context(|| format!("The CA offers no {} challenge for {}", kind, domain))?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+        match self.config.challenge {
+            AcmeChallenge::Http01 => {
+                self.http_challenges
+                    .lock()
+                    .insert(challenge.token.clone(), key_authorization);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let certificate = alpn_certificate(&domain, &key_authorization)?;
+                self.resolver
+                    .alpn_challenges
+                    .lock()
+                    .insert(domain.clone(), certificate);
+            }
+        }
+
+        let validated = async {
+            self.post(session, &challenge.url, Some(&json!({})), JSON)
+                .await?;
+            self.poll(
+                session,
+                url,
+                |authorization: &Authorization| match authorization.status.as_str() {
+                    "valid" => Ok(true),
+                    "pending" => Ok(false),
+                    other => bail!("Validation of {} is {}", domain, other),
+                },
+            )
+            .await
+        }
+        .await;
+        self.http_challenges.lock().remove(&challenge.token);
+        self.resolver.alpn_challenges.lock().remove(&domain);
+        validated.map(|_| ())
+    }
+
+    /// POST-as-GET `url` until `done` accepts the resource
+    async fn poll<T: DeserializeOwned>(
+        &self,
+        session: &mut Session,
+        url: &str,
+        done: impl Fn(&T) -> Result<bool>,
+    ) -> Result<T> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource = parse(&self.post(session, url, None, JSON).await?)?;
+            if done(&resource)? {
+                return Ok(resource);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        bail!("The CA didn't finish {} in time", url)
+    }
+
+    /// Signed POST, or POST-as-GET without `payload`. A rejected nonce is retried once.
+    async fn post(
+        &self,
+        session: &mut Session,
+        url: &str,
+        payload: Option<&Value>,
+        accept: &str,
+    ) -> Result<HttpResponse> {
+        let target = HttpUrl::parse(url)?;
+        for attempt in 0..2 {
+            let nonce = match session.nonce.take() {
+                Some(nonce) => nonce,
+                None => new_nonce(&session.directory).await?,
+            };
+            let body = self.jws(url, &nonce, session.kid.as_deref(), payload)?;
+            let response = send(
+                "POST",
+                &target,
+                accept,
+                Some(("application/jose+json", body.as_slice())),
+            )
+            .await?;
+            session.nonce = response.header("replay-nonce").map(str::to_string);
+            if response.is_success() {
+                return Ok(response);
+            }
+            let problem: Option<Problem> = serde_json::from_slice(&response.body).ok();
+            match problem {
+                Some(problem) if attempt == 0 && problem.kind.ends_with(":badNonce") => continue,
+                Some(problem) => bail!(
+                    "{} failed with HTTP {}: {} ({})",
+                    url,
+                    response.status,
+                    problem.detail,
+                    problem.kind
+                ),
+                None => bail!("{} failed with HTTP {}", url, response.status),
+            }
+        }
+        bail!("{} kept rejecting the nonce", url)
+    }
+
+    /// Flattened JWS of a request, signed with the account key; the key itself is sent
+    /// until the account URL is known
+    fn jws(
+        &self,
+        url: &str,
+        nonce: &str,
+        kid: Option<&str>,
+        payload: Option<&Value>,
+    ) -> Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match kid {
+            Some(kid) => protected["kid"] = kid.into(),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .account_key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow!("Failed to sign ACME request"))?;
+        Ok(serde_json::to_vec(&json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))?)
+    }
+
+    /// Coordinates of the account key; the public key is `0x04 || x || y`
+    fn jwk_coordinates(&self) -> (String, String) {
+        let point = self.account_key.public_key().as_ref();
+        (
+            URL_SAFE_NO_PAD.encode(&point[1..33]),
+            URL_SAFE_NO_PAD.encode(&point[33..65]),
+        )
+    }
+
+    fn jwk(&self) -> Value {
+        let (x, y) = self.jwk_coordinates();
+        json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y })
+    }
+
+    /// JWK thumbprint (RFC 7638): the members in lexicographic order, without whitespace
+    fn thumbprint(&self) -> String {
+        let (x, y) = self.jwk_coordinates();
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        URL_SAFE_NO_PAD.encode(Sha256::digest(jwk.as_bytes()))
+    }
+
+    /// Serve a PEM certificate chain and key from now on; returns the expiry
+    fn install(&self, cert_pem: &[u8], key_pem: &[u8]) -> Result<DateTime<Utc>> {
+        let certs = rustls_pemfile::certs(&mut &cert_pem[..])
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .context("Failed to parse the certificate chain")?;
+        let key = rustls_pemfile::private_key(&mut &key_pem[..])
+            .context("Failed to parse the certificate key")?
+            .context("No certificate key")?;
+        let expires = certs
+            .first()
+            .and_then(|cert| cert_not_after(cert.as_ref()))
+            .context("Certificate has no readable expiry")?;
+        *self.resolver.certificate.write() = Some(certified_key(certs, key)?);
+        *self.expires.lock() = Some(expires);
+        Ok(expires)
+    }
+}
+
+async fn http_challenge(
+    State(acme): State<Arc<Acme>>,
+    Path(token): Path<String>,
+) -> Result<String, StatusCode> {
+    acme.http_challenges
+        .lock()
+        .get(&token)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn send(
+    method: &str,
+    url: &HttpUrl,
+    accept: &str,
+    body: Option<(&str, &[u8])>,
+) -> Result<HttpResponse> {
+    tokio::time::timeout(
+        REQUEST_TIMEOUT,
+        http_client::send(method, url, [("Accept", accept)], body),
+    )
+    .await
+    .map_err(|_| anyhow!("No response from the CA within {:?}", REQUEST_TIMEOUT))?
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T> {
+    let response = send("GET", &HttpUrl::parse(url)?, JSON, None).await?;
+    if !response.is_success() {
+        bail!("{} failed with HTTP {}", url, response.status);
+    }
+    parse(&response)
+}
+
+async fn new_nonce(directory: &Directory) -> Result<String> {
+    let response = send("GET", &HttpUrl::parse(&directory.new_nonce)?, JSON, None).await?;
+    response
+        .header("replay-nonce")
+        .map(str::to_string)
+        .context("The CA returned no nonce")
+}
+
+fn parse<T: DeserializeOwned>(response: &HttpResponse) -> Result<T> {
+    serde_json::from_slice(&response.body).context("Unexpected response from the CA")
+}
+
+/// DER CSR for `domains` and the PEM key of the certificate
+fn certificate_request(domains: &[String]) -> Result<(Vec<u8>, String)> {
+    let mut params = CertificateParams::new(domains.to_vec());
+    params
+        .distinguished_name
+        .push(DnType::CommonName, domains[0].clone());
+    let request = Certificate::from_params(params).context("Failed to generate key")?;
+    let csr = request
+        .serialize_request_der()
+        .context("Failed to encode certificate request")?;
+    Ok((csr, request.serialize_private_key_pem()))
+}
+
+/// Self-signed `tls-alpn-01` certificate for `domain` (RFC 8737)
+fn alpn_certificate(domain: &str, key_authorization: &str) -> Result<Arc<CertifiedKey>> {
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(&Sha256::digest(
+        key_authorization.as_bytes(),
+    ))];
+    let cert = Certificate::from_params(params).context("Failed to generate certificate")?;
+    let der = cert
+        .serialize_der()
+        .context("Failed to encode certificate")?;
+    certified_key(
+        vec![CertificateDer::from(der)],
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.serialize_private_key_der())),
+    )
+}
+
+fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<CertifiedKey>> {
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported certificate key: {}", e))?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    fn config(dir: &std::path::Path) -> AcmeConfig {
+        serde_json::from_value(json!({
+            "domains": ["proxy.example.com"],
+            "dir": dir.to_string_lossy(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_requests_are_signed_with_the_persisted_account_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let acme = Acme::new(config(dir.path())).unwrap();
+        assert!(acme.needs_renewal());
+
+        let body = acme
+            .jws(
+                "https://ca.example/new-order",
+                "nonce-1",
+                Some("https://ca.example/acct/1"),
+                Some(&json!({ "identifiers": [] })),
+            )
+            .unwrap();
+        let jws: Value = serde_json::from_slice(&body).unwrap();
+        let field = |name: &str| jws[name].as_str().unwrap().to_string();
+        let protected: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(field("protected")).unwrap()).unwrap();
+        assert_eq!(protected["kid"], "https://ca.example/acct/1");
+        assert_eq!(protected["nonce"], "nonce-1");
+        let signed = format!("{}.{}", field("protected"), field("payload"));
+        UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_FIXED,
+            acme.account_key.public_key().as_ref(),
+        )
+        .verify(
+            signed.as_bytes(),
+            &URL_SAFE_NO_PAD.decode(field("signature")).unwrap(),
+        )
+        .unwrap();
+
+        // The account (and its thumbprint in key authorizations) survives restarts
+        let thumbprint = acme.thumbprint();
+        assert_eq!(thumbprint.len(), 43);
+        assert_eq!(
+            Acme::new(config(dir.path())).unwrap().thumbprint(),
+            thumbprint
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Fan-in of many topics into one aggregated topic
+//!
+//! An `aggregate` rule action holds back the messages its rule matches and publishes them
+//! together on the action's topic once `maxMessages` are collected or `windowMs` after the
+//! first one, whichever comes first. The `array` format publishes
+//! `[{"topic": ..., "payload": ...}, ...]` in arrival order, `object` publishes
+//! `{"<topic>": <payload>, ...}` with the latest payload per topic. JSON payloads are
+//! embedded as they are, other payloads as strings. The combined message is forwarded
+//! through the normal routing (without rules) with the highest QoS of its messages and
+//! without the retain flag or MQTT 5.0 properties. Unlike `batching`, which packs one
+//! topic per downstream broker, this cuts the message count towards every broker.
+
+use crate::connection_manager::ConnectionManager;
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+/// Aggregate topics filled at once; messages for further ones are forwarded singly
+const MAX_PENDING_AGGREGATES: usize = 10_000;
+/// How often aggregates are checked for an elapsed window
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateFormat {
+    #[default]
+    Array,
+    Object,
+}
+
+/// One `aggregate` action of a matching rule, with its topic's wildcards substituted
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateStep {
+    pub topic: String,
+    pub window_ms: u64,
+    pub max_messages: usize,
+    pub format: AggregateFormat,
+}
+
+struct PendingAggregate {
+    due: Instant,
+    qos: QoS,
+    max_messages: usize,
+    format: AggregateFormat,
+    entries: Vec<(String, Value)>,
+}
+
+/// A combined message ready to forward
+#[derive(Debug, PartialEq)]
+pub struct Aggregate {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub messages: usize,
+}
+
+/// Aggregates being filled, by output topic
+#[derive(Default)]
+pub struct Aggregator {
+    pending: Mutex<HashMap<String, PendingAggregate>>,
+}
+
+impl Aggregator {
+    /// Add a message to the aggregate of `step`, and return the aggregate once it is full.
+    /// The window and size of an aggregate are those of the step that started it.
+    pub fn push(
+        &self,
+        step: &AggregateStep,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        now: Instant,
+    ) -> Result<Option<Aggregate>> {
+        let mut pending = self.pending.lock();
+        if !pending.contains_key(&step.topic) {
+            if pending.len() >= MAX_PENDING_AGGREGATES {
+                bail!("Too many aggregates pending");
+            }
+            pending.insert(
+                step.topic.clone(),
+                PendingAggregate {
+                    due: now + Duration::from_millis(step.window_ms),
+                    qos,
+                    max_messages: step.max_messages.max(1),
+                    format: step.format,
+                    entries: Vec::new(),
+                },
+            );
+        }
+        let aggregate = pending
+            .get_mut(&step.topic)
+            .expect("aggregate was just inserted");
+        if qos > aggregate.qos {
+            aggregate.qos = qos;
+        }
+        aggregate.entries.push((
+            topic.to_string(),
+            serde_json::from_slice(payload)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).into())),
+        ));
+        if aggregate.entries.len() >= aggregate.max_messages {
+            let aggregate = pending.remove(&step.topic).expect("aggregate exists");
+            return Ok(Some(finish(step.topic.clone(), aggregate)));
+        }
+        Ok(None)
+    }
+
+    /// Aggregates whose window has passed, or all of them with `now` unset (on shutdown)
+    pub fn flush(&self, now: Option<Instant>) -> Vec<Aggregate> {
+        let mut pending = self.pending.lock();
+        let due: Vec<String> = pending
+            .iter()
+            .filter(|(_, aggregate)| now.is_none_or(|now| aggregate.due <= now))
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|topic| {
+                let aggregate = pending.remove(&topic)?;
+                Some(finish(topic, aggregate))
+            })
+            .collect()
+    }
+}
+
+fn finish(topic: String, aggregate: PendingAggregate) -> Aggregate {
+    let messages = aggregate.entries.len();
+    let combined = match aggregate.format {
+        AggregateFormat::Array => Value::Array(
+            aggregate
+                .entries
+                .into_iter()
+                .map(|(topic, payload)| serde_json::json!({"topic": topic, "payload": payload}))
+                .collect(),
+        ),
+        AggregateFormat::Object => {
+            Value::Object(aggregate.entries.into_iter().collect::<Map<_, _>>())
+        }
+    };
+    Aggregate {
+        topic,
+        payload: Bytes::from(combined.to_string()),
+        qos: aggregate.qos,
+        messages,
+    }
+}
+
+/// Forward aggregates as their windows pass until `shutdown` is cancelled, then forward
+/// the rest
+pub async fn run_aggregate_flush(
+    connection_manager: Arc<RwLock<ConnectionManager>>,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = interval.tick() => {
+                connection_manager
+                    .read()
+                    .await
+                    .flush_aggregates(Some(Instant::now()))
+                    .await
+            }
+        }
+    }
+    connection_manager.read().await.flush_aggregates(None).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(format: AggregateFormat) -> AggregateStep {
+        AggregateStep {
+            topic: "site-a/sensors".to_string(),
+            window_ms: 1000,
+            max_messages: 3,
+            format,
+        }
+    }
+
+    #[test]
+    fn test_aggregates_flush_when_full_or_due() {
+        let aggregator = Aggregator::default();
+        let start = Instant::now();
+        let array = step(AggregateFormat::Array);
+
+        for (topic, payload) in [("sensors/a", &b"21.5"[..]), ("sensors/b", b"open")] {
+            assert_eq!(
+                aggregator
+                    .push(&array, topic, payload, QoS::AtMostOnce, start)
+                    .unwrap(),
+                None
+            );
+        }
+        let full = aggregator
+            .push(&array, "sensors/a", br#"{"t":22}"#, QoS::AtLeastOnce, start)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&full.payload).unwrap(),
+            serde_json::json!([
+                {"topic": "sensors/a", "payload": 21.5},
+                {"topic": "sensors/b", "payload": "open"},
+                {"topic": "sensors/a", "payload": {"t": 22}}
+            ])
+        );
+        assert_eq!((full.qos, full.messages), (QoS::AtLeastOnce, 3));
+
+        let object = step(AggregateFormat::Object);
+        aggregator
+            .push(&object, "sensors/a", b"1", QoS::AtMostOnce, start)
+            .unwrap();
+        aggregator
+            .push(&object, "sensors/a", b"2", QoS::AtMostOnce, start)
+            .unwrap();
+        assert!(aggregator
+            .flush(Some(start + Duration::from_millis(999)))
+            .is_empty());
+        let due = aggregator.flush(Some(start + Duration::from_millis(1000)));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].payload, Bytes::from_static(br#"{"sensors/a":2}"#));
+        assert_eq!(due[0].messages, 2);
+    }
+}
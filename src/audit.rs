@@ -0,0 +1,265 @@
+//! Audit log of configuration changes made through the web API
+//!
+//! Adding, updating, deleting and toggling brokers and saving the main broker settings each
+//! append one JSON line to `web_ui.audit_log_path`: when, what changed and who changed it.
+//! The API has no login of its own, so the user is the one an authenticating reverse proxy
+//! puts in `X-Forwarded-User` or `Remote-User`; the address is the peer of the connection,
+//! with `X-Forwarded-For` kept alongside. Passwords are recorded as changed, never with
+//! their value. The file is only ever appended to; `GET /api/audit` reads it back newest
+//! first.
+
+use crate::broker_diff::FieldChange;
+use anyhow::{Context, Result};
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tracing::error;
+
+/// Shown instead of passwords
+const HIDDEN: &str = "********";
+/// Headers an authenticating reverse proxy passes the user in, in order of preference
+const USER_HEADERS: [&str; 2] = ["x-forwarded-user", "remote-user"];
+/// Target of main broker settings changes
+pub const MAIN_BROKER_TARGET: &str = "main-broker";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    BrokerAdded,
+    BrokerUpdated,
+    BrokerDeleted,
+    BrokerToggled,
+    MainBrokerUpdated,
+}
+
+/// Who made a change, as far as the request tells
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Actor {
+    pub user: Option<String>,
+    pub remote_addr: Option<String>,
+    pub forwarded_for: Option<String>,
+}
+
+impl Actor {
+    pub fn from_request(headers: &HeaderMap, peer: Option<SocketAddr>) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        Self {
+            user: USER_HEADERS.iter().find_map(|name| header(name)),
+            remote_addr: peer.map(|peer| peer.ip().to_string()),
+            forwarded_for: header("x-forwarded-for"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub action: AuditAction,
+    /// Broker ID, or `main-broker`
+    pub target: String,
+    /// Broker name at the time of the change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub changes: Vec<FieldChange>,
+    #[serde(flatten)]
+    pub actor: Actor,
+}
+
+/// Fields that differ between two serialized configs, either of which may be missing (an
+/// add or a delete). `password_set` tells whether the change set a password, as only the
+/// hidden placeholder is compared.
+pub fn changes<T: Serialize>(
+    old: Option<&T>,
+    new: Option<&T>,
+    password_set: bool,
+) -> Vec<FieldChange> {
+    let fields = |config: Option<&T>| match config.map(serde_json::to_value) {
+        Some(Ok(Value::Object(fields))) => fields,
+        _ => serde_json::Map::new(),
+    };
+    let old_fields = fields(old);
+    let mut new_fields = fields(new);
+    let hidden = |fields: &serde_json::Map<String, Value>| match fields.get("password") {
+        Some(Value::Null) | None => Value::Null,
+        Some(_) => HIDDEN.into(),
+    };
+    let (old_password, new_password) = (hidden(&old_fields), hidden(&new_fields));
+
+    let mut changes = Vec::new();
+    for (field, old_value) in old_fields {
+        let new_value = new_fields.remove(&field).unwrap_or(Value::Null);
+        if field != "password" && old_value != new_value {
+            changes.push(FieldChange {
+                field,
+                old: old_value,
+                new: new_value,
+            });
+        }
+    }
+    for (field, new_value) in new_fields {
+        if field != "password" && !new_value.is_null() {
+            changes.push(FieldChange {
+                field,
+                old: Value::Null,
+                new: new_value,
+            });
+        }
+    }
+    if password_set || old_password != new_password {
+        changes.push(FieldChange {
+            field: "password".to_string(),
+            old: old_password,
+            new: new_password,
+        });
+    }
+    changes
+}
+
+/// Append-only JSON Lines file of `AuditEntry`s
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open audit log: {:?}", path))?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append an entry now. The change it describes is already applied, so a failed
+    /// write is logged rather than returned.
+    pub fn record(
+        &self,
+        actor: Actor,
+        action: AuditAction,
+        target: &str,
+        name: Option<&str>,
+        changes: Vec<FieldChange>,
+    ) {
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            action,
+            target: target.to_string(),
+            name: name.map(str::to_string),
+            changes,
+            actor,
+        };
+        if let Err(e) = self.append(&entry) {
+            error!("Failed to write audit log {:?}: {:#}", self.path, e);
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock();
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// The latest `limit` entries, newest first, optionally only those of `target`
+    pub fn latest(&self, limit: usize, target: Option<&str>) -> Result<Vec<AuditEntry>> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("Failed to read audit log: {:?}", self.path))?;
+        let mut entries: Vec<AuditEntry> = BufReader::new(file)
+            .lines()
+            .map_while(|line| line.ok())
+            // A line being appended right now doesn't parse yet
+            .filter_map(|line| serde_json::from_str(&line).ok())
+            .filter(|entry: &AuditEntry| target.is_none_or(|target| entry.target == target))
+            .collect();
+        entries.reverse();
+        entries.truncate(limit);
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_audit_log_records_changes_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::open(dir.path().join("audit/audit.jsonl")).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("remote-user", HeaderValue::from_static("alice"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("10.0.0.7"));
+        let actor = Actor::from_request(&headers, Some("127.0.0.1:40000".parse().unwrap()));
+        assert_eq!(actor.user.as_deref(), Some("alice"));
+        assert_eq!(actor.remote_addr.as_deref(), Some("127.0.0.1"));
+
+        let old = serde_json::json!({"address": "mosquitto", "port": 1883, "password": null});
+        let new = serde_json::json!({"address": "mosquitto", "port": 8883, "password": HIDDEN});
+        let updated = changes(Some(&old), Some(&new), true);
+        assert_eq!(
+            updated,
+            vec![
+                FieldChange {
+                    field: "port".to_string(),
+                    old: 1883.into(),
+                    new: 8883.into(),
+                },
+                FieldChange {
+                    field: "password".to_string(),
+                    old: Value::Null,
+                    new: HIDDEN.into(),
+                },
+            ]
+        );
+        log.record(
+            actor.clone(),
+            AuditAction::MainBrokerUpdated,
+            MAIN_BROKER_TARGET,
+            None,
+            updated,
+        );
+        log.record(
+            Actor::default(),
+            AuditAction::BrokerAdded,
+            "b1",
+            Some("Cloud"),
+            changes(None, Some(&new), false),
+        );
+
+        let entries = log.latest(10, None).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, AuditAction::BrokerAdded);
+        assert_eq!(entries[0].changes.len(), 3);
+        assert_eq!(entries[1].actor, actor);
+        let main = log.latest(10, Some(MAIN_BROKER_TARGET)).unwrap();
+        assert_eq!(main.len(), 1);
+        assert_eq!(log.latest(1, None).unwrap()[0].target, "b1");
+    }
+}
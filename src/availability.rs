@@ -0,0 +1,77 @@
+//! Availability messages for listener clients
+//!
+//! Devices connect to the proxy, not to the downstream brokers, so consumers on those
+//! brokers never see a device's session begin or end (and its last will, if any, only
+//! reaches the proxy). With `[availability]` enabled, the proxy publishes an online
+//! message when a client connects and an offline message when its connection ends,
+//! gracefully or not. The messages are forwarded like any other, so they reach the brokers
+//! whose topic filters match the availability topic.
+//!
+//! A client whose connection is taken over by a new one with the same ID stays online.
+
+use crate::broker_client;
+use crate::config::AvailabilityConfig;
+use bytes::Bytes;
+use rumqttc::QoS;
+
+#[derive(Default)]
+pub struct Availability {
+    config: AvailabilityConfig,
+}
+
+impl Availability {
+    pub fn new(config: AvailabilityConfig) -> Self {
+        Self { config }
+    }
+
+    /// Topic and payload of a client's availability message; None when disabled or when
+    /// the client ID can't be part of a topic
+    pub fn message(&self, client_id: &str, online: bool) -> Option<(String, Bytes)> {
+        if !self.config.enabled || client_id.contains(['+', '#', '/']) {
+            return None;
+        }
+        let payload = if online {
+            &self.config.online_payload
+        } else {
+            &self.config.offline_payload
+        };
+        Some((
+            self.config.topic.replace("{client_id}", client_id),
+            Bytes::from(payload.clone()),
+        ))
+    }
+
+    pub fn qos(&self) -> QoS {
+        broker_client::qos_from_level(self.config.qos)
+    }
+
+    pub fn retain(&self) -> bool {
+        self.config.retain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_availability_messages() {
+        assert_eq!(Availability::default().message("sensor-1", true), None);
+
+        let availability = Availability::new(AvailabilityConfig {
+            enabled: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            availability.message("sensor-1", true),
+            Some(("clients/sensor-1/status".to_string(), Bytes::from("online")))
+        );
+        assert_eq!(
+            availability.message("sensor-1", false).unwrap().1,
+            Bytes::from("offline")
+        );
+        assert_eq!(availability.message("site/sensor-1", true), None);
+        assert_eq!(availability.qos(), QoS::AtLeastOnce);
+        assert!(availability.retain());
+    }
+}
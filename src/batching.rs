@@ -0,0 +1,224 @@
+//! Coalescing of high-rate topics towards a downstream broker
+//!
+//! Telemetry aggregation targets often cope better with fewer, larger messages. A batching
+//! rule collects the messages on each matching topic and publishes them as one JSON array
+//! on the same topic once `maxMessages` are collected or `maxDelayMs` after the first one,
+//! whichever comes first. JSON payloads become array elements as they are, other payloads
+//! become strings. Batches are published with the highest QoS of their messages and
+//! without the retain flag or MQTT 5.0 properties.
+
+use crate::connection_manager::ConnectionManager;
+use bytes::Bytes;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Topics batched at once per broker; messages on further topics are forwarded singly
+const MAX_BATCHED_TOPICS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchRule {
+    /// Topic pattern the rule applies to (`+`/`#` wildcards)
+    pub topic: String,
+    #[serde(default = "default_max_messages")]
+    pub max_messages: usize,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+fn default_max_messages() -> usize {
+    100
+}
+
+fn default_max_delay_ms() -> u64 {
+    1000
+}
+
+struct PendingBatch {
+    rule: usize,
+    due: Instant,
+    qos: QoS,
+    elements: Vec<Value>,
+}
+
+/// A batch ready to publish
+#[derive(Debug, PartialEq)]
+pub struct Batch {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub messages: usize,
+}
+
+/// Per-topic batches for one broker
+pub struct Batcher {
+    rules: Vec<BatchRule>,
+    pending: HashMap<String, PendingBatch>,
+}
+
+impl Batcher {
+    pub fn new(rules: Vec<BatchRule>) -> Self {
+        Self {
+            rules,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Add a message to its topic's batch; returns the payload back if no rule batches
+    /// it, and the batch once it is full
+    pub fn push(
+        &mut self,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+        now: Instant,
+    ) -> Result<Option<Batch>, Bytes> {
+        if !self.pending.contains_key(topic) {
+            let Some(rule) = self.rules.iter().position(|rule| {
+                !rule.topic.is_empty()
+                    && ConnectionManager::topic_matches_pattern(&rule.topic, topic)
+            }) else {
+                return Err(payload);
+            };
+            if self.pending.len() >= MAX_BATCHED_TOPICS {
+                return Err(payload);
+            }
+            self.pending.insert(
+                topic.to_string(),
+                PendingBatch {
+                    rule,
+                    due: now + Duration::from_millis(self.rules[rule].max_delay_ms),
+                    qos,
+                    elements: Vec::new(),
+                },
+            );
+        }
+        let batch = self
+            .pending
+            .get_mut(topic)
+            .expect("batch was just inserted");
+        if qos > batch.qos {
+            batch.qos = qos;
+        }
+        batch.elements.push(
+            serde_json::from_slice(&payload)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&payload).into())),
+        );
+        if batch.elements.len() >= self.rules[batch.rule].max_messages.max(1) {
+            let batch = self.pending.remove(topic).expect("batch exists");
+            return Ok(Some(Self::finish(topic.to_string(), batch)));
+        }
+        Ok(None)
+    }
+
+    /// When the earliest batch is due
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.pending.values().map(|batch| batch.due).min()
+    }
+
+    /// Batches whose delay has passed
+    pub fn flush_due(&mut self, now: Instant) -> Vec<Batch> {
+        let due: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, batch)| batch.due <= now)
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|topic| {
+                let batch = self.pending.remove(&topic)?;
+                Some(Self::finish(topic, batch))
+            })
+            .collect()
+    }
+
+    /// Every batch, due or not (on shutdown)
+    pub fn flush_all(&mut self) -> Vec<Batch> {
+        self.pending
+            .drain()
+            .map(|(topic, batch)| Self::finish(topic, batch))
+            .collect()
+    }
+
+    fn finish(topic: String, batch: PendingBatch) -> Batch {
+        let messages = batch.elements.len();
+        Batch {
+            topic,
+            payload: Bytes::from(Value::Array(batch.elements).to_string()),
+            qos: batch.qos,
+            messages,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batches_flush_when_full_or_due() {
+        let mut batcher = Batcher::new(vec![BatchRule {
+            topic: "telemetry/#".to_string(),
+            max_messages: 3,
+            max_delay_ms: 500,
+        }]);
+        let start = Instant::now();
+
+        assert_eq!(
+            batcher.push("status", Bytes::from_static(b"up"), QoS::AtMostOnce, start),
+            Err(Bytes::from_static(b"up"))
+        );
+        assert_eq!(
+            batcher.push(
+                "telemetry/a",
+                Bytes::from_static(b"1"),
+                QoS::AtMostOnce,
+                start
+            ),
+            Ok(None)
+        );
+        assert_eq!(
+            batcher.push(
+                "telemetry/a",
+                Bytes::from_static(b"text"),
+                QoS::AtLeastOnce,
+                start
+            ),
+            Ok(None)
+        );
+        let full = batcher
+            .push(
+                "telemetry/a",
+                Bytes::from_static(br#"{"t":2}"#),
+                QoS::AtMostOnce,
+                start,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(full.payload, Bytes::from_static(br#"[1,"text",{"t":2}]"#));
+        assert_eq!((full.qos, full.messages), (QoS::AtLeastOnce, 3));
+
+        batcher
+            .push(
+                "telemetry/b",
+                Bytes::from_static(b"7"),
+                QoS::AtMostOnce,
+                start,
+            )
+            .unwrap();
+        assert_eq!(
+            batcher.next_flush(),
+            Some(start + Duration::from_millis(500))
+        );
+        assert!(batcher
+            .flush_due(start + Duration::from_millis(499))
+            .is_empty());
+        let due = batcher.flush_due(start + Duration::from_millis(500));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].payload, Bytes::from_static(b"[7]"));
+        assert_eq!(batcher.next_flush(), None);
+    }
+}
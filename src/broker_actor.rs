@@ -0,0 +1,2230 @@
+//! One task per downstream broker
+//!
+//! Each broker connection is owned by a single actor task: it holds the MQTT client, the
+//! reverse connection to the main broker and the flap detector, and is
+//! only reached through its command channel. The connection manager keeps a
+//! `BrokerHandle` per broker and never touches connection state directly, so replacing
+//! or removing a broker is a matter of shutting down one task and waiting for it.
+//!
+//! Eventloops are polled by small pump tasks owned by the actor. Polling them directly
+//! inside the actor's `select!` would drop in-flight `poll()` futures whenever a command
+//! arrives, which can stall reconnects under load.
+
+use crate::batching::{Batch, Batcher};
+use crate::broker_client::{
+    self, BrokerClient, BrokerEvent, BrokerEventLoop, PendingAck, PROTOCOL_V5,
+};
+use crate::broker_storage::{BrokerConfig, RetainPolicy, SubscriptionMode};
+use crate::client_registry::{ClientMessage, ClientRegistry};
+use crate::compression;
+use crate::connection_manager::build_tls_config;
+use crate::connection_manager::ConnectionManager;
+use crate::crypto;
+use crate::dedup::{DedupSettings, DedupStore};
+use crate::loop_prevention::{self, OriginTagger, ORIGIN_PROPERTY};
+use crate::metrics::{self, Metrics};
+use crate::mqtt_v5::{self, PropertyValue};
+use crate::offline_buffer::{OfflineBuffer, OfflineBufferConfig};
+use crate::replay_protection::{ReplayGuard, ReplayVerdict};
+use crate::resource_profile;
+use crate::sampling::Sampler;
+use crate::send_queue::{Pushed, QueuePolicy, SendQueue};
+use crate::stats::{BandwidthStats, RttHistory};
+use crate::subscription_table::{self, SubscriptionTable};
+use crate::topic_rewrite;
+use crate::transform::{self, PayloadTransform};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use rumqttc::{v5, AsyncClient, MqttOptions, QoS, TlsConfiguration, Transport};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+/// Session expiry requested from MQTT 5.0 brokers when `clean_session` is off
+const DEFAULT_SESSION_EXPIRY_SECS: u32 = 24 * 60 * 60;
+
+/// How long a forward waits for room in a full send queue with the `block` policy
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `publish_acked` waits for the broker's acknowledgement once the task accepted it
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long shutdown waits for the DISCONNECT to reach the broker
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A session shorter than this counts towards flapping
+const FLAP_SESSION_MAX: Duration = Duration::from_secs(10);
+/// Window in which short sessions are counted
+const FLAP_WINDOW: Duration = Duration::from_secs(60);
+/// Short sessions within the window that mark a broker as flapping
+const FLAP_THRESHOLD: usize = 3;
+
+/// Detects rapid ConnAck/disconnect cycles, the typical symptom of another client
+/// (a second proxy instance or a leftover process) taking over the same session
+#[derive(Default)]
+struct FlapDetector {
+    connected_at: Option<Instant>,
+    short_sessions: VecDeque<Instant>,
+    flapping: bool,
+}
+
+impl FlapDetector {
+    fn on_connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+    }
+
+    /// Returns true when this disconnect starts a flapping episode
+    fn on_disconnected(&mut self, now: Instant) -> bool {
+        let Some(connected_at) = self.connected_at.take() else {
+            // Connect attempt failed; not a session
+            return false;
+        };
+        if now.duration_since(connected_at) >= FLAP_SESSION_MAX {
+            return false;
+        }
+
+        self.short_sessions.push_back(now);
+        while self
+            .short_sessions
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > FLAP_WINDOW)
+        {
+            self.short_sessions.pop_front();
+        }
+
+        if !self.flapping && self.short_sessions.len() >= FLAP_THRESHOLD {
+            self.flapping = true;
+            return true;
+        }
+        false
+    }
+
+    /// A session survived a full keep-alive round trip, so it is stable again
+    fn on_stable(&mut self, now: Instant) {
+        if self
+            .connected_at
+            .is_some_and(|t| now.duration_since(t) >= FLAP_SESSION_MAX)
+        {
+            self.flapping = false;
+            self.short_sessions.clear();
+        }
+    }
+}
+
+/// Whether `topic` matches one of a broker's (non-empty) topic patterns
+fn matches_any(patterns: &[String], topic: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        !pattern.is_empty() && ConnectionManager::topic_matches_pattern(pattern, topic)
+    })
+}
+
+/// Create a hash from topic and payload for deduplication
+fn message_hash(topic: &str, payload: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    topic.hash(&mut hasher);
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Connection state published by the broker task for status queries
+#[derive(Default)]
+pub struct BrokerHealth {
+    pub connected: AtomicBool,
+    /// Set while the connection is flapping (see `FlapDetector`)
+    pub flapping: AtomicBool,
+    /// Keep-alive round trips measured by the broker task
+    pub rtt: RttHistory,
+    /// Messages held while disconnected (see `OfflineBuffer`)
+    pub buffered: AtomicUsize,
+    /// Time of the last event from the broker's eventloop, for the watchdog
+    pub last_progress: Mutex<Option<Instant>>,
+    /// Set while health-check probes fail or are slow (see `record_probe`)
+    pub degraded: AtomicBool,
+    /// Round trip of the last acknowledged health-check probe
+    pub probe_latency: Mutex<Option<Duration>>,
+    /// Health-check probes failed in a row
+    pub probe_failures: AtomicU32,
+}
+
+impl BrokerHealth {
+    /// Record a health-check probe's round trip, `None` if it failed. The broker is
+    /// degraded after `max_failures` failures in a row or a round trip above `max_latency`,
+    /// until a probe is acknowledged in time again. Returns whether it is degraded.
+    pub fn record_probe(
+        &self,
+        latency: Option<Duration>,
+        max_latency: Duration,
+        max_failures: u32,
+    ) -> bool {
+        let degraded = match latency {
+            Some(latency) => {
+                *self.probe_latency.lock() = Some(latency);
+                self.probe_failures.store(0, Ordering::Relaxed);
+                latency > max_latency
+            }
+            None => self.probe_failures.fetch_add(1, Ordering::Relaxed) + 1 >= max_failures,
+        };
+        self.degraded.store(degraded, Ordering::Relaxed);
+        degraded
+    }
+}
+
+/// Requests to a broker task other than publishes (see `SendQueue`)
+enum BrokerCommand {
+    /// Publish a health-check message straight to the connection (see `probe`)
+    Probe { topic: String, acked: AckSender },
+    /// Send the configured and local client subscriptions again
+    Resubscribe,
+}
+
+/// Why a publish did not reach a broker
+pub enum PublishError {
+    /// The broker's send queue is full or the broker did not acknowledge in time
+    Timeout,
+    Failed(anyhow::Error),
+}
+
+type AckSender = oneshot::Sender<Result<()>>;
+
+/// A publish handed to a broker's send queue
+pub struct PendingPublish {
+    queued: Enqueue,
+    acked: Option<oneshot::Receiver<Result<()>>>,
+}
+
+enum Enqueue {
+    Done(std::result::Result<(), PublishError>),
+    /// The queue is full and its policy is to wait for room
+    Blocked(Arc<SendQueue<OutgoingPublish>>, OutgoingPublish),
+}
+
+impl PendingPublish {
+    /// Wait until the message is in the broker's send queue, and for `publish_acked`
+    /// also until the broker acknowledged it
+    pub async fn outcome(self) -> std::result::Result<(), PublishError> {
+        match self.queued {
+            Enqueue::Done(result) => result?,
+            Enqueue::Blocked(queue, message) => {
+                enqueue_result(queue.push_wait(message, PUBLISH_TIMEOUT).await)?
+            }
+        }
+        let Some(acked) = self.acked else {
+            return Ok(());
+        };
+        match tokio::time::timeout(ACK_TIMEOUT, acked).await {
+            Ok(Ok(result)) => result.map_err(PublishError::Failed),
+            // Sampling dropped the message, or the connection lost track of it
+            Ok(Err(_)) => Err(PublishError::Failed(anyhow::anyhow!(
+                "Message was not sent to the broker"
+            ))),
+            Err(_) => Err(PublishError::Timeout),
+        }
+    }
+}
+
+fn enqueue_result(pushed: Pushed<OutgoingPublish>) -> std::result::Result<(), PublishError> {
+    match pushed {
+        Pushed::Queued => Ok(()),
+        Pushed::Evicted(evicted) => {
+            debug!(
+                "Send queue full; dropped the oldest message (on '{}')",
+                evicted.topic
+            );
+            Ok(())
+        }
+        Pushed::Full(_) => Err(PublishError::Timeout),
+        Pushed::Closed(_) => Err(PublishError::Failed(anyhow::anyhow!(
+            "Broker task has stopped"
+        ))),
+    }
+}
+
+/// A publish as handed to the client, held back by the sampler for keep-last
+struct OutgoingPublish {
+    topic: String,
+    payload: Bytes,
+    qos: QoS,
+    retain: bool,
+    properties: Option<mqtt_v5::Properties>,
+    acked: Option<AckSender>,
+    /// When the message was handed to the broker task; the time since is taken off its
+    /// message expiry interval
+    queued: Instant,
+}
+
+/// One of a broker's connections and the publishes waiting for its acknowledgements
+struct Connection {
+    client: BrokerClient,
+    connected: bool,
+    acks: PublishAcks<AckSender>,
+}
+
+impl Connection {
+    fn new(client: BrokerClient) -> Self {
+        Self {
+            client,
+            connected: false,
+            acks: PublishAcks::default(),
+        }
+    }
+
+    fn on_publish_sent(&mut self, pkid: u16) {
+        if let Some(acked) = self.acks.on_publish_sent(pkid) {
+            let _ = acked.send(Ok(()));
+        }
+    }
+
+    fn on_publish_acked(&mut self, pkid: u16) {
+        if let Some(acked) = self.acks.on_publish_acked(pkid) {
+            let _ = acked.send(Ok(()));
+        }
+    }
+}
+
+/// Whatever waits for the acknowledgements of the publishes handed to a client
+struct PublishAcks<T> {
+    /// Waiters of publishes handed to the client, in order. The client sends publishes
+    /// in the order it gets them, so each `PublishSent` event belongs to the front entry
+    /// (`None` for publishes nobody waits for).
+    unsent: VecDeque<Option<T>>,
+    /// Waiters of QoS 1/2 publishes on the wire, by packet ID, until the broker acks them
+    inflight: HashMap<u16, Option<T>>,
+}
+
+impl<T> Default for PublishAcks<T> {
+    fn default() -> Self {
+        Self {
+            unsent: VecDeque::new(),
+            inflight: HashMap::new(),
+        }
+    }
+}
+
+impl<T> PublishAcks<T> {
+    /// The waiter that is done now, for a QoS 0 publish
+    fn on_publish_sent(&mut self, pkid: u16) -> Option<T> {
+        // Unacknowledged publishes are sent again with their packet ID after a reconnect
+        if pkid != 0 && self.inflight.contains_key(&pkid) {
+            return None;
+        }
+        let waiter = self.unsent.pop_front()?;
+        if pkid == 0 {
+            // QoS 0 is never acknowledged; being on the wire is all there is
+            return waiter;
+        }
+        self.inflight.insert(pkid, waiter);
+        None
+    }
+
+    fn on_publish_acked(&mut self, pkid: u16) -> Option<T> {
+        self.inflight.remove(&pkid).flatten()
+    }
+}
+
+/// Output of the eventloop pump tasks
+enum PumpEvent {
+    /// An event of the downstream connection with this index
+    Downstream(usize, Result<BrokerEvent>),
+    Reverse(Result<BrokerEvent>),
+}
+
+/// The connection manager's side of a broker task
+pub struct BrokerHandle {
+    pub config: BrokerConfig,
+    pub health: Arc<BrokerHealth>,
+    queue: Arc<SendQueue<OutgoingPublish>>,
+    commands: mpsc::Sender<BrokerCommand>,
+    shutdown_tx: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl BrokerHandle {
+    /// Start the task that owns the connection to `config`'s broker
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn(
+        config: BrokerConfig,
+        main_broker_address: &str,
+        main_broker_port: u16,
+        main_broker_protocol: u8,
+        bandwidth: Arc<BandwidthStats>,
+        dedup: Arc<dyn DedupStore>,
+        dedup_settings: DedupSettings,
+        subscription_qos: QoS,
+        origin_tagger: Option<Arc<OriginTagger>>,
+        replay_guard: Option<Arc<ReplayGuard>>,
+        client_registry: Arc<ClientRegistry>,
+    ) -> Result<Self> {
+        let (actor, eventloops, main_eventloop) = BrokerActor::new(
+            &config,
+            main_broker_address,
+            main_broker_port,
+            main_broker_protocol,
+            bandwidth,
+            dedup,
+            dedup_settings,
+            subscription_qos,
+            origin_tagger,
+            replay_guard,
+            client_registry,
+        )?;
+        let limits = resource_profile::limits();
+        let queue = Arc::new(SendQueue::new(
+            config.send_queue.capacity.unwrap_or(limits.broker_commands),
+            config.send_queue.policy,
+        ));
+        let (commands, commands_rx) = mpsc::channel(limits.broker_commands);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let health = Arc::clone(&actor.health);
+        let task = tokio::spawn(
+            actor
+                .run(
+                    eventloops,
+                    main_eventloop,
+                    Arc::clone(&queue),
+                    commands_rx,
+                    shutdown_rx,
+                )
+                .instrument(info_span!("broker", broker = %config.name)),
+        );
+
+        Ok(Self {
+            config,
+            health,
+            queue,
+            commands,
+            shutdown_tx,
+            task,
+        })
+    }
+
+    /// Commands waiting for the broker task, and the queue's capacity
+    pub fn command_queue(&self) -> (usize, usize) {
+        let capacity = self.commands.max_capacity();
+        (capacity - self.commands.capacity(), capacity)
+    }
+
+    /// Messages waiting in the send queue, its capacity and the messages it dropped
+    pub fn send_queue(&self) -> (usize, usize, u64) {
+        (
+            self.queue.len(),
+            self.queue.capacity(),
+            self.queue.dropped(),
+        )
+    }
+
+    /// How long the broker's eventloop has gone without an event. A healthy one sees at
+    /// least a ping per keep-alive interval, or reconnect attempts while disconnected.
+    pub fn stalled_for(&self, now: Instant) -> Duration {
+        self.health
+            .last_progress
+            .lock()
+            .map_or(Duration::ZERO, |at| now.saturating_duration_since(at))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.health.connected.load(Ordering::Relaxed)
+    }
+
+    /// The topic a message on `topic` is published under on this broker
+    pub fn rewrite_topic<'a>(&self, topic: &'a str) -> Cow<'a, str> {
+        topic_rewrite::apply(&self.config.topic_rewrites, topic)
+    }
+
+    /// Whether the broker task keeps messages while disconnected
+    pub fn buffers_offline(&self) -> bool {
+        self.config.offline_buffer.max_messages > 0
+    }
+
+    /// Hand a message to the broker task without waiting; await the returned
+    /// `PendingPublish` for the outcome
+    pub fn publish(
+        &self,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+        properties: Option<&mqtt_v5::Properties>,
+    ) -> PendingPublish {
+        self.send_publish(topic, payload, qos, retain, properties, false)
+    }
+
+    /// Like `publish`, but the outcome waits for the broker's acknowledgement: PUBACK for
+    /// QoS 1, PUBCOMP for QoS 2, and the message being written to the network for QoS 0
+    pub fn publish_acked(
+        &self,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+        properties: Option<&mqtt_v5::Properties>,
+    ) -> PendingPublish {
+        self.send_publish(topic, payload, qos, retain, properties, true)
+    }
+
+    fn send_publish(
+        &self,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+        properties: Option<&mqtt_v5::Properties>,
+        ack: bool,
+    ) -> PendingPublish {
+        send_publish(&self.queue, topic, payload, qos, retain, properties, ack)
+    }
+
+    /// Publishes to this broker without borrowing the handle, e.g. from a task that runs
+    /// after the connection manager's lock is released
+    pub fn sender(&self) -> BrokerSender {
+        BrokerSender {
+            queue: Arc::clone(&self.queue),
+        }
+    }
+
+    /// Publish a QoS 1 probe on `topic`, bypassing sampling, batching and the offline
+    /// buffer; the receiver resolves once the broker acknowledged it. `None` if the task
+    /// is not accepting commands.
+    pub fn probe(&self, topic: &str) -> Option<oneshot::Receiver<Result<()>>> {
+        let (acked, acked_rx) = oneshot::channel();
+        self.commands
+            .try_send(BrokerCommand::Probe {
+                topic: topic.to_string(),
+                acked,
+            })
+            .ok()?;
+        Some(acked_rx)
+    }
+
+    /// Replay the broker's subscriptions and those of local clients; false if the task is
+    /// not accepting commands
+    pub fn resubscribe(&self) -> bool {
+        self.commands.try_send(BrokerCommand::Resubscribe).is_ok()
+    }
+
+    /// Disconnect from the broker and wait for its task (and the task's pumps) to finish
+    pub async fn shutdown(self) {
+        let _ = self.shutdown_tx.send(true);
+        let mut task = self.task;
+        if tokio::time::timeout(DISCONNECT_TIMEOUT * 2, &mut task)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Broker '{}' task did not stop in time; aborting",
+                self.config.name
+            );
+            task.abort();
+            let _ = task.await;
+        }
+    }
+}
+
+/// Hand a message to a broker task (see `BrokerHandle::sender`)
+#[derive(Clone)]
+pub struct BrokerSender {
+    queue: Arc<SendQueue<OutgoingPublish>>,
+}
+
+impl BrokerSender {
+    /// `BrokerHandle::publish`, or `publish_acked` with `ack`
+    pub fn publish(
+        &self,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+        properties: Option<&mqtt_v5::Properties>,
+        ack: bool,
+    ) -> PendingPublish {
+        send_publish(&self.queue, topic, payload, qos, retain, properties, ack)
+    }
+}
+
+fn send_publish(
+    queue: &Arc<SendQueue<OutgoingPublish>>,
+    topic: &str,
+    payload: Bytes,
+    qos: QoS,
+    retain: bool,
+    properties: Option<&mqtt_v5::Properties>,
+    ack: bool,
+) -> PendingPublish {
+    let (acked, acked_rx) = if ack {
+        let (tx, rx) = oneshot::channel();
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+    let message = OutgoingPublish {
+        topic: topic.to_string(),
+        payload,
+        qos,
+        retain,
+        properties: properties.cloned(),
+        acked,
+        queued: Instant::now(),
+    };
+    let queued = match queue.try_push(message) {
+        Pushed::Full(message) if queue.policy() == QueuePolicy::Block => {
+            Enqueue::Blocked(Arc::clone(queue), message)
+        }
+        pushed => Enqueue::Done(enqueue_result(pushed)),
+    };
+    PendingPublish {
+        queued,
+        acked: acked_rx,
+    }
+}
+
+/// State owned by a broker task
+struct BrokerActor {
+    broker_id: String,
+    name: String,
+    client_id: String,
+    bidirectional: bool,
+    protocol_version: u8,
+    persistent_session: bool,
+    /// Configured QoS of this broker's subscriptions
+    subscription_qos: QoS,
+    subscribe_topics: Vec<String>,
+    /// Subscribe only to demanded filters, not to `subscribe_topics` as such
+    on_demand: bool,
+    /// Filters local clients want, subscribed on bidirectional brokers
+    client_subscriptions: Arc<SubscriptionTable>,
+    /// Listener clients, which receive what this broker sends on their filters
+    client_registry: Arc<ClientRegistry>,
+    /// Client filters currently subscribed on this broker
+    client_filters: HashSet<String>,
+    /// Topic patterns whose payloads are gzip-compressed on this broker
+    compress_topics: Vec<String>,
+    /// Topic patterns whose payloads are encrypted on this broker
+    encrypt_topics: Vec<String>,
+    payload_key: Option<[u8; 32]>,
+    /// Topic patterns whose payloads are signed on this broker
+    sign_topics: Vec<String>,
+    signing_key: Option<Vec<u8>>,
+    /// Decimates high-volume topics before they reach this broker
+    sampler: Sampler<OutgoingPublish>,
+    /// Coalesces high-rate topics into JSON-array publishes
+    batcher: Batcher,
+    /// Messages forwarded while disconnected, sent on reconnect
+    offline: OfflineBuffer<OutgoingPublish>,
+    /// Messages evicted from the full offline buffer during the current outage
+    offline_evicted: usize,
+    /// Adapt payloads to this broker's schema
+    transforms: Vec<PayloadTransform>,
+    /// The first connection, which also subscribes and acknowledges
+    client: BrokerClient,
+    /// All connections to the broker (`connectionCount`); publishes take turns
+    connections: Vec<Connection>,
+    next_connection: usize,
+    /// Reverse connection to the main broker (bidirectional brokers only)
+    main_client: Option<BrokerClient>,
+    /// Messages relayed to the main broker, acknowledged to this broker once the main
+    /// broker acknowledged them
+    relay_acks: PublishAcks<PendingAck>,
+    /// Pass MQTT 5.0 properties of forwarded messages on to this broker
+    forward_properties: bool,
+    /// Retain flag of messages relayed to the main broker
+    reverse_retain: RetainPolicy,
+    health: Arc<BrokerHealth>,
+    bandwidth: Arc<BandwidthStats>,
+    metrics: Arc<Metrics>,
+    /// Records messages forwarded to this broker, for echo detection
+    dedup: Arc<dyn DedupStore>,
+    /// Identifies this broker in the dedup store
+    dedup_scope: String,
+    /// Whether echo detection runs for this broker, its window and cache limit
+    dedup_settings: DedupSettings,
+    /// Marks forwarded messages with this proxy's origin (loop prevention)
+    origin_tagger: Option<Arc<OriginTagger>>,
+    /// Drops commands this broker relays back more than once
+    replay_guard: Option<Arc<ReplayGuard>>,
+    flap_detector: FlapDetector,
+    /// Time the last PINGREQ went out, to measure the broker round trip on PINGRESP
+    ping_sent: Option<Instant>,
+}
+
+impl BrokerActor {
+    /// The actor for `config`'s broker, with the eventloops of its downstream connections
+    /// and of its reverse connection to the main broker
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        config: &BrokerConfig,
+        main_broker_address: &str,
+        main_broker_port: u16,
+        main_broker_protocol: u8,
+        bandwidth: Arc<BandwidthStats>,
+        dedup: Arc<dyn DedupStore>,
+        dedup_settings: DedupSettings,
+        subscription_qos: QoS,
+        origin_tagger: Option<Arc<OriginTagger>>,
+        replay_guard: Option<Arc<ReplayGuard>>,
+        client_registry: Arc<ClientRegistry>,
+    ) -> Result<(Self, Vec<BrokerEventLoop>, Option<BrokerEventLoop>)> {
+        let client_id = match config.client_id.as_deref().filter(|id| !id.is_empty()) {
+            Some(id) => id.to_string(),
+            None => format!("{}-{}", config.client_id_prefix, uuid::Uuid::new_v4()),
+        };
+        let keep_alive = Duration::from_secs(60);
+        let limits = resource_profile::limits();
+        // A resumable session needs a stable client ID; with one, subscribe at QoS 1 so the
+        // broker queues messages while the proxy is away, and ack only after relaying them
+        let persistent_session = !config.clean_session && config.client_id.is_some();
+        if !config.clean_session && !persistent_session {
+            warn!(
+                "Broker '{}' has cleanSession disabled but no clientId; the session cannot be resumed",
+                config.name
+            );
+        }
+        let credentials = config.username.as_ref().zip(config.password.as_ref());
+        // Encrypted topics need the key shared with the peer proxies
+        let payload_key = if config.encrypt_topics.is_empty() {
+            None
+        } else {
+            Some(
+                crypto::payload_key()
+                    .context("encryptTopics requires MQTT_PROXY_PAYLOAD_SECRET to be set")?,
+            )
+        };
+        let signing_key = if config.sign_topics.is_empty() {
+            None
+        } else {
+            Some(
+                crypto::signing_key()
+                    .context("signTopics requires MQTT_PROXY_SIGNING_SECRET to be set")?,
+            )
+        };
+
+        // Configure TLS if enabled
+        let transport = if config.use_tls {
+            let tls_config = build_tls_config(config)?;
+            if config.insecure_skip_verify {
+                warn!(
+                    "TLS enabled for broker '{}' (insecure: certificate verification disabled)",
+                    config.name
+                );
+            } else if config.skip_hostname_verification {
+                warn!(
+                    "TLS enabled for broker '{}' (hostname verification disabled)",
+                    config.name
+                );
+            } else {
+                info!("TLS enabled for broker '{}'", config.name);
+            }
+            Some(Transport::tls_with_config(TlsConfiguration::Rustls(
+                Arc::new(tls_config),
+            )))
+        } else {
+            None
+        };
+
+        // Only the first connection subscribes, so only it can have a session to resume
+        let connect = |client_id: &str, primary: bool| {
+            let clean_session = config.clean_session || !primary;
+            let manual_acks = persistent_session && primary;
+            let transport = transport.clone();
+            if config.protocol_version == PROTOCOL_V5 {
+                let mut mqtt_options =
+                    v5::MqttOptions::new(client_id, &config.address, config.port);
+                mqtt_options.set_keep_alive(keep_alive);
+                mqtt_options.set_clean_start(clean_session);
+                mqtt_options.set_manual_acks(manual_acks);
+                if !clean_session {
+                    // v5 sessions end on disconnect unless an expiry interval is requested
+                    mqtt_options.set_connect_properties(v5::mqttbytes::v5::ConnectProperties {
+                        session_expiry_interval: Some(DEFAULT_SESSION_EXPIRY_SECS),
+                        ..Default::default()
+                    });
+                }
+                if let Some((username, password)) = credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(transport) = transport {
+                    mqtt_options.set_transport(transport);
+                }
+                let (client, eventloop) = v5::AsyncClient::new(mqtt_options, limits.client_queue);
+                (
+                    BrokerClient::V5(client),
+                    BrokerEventLoop::V5(Box::new(eventloop)),
+                )
+            } else {
+                let mut mqtt_options = MqttOptions::new(client_id, &config.address, config.port);
+                mqtt_options.set_keep_alive(keep_alive);
+                mqtt_options.set_clean_session(clean_session);
+                mqtt_options.set_manual_acks(manual_acks);
+                if let Some((username, password)) = credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(transport) = transport {
+                    mqtt_options.set_transport(transport);
+                }
+                let (client, eventloop) = AsyncClient::new(mqtt_options, limits.client_queue);
+                (
+                    BrokerClient::V4(client),
+                    BrokerEventLoop::V4(Box::new(eventloop)),
+                )
+            }
+        };
+        // Extra connections only publish, to spread the load on fat links
+        let (connections, eventloops): (Vec<_>, Vec<_>) = (0..config.connection_count.max(1))
+            .map(|i| {
+                let (client, eventloop) = if i == 0 {
+                    connect(&client_id, true)
+                } else {
+                    connect(&format!("{}-{}", client_id, i), false)
+                };
+                (Connection::new(client), eventloop)
+            })
+            .unzip();
+        let client = connections[0].client.clone();
+
+        // Connection to the main broker for bidirectional traffic. It is only used for
+        // publishing: forward_message already covers main broker -> downstream
+        let reverse = if config.bidirectional {
+            let main_client_id = match config.client_id.as_deref().filter(|id| !id.is_empty()) {
+                Some(id) => format!("{}-reverse", id),
+                None => format!(
+                    "{}-reverse-{}",
+                    config.client_id_prefix,
+                    uuid::Uuid::new_v4()
+                ),
+            };
+            // Over MQTT 5.0, relayed messages keep their properties
+            Some(broker_client::plain_connection(
+                main_broker_protocol,
+                &main_client_id,
+                main_broker_address,
+                main_broker_port,
+                None,
+            ))
+        } else {
+            None
+        };
+
+        let health = Arc::new(BrokerHealth::default());
+        *health.last_progress.lock() = Some(Instant::now());
+
+        // Use subscription_topics if configured, otherwise fall back to topics
+        let subscribe_topics = if config.subscription_topics.is_empty() {
+            config.topics.clone()
+        } else {
+            config.subscription_topics.clone()
+        };
+
+        let (main_client, main_eventloop) = match reverse {
+            Some((client, eventloop)) => (Some(client), Some(eventloop)),
+            None => (None, None),
+        };
+        let actor = BrokerActor {
+            broker_id: config.id.clone(),
+            name: config.name.clone(),
+            client_id,
+            bidirectional: config.bidirectional,
+            protocol_version: config.protocol_version,
+            persistent_session,
+            subscription_qos,
+            subscribe_topics,
+            on_demand: config.subscription_mode == SubscriptionMode::OnDemand,
+            client_subscriptions: client_registry.subscription_table(),
+            client_registry,
+            client_filters: HashSet::new(),
+            compress_topics: config.compress_topics.clone(),
+            encrypt_topics: config.encrypt_topics.clone(),
+            payload_key,
+            sign_topics: config.sign_topics.clone(),
+            signing_key,
+            sampler: Sampler::new(config.sampling.clone()),
+            batcher: Batcher::new(config.batching.clone()),
+            offline: OfflineBuffer::new(&OfflineBufferConfig {
+                max_messages: config
+                    .offline_buffer
+                    .max_messages
+                    .min(limits.max_offline_buffer),
+                ..config.offline_buffer.clone()
+            }),
+            offline_evicted: 0,
+            transforms: config.transforms.clone(),
+            client,
+            connections,
+            next_connection: 0,
+            main_client,
+            relay_acks: PublishAcks::default(),
+            forward_properties: config.forward_properties,
+            reverse_retain: config.reverse_retain,
+            health,
+            bandwidth,
+            metrics: Metrics::global(),
+            dedup_scope: format!("{}:{}", config.address, config.port),
+            dedup,
+            dedup_settings,
+            // Only messages that can come back need tagging
+            origin_tagger: origin_tagger.filter(|_| config.bidirectional),
+            replay_guard: replay_guard.filter(|_| config.bidirectional),
+            flap_detector: FlapDetector::default(),
+            ping_sent: None,
+        };
+        Ok((actor, eventloops, main_eventloop))
+    }
+
+    async fn run(
+        mut self,
+        eventloops: Vec<BrokerEventLoop>,
+        main_eventloop: Option<BrokerEventLoop>,
+        queue: Arc<SendQueue<OutgoingPublish>>,
+        mut commands: mpsc::Receiver<BrokerCommand>,
+        mut shutdown_rx: watch::Receiver<bool>,
+    ) {
+        let (events_tx, mut events) = mpsc::channel(resource_profile::limits().broker_events);
+        let mut pumps: Vec<_> = eventloops
+            .into_iter()
+            .enumerate()
+            .map(|(index, eventloop)| {
+                tokio::spawn(pump_downstream(index, eventloop, events_tx.clone()))
+            })
+            .collect();
+        if let Some(main_eventloop) = main_eventloop {
+            info!("Starting reverse connection eventloop for '{}'", self.name);
+            pumps.push(tokio::spawn(pump_reverse(main_eventloop, events_tx)));
+        } else {
+            drop(events_tx);
+        }
+
+        let mut client_demand = self.client_subscriptions.watch();
+        loop {
+            let release_at = [self.sampler.next_release(), self.batcher.next_flush()]
+                .into_iter()
+                .flatten()
+                .min();
+            tokio::select! {
+                _ = shutdown_rx.changed() => break,
+                message = queue.pop() => self.handle_publish(message).await,
+                Some(command) = commands.recv() => self.handle_command(command).await,
+                Ok(()) = client_demand.changed(), if self.bidirectional => {
+                    self.sync_client_subscriptions()
+                }
+                _ = sleep_until_release(release_at), if release_at.is_some() => {
+                    self.release_sampled().await;
+                    let batches = self.batcher.flush_due(Instant::now());
+                    self.publish_batches(batches).await;
+                }
+                Some(event) = events.recv() => match event {
+                    PumpEvent::Downstream(0, result) => {
+                        *self.health.last_progress.lock() = Some(Instant::now());
+                        self.handle_event(result).await
+                    }
+                    PumpEvent::Downstream(index, result) => {
+                        self.handle_extra_event(index, result)
+                    }
+                    PumpEvent::Reverse(result) => self.handle_reverse_event(result),
+                },
+                else => break,
+            }
+        }
+
+        info!("Shutting down connection for broker '{}'", self.name);
+        queue.close();
+        let unsent = queue.drain();
+        if !unsent.is_empty() {
+            warn!(
+                "Dropping {} queued messages for broker '{}'",
+                unsent.len(),
+                self.name
+            );
+        }
+        let batches = self.batcher.flush_all();
+        self.publish_batches(batches).await;
+        self.set_connected(false);
+        if let Some(main_client) = &self.main_client {
+            let _ = main_client.try_disconnect();
+        }
+        let mut disconnecting = self
+            .connections
+            .iter()
+            .filter(|connection| connection.client.try_disconnect().is_ok())
+            .count();
+        if disconnecting > 0 {
+            // Keep the pumps running until the DISCONNECTs are on the wire
+            let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, async {
+                while let Some(event) = events.recv().await {
+                    if matches!(
+                        event,
+                        PumpEvent::Downstream(_, Ok(BrokerEvent::Disconnected))
+                    ) {
+                        disconnecting -= 1;
+                        if disconnecting == 0 {
+                            break;
+                        }
+                    }
+                }
+            })
+            .await;
+        }
+        for pump in pumps {
+            pump.abort();
+            let _ = pump.await;
+        }
+    }
+
+    async fn handle_publish(&mut self, message: OutgoingPublish) {
+        let topic = message.topic.clone();
+        // Messages dropped or held back by sampling are not forwarded now
+        let Some(message) = self.sampler.offer(&topic, message, Instant::now()) else {
+            return;
+        };
+        if let Err(e) = self.publish_or_batch(message).await {
+            warn!(
+                "Failed to forward message on '{}' to broker '{}': {}",
+                topic, self.name, e
+            );
+        }
+    }
+
+    async fn handle_command(&mut self, command: BrokerCommand) {
+        match command {
+            BrokerCommand::Probe { topic, acked } => {
+                if !self.health.connected.load(Ordering::Relaxed) {
+                    let _ = acked.send(Err(anyhow::anyhow!("Broker is not connected")));
+                    return;
+                }
+                let message = OutgoingPublish {
+                    topic,
+                    payload: Bytes::from(chrono::Utc::now().timestamp_millis().to_string()),
+                    qos: QoS::AtLeastOnce,
+                    retain: false,
+                    properties: None,
+                    acked: Some(acked),
+                    queued: Instant::now(),
+                };
+                if let Err(e) = self.send(message).await {
+                    debug!("Health-check probe to '{}' failed: {}", self.name, e);
+                }
+            }
+            BrokerCommand::Resubscribe => {
+                info!("Resubscribing on broker '{}'", self.name);
+                self.subscribe_bidirectional();
+                self.client_filters.clear();
+                self.sync_client_subscriptions();
+            }
+        }
+    }
+
+    async fn publish(&mut self, message: OutgoingPublish) -> Result<()> {
+        if self.offline.is_enabled() && !self.health.connected.load(Ordering::Relaxed) {
+            if let Some(evicted) = self.offline.push(message, Instant::now()) {
+                self.offline_evicted += 1;
+                if self.offline_evicted == 1 {
+                    warn!(
+                        "Offline buffer for broker '{}' is full; dropping the oldest messages (first on '{}')",
+                        self.name, evicted.topic
+                    );
+                }
+            }
+            self.health
+                .buffered
+                .store(self.offline.len(), Ordering::Relaxed);
+            return Ok(());
+        }
+        self.send(message).await
+    }
+
+    /// Publish the messages buffered while disconnected, oldest first
+    async fn flush_offline(&mut self) {
+        let (messages, expired) = self.offline.drain(Instant::now());
+        self.health.buffered.store(0, Ordering::Relaxed);
+        if messages.is_empty() && expired == 0 {
+            return;
+        }
+        info!(
+            "Sending {} messages buffered for broker '{}' while disconnected ({} expired, {} evicted)",
+            messages.len(),
+            self.name,
+            expired,
+            self.offline_evicted
+        );
+        self.offline_evicted = 0;
+        for message in messages {
+            let topic = message.topic.clone();
+            if let Err(e) = self.send(message).await {
+                warn!(
+                    "Failed to send buffered message on '{}' to broker '{}': {}",
+                    topic, self.name, e
+                );
+            }
+        }
+    }
+
+    async fn send(&mut self, message: OutgoingPublish) -> Result<()> {
+        let OutgoingPublish {
+            topic,
+            payload,
+            qos,
+            retain,
+            mut properties,
+            acked,
+            queued,
+        } = message;
+        if !self.forward_properties {
+            properties = None;
+        }
+        // A tag from another proxy would hide ours
+        loop_prevention::take_origin(&mut properties);
+        let payload = transform::apply(&self.transforms, &topic, payload);
+        let hash = message_hash(&topic, &payload);
+        let mut payload = self
+            .seal_payload(&topic, payload)
+            .context("Failed to encrypt payload")?;
+        if let Some(tagger) = &self.origin_tagger {
+            if self.protocol_version == PROTOCOL_V5 {
+                properties.get_or_insert_with(Default::default).0.push((
+                    mqtt_v5::property::USER_PROPERTY,
+                    PropertyValue::Utf8StringPair(ORIGIN_PROPERTY.to_string(), tagger.next_tag()),
+                ));
+            } else if tagger.wraps_payloads() {
+                payload = tagger.wrap(&payload);
+            }
+        }
+        let len = payload.len();
+        let connection = self.next_connection();
+        let published = connection.client.try_publish(
+            &topic,
+            qos,
+            retain,
+            payload,
+            properties.as_ref(),
+            queued.elapsed(),
+        )?;
+        if published {
+            connection.acks.unsent.push_back(acked);
+        } else {
+            debug!(
+                "Dropped message on '{}' for '{}': its message expiry interval passed",
+                topic, self.name
+            );
+            if let Some(acked) = acked {
+                let _ = acked.send(Err(anyhow::anyhow!("Message expired before it was sent")));
+            }
+            return Ok(());
+        }
+        self.bandwidth
+            .record_sent(&self.broker_id, &self.name, &topic, len);
+        // For bidirectional brokers, record the hash so we can detect echoes
+        if self.detects_echoes() {
+            if let Err(e) = self
+                .dedup
+                .record(&self.dedup_scope, hash, &self.dedup_settings)
+                .await
+            {
+                warn!(
+                    "Failed to record message for echo detection (broker: '{}'): {}",
+                    self.name, e
+                );
+            }
+            debug!(
+                "  📝 Recorded hash for echo detection (broker: '{}')",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Add a message to its topic's batch, or publish it if no batching rule matches.
+    /// Messages someone waits to see acknowledged are never batched.
+    async fn publish_or_batch(&mut self, mut message: OutgoingPublish) -> Result<()> {
+        if message.acked.is_some() {
+            return self.publish(message).await;
+        }
+        match self
+            .batcher
+            .push(&message.topic, message.payload, message.qos, Instant::now())
+        {
+            Ok(batch) => {
+                self.publish_batches(batch.into_iter().collect()).await;
+                Ok(())
+            }
+            Err(payload) => {
+                message.payload = payload;
+                self.publish(message).await
+            }
+        }
+    }
+
+    async fn publish_batches(&mut self, batches: Vec<Batch>) {
+        for batch in batches {
+            debug!(
+                "Publishing batch of {} messages on '{}' to broker '{}'",
+                batch.messages, batch.topic, self.name
+            );
+            let topic = batch.topic.clone();
+            let message = OutgoingPublish {
+                topic: batch.topic,
+                payload: batch.payload,
+                qos: batch.qos,
+                retain: false,
+                properties: None,
+                acked: None,
+                queued: Instant::now(),
+            };
+            if let Err(e) = self.publish(message).await {
+                warn!(
+                    "Failed to forward batch on '{}' to broker '{}': {}",
+                    topic, self.name, e
+                );
+            }
+        }
+    }
+
+    /// Forward the messages sampling held back once their window has ended
+    async fn release_sampled(&mut self) {
+        for message in self.sampler.release_due(Instant::now()) {
+            let topic = message.topic.clone();
+            if let Err(e) = self.publish_or_batch(message).await {
+                warn!(
+                    "Failed to forward sampled message on '{}' to broker '{}': {}",
+                    topic, self.name, e
+                );
+            }
+        }
+    }
+
+    /// Update the health flag, and the broker connection gauge on a change
+    fn set_connected(&self, connected: bool) {
+        if self.health.connected.swap(connected, Ordering::Relaxed) != connected {
+            if connected {
+                self.metrics.broker_connections.inc();
+            } else {
+                self.metrics.broker_connections.dec();
+            }
+        }
+    }
+
+    async fn handle_event(&mut self, result: Result<BrokerEvent>) {
+        match result {
+            Ok(BrokerEvent::ConnAck { session_present }) => {
+                self.set_connected(true);
+                self.connections[0].connected = true;
+                self.flap_detector.on_connected(Instant::now());
+                info!(
+                    "Broker '{}' connected as '{}' (bidirectional: {}, MQTT {})",
+                    self.name,
+                    self.client_id,
+                    self.bidirectional,
+                    if self.protocol_version == PROTOCOL_V5 {
+                        "5.0"
+                    } else {
+                        "3.1.1"
+                    }
+                );
+                if session_present {
+                    info!(
+                        "Broker '{}' resumed the previous session; queued messages will be relayed",
+                        self.name
+                    );
+                }
+
+                self.flush_offline().await;
+
+                // Subscribe to topics on bidirectional brokers to receive their messages
+                if self.bidirectional {
+                    self.subscribe_bidirectional();
+                    if !session_present {
+                        self.client_filters.clear();
+                    }
+                    self.sync_client_subscriptions();
+                }
+            }
+            Ok(BrokerEvent::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+                ack,
+                properties,
+            }) => {
+                self.relay_to_main(topic, payload, qos, retain, ack, properties)
+                    .await
+            }
+            Ok(BrokerEvent::PublishSent { pkid }) => self.connections[0].on_publish_sent(pkid),
+            Ok(BrokerEvent::PublishAcked { pkid }) => self.connections[0].on_publish_acked(pkid),
+            Ok(BrokerEvent::PingReq) => {
+                self.ping_sent = Some(Instant::now());
+            }
+            Ok(BrokerEvent::PingResp) => {
+                if let Some(sent) = self.ping_sent.take() {
+                    let elapsed = sent.elapsed();
+                    debug!(
+                        "Broker '{}' round trip: {:.1}ms",
+                        self.name,
+                        elapsed.as_secs_f64() * 1000.0
+                    );
+                    self.health.rtt.record(elapsed);
+                }
+                self.flap_detector.on_stable(Instant::now());
+                if !self.flap_detector.flapping
+                    && self.health.flapping.swap(false, Ordering::Relaxed)
+                {
+                    info!("Broker '{}' connection is stable again", self.name);
+                }
+            }
+            Ok(_) => {
+                // Other events - connection is active
+            }
+            Err(e) => {
+                self.ping_sent = None;
+                self.set_connected(false);
+                self.connections[0].connected = false;
+                if self.flap_detector.on_disconnected(Instant::now()) {
+                    self.health.flapping.store(true, Ordering::Relaxed);
+                    error!(
+                        "Broker '{}' connection is flapping ({} sessions under {}s within {}s) - \
+                         another client (a second proxy instance or a leftover process) \
+                         is likely taking over the session with the same client ID",
+                        self.name,
+                        FLAP_THRESHOLD,
+                        FLAP_SESSION_MAX.as_secs(),
+                        FLAP_WINDOW.as_secs()
+                    );
+                }
+                warn!("MQTT connection error for '{}': {}", self.name, e);
+            }
+        }
+    }
+
+    /// Events of the extra, publish-only connections
+    fn handle_extra_event(&mut self, index: usize, result: Result<BrokerEvent>) {
+        let connection = &mut self.connections[index];
+        match result {
+            Ok(BrokerEvent::ConnAck { .. }) => {
+                connection.connected = true;
+                debug!("Broker '{}' connection {} connected", self.name, index + 1);
+            }
+            Ok(BrokerEvent::PublishSent { pkid }) => connection.on_publish_sent(pkid),
+            Ok(BrokerEvent::PublishAcked { pkid }) => connection.on_publish_acked(pkid),
+            Ok(_) => {}
+            Err(e) => {
+                if connection.connected {
+                    warn!(
+                        "MQTT connection error for '{}' (connection {}): {}",
+                        self.name,
+                        index + 1,
+                        e
+                    );
+                }
+                connection.connected = false;
+            }
+        }
+    }
+
+    /// The connection for the next publish: connected ones take turns, and the first
+    /// connection queues while none is connected
+    fn next_connection(&mut self) -> &mut Connection {
+        let count = self.connections.len();
+        let index = (0..count)
+            .map(|offset| (self.next_connection + offset) % count)
+            .find(|&index| self.connections[index].connected)
+            .unwrap_or(0);
+        self.next_connection = (index + 1) % count;
+        &mut self.connections[index]
+    }
+
+    /// Filters subscribed on a bidirectional broker for its configured topics
+    fn bidirectional_filters(&self) -> Vec<String> {
+        if self.subscribe_topics.is_empty() {
+            vec!["#".to_string()] // Subscribe to all topics if none specified
+        } else {
+            self.subscribe_topics
+                .iter()
+                .map(|t| {
+                    if t.ends_with('#') || t.ends_with('+') {
+                        t.clone()
+                    } else {
+                        format!("{}/#", t)
+                    }
+                })
+                .collect()
+        }
+    }
+
+    /// A resumable session needs at least QoS 1 for the broker to queue messages
+    fn subscribe_qos(&self) -> QoS {
+        if self.persistent_session && self.subscription_qos == QoS::AtMostOnce {
+            QoS::AtLeastOnce
+        } else {
+            self.subscription_qos
+        }
+    }
+
+    fn subscribe_bidirectional(&self) {
+        // Subscriptions follow the demand instead (see `sync_client_subscriptions`)
+        if self.on_demand {
+            return;
+        }
+        for topic in &self.bidirectional_filters() {
+            match self.client.try_subscribe(topic, self.subscribe_qos()) {
+                Ok(_) => info!(
+                    "Subscribed to '{}' on bidirectional broker '{}'",
+                    topic, self.name
+                ),
+                Err(e) => warn!(
+                    "Failed to subscribe to '{}' on '{}': {}",
+                    topic, self.name, e
+                ),
+            }
+        }
+    }
+
+    /// Filters this broker should subscribe to for the current demand. In on-demand mode
+    /// these are limited to its configured topics, if it has any.
+    fn wanted_filters(&self) -> HashSet<String> {
+        let demand = self.client_subscriptions.filters();
+        if !self.on_demand || self.subscribe_topics.is_empty() {
+            return demand.into_iter().collect();
+        }
+        let allowed = self.bidirectional_filters();
+        demand
+            .iter()
+            .flat_map(|filter| subscription_table::restrict(filter, &allowed))
+            .collect()
+    }
+
+    /// Subscribe to the filters local clients (and main broker consumers) want that aren't
+    /// subscribed yet, and unsubscribe from those nobody wants anymore (see
+    /// `SubscriptionTable`)
+    fn sync_client_subscriptions(&mut self) {
+        if !self.bidirectional || !self.health.connected.load(Ordering::Relaxed) {
+            return;
+        }
+        let wanted = self.wanted_filters();
+        for filter in self.client_filters.difference(&wanted) {
+            // The broker's own subscriptions stay
+            if !self.on_demand && self.bidirectional_filters().contains(filter) {
+                continue;
+            }
+            match self.client.try_unsubscribe(filter) {
+                Ok(_) => debug!("Unsubscribed from '{}' on broker '{}'", filter, self.name),
+                Err(e) => warn!(
+                    "Failed to unsubscribe from '{}' on broker '{}': {}",
+                    filter, self.name, e
+                ),
+            }
+        }
+        let mut subscribed = HashSet::new();
+        for filter in wanted {
+            if self.client_filters.contains(&filter) {
+                subscribed.insert(filter);
+                continue;
+            }
+            match self.client.try_subscribe(&filter, self.subscribe_qos()) {
+                Ok(_) => {
+                    info!("📝 Subscribed to '{}' on broker '{}'", filter, self.name);
+                    subscribed.insert(filter);
+                }
+                Err(e) => warn!(
+                    "Failed to subscribe to '{}' on broker '{}': {}",
+                    filter, self.name, e
+                ),
+            }
+        }
+        self.client_filters = subscribed;
+    }
+
+    /// Forward a message from a bidirectional broker back to the main broker and to the
+    /// listener clients subscribed to it
+    async fn relay_to_main(
+        &mut self,
+        topic: String,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+        ack: PendingAck,
+        mut properties: Option<mqtt_v5::Properties>,
+    ) {
+        let received = Instant::now();
+        let origin = loop_prevention::take_origin(&mut properties);
+        // Acknowledged right away unless relayed, or left for the broker to redeliver
+        let mut ack = Some(ack);
+
+        if self.bidirectional && self.main_client.is_some() {
+            self.bandwidth
+                .record_received(&self.broker_id, &self.name, &topic, payload.len());
+
+            if let Some(tagger) = &self.origin_tagger {
+                // Our own messages coming back, however late
+                if origin.as_deref().is_some_and(|tag| tagger.is_own_tag(tag))
+                    || tagger.is_own_payload(&payload)
+                {
+                    debug!(
+                        "🔄 Skipping message from '{}' tagged with our origin: topic='{}'",
+                        self.name, topic
+                    );
+                    self.ack_relayed(ack.as_ref());
+                    return;
+                }
+            }
+            // Wrapped by a peer proxy: pass on the original payload
+            let payload = match loop_prevention::unwrap(&payload) {
+                Some((_, _, inner)) => payload.slice_ref(inner),
+                None => payload,
+            };
+
+            match self.open_payload(&topic, payload) {
+                // Never relay unsigned, plaintext or tampered messages on protected topics
+                Err(reason) => warn!(
+                    "Dropping message from '{}' on protected topic '{}': {}",
+                    self.name, topic, reason
+                ),
+                // Check if this message was recently forwarded TO this broker (echo detection)
+                Ok(payload)
+                    if self.detects_echoes()
+                        && self.is_echo(message_hash(&topic, &payload)).await =>
+                {
+                    debug!(
+                        "🔄 Skipping echo from '{}': topic='{}' (already on Mosquitto)",
+                        self.name, topic
+                    )
+                }
+                Ok(payload) if self.is_replayed_command(&topic, &payload, properties.as_ref()) => {}
+                Ok(payload) => {
+                    self.deliver_to_clients(&topic, &payload, qos, retain).await;
+                    if let Some(main_client) = &self.main_client {
+                        debug!(
+                            "📤 Publishing to main broker from '{}': topic='{}', {} bytes",
+                            self.name,
+                            topic,
+                            payload.len()
+                        );
+                        let retain = self.reverse_retain.apply(retain);
+                        let size = payload.len();
+                        match main_client.try_publish(
+                            &topic,
+                            qos,
+                            retain,
+                            payload,
+                            properties.as_ref(),
+                            received.elapsed(),
+                        ) {
+                            Ok(false) => debug!(
+                                "Dropped message from '{}' on '{}': its message expiry interval passed",
+                                self.name, topic
+                            ),
+                            Ok(true) => {
+                                self.metrics.observe_publish(
+                                    &self.name,
+                                    metrics::INBOUND,
+                                    size,
+                                    received.elapsed().as_secs_f64(),
+                                );
+                                // Acknowledged once the main broker has it
+                                self.relay_acks.unsent.push_back(ack.take());
+                            }
+                            Err(e) => {
+                                ack = None;
+                                warn!(
+                                    "Failed to publish to main broker from '{}': {}",
+                                    self.name, e
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        self.ack_relayed(ack.as_ref());
+    }
+
+    /// Whether replay protection drops a message received from this broker
+    fn is_replayed_command(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        properties: Option<&mqtt_v5::Properties>,
+    ) -> bool {
+        let Some(guard) = &self.replay_guard else {
+            return false;
+        };
+        match guard.check(
+            &self.broker_id,
+            topic,
+            payload,
+            properties,
+            chrono::Utc::now(),
+        ) {
+            ReplayVerdict::Accept => false,
+            ReplayVerdict::Replay => {
+                warn!(
+                    "Dropping replayed command from '{}' on '{}'",
+                    self.name, topic
+                );
+                true
+            }
+            ReplayVerdict::MissingId => {
+                warn!(
+                    "Dropping command without a correlation ID from '{}' on '{}'",
+                    self.name, topic
+                );
+                true
+            }
+        }
+    }
+
+    /// Pass a message received from this broker to the listener clients subscribed to its
+    /// topic, and keep it for later subscribers if it is retained
+    async fn deliver_to_clients(&self, topic: &str, payload: &Bytes, qos: QoS, retain: bool) {
+        // Each client gets it at no more than the QoS its subscription was granted
+        let message = ClientMessage {
+            topic: topic.to_string(),
+            payload: payload.clone(),
+            qos,
+            retain: false,
+        };
+        if retain {
+            self.client_registry.retain_message(ClientMessage {
+                retain: true,
+                ..message.clone()
+            });
+        }
+        self.client_registry
+            .forward_to_subscribers(topic, message)
+            .await;
+    }
+
+    /// Acknowledge a message that needs no redelivery
+    fn ack_relayed(&self, ack: Option<&PendingAck>) {
+        let Some(ack) = ack else {
+            return;
+        };
+        // Unacknowledged messages are redelivered by the broker on the next connect
+        if self.persistent_session {
+            if let Err(e) = self.client.try_ack(ack) {
+                warn!("Failed to acknowledge message from '{}': {}", self.name, e);
+            }
+        }
+    }
+
+    fn handle_reverse_event(&mut self, result: Result<BrokerEvent>) {
+        match result {
+            Ok(BrokerEvent::ConnAck { .. }) => {
+                info!(
+                    "Reverse connection to main broker established for '{}'",
+                    self.name
+                );
+            }
+            Ok(BrokerEvent::PublishSent { pkid }) => {
+                let ack = self.relay_acks.on_publish_sent(pkid);
+                self.ack_relayed(ack.as_ref());
+            }
+            Ok(BrokerEvent::PublishAcked { pkid }) => {
+                let ack = self.relay_acks.on_publish_acked(pkid);
+                self.ack_relayed(ack.as_ref());
+            }
+            Ok(_) => {
+                // Other events - outgoing publishes are being sent
+            }
+            Err(e) => warn!("Reverse connection error for '{}': {}", self.name, e),
+        }
+    }
+
+    /// Compress, encrypt and sign a payload published on this broker as its topic
+    /// requires. Returns None if encryption fails.
+    fn seal_payload(&self, topic: &str, payload: Bytes) -> Option<Bytes> {
+        // Compress first: ciphertext doesn't compress
+        let payload = if matches_any(&self.compress_topics, topic) {
+            Bytes::from(compression::gzip(&payload))
+        } else {
+            payload
+        };
+        let payload = match self.payload_key.as_ref() {
+            Some(key) if matches_any(&self.encrypt_topics, topic) => {
+                Bytes::from(crypto::encrypt_payload(key, topic, &payload)?)
+            }
+            _ => payload,
+        };
+        Some(match self.signing_key.as_deref() {
+            Some(key) if matches_any(&self.sign_topics, topic) => {
+                Bytes::from(crypto::sign_payload(key, topic, &payload))
+            }
+            _ => payload,
+        })
+    }
+
+    /// Verify, decrypt and decompress a payload received from this broker; the error says
+    /// why it must not be relayed
+    fn open_payload(
+        &self,
+        topic: &str,
+        payload: Bytes,
+    ) -> std::result::Result<Bytes, &'static str> {
+        let payload = match self.signing_key.as_deref() {
+            Some(key) if matches_any(&self.sign_topics, topic) => {
+                match crypto::verify_payload(key, topic, &payload) {
+                    Some(body) => payload.slice_ref(body),
+                    None => return Err("missing or invalid signature"),
+                }
+            }
+            _ => payload,
+        };
+        let payload = match self.payload_key.as_ref() {
+            Some(key) if matches_any(&self.encrypt_topics, topic) => {
+                crypto::decrypt_payload(key, topic, &payload)
+                    .map(Bytes::from)
+                    .ok_or("not encrypted with the shared key")?
+            }
+            _ => payload,
+        };
+        if !matches_any(&self.compress_topics, topic) {
+            return Ok(payload);
+        }
+        match compression::decompress(&payload, compression::DEFAULT_MAX_DECOMPRESSED_BYTES) {
+            Ok(Some(decompressed)) => Ok(Bytes::from(decompressed)),
+            Ok(None) => Ok(payload),
+            Err(_) => Err("corrupt or oversized compressed payload"),
+        }
+    }
+
+    /// Messages exchanged with this broker carry origin tags (loop prevention over MQTT
+    /// 5.0), which replace hash-based echo detection: that misses echoes slower than its
+    /// window and drops legitimate repeats of a message
+    fn tags_origin(&self) -> bool {
+        self.origin_tagger.is_some() && self.protocol_version == PROTOCOL_V5
+    }
+
+    /// Forwards are hashed and matching messages received back are dropped
+    fn detects_echoes(&self) -> bool {
+        self.bidirectional && self.dedup_settings.enabled && !self.tags_origin()
+    }
+
+    /// Whether `hash` matches a message recently forwarded to this broker
+    async fn is_echo(&self, hash: u64) -> bool {
+        match self
+            .dedup
+            .is_echo(&self.dedup_scope, hash, &self.dedup_settings)
+            .await
+        {
+            Ok(echo) => echo,
+            Err(e) => {
+                // Relaying a duplicate is better than dropping a message
+                warn!("Echo detection unavailable for '{}': {}", self.name, e);
+                false
+            }
+        }
+    }
+}
+
+/// Wait until the sampled messages and batches held back are due at `release_at`
+async fn sleep_until_release(release_at: Option<Instant>) {
+    if let Some(release_at) = release_at {
+        tokio::time::sleep_until(release_at.into()).await;
+    }
+}
+
+/// Drive the downstream eventloop and hand its events to the broker task
+async fn pump_downstream(
+    index: usize,
+    mut eventloop: BrokerEventLoop,
+    events: mpsc::Sender<PumpEvent>,
+) {
+    loop {
+        let result = eventloop.poll().await;
+        let failed = result.is_err();
+        if events
+            .send(PumpEvent::Downstream(index, result))
+            .await
+            .is_err()
+        {
+            break;
+        }
+        if failed {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// Drive the reverse connection's eventloop; it is needed to send the relayed publishes
+async fn pump_reverse(mut eventloop: BrokerEventLoop, events: mpsc::Sender<PumpEvent>) {
+    loop {
+        let result = eventloop.poll().await;
+        let failed = result.is_err();
+        if events.send(PumpEvent::Reverse(result)).await.is_err() {
+            break;
+        }
+        if failed {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+impl BrokerHandle {
+    /// A connected broker without a connection, for tests: publishes waiting for an
+    /// acknowledgement get one while `acks` is set and fail otherwise
+    pub(crate) fn stub(config: BrokerConfig, acks: Arc<AtomicBool>) -> Self {
+        let queue = Arc::new(SendQueue::new(16, QueuePolicy::default()));
+        let (commands, _) = mpsc::channel(1);
+        let (shutdown_tx, _) = watch::channel(false);
+        let health = Arc::new(BrokerHealth::default());
+        health.connected.store(true, Ordering::Relaxed);
+        let task = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move {
+                loop {
+                    let message = queue.pop().await;
+                    if let Some(acked) = message.acked {
+                        let _ = acked.send(if acks.load(Ordering::Relaxed) {
+                            Ok(())
+                        } else {
+                            Err(anyhow::anyhow!("Not acknowledged"))
+                        });
+                    }
+                }
+            }
+        });
+        Self {
+            config,
+            health,
+            queue,
+            commands,
+            shutdown_tx,
+            task,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker_client::PROTOCOL_V4;
+    use crate::dedup::MemoryDedupStore;
+    use async_trait::async_trait;
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
+
+    #[test]
+    fn test_probes_mark_broker_degraded() {
+        let health = BrokerHealth::default();
+        let max_latency = Duration::from_millis(500);
+        let fast = Some(Duration::from_millis(20));
+
+        assert!(!health.record_probe(fast, max_latency, 2));
+        assert!(!health.record_probe(None, max_latency, 2));
+        assert!(health.record_probe(None, max_latency, 2));
+        // An acknowledged probe clears it, a slow one sets it again
+        assert!(!health.record_probe(fast, max_latency, 2));
+        assert!(health.record_probe(Some(Duration::from_secs(1)), max_latency, 2));
+        assert_eq!(*health.probe_latency.lock(), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_flap_detector_flags_repeated_short_sessions() {
+        let mut detector = FlapDetector::default();
+        let start = Instant::now();
+
+        for i in 0..FLAP_THRESHOLD as u64 {
+            let t = start + Duration::from_secs(i * 5);
+            detector.on_connected(t);
+            let started = detector.on_disconnected(t + Duration::from_secs(1));
+            assert_eq!(started, i + 1 == FLAP_THRESHOLD as u64);
+        }
+        assert!(detector.flapping);
+
+        // Further short sessions don't raise a second alert
+        let t = start + Duration::from_secs(20);
+        detector.on_connected(t);
+        assert!(!detector.on_disconnected(t + Duration::from_secs(1)));
+
+        // A session that survives long enough clears the state
+        let t = start + Duration::from_secs(30);
+        detector.on_connected(t);
+        detector.on_stable(t + FLAP_SESSION_MAX);
+        assert!(!detector.flapping);
+    }
+
+    #[test]
+    fn test_flap_detector_ignores_long_sessions_and_failed_connects() {
+        let mut detector = FlapDetector::default();
+        let start = Instant::now();
+
+        for i in 0..10u64 {
+            // Failed connect attempts (no ConnAck)
+            assert!(!detector.on_disconnected(start + Duration::from_secs(i)));
+        }
+        for i in 0..10u64 {
+            let t = start + Duration::from_secs(100 + i * 60);
+            detector.on_connected(t);
+            assert!(!detector.on_disconnected(t + FLAP_SESSION_MAX));
+        }
+        assert!(!detector.flapping);
+    }
+
+    #[test]
+    fn test_relayed_messages_wait_for_the_main_broker_ack() {
+        let mut acks = PublishAcks::default();
+        acks.unsent.extend([Some("a"), Some("b"), Some("c")]);
+
+        // QoS 1 publishes get packet IDs and wait for the PUBACK; QoS 0 is done when sent
+        assert_eq!(acks.on_publish_sent(1), None);
+        assert_eq!(acks.on_publish_sent(0), Some("b"));
+        assert_eq!(acks.on_publish_sent(2), None);
+        // Sent again after a reconnect
+        assert_eq!(acks.on_publish_sent(1), None);
+        assert!(acks.unsent.is_empty());
+
+        assert_eq!(acks.on_publish_acked(2), Some("c"));
+        assert_eq!(acks.on_publish_acked(1), Some("a"));
+        assert_eq!(acks.on_publish_acked(1), None);
+    }
+
+    /// Counts the calls echo detection makes
+    #[derive(Default)]
+    struct CountingDedupStore {
+        records: AtomicUsize,
+        checks: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl DedupStore for CountingDedupStore {
+        async fn record(&self, _scope: &str, _hash: u64, _settings: &DedupSettings) -> Result<()> {
+            self.records.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        async fn is_echo(
+            &self,
+            _scope: &str,
+            _hash: u64,
+            _settings: &DedupSettings,
+        ) -> Result<bool> {
+            self.checks.fetch_add(1, Ordering::Relaxed);
+            Ok(false)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_origin_tags_replace_echo_detection_on_v5_only() {
+        for (protocol_version, hashed) in [(PROTOCOL_V5, 0), (PROTOCOL_V4, 1)] {
+            let config: BrokerConfig = serde_json::from_value(serde_json::json!({
+                "id": "b1",
+                "name": "Bridge",
+                "address": "127.0.0.1",
+                "port": 1,
+                "clientIdPrefix": "test",
+                "bidirectional": true,
+                "protocolVersion": protocol_version
+            }))
+            .unwrap();
+            let dedup = Arc::new(CountingDedupStore::default());
+            let (mut actor, _eventloops, _main_eventloop) = BrokerActor::new(
+                &config,
+                "127.0.0.1",
+                1,
+                4,
+                Arc::new(BandwidthStats::new()),
+                Arc::clone(&dedup) as Arc<dyn DedupStore>,
+                DedupSettings::default(),
+                QoS::AtMostOnce,
+                Some(Arc::new(OriginTagger::new("proxy-a", false))),
+                None,
+                Arc::new(ClientRegistry::new()),
+            )
+            .unwrap();
+
+            actor
+                .send(OutgoingPublish {
+                    topic: "sensors/1".to_string(),
+                    payload: Bytes::from_static(b"21.5"),
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                    properties: None,
+                    acked: None,
+                    queued: Instant::now(),
+                })
+                .await
+                .unwrap();
+            actor
+                .relay_to_main(
+                    "sensors/1".to_string(),
+                    Bytes::from_static(b"21.5"),
+                    QoS::AtMostOnce,
+                    false,
+                    PendingAck::V4(rumqttc::Publish::new("sensors/1", QoS::AtMostOnce, "21.5")),
+                    None,
+                )
+                .await;
+
+            assert_eq!(dedup.records.load(Ordering::Relaxed), hashed);
+            assert_eq!(dedup.checks.load(Ordering::Relaxed), hashed);
+        }
+    }
+
+    /// An MQTT 5.0 bridge whose publishes (to the broker and to the main broker) end up in
+    /// the returned receivers instead of a network connection
+    fn v5_bridge(
+        forward_properties: bool,
+    ) -> (
+        BrokerActor,
+        flume::Receiver<v5::Request>,
+        flume::Receiver<v5::Request>,
+    ) {
+        let config: BrokerConfig = serde_json::from_value(serde_json::json!({
+            "id": "b1",
+            "name": "Bridge",
+            "address": "127.0.0.1",
+            "port": 1,
+            "clientIdPrefix": "test",
+            "bidirectional": true,
+            "protocolVersion": PROTOCOL_V5,
+            "forwardProperties": forward_properties
+        }))
+        .unwrap();
+        let (mut actor, _eventloops, _main_eventloop) = BrokerActor::new(
+            &config,
+            "127.0.0.1",
+            1,
+            PROTOCOL_V5,
+            Arc::new(BandwidthStats::new()),
+            Arc::new(MemoryDedupStore::new()),
+            DedupSettings::default(),
+            QoS::AtMostOnce,
+            None,
+            None,
+            Arc::new(ClientRegistry::new()),
+        )
+        .unwrap();
+        let (broker_tx, broker_rx) = flume::unbounded();
+        actor.connections[0].client = BrokerClient::V5(v5::AsyncClient::from_senders(broker_tx));
+        let (main_tx, main_rx) = flume::unbounded();
+        actor.main_client = Some(BrokerClient::V5(v5::AsyncClient::from_senders(main_tx)));
+        (actor, broker_rx, main_rx)
+    }
+
+    fn properties(expiry: u32) -> mqtt_v5::Properties {
+        let mut properties = mqtt_v5::Properties::default();
+        properties.push(
+            mqtt_v5::property::CONTENT_TYPE,
+            PropertyValue::Utf8String("application/json".into()),
+        );
+        properties.push(
+            mqtt_v5::property::MESSAGE_EXPIRY_INTERVAL,
+            PropertyValue::FourByteInteger(expiry),
+        );
+        properties
+    }
+
+    fn published_properties(requests: &flume::Receiver<v5::Request>) -> Option<PublishProperties> {
+        match requests.try_recv().expect("a publish") {
+            v5::Request::Publish(publish) => publish.properties,
+            _ => panic!("expected a publish"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_properties_off_strips_outbound_properties() {
+        for (forward_properties, content_type) in [(true, Some("application/json")), (false, None)]
+        {
+            let (mut actor, broker_rx, _main_rx) = v5_bridge(forward_properties);
+            actor
+                .send(OutgoingPublish {
+                    topic: "sensors/1".to_string(),
+                    payload: Bytes::from_static(b"{}"),
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                    properties: Some(properties(60)),
+                    acked: None,
+                    queued: Instant::now(),
+                })
+                .await
+                .unwrap();
+            let properties = published_properties(&broker_rx);
+            assert_eq!(
+                properties.and_then(|p| p.content_type).as_deref(),
+                content_type
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expiry_interval_counts_time_in_the_proxy() {
+        let (mut actor, broker_rx, _main_rx) = v5_bridge(true);
+        let send = |queued_secs_ago: u64, acked: Option<AckSender>| OutgoingPublish {
+            topic: "sensors/1".to_string(),
+            payload: Bytes::from_static(b"{}"),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: Some(properties(60)),
+            acked,
+            queued: Instant::now() - Duration::from_secs(queued_secs_ago),
+        };
+
+        actor.send(send(20, None)).await.unwrap();
+        let properties = published_properties(&broker_rx).unwrap();
+        assert_eq!(properties.message_expiry_interval, Some(40));
+
+        // Expired while waiting: dropped, and whoever waits for it is told
+        let (acked, acked_rx) = oneshot::channel();
+        actor.send(send(61, Some(acked))).await.unwrap();
+        assert!(broker_rx.try_recv().is_err());
+        assert!(acked_rx.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relayed_messages_keep_properties() {
+        // forward_properties only applies to messages sent to the broker
+        let (mut actor, _broker_rx, main_rx) = v5_bridge(false);
+        let mut properties = properties(60);
+        properties.push(
+            mqtt_v5::property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair("site".into(), "north".into()),
+        );
+        actor
+            .relay_to_main(
+                "commands/1".to_string(),
+                Bytes::from_static(b"{}"),
+                QoS::AtMostOnce,
+                false,
+                PendingAck::V4(rumqttc::Publish::new("commands/1", QoS::AtMostOnce, "{}")),
+                Some(properties),
+            )
+            .await;
+
+        let properties = published_properties(&main_rx).unwrap();
+        assert_eq!(properties.content_type.as_deref(), Some("application/json"));
+        assert_eq!(properties.message_expiry_interval, Some(60));
+        assert_eq!(
+            properties.user_properties,
+            vec![("site".to_string(), "north".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extra_connections_take_turns_and_route_acks() {
+        let config: BrokerConfig = serde_json::from_value(serde_json::json!({
+            "id": "b1",
+            "name": "Pooled",
+            "address": "127.0.0.1",
+            "port": 1,
+            "clientIdPrefix": "test",
+            "connectionCount": 3
+        }))
+        .unwrap();
+        let (mut actor, _eventloops, _main_eventloop) = BrokerActor::new(
+            &config,
+            "127.0.0.1",
+            1,
+            4,
+            Arc::new(BandwidthStats::new()),
+            Arc::new(MemoryDedupStore::new()),
+            DedupSettings::default(),
+            QoS::AtMostOnce,
+            None,
+            None,
+            Arc::new(ClientRegistry::new()),
+        )
+        .unwrap();
+        let requests: Vec<_> = actor
+            .connections
+            .iter_mut()
+            .map(|connection| {
+                let (tx, rx) = flume::unbounded();
+                connection.client = BrokerClient::V4(AsyncClient::from_senders(tx));
+                rx
+            })
+            .collect();
+        let publish = || {
+            let (acked, acked_rx) = oneshot::channel();
+            let message = OutgoingPublish {
+                topic: "sensors/1".to_string(),
+                payload: Bytes::from_static(b"21.5"),
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                properties: None,
+                acked: Some(acked),
+                queued: Instant::now(),
+            };
+            (message, acked_rx)
+        };
+        let sent_on = |requests: &[flume::Receiver<rumqttc::Request>]| -> Vec<usize> {
+            requests.iter().map(|rx| rx.drain().count()).collect()
+        };
+
+        // Nothing connected: everything queues on the first connection
+        for _ in 0..2 {
+            let (message, _) = publish();
+            actor.send(message).await.unwrap();
+        }
+        assert_eq!(sent_on(&requests), vec![2, 0, 0]);
+
+        // The connected extra connections take turns; the first one isn't connected
+        for index in [1, 2] {
+            actor.handle_extra_event(
+                index,
+                Ok(BrokerEvent::ConnAck {
+                    session_present: false,
+                }),
+            );
+        }
+        let mut pending = Vec::new();
+        for _ in 0..3 {
+            let (message, acked_rx) = publish();
+            actor.send(message).await.unwrap();
+            pending.push(acked_rx);
+        }
+        assert_eq!(sent_on(&requests), vec![0, 2, 1]);
+        let [mut first, mut second, mut third]: [_; 3] = pending.try_into().unwrap();
+
+        // Acknowledgements resolve the publish of the connection they arrived on
+        actor.handle_extra_event(2, Ok(BrokerEvent::PublishSent { pkid: 1 }));
+        actor.handle_extra_event(2, Ok(BrokerEvent::PublishAcked { pkid: 1 }));
+        assert!(second.try_recv().unwrap().is_ok());
+        assert!(first.try_recv().is_err());
+        actor.handle_extra_event(1, Ok(BrokerEvent::PublishSent { pkid: 1 }));
+        actor.handle_extra_event(1, Ok(BrokerEvent::PublishSent { pkid: 2 }));
+        actor.handle_extra_event(1, Ok(BrokerEvent::PublishAcked { pkid: 2 }));
+        assert!(third.try_recv().unwrap().is_ok());
+        assert!(first.try_recv().is_err());
+
+        // A connection that drops out is skipped
+        actor.handle_extra_event(1, Err(anyhow::anyhow!("connection reset")));
+        for _ in 0..2 {
+            let (message, _) = publish();
+            actor.send(message).await.unwrap();
+        }
+        assert_eq!(sent_on(&requests), vec![0, 0, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_task_without_broker() {
+        let config: BrokerConfig = serde_json::from_value(serde_json::json!({
+            "id": "b1",
+            "name": "Unreachable",
+            "address": "127.0.0.1",
+            "port": 1,
+            "clientIdPrefix": "test",
+            "enabled": true,
+            "connectionCount": 3
+        }))
+        .unwrap();
+        let handle = BrokerHandle::spawn(
+            config,
+            "127.0.0.1",
+            1,
+            4,
+            Arc::new(BandwidthStats::new()),
+            Arc::new(MemoryDedupStore::new()),
+            DedupSettings::default(),
+            QoS::AtMostOnce,
+            None,
+            None,
+            Arc::new(ClientRegistry::new()),
+        )
+        .unwrap();
+        assert!(!handle.is_connected());
+
+        tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .expect("broker task should stop");
+    }
+}
@@ -0,0 +1,409 @@
+//! Protocol-agnostic wrapper around rumqttc's MQTT 3.1.1 and 5.0 clients
+//!
+//! Downstream brokers can be configured with either protocol version. The connection
+//! manager talks to both through `BrokerClient` and `BrokerEventLoop` so forwarding,
+//! echo detection and statistics stay in one place.
+
+use crate::mqtt_v5::{self, PropertyValue};
+use crate::resource_profile;
+use anyhow::Result;
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use rumqttc::{v5, AsyncClient, Event, EventLoop, Incoming, MqttOptions, Outgoing, QoS};
+use std::time::Duration;
+
+/// Default protocol level for downstream brokers (MQTT 3.1.1)
+pub const PROTOCOL_V4: u8 = 4;
+/// Protocol level for MQTT 5.0 downstream brokers
+pub const PROTOCOL_V5: u8 = 5;
+
+/// QoS for a configured level (0, 1 or 2; anything higher counts as 2)
+pub fn qos_from_level(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+/// Publishing side of a downstream broker connection
+#[derive(Clone)]
+pub enum BrokerClient {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl BrokerClient {
+    /// Queue a message for publishing; properties are only sent to MQTT 5.0 brokers.
+    /// Never waits, so it is safe to call from the task that drives the eventloop.
+    /// `waited` is how long the message was held since it was received; returns false
+    /// without publishing if that used up its message expiry interval.
+    pub fn try_publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Bytes,
+        properties: Option<&mqtt_v5::Properties>,
+        waited: Duration,
+    ) -> Result<bool> {
+        let properties = match properties.filter(|p| !p.is_empty()) {
+            Some(properties) => match publish_properties(properties, waited) {
+                Some(properties) => Some(properties),
+                None => return Ok(false),
+            },
+            None => None,
+        };
+        match self {
+            BrokerClient::V4(client) => client.try_publish(topic, qos, retain, payload)?,
+            BrokerClient::V5(client) => match properties {
+                Some(properties) => client.try_publish_with_properties(
+                    topic,
+                    to_v5_qos(qos),
+                    retain,
+                    payload,
+                    properties,
+                )?,
+                None => client.try_publish(topic, to_v5_qos(qos), retain, payload)?,
+            },
+        }
+        Ok(true)
+    }
+
+    /// Subscribe, waiting for room in the client's request queue
+    pub async fn subscribe(&self, topic: &str, qos: QoS) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.subscribe(topic, qos).await?,
+            BrokerClient::V5(client) => client.subscribe(topic, to_v5_qos(qos)).await?,
+        }
+        Ok(())
+    }
+
+    pub fn try_subscribe(&self, topic: &str, qos: QoS) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.try_subscribe(topic, qos)?,
+            BrokerClient::V5(client) => client.try_subscribe(topic, to_v5_qos(qos))?,
+        }
+        Ok(())
+    }
+
+    pub fn try_unsubscribe(&self, topic: &str) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.try_unsubscribe(topic)?,
+            BrokerClient::V5(client) => client.try_unsubscribe(topic)?,
+        }
+        Ok(())
+    }
+
+    /// Acknowledge a received publish (only when the connection uses manual acks)
+    pub fn try_ack(&self, ack: &PendingAck) -> Result<()> {
+        match (self, ack) {
+            (BrokerClient::V4(client), PendingAck::V4(publish)) => client.try_ack(publish)?,
+            (BrokerClient::V5(client), PendingAck::V5(publish)) => client.try_ack(publish)?,
+            _ => anyhow::bail!("Acknowledgement does not match the client protocol"),
+        }
+        Ok(())
+    }
+
+    pub fn try_disconnect(&self) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.try_disconnect()?,
+            BrokerClient::V5(client) => client.try_disconnect()?,
+        }
+        Ok(())
+    }
+}
+
+/// Plain TCP connection without a persistent session, as used for the main broker
+pub fn plain_connection(
+    protocol_version: u8,
+    client_id: &str,
+    address: &str,
+    port: u16,
+    credentials: Option<(&str, &str)>,
+) -> (BrokerClient, BrokerEventLoop) {
+    let keep_alive = Duration::from_secs(60);
+    let cap = resource_profile::limits().client_queue;
+    if protocol_version == PROTOCOL_V5 {
+        let mut options = v5::MqttOptions::new(client_id, address, port);
+        options.set_keep_alive(keep_alive);
+        if let Some((username, password)) = credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, eventloop) = v5::AsyncClient::new(options, cap);
+        (
+            BrokerClient::V5(client),
+            BrokerEventLoop::V5(Box::new(eventloop)),
+        )
+    } else {
+        let mut options = MqttOptions::new(client_id, address, port);
+        options.set_keep_alive(keep_alive);
+        if let Some((username, password)) = credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, eventloop) = AsyncClient::new(options, cap);
+        (
+            BrokerClient::V4(client),
+            BrokerEventLoop::V4(Box::new(eventloop)),
+        )
+    }
+}
+
+/// A received publish that still has to be acknowledged to the broker
+pub enum PendingAck {
+    V4(rumqttc::Publish),
+    V5(Box<v5::mqttbytes::v5::Publish>),
+}
+
+/// Events from a downstream broker the connection handler acts on
+pub enum BrokerEvent {
+    ConnAck {
+        /// The broker resumed a previous session (queued messages will follow)
+        session_present: bool,
+    },
+    Publish {
+        topic: String,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+        /// Pass to `BrokerClient::try_ack` once handled; ignore unless manual acks are enabled
+        ack: PendingAck,
+        /// PUBLISH properties (MQTT 5.0 only), in the listener's form so they can be
+        /// forwarded as received. Includes the `x-proxy-origin` tag, see `loop_prevention`.
+        properties: Option<mqtt_v5::Properties>,
+    },
+    /// One of our PUBLISHes was written to the network (packet ID 0 for QoS 0)
+    PublishSent {
+        pkid: u16,
+    },
+    /// The broker acknowledged one of our PUBLISHes (PUBACK for QoS 1, PUBCOMP for QoS 2)
+    PublishAcked {
+        pkid: u16,
+    },
+    PingReq,
+    PingResp,
+    /// Our DISCONNECT was written to the network
+    Disconnected,
+    Other,
+}
+
+/// Receiving side of a downstream broker connection
+pub enum BrokerEventLoop {
+    V4(Box<EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+impl BrokerEventLoop {
+    /// Drive the connection and return the next relevant event
+    pub async fn poll(&mut self) -> Result<BrokerEvent> {
+        match self {
+            BrokerEventLoop::V4(eventloop) => Ok(match eventloop.poll().await? {
+                Event::Incoming(Incoming::ConnAck(connack)) => BrokerEvent::ConnAck {
+                    session_present: connack.session_present,
+                },
+                Event::Incoming(Incoming::Publish(publish)) => BrokerEvent::Publish {
+                    topic: publish.topic.clone(),
+                    payload: publish.payload.clone(),
+                    qos: publish.qos,
+                    retain: publish.retain,
+                    ack: PendingAck::V4(publish),
+                    properties: None,
+                },
+                Event::Incoming(Incoming::PubAck(puback)) => {
+                    BrokerEvent::PublishAcked { pkid: puback.pkid }
+                }
+                Event::Incoming(Incoming::PubComp(pubcomp)) => {
+                    BrokerEvent::PublishAcked { pkid: pubcomp.pkid }
+                }
+                Event::Incoming(Incoming::PingResp) => BrokerEvent::PingResp,
+                Event::Outgoing(Outgoing::Publish(pkid)) => BrokerEvent::PublishSent { pkid },
+                Event::Outgoing(Outgoing::PingReq) => BrokerEvent::PingReq,
+                Event::Outgoing(Outgoing::Disconnect) => BrokerEvent::Disconnected,
+                _ => BrokerEvent::Other,
+            }),
+            BrokerEventLoop::V5(eventloop) => Ok(match eventloop.poll().await? {
+                v5::Event::Incoming(v5::Incoming::ConnAck(connack)) => BrokerEvent::ConnAck {
+                    session_present: connack.session_present,
+                },
+                v5::Event::Incoming(v5::Incoming::Publish(publish)) => BrokerEvent::Publish {
+                    topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                    payload: publish.payload.clone(),
+                    qos: from_v5_qos(publish.qos),
+                    retain: publish.retain,
+                    properties: publish.properties.as_ref().map(from_publish_properties),
+                    ack: PendingAck::V5(Box::new(publish)),
+                },
+                v5::Event::Incoming(v5::Incoming::PubAck(puback)) => {
+                    BrokerEvent::PublishAcked { pkid: puback.pkid }
+                }
+                v5::Event::Incoming(v5::Incoming::PubComp(pubcomp)) => {
+                    BrokerEvent::PublishAcked { pkid: pubcomp.pkid }
+                }
+                v5::Event::Incoming(v5::Incoming::PingResp(_)) => BrokerEvent::PingResp,
+                v5::Event::Outgoing(Outgoing::Publish(pkid)) => BrokerEvent::PublishSent { pkid },
+                v5::Event::Outgoing(Outgoing::PingReq) => BrokerEvent::PingReq,
+                v5::Event::Outgoing(Outgoing::Disconnect) => BrokerEvent::Disconnected,
+                _ => BrokerEvent::Other,
+            }),
+        }
+    }
+}
+
+fn to_v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn from_v5_qos(qos: v5::mqttbytes::QoS) -> QoS {
+    match qos {
+        v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+        v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+        v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+/// Map listener-side PUBLISH properties onto rumqttc's outgoing properties.
+/// Topic aliases and subscription identifiers are per-connection and are not forwarded.
+/// The message expiry interval is reduced by `waited`, the time the message was held, as
+/// a broker does; `None` once it has expired.
+pub fn publish_properties(
+    properties: &mqtt_v5::Properties,
+    waited: Duration,
+) -> Option<PublishProperties> {
+    let waited = u32::try_from(waited.as_secs()).unwrap_or(u32::MAX);
+    let mut out = PublishProperties::default();
+    for (id, value) in &properties.0 {
+        match (*id, value) {
+            (mqtt_v5::property::PAYLOAD_FORMAT_INDICATOR, PropertyValue::Byte(v)) => {
+                out.payload_format_indicator = Some(*v)
+            }
+            (mqtt_v5::property::MESSAGE_EXPIRY_INTERVAL, PropertyValue::FourByteInteger(v)) => {
+                match v.checked_sub(waited) {
+                    // Held for the whole interval
+                    Some(0) if waited > 0 => return None,
+                    Some(remaining) => out.message_expiry_interval = Some(remaining),
+                    None => return None,
+                }
+            }
+            (mqtt_v5::property::CONTENT_TYPE, PropertyValue::Utf8String(v)) => {
+                out.content_type = Some(v.clone())
+            }
+            (mqtt_v5::property::RESPONSE_TOPIC, PropertyValue::Utf8String(v)) => {
+                out.response_topic = Some(v.clone())
+            }
+            (mqtt_v5::property::CORRELATION_DATA, PropertyValue::BinaryData(v)) => {
+                out.correlation_data = Some(Bytes::copy_from_slice(v))
+            }
+            (mqtt_v5::property::USER_PROPERTY, PropertyValue::Utf8StringPair(k, v)) => {
+                out.user_properties.push((k.clone(), v.clone()))
+            }
+            _ => {}
+        }
+    }
+    Some(out)
+}
+
+/// Map the properties of a PUBLISH received from an MQTT 5.0 broker back to the listener's
+/// form, so they can be forwarded like those of a client publish
+pub fn from_publish_properties(properties: &PublishProperties) -> mqtt_v5::Properties {
+    let mut out = mqtt_v5::Properties::default();
+    if let Some(v) = properties.payload_format_indicator {
+        out.push(
+            mqtt_v5::property::PAYLOAD_FORMAT_INDICATOR,
+            PropertyValue::Byte(v),
+        );
+    }
+    if let Some(v) = properties.message_expiry_interval {
+        out.push(
+            mqtt_v5::property::MESSAGE_EXPIRY_INTERVAL,
+            PropertyValue::FourByteInteger(v),
+        );
+    }
+    if let Some(v) = &properties.content_type {
+        out.push(
+            mqtt_v5::property::CONTENT_TYPE,
+            PropertyValue::Utf8String(v.clone()),
+        );
+    }
+    if let Some(v) = &properties.response_topic {
+        out.push(
+            mqtt_v5::property::RESPONSE_TOPIC,
+            PropertyValue::Utf8String(v.clone()),
+        );
+    }
+    if let Some(v) = &properties.correlation_data {
+        out.push(
+            mqtt_v5::property::CORRELATION_DATA,
+            PropertyValue::BinaryData(v.to_vec()),
+        );
+    }
+    for (k, v) in &properties.user_properties {
+        out.push(
+            mqtt_v5::property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair(k.clone(), v.clone()),
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_properties_mapping() {
+        let mut props = mqtt_v5::Properties::default();
+        props.push(
+            mqtt_v5::property::MESSAGE_EXPIRY_INTERVAL,
+            PropertyValue::FourByteInteger(30),
+        );
+        props.push(
+            mqtt_v5::property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair("site".into(), "north".into()),
+        );
+        props.push(
+            mqtt_v5::property::TOPIC_ALIAS,
+            PropertyValue::TwoByteInteger(3),
+        );
+
+        let out = publish_properties(&props, Duration::ZERO).unwrap();
+        assert_eq!(out.message_expiry_interval, Some(30));
+        assert_eq!(
+            out.user_properties,
+            vec![("site".to_string(), "north".to_string())]
+        );
+        assert_eq!(out.topic_alias, None);
+
+        // Properties from an MQTT 5.0 broker come back in the listener's form
+        let back = from_publish_properties(&out);
+        assert_eq!(
+            back.get(mqtt_v5::property::MESSAGE_EXPIRY_INTERVAL),
+            Some(&PropertyValue::FourByteInteger(30))
+        );
+        assert_eq!(
+            back.user_properties().collect::<Vec<_>>(),
+            vec![("site", "north")]
+        );
+        assert_eq!(back.get(mqtt_v5::property::TOPIC_ALIAS), None);
+    }
+
+    #[test]
+    fn test_message_expiry_counts_down_while_held() {
+        let mut props = mqtt_v5::Properties::default();
+        props.push(
+            mqtt_v5::property::MESSAGE_EXPIRY_INTERVAL,
+            PropertyValue::FourByteInteger(30),
+        );
+        let expiry = |waited| {
+            publish_properties(&props, Duration::from_millis(waited))
+                .map(|out| out.message_expiry_interval)
+        };
+        assert_eq!(expiry(12_500), Some(Some(18)));
+        assert_eq!(expiry(29_999), Some(Some(1)));
+        assert_eq!(expiry(30_000), None);
+        assert_eq!(expiry(45_000), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Preview of broker updates
+//!
+//! `POST /api/brokers/:id/preview` takes the body of an update and reports what saving it
+//! would change: the fields, and the effect on the running connection. Any change to an
+//! enabled broker recreates its connection, which loses the messages held in its offline
+//! buffer; an update that changes nothing leaves the connection alone.
+
+use crate::broker_storage::BrokerConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Shown instead of passwords
+const HIDDEN: &str = "********";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    /// Field name as in the API (camelCase)
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionImpact {
+    /// Nothing changes for the connection, or the broker stays disabled
+    Unchanged,
+    /// The connection is closed and opened again with the new settings
+    Reconnect,
+    /// The broker is enabled by the update
+    Connect,
+    /// The broker is disabled by the update
+    Disconnect,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePreview {
+    pub changes: Vec<FieldChange>,
+    pub connection: ConnectionImpact,
+    pub will_reconnect: bool,
+    /// Topics subscribed on the broker (bidirectional brokers) before and after
+    pub subscriptions_added: Vec<String>,
+    pub subscriptions_removed: Vec<String>,
+    /// Messages in the offline buffer that the new connection won't send
+    pub buffered_messages_lost: usize,
+}
+
+/// Fields that differ between `old` and `new`. A password counts as changed only when
+/// `new` sets one other than the placeholder, as the store keeps the old one otherwise.
+pub fn changes(old: &BrokerConfig, new: &BrokerConfig) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    let password_changed = new.password.as_deref().is_some_and(|p| p != HIDDEN);
+    let mut old_fields = to_fields(old);
+    let mut new_fields = to_fields(new);
+    for (field, old_value) in old_fields.iter_mut() {
+        // The store sets the revision, it isn't part of the settings
+        if field == "password" || field == "revision" {
+            continue;
+        }
+        let new_value = new_fields.remove(field).unwrap_or(Value::Null);
+        if *old_value != new_value {
+            changes.push(FieldChange {
+                field: field.clone(),
+                old: old_value.take(),
+                new: new_value,
+            });
+        }
+    }
+    if password_changed {
+        changes.push(FieldChange {
+            field: "password".to_string(),
+            old: old.password.as_ref().map_or(Value::Null, |_| HIDDEN.into()),
+            new: HIDDEN.into(),
+        });
+    }
+    changes
+}
+
+/// What saving `new` over `old` does; `buffered` is the broker's offline buffer size
+pub fn preview(old: &BrokerConfig, new: &BrokerConfig, buffered: usize) -> UpdatePreview {
+    let changes = changes(old, new);
+    let connection = match (old.enabled, new.enabled) {
+        (false, false) => ConnectionImpact::Unchanged,
+        (false, true) => ConnectionImpact::Connect,
+        (true, false) => ConnectionImpact::Disconnect,
+        (true, true) if changes.is_empty() => ConnectionImpact::Unchanged,
+        (true, true) => ConnectionImpact::Reconnect,
+    };
+    let old_subscriptions = subscriptions(old);
+    let new_subscriptions = subscriptions(new);
+    UpdatePreview {
+        subscriptions_added: new_subscriptions
+            .iter()
+            .filter(|topic| !old_subscriptions.contains(topic))
+            .cloned()
+            .collect(),
+        subscriptions_removed: old_subscriptions
+            .iter()
+            .filter(|topic| !new_subscriptions.contains(topic))
+            .cloned()
+            .collect(),
+        buffered_messages_lost: match connection {
+            ConnectionImpact::Reconnect | ConnectionImpact::Disconnect => buffered,
+            _ => 0,
+        },
+        will_reconnect: connection == ConnectionImpact::Reconnect,
+        connection,
+        changes,
+    }
+}
+
+fn to_fields(config: &BrokerConfig) -> serde_json::Map<String, Value> {
+    match serde_json::to_value(config) {
+        Ok(Value::Object(fields)) => fields,
+        _ => serde_json::Map::new(),
+    }
+}
+
+/// Topics an enabled bidirectional broker subscribes to
+fn subscriptions(config: &BrokerConfig) -> Vec<String> {
+    if !config.enabled || !config.bidirectional {
+        return Vec::new();
+    }
+    if config.subscription_topics.is_empty() {
+        config.topics.clone()
+    } else {
+        config.subscription_topics.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broker() -> BrokerConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": "b1",
+            "name": "Cloud",
+            "address": "cloud.example.com",
+            "port": 1883,
+            "clientIdPrefix": "proxy",
+            "password": HIDDEN,
+            "enabled": true,
+            "bidirectional": true,
+            "topics": ["sensors/#"]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_preview_reports_changes_and_impact() {
+        let old = broker();
+        assert_eq!(
+            preview(&old, &old, 5).connection,
+            ConnectionImpact::Unchanged
+        );
+
+        let new = BrokerConfig {
+            port: 8883,
+            password: Some("secret".to_string()),
+            topics: vec!["sensors/#".to_string(), "alarms/#".to_string()],
+            ..old.clone()
+        };
+        let result = preview(&old, &new, 5);
+        let fields: Vec<&str> = result.changes.iter().map(|c| c.field.as_str()).collect();
+        assert_eq!(fields, vec!["port", "topics", "password"]);
+        assert_eq!(result.changes[2].new, Value::from(HIDDEN));
+        assert!(result.will_reconnect);
+        assert_eq!(result.subscriptions_added, vec!["alarms/#".to_string()]);
+        assert!(result.subscriptions_removed.is_empty());
+        assert_eq!(result.buffered_messages_lost, 5);
+
+        let disabled = BrokerConfig {
+            enabled: false,
+            ..old.clone()
+        };
+        let result = preview(&old, &disabled, 0);
+        assert_eq!(result.connection, ConnectionImpact::Disconnect);
+        assert_eq!(result.subscriptions_removed, vec!["sensors/#".to_string()]);
+    }
+}
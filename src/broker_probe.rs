@@ -0,0 +1,488 @@
+//! Capability probing of a broker before it is saved
+//!
+//! `POST /api/brokers/probe` connects to a candidate broker the way the add-broker form
+//! describes it and reports what works: the TCP connect time, the TLS handshake (version,
+//! cipher suite, ALPN, server certificate), which MQTT protocol versions the broker speaks
+//! and accepts with the given credentials, and whether it also accepts clients without
+//! credentials. Each attempt is a separate short-lived connection with its own client ID
+//! and a clean session, so nothing stays behind on the broker.
+
+use crate::broker_client::{PROTOCOL_V4, PROTOCOL_V5};
+use crate::broker_storage::BrokerConfig;
+use crate::connection_manager::build_tls_config;
+use crate::listener_tls::{cert_not_after, peer_common_name};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rustls_pki_types::ServerName;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// Time allowed for each step (connect, handshake, CONNACK)
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEP_ALIVE_SECS: u16 = 10;
+/// CONNACK return codes (3.1.1) and reason codes (5.0) refusing the protocol version
+const UNSUPPORTED_PROTOCOL: [u8; 2] = [0x01, 0x84];
+/// CONNACK codes refusing the credentials or the client
+const AUTH_REJECTED: [u8; 6] = [0x04, 0x05, 0x86, 0x87, 0x8C, 0x8A];
+
+/// The connection settings of the add-broker form
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeRequest {
+    pub address: String,
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub use_tls: bool,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    #[serde(default)]
+    pub skip_hostname_verification: bool,
+    #[serde(default)]
+    pub ca_cert_path: Option<String>,
+    #[serde(default)]
+    pub client_cert_path: Option<String>,
+    #[serde(default)]
+    pub client_key_path: Option<String>,
+    #[serde(default)]
+    pub tls_verify_hostname: Option<String>,
+    #[serde(default)]
+    pub alpn_protocols: Vec<String>,
+}
+
+impl ProbeRequest {
+    fn credentials(&self) -> Option<(&str, &str)> {
+        let username = self.username.as_deref().filter(|u| !u.is_empty())?;
+        Some((username, self.password.as_deref().unwrap_or_default()))
+    }
+
+    /// A broker config with the TLS settings of the request, for `build_tls_config`
+    fn tls_broker_config(&self) -> Result<BrokerConfig> {
+        let non_empty = |value: &Option<String>| value.clone().filter(|v| !v.is_empty());
+        serde_json::from_value(serde_json::json!({
+            "id": "probe",
+            "name": "probe",
+            "address": self.address,
+            "port": self.port,
+            "clientIdPrefix": "probe",
+            "useTls": true,
+            "insecureSkipVerify": self.insecure_skip_verify,
+            "skipHostnameVerification": self.skip_hostname_verification,
+            "caCertPath": non_empty(&self.ca_cert_path),
+            "clientCertPath": non_empty(&self.client_cert_path),
+            "clientKeyPath": non_empty(&self.client_key_path),
+            "tlsVerifyHostname": non_empty(&self.tls_verify_hostname),
+            "alpnProtocols": self.alpn_protocols,
+        }))
+        .context("Invalid TLS settings")
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeReport {
+    /// A TCP connection (and the TLS handshake, with TLS) succeeded
+    pub reachable: bool,
+    pub tcp_connect_ms: Option<f64>,
+    pub tls: Option<TlsReport>,
+    /// One attempt per protocol version, with the given credentials
+    pub protocols: Vec<ProtocolReport>,
+    /// Whether the broker accepts clients without credentials, if that could be told
+    pub anonymous_allowed: Option<bool>,
+    /// Highest protocol version that was accepted (or at least spoken)
+    pub suggested_protocol_version: Option<u8>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsReport {
+    pub handshake_ms: Option<f64>,
+    pub version: Option<String>,
+    pub cipher_suite: Option<String>,
+    pub alpn_protocol: Option<String>,
+    pub peer_common_name: Option<String>,
+    pub peer_not_after: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolReport {
+    pub protocol_version: u8,
+    /// The broker answered in this protocol version (even if it refused the client)
+    pub supported: bool,
+    pub accepted: bool,
+    /// CONNACK return code (3.1.1) or reason code (5.0)
+    pub return_code: Option<u8>,
+    /// Time from CONNECT to CONNACK
+    pub connack_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Probe the broker of `request`; failures are part of the report
+pub async fn probe(request: &ProbeRequest) -> ProbeReport {
+    let mut report = ProbeReport::default();
+    let connector = match request.use_tls {
+        true => match request
+            .tls_broker_config()
+            .and_then(|c| build_tls_config(&c))
+        {
+            Ok(tls_config) => Some(TlsConnector::from(Arc::new(tls_config))),
+            Err(e) => {
+                report.error = Some(format!("{:#}", e));
+                return report;
+            }
+        },
+        false => None,
+    };
+
+    // The first connection measures TCP and TLS, then carries the first attempt
+    let started = Instant::now();
+    let tcp = match timeout(TcpStream::connect((request.address.as_str(), request.port))).await {
+        Ok(tcp) => tcp,
+        Err(e) => {
+            report.error = Some(format!(
+                "Failed to connect to {}:{}: {:#}",
+                request.address, request.port, e
+            ));
+            return report;
+        }
+    };
+    report.tcp_connect_ms = Some(millis(started));
+    let stream: Box<dyn Stream> = match &connector {
+        Some(connector) => {
+            let (tls, tls_report) = handshake(connector, request, tcp).await;
+            report.tls = Some(tls_report);
+            match tls {
+                Some(tls) => tls,
+                None => return report,
+            }
+        }
+        None => Box::new(tcp),
+    };
+    report.reachable = true;
+
+    let credentials = request.credentials();
+    let mut first = Some(stream);
+    for protocol_version in [PROTOCOL_V5, PROTOCOL_V4] {
+        let stream = match first.take() {
+            Some(stream) => Ok(stream),
+            None => connect(request, &connector).await,
+        };
+        let attempt = match stream {
+            Ok(stream) => attempt(stream, protocol_version, credentials).await,
+            Err(e) => ProtocolReport::failed(protocol_version, e),
+        };
+        report.protocols.push(attempt);
+    }
+    report
+        .protocols
+        .sort_by_key(|attempt| attempt.protocol_version);
+
+    let best = |accepted: bool| {
+        report
+            .protocols
+            .iter()
+            .rev()
+            .find(|attempt| attempt.supported && (attempt.accepted || !accepted))
+            .map(|attempt| attempt.protocol_version)
+    };
+    report.suggested_protocol_version = best(true).or_else(|| best(false));
+    report.anonymous_allowed = match (credentials, report.suggested_protocol_version) {
+        (_, None) => None,
+        (None, Some(_)) => Some(report.protocols.iter().any(|attempt| attempt.accepted)),
+        (Some(_), Some(protocol_version)) => match connect(request, &connector).await {
+            Ok(stream) => {
+                let anonymous = attempt(stream, protocol_version, None).await;
+                match anonymous.return_code {
+                    _ if anonymous.accepted => Some(true),
+                    Some(code) if AUTH_REJECTED.contains(&code) => Some(false),
+                    _ => None,
+                }
+            }
+            Err(_) => None,
+        },
+    };
+    report
+}
+
+async fn connect(
+    request: &ProbeRequest,
+    connector: &Option<TlsConnector>,
+) -> Result<Box<dyn Stream>> {
+    let tcp = timeout(TcpStream::connect((request.address.as_str(), request.port))).await?;
+    match connector {
+        Some(connector) => {
+            let tls = timeout(connector.connect(server_name(request)?, tcp)).await?;
+            Ok(Box::new(tls))
+        }
+        None => Ok(Box::new(tcp)),
+    }
+}
+
+/// TLS handshake over `tcp`, with what it revealed about the server
+async fn handshake(
+    connector: &TlsConnector,
+    request: &ProbeRequest,
+    tcp: TcpStream,
+) -> (Option<Box<dyn Stream>>, TlsReport) {
+    let mut report = TlsReport::default();
+    let started = Instant::now();
+    let tls = match server_name(request) {
+        Ok(server_name) => timeout(connector.connect(server_name, tcp)).await,
+        Err(e) => Err(e),
+    };
+    let tls = match tls {
+        Ok(tls) => tls,
+        Err(e) => {
+            report.error = Some(format!("{:#}", e));
+            return (None, report);
+        }
+    };
+    report.handshake_ms = Some(millis(started));
+    let (_, connection) = tls.get_ref();
+    report.version = connection
+        .protocol_version()
+        .map(|version| format!("{:?}", version));
+    report.cipher_suite = connection
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()));
+    report.alpn_protocol = connection
+        .alpn_protocol()
+        .map(|protocol| String::from_utf8_lossy(protocol).into_owned());
+    if let Some(cert) = connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    {
+        report.peer_common_name = peer_common_name(cert);
+        report.peer_not_after = cert_not_after(cert);
+    }
+    (Some(Box::new(tls)), report)
+}
+
+/// The address, like the broker connection sends as SNI; `tls_verify_hostname` only
+/// changes the name the certificate is verified against (see `build_tls_config`)
+fn server_name(request: &ProbeRequest) -> Result<ServerName<'static>> {
+    ServerName::try_from(request.address.clone())
+        .with_context(|| format!("Invalid server name '{}'", request.address))
+}
+
+/// Send CONNECT and read the CONNACK
+async fn attempt(
+    mut stream: Box<dyn Stream>,
+    protocol_version: u8,
+    credentials: Option<(&str, &str)>,
+) -> ProtocolReport {
+    let client_id = format!(
+        "mqtt-proxy-probe-{}",
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    );
+    let started = Instant::now();
+    let connack: Result<u8> = async {
+        stream
+            .write_all(&connect_packet(protocol_version, &client_id, credentials))
+            .await?;
+        timeout(read_connack(&mut stream)).await
+    }
+    .await;
+    let return_code = match connack {
+        Ok(return_code) => return_code,
+        // Brokers without 5.0 support may just close the connection
+        Err(e) => return ProtocolReport::failed(protocol_version, e),
+    };
+    let accepted = return_code == 0;
+    if accepted {
+        // DISCONNECT, normal disconnection
+        let _ = stream.write_all(&[0xE0, 0x00]).await;
+    }
+    let _ = stream.shutdown().await;
+    ProtocolReport {
+        protocol_version,
+        supported: !UNSUPPORTED_PROTOCOL.contains(&return_code),
+        accepted,
+        return_code: Some(return_code),
+        connack_ms: Some(millis(started)),
+        error: None,
+    }
+}
+
+impl ProtocolReport {
+    fn failed(protocol_version: u8, error: anyhow::Error) -> Self {
+        Self {
+            protocol_version,
+            supported: false,
+            accepted: false,
+            return_code: None,
+            connack_ms: None,
+            error: Some(format!("{:#}", error)),
+        }
+    }
+}
+
+/// CONNECT with a clean session, no will and (with 5.0) no properties
+fn connect_packet(
+    protocol_version: u8,
+    client_id: &str,
+    credentials: Option<(&str, &str)>,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, "MQTT");
+    body.push(protocol_version);
+    body.push(if credentials.is_some() { 0xC2 } else { 0x02 });
+    body.extend_from_slice(&KEEP_ALIVE_SECS.to_be_bytes());
+    if protocol_version == PROTOCOL_V5 {
+        body.push(0);
+    }
+    put_string(&mut body, client_id);
+    if let Some((username, password)) = credentials {
+        put_string(&mut body, username);
+        put_string(&mut body, password);
+    }
+
+    let mut packet = vec![0x10];
+    let mut remaining = body.len();
+    loop {
+        let byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(&body);
+    packet
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+/// The return or reason code of the CONNACK; 3.1.1 and 5.0 have it at the same place
+async fn read_connack(stream: &mut Box<dyn Stream>) -> Result<u8> {
+    let packet_type = stream
+        .read_u8()
+        .await
+        .context("Connection closed without CONNACK")?;
+    if packet_type != 0x20 {
+        bail!("Expected CONNACK, got packet type {:#04x}", packet_type);
+    }
+    let mut remaining = 0usize;
+    for shift in [0, 7, 14, 21] {
+        let byte = stream.read_u8().await?;
+        remaining |= usize::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if !(2..=1024).contains(&remaining) {
+        bail!("Invalid CONNACK length {}", remaining);
+    }
+    let mut body = vec![0u8; remaining];
+    stream.read_exact(&mut body).await?;
+    Ok(body[1])
+}
+
+async fn timeout<T, E>(future: impl std::future::Future<Output = Result<T, E>>) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    match tokio::time::timeout(STEP_TIMEOUT, future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => bail!("No response within {:?}", STEP_TIMEOUT),
+    }
+}
+
+fn millis(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_connect_packet() {
+        assert_eq!(
+            connect_packet(PROTOCOL_V4, "p", Some(("u", "pw"))),
+            [
+                &[0x10, 20, 0, 4][..],
+                b"MQTT",
+                &[4, 0xC2, 0, 10, 0, 1, b'p', 0, 1, b'u', 0, 2, b'p', b'w'],
+            ]
+            .concat()
+        );
+        let v5 = connect_packet(PROTOCOL_V5, "p", None);
+        assert_eq!(&v5[8..], &[5, 0x02, 0, 10, 0, 0, 1, b'p']);
+    }
+
+    /// A 3.1.1-only broker that wants the username "admin"
+    async fn fake_broker() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut connect = vec![0u8; 256];
+                let len = socket.read(&mut connect).await.unwrap();
+                let connect = &connect[..len];
+                let return_code = match (connect[8], connect[9] & 0x80 != 0) {
+                    (5, _) => 0x01,
+                    (_, true) if connect.windows(5).any(|w| w == b"admin") => 0x00,
+                    _ => 0x05,
+                };
+                socket
+                    .write_all(&[0x20, 0x02, 0x00, return_code])
+                    .await
+                    .unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_probe_reports_protocols_and_anonymous_access() {
+        let request: ProbeRequest = serde_json::from_value(serde_json::json!({
+            "address": "127.0.0.1",
+            "port": fake_broker().await,
+            "username": "admin",
+            "password": "secret"
+        }))
+        .unwrap();
+        let report = probe(&request).await;
+
+        assert!(report.reachable);
+        assert!(report.tls.is_none());
+        let outcomes: Vec<(u8, bool, bool)> = report
+            .protocols
+            .iter()
+            .map(|attempt| {
+                (
+                    attempt.protocol_version,
+                    attempt.supported,
+                    attempt.accepted,
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![(PROTOCOL_V4, true, true), (PROTOCOL_V5, false, false)]
+        );
+        assert_eq!(report.suggested_protocol_version, Some(PROTOCOL_V4));
+        assert_eq!(report.anonymous_allowed, Some(false));
+    }
+}
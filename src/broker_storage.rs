@@ -1,11 +1,35 @@
+use crate::batching::BatchRule;
+use crate::config::StorageConfig;
 use crate::crypto::{decrypt_password, encrypt_password, warn_if_encryption_not_configured};
+use crate::debounced_write::DebouncedWriter;
+use crate::dedup::DedupOverride;
+use crate::offline_buffer::OfflineBufferConfig;
+use crate::payload_filter::PayloadFilter;
+use crate::remote_config::HttpBrokerStorage;
+use crate::sampling::SamplingRule;
+use crate::send_queue::SendQueueConfig;
+use crate::topic_rewrite::TopicRewrite;
+use crate::transform::PayloadTransform;
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tracing::{error, info, warn};
 
+/// A broker name already in use, the error of `add` and `update`
+#[derive(Debug, thiserror::Error)]
+#[error("Broker with name '{0}' already exists")]
+pub struct DuplicateName(pub String);
+
+/// An update based on an older revision, the error of `update`; holds the current config
+/// (password hidden)
+#[derive(Debug, thiserror::Error)]
+#[error("Broker '{}' was changed in the meantime (now revision {})", .0.name, .0.revision)]
+pub struct RevisionMismatch(pub Box<BrokerConfig>);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BrokerConfig {
@@ -14,6 +38,13 @@ pub struct BrokerConfig {
     pub address: String,
     pub port: u16,
     pub client_id_prefix: String,
+    /// Fixed client ID for this broker. When unset, `{client_id_prefix}-{uuid}` is generated
+    /// on every connect; a stable ID lets the broker keep sessions and ACLs keyed by client ID.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Start a new session on every connect (MQTT `clean_session` / v5 `clean_start`)
+    #[serde(default = "default_true")]
+    pub clean_session: bool,
     #[serde(default)]
     pub username: Option<String>,
     #[serde(default)]
@@ -24,8 +55,33 @@ pub struct BrokerConfig {
     pub use_tls: bool,
     #[serde(default)]
     pub insecure_skip_verify: bool,
+    /// Verify the certificate chain but accept a certificate whose names don't match
+    /// `address` (CA-signed appliance brokers reached by IP)
+    #[serde(default)]
+    pub skip_hostname_verification: bool,
     #[serde(default)]
     pub ca_cert_path: Option<String>,
+    /// Client certificate (PEM) presented to brokers that require mutual TLS
+    #[serde(default)]
+    pub client_cert_path: Option<String>,
+    /// Private key (PEM) of `client_cert_path`
+    #[serde(default)]
+    pub client_key_path: Option<String>,
+    /// Hostname to verify the broker certificate against when it differs from `address`.
+    /// Only certificate validation uses it: the SNI sent in the handshake is still
+    /// `address`, as rumqttc derives it from the dial address.
+    #[serde(default)]
+    pub tls_verify_hostname: Option<String>,
+    /// ALPN protocols offered in the TLS handshake (e.g. `x-amzn-mqtt-ca` for AWS IoT on 443)
+    #[serde(default)]
+    pub alpn_protocols: Vec<String>,
+    /// MQTT protocol level for the connection: 4 (3.1.1, default) or 5 (5.0)
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u8,
+    /// Pass the MQTT 5.0 properties of forwarded messages (user properties, content type,
+    /// response topic, correlation data) on to this broker
+    #[serde(default = "default_true")]
+    pub forward_properties: bool,
     #[serde(default)]
     pub bidirectional: bool,
     /// Topics to filter which messages get forwarded to this broker
@@ -34,12 +90,119 @@ pub struct BrokerConfig {
     /// Topics to subscribe to on bidirectional brokers (if empty, uses topics list)
     #[serde(default)]
     pub subscription_topics: Vec<String>,
+    /// When a bidirectional broker subscribes: to its topics at connect (`static`), or
+    /// only to what local clients and main broker consumers want (`on_demand`)
+    #[serde(default)]
+    pub subscription_mode: SubscriptionMode,
+    /// QoS (0-2) of the subscriptions on this broker; `[subscriptions] qos` if unset
+    #[serde(default)]
+    pub subscription_qos: Option<u8>,
+    /// Topic patterns whose payloads are AES-GCM encrypted before they reach this broker
+    /// and decrypted when relayed back (key from MQTT_PROXY_PAYLOAD_SECRET)
+    #[serde(default)]
+    pub encrypt_topics: Vec<String>,
+    /// Topic patterns (typically command topics) whose payloads are HMAC-signed before they
+    /// reach this broker; unsigned messages relayed back on them are dropped
+    #[serde(default)]
+    pub sign_topics: Vec<String>,
+    /// Decimation rules for high-volume topics on this broker (first matching rule wins)
+    #[serde(default)]
+    pub sampling: Vec<SamplingRule>,
+    /// Coalesce messages on high-rate topics into JSON-array publishes
+    #[serde(default)]
+    pub batching: Vec<BatchRule>,
+    /// Payload transformations (rename, scale, static fields) for this broker's schema
+    #[serde(default)]
+    pub transforms: Vec<PayloadTransform>,
+    /// Topic patterns whose payloads are gzip-compressed before they reach this broker
+    /// (and decompressed when relayed back)
+    #[serde(default)]
+    pub compress_topics: Vec<String>,
+    /// Messages kept for this broker while it is disconnected
+    #[serde(default)]
+    pub offline_buffer: OfflineBufferConfig,
+    /// Size and overflow policy of the queue between forwarding and this broker's task
+    #[serde(default)]
+    pub send_queue: SendQueueConfig,
+    /// Connections opened to the broker; publishes are spread over them round-robin. The
+    /// extra connections use the client ID with `-1`, `-2`, ... appended.
+    #[serde(default = "default_connection_count")]
+    pub connection_count: usize,
+    /// Topic rewrites (prefix add/strip, pattern substitution) for this broker's namespace
+    #[serde(default)]
+    pub topic_rewrites: Vec<TopicRewrite>,
+    /// Retain flag of messages relayed from this (bidirectional) broker to the main broker
+    #[serde(default)]
+    pub reverse_retain: RetainPolicy,
+    /// Payload conditions (e.g. `$.battery < 20`) messages must meet to be forwarded here
+    #[serde(default)]
+    pub payload_filters: Vec<PayloadFilter>,
+    /// Higher priority brokers come first with `priority_mode` (default 0)
+    #[serde(default)]
+    pub priority: i32,
+    /// Catch-all broker: receives every message that no other broker's filters match
+    /// (its own `topics` are ignored). At most one broker is the default.
+    #[serde(default, rename = "default")]
+    pub is_default: bool,
+    /// Echo detection overrides (enabled, window, cache limit) for this broker
+    #[serde(default)]
+    pub dedup: DedupOverride,
+    /// Incremented by the store on every change; the API's `ETag`. An update must be based
+    /// on the current revision.
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// How a bidirectional broker's subscriptions are chosen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionMode {
+    /// Subscribe to the configured topics on connect, plus what local clients want
+    #[default]
+    Static,
+    /// Subscribe only to the demanded filters that fall within the configured topics, and
+    /// unsubscribe when the demand is gone (see `subscription_table`)
+    OnDemand,
+}
+
+/// What happens to the retain flag of a relayed message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetainPolicy {
+    /// Keep the flag the message arrived with
+    #[default]
+    Preserve,
+    /// Never retain, so relayed messages can't overwrite retained state
+    Strip,
+    /// Always retain
+    Force,
+}
+
+impl RetainPolicy {
+    pub fn apply(self, retain: bool) -> bool {
+        match self {
+            RetainPolicy::Preserve => retain,
+            RetainPolicy::Strip => false,
+            RetainPolicy::Force => true,
+        }
+    }
 }
 
 fn default_true() -> bool {
     true
 }
 
+/// Upper bound for `connectionCount` accepted by the API
+pub const MAX_CONNECTION_COUNT: usize = 16;
+
+fn default_connection_count() -> usize {
+    1
+}
+
+fn default_protocol_version() -> u8 {
+    crate::broker_client::PROTOCOL_V4
+}
+
 impl BrokerConfig {
     /// Returns a copy with the password encrypted (for storage)
     fn with_encrypted_password(&self) -> Self {
@@ -75,6 +238,18 @@ impl BrokerConfig {
         }
         config
     }
+
+    /// Whether `other` configures the broker the same way, whatever its revision
+    pub fn same_settings(&self, other: &BrokerConfig) -> bool {
+        let settings = |config: &BrokerConfig| {
+            serde_json::to_value(BrokerConfig {
+                revision: 0,
+                ..config.clone()
+            })
+            .ok()
+        };
+        settings(self) == settings(other)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -82,13 +257,83 @@ struct BrokerStore {
     brokers: Vec<BrokerConfig>,
 }
 
-pub struct BrokerStorage {
-    store_path: PathBuf,
+/// Where broker configurations are kept: a local file (`FileBrokerStorage`) or a central
+/// management service (`HttpBrokerStorage`, see `remote_config`)
+#[async_trait]
+pub trait BrokerStorage: Send + Sync {
+    /// Returns all brokers with passwords hidden (for API responses)
+    async fn list(&self) -> Vec<BrokerConfig>;
+
+    /// Returns all brokers with decrypted passwords (for internal use)
+    async fn list_with_passwords(&self) -> Vec<BrokerConfig>;
+
+    /// Returns a broker with password hidden (for API responses)
+    async fn get(&self, id: &str) -> Option<BrokerConfig>;
+
+    /// Returns a broker with decrypted password (for internal use)
+    async fn get_with_password(&self, id: &str) -> Option<BrokerConfig>;
+
+    async fn add(&self, broker: BrokerConfig) -> Result<()>;
+
+    /// Replace a broker's config. `updated.revision` must be the stored revision; the new
+    /// revision is returned.
+    async fn update(&self, id: &str, updated: BrokerConfig) -> Result<u64>;
+
+    async fn delete(&self, id: &str) -> Result<()>;
+
+    async fn toggle_enabled(&self, id: &str, enabled: bool) -> Result<()>;
+
+    /// Pick up changes made elsewhere; true if the brokers changed
+    async fn refresh(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Write changes still waiting for the flush interval
+    fn flush(&self) -> Result<()>;
+
+    /// Log what was loaded at startup
+    async fn init_defaults(&self) -> Result<()> {
+        let count = self.list().await.len();
+        if count > 0 {
+            info!("Loaded {} existing broker(s) from storage", count);
+        } else {
+            info!("No brokers configured. Add brokers via Web UI at http://localhost:3000");
+        }
+        Ok(())
+    }
+}
+
+/// Create the storage selected in the configuration
+pub async fn build_broker_storage(config: &StorageConfig) -> Result<Arc<dyn BrokerStorage>> {
+    let flush_interval = Duration::from_millis(config.flush_interval_ms);
+    let file = FileBrokerStorage::with_flush_interval(&config.broker_store_path, flush_interval)?;
+    match config
+        .remote
+        .as_ref()
+        .filter(|remote| remote.brokers_url.is_some())
+    {
+        Some(remote) => Ok(Arc::new(HttpBrokerStorage::open(remote, file).await?)),
+        None => Ok(Arc::new(file)),
+    }
+}
+
+/// Brokers kept in a JSON file, written through `debounced_write`
+pub struct FileBrokerStorage {
+    writer: DebouncedWriter,
     store: Arc<RwLock<BrokerStore>>,
 }
 
-impl BrokerStorage {
+impl FileBrokerStorage {
     pub fn new<P: AsRef<Path>>(store_path: P) -> Result<Self> {
+        Self::with_flush_interval(store_path, Duration::ZERO)
+    }
+
+    /// Like `new`, but changes are written at most once per `flush_interval` (see
+    /// `debounced_write`); call `flush` before exiting
+    pub fn with_flush_interval<P: AsRef<Path>>(
+        store_path: P,
+        flush_interval: Duration,
+    ) -> Result<Self> {
         let store_path = store_path.as_ref().to_path_buf();
 
         // Check if encryption is configured
@@ -115,13 +360,35 @@ impl BrokerStorage {
         };
 
         Ok(Self {
-            store_path,
+            writer: DebouncedWriter::new(store_path, flush_interval),
             store: Arc::new(RwLock::new(store)),
         })
     }
 
-    /// Returns all brokers with passwords hidden (for API responses)
-    pub async fn list(&self) -> Vec<BrokerConfig> {
+    /// Replace all brokers with those in `contents`, JSON in the format of the store file
+    /// (e.g. as fetched by `HttpBrokerStorage`)
+    pub async fn replace(&self, contents: &str) -> Result<()> {
+        let mut replacement: BrokerStore =
+            serde_json::from_str(contents).context("Invalid broker list")?;
+        for broker in &mut replacement.brokers {
+            *broker = broker.with_encrypted_password();
+        }
+        *self.store.write().await = replacement;
+        self.save().await
+    }
+
+    async fn save(&self) -> Result<()> {
+        let store = self.store.read().await;
+        let json =
+            serde_json::to_string_pretty(&*store).context("Failed to serialize broker store")?;
+        drop(store);
+        self.writer.write(json)
+    }
+}
+
+#[async_trait]
+impl BrokerStorage for FileBrokerStorage {
+    async fn list(&self) -> Vec<BrokerConfig> {
         let store = self.store.read().await;
         store
             .brokers
@@ -130,8 +397,7 @@ impl BrokerStorage {
             .collect()
     }
 
-    /// Returns all brokers with decrypted passwords (for internal use)
-    pub async fn list_with_passwords(&self) -> Vec<BrokerConfig> {
+    async fn list_with_passwords(&self) -> Vec<BrokerConfig> {
         let store = self.store.read().await;
         store
             .brokers
@@ -140,8 +406,7 @@ impl BrokerStorage {
             .collect()
     }
 
-    /// Returns a broker with password hidden (for API responses)
-    pub async fn get(&self, id: &str) -> Option<BrokerConfig> {
+    async fn get(&self, id: &str) -> Option<BrokerConfig> {
         let store = self.store.read().await;
         store
             .brokers
@@ -150,8 +415,7 @@ impl BrokerStorage {
             .map(|b| b.with_hidden_password())
     }
 
-    /// Returns a broker with decrypted password (for internal use)
-    pub async fn get_with_password(&self, id: &str) -> Option<BrokerConfig> {
+    async fn get_with_password(&self, id: &str) -> Option<BrokerConfig> {
         let store = self.store.read().await;
         store
             .brokers
@@ -160,7 +424,7 @@ impl BrokerStorage {
             .map(|b| b.with_decrypted_password())
     }
 
-    pub async fn add(&self, broker: BrokerConfig) -> Result<()> {
+    async fn add(&self, broker: BrokerConfig) -> Result<()> {
         let mut store = self.store.write().await;
 
         // Check for duplicate ID or name
@@ -168,7 +432,7 @@ impl BrokerStorage {
             anyhow::bail!("Broker with ID '{}' already exists", broker.id);
         }
         if store.brokers.iter().any(|b| b.name == broker.name) {
-            anyhow::bail!("Broker with name '{}' already exists", broker.name);
+            return Err(DuplicateName(broker.name).into());
         }
 
         // Encrypt password before storing
@@ -180,7 +444,7 @@ impl BrokerStorage {
         Ok(())
     }
 
-    pub async fn update(&self, id: &str, updated: BrokerConfig) -> Result<()> {
+    async fn update(&self, id: &str, updated: BrokerConfig) -> Result<u64> {
         let mut store = self.store.write().await;
 
         let index = store
@@ -188,6 +452,10 @@ impl BrokerStorage {
             .iter()
             .position(|b| b.id == id)
             .ok_or_else(|| anyhow::anyhow!("Broker with ID '{}' not found", id))?;
+        if updated.revision != store.brokers[index].revision {
+            let current = store.brokers[index].with_hidden_password();
+            return Err(RevisionMismatch(Box::new(current)).into());
+        }
 
         // Check for name conflicts (excluding the current broker)
         if store
@@ -196,7 +464,7 @@ impl BrokerStorage {
             .enumerate()
             .any(|(i, b)| i != index && b.name == updated.name)
         {
-            anyhow::bail!("Broker with name '{}' already exists", updated.name);
+            return Err(DuplicateName(updated.name).into());
         }
 
         // Handle password: if not provided or is the hidden placeholder, keep existing
@@ -216,15 +484,17 @@ impl BrokerStorage {
             }
         }
 
+        let revision = updated.revision + 1;
+        config_to_store.revision = revision;
         store.brokers[index] = config_to_store;
         drop(store);
 
         self.save().await?;
         info!("Broker '{}' updated successfully", id);
-        Ok(())
+        Ok(revision)
     }
 
-    pub async fn delete(&self, id: &str) -> Result<()> {
+    async fn delete(&self, id: &str) -> Result<()> {
         let mut store = self.store.write().await;
 
         let index = store
@@ -241,7 +511,7 @@ impl BrokerStorage {
         Ok(())
     }
 
-    pub async fn toggle_enabled(&self, id: &str, enabled: bool) -> Result<()> {
+    async fn toggle_enabled(&self, id: &str, enabled: bool) -> Result<()> {
         let mut store = self.store.write().await;
 
         let broker = store
@@ -251,6 +521,7 @@ impl BrokerStorage {
             .ok_or_else(|| anyhow::anyhow!("Broker with ID '{}' not found", id))?;
 
         broker.enabled = enabled;
+        broker.revision += 1;
         drop(store);
 
         self.save().await?;
@@ -262,34 +533,8 @@ impl BrokerStorage {
         Ok(())
     }
 
-    async fn save(&self) -> Result<()> {
-        let store = self.store.read().await;
-        let json =
-            serde_json::to_string_pretty(&*store).context("Failed to serialize broker store")?;
-
-        // Write to temp file first, then rename (atomic operation)
-        let temp_path = self.store_path.with_extension("tmp");
-        std::fs::write(&temp_path, json)
-            .with_context(|| format!("Failed to write temp file: {:?}", temp_path))?;
-
-        std::fs::rename(&temp_path, &self.store_path)
-            .with_context(|| format!("Failed to save broker store: {:?}", self.store_path))?;
-
-        Ok(())
-    }
-
-    /// Initialize storage (creates empty file if needed)
-    pub async fn init_defaults(&self) -> Result<()> {
-        let store = self.store.read().await;
-        if !store.brokers.is_empty() {
-            info!(
-                "Loaded {} existing broker(s) from storage",
-                store.brokers.len()
-            );
-        } else {
-            info!("No brokers configured. Add brokers via Web UI at http://localhost:3000");
-        }
-        Ok(())
+    fn flush(&self) -> Result<()> {
+        self.writer.flush()
     }
 }
 
@@ -303,7 +548,7 @@ mod tests {
         let temp_dir = TempDir::new().unwrap();
         let store_path = temp_dir.path().join("brokers.json");
 
-        let storage = BrokerStorage::new(&store_path).unwrap();
+        let storage = FileBrokerStorage::new(&store_path).unwrap();
 
         // Add a broker
         let broker = BrokerConfig {
@@ -312,15 +557,42 @@ mod tests {
             address: "localhost".to_string(),
             port: 1883,
             client_id_prefix: "test".to_string(),
+            client_id: None,
+            clean_session: true,
             username: None,
             password: None,
             enabled: true,
             use_tls: false,
             insecure_skip_verify: false,
+            skip_hostname_verification: false,
             ca_cert_path: None,
+            client_cert_path: None,
+            client_key_path: None,
+            tls_verify_hostname: None,
+            alpn_protocols: vec![],
+            protocol_version: 4,
             bidirectional: false,
             topics: vec![],
             subscription_topics: vec![],
+            subscription_mode: SubscriptionMode::default(),
+            subscription_qos: None,
+            encrypt_topics: vec![],
+            sign_topics: vec![],
+            sampling: vec![],
+            batching: vec![],
+            transforms: vec![],
+            compress_topics: vec![],
+            offline_buffer: OfflineBufferConfig::default(),
+            send_queue: SendQueueConfig::default(),
+            topic_rewrites: vec![],
+            reverse_retain: RetainPolicy::default(),
+            forward_properties: true,
+            connection_count: 1,
+            payload_filters: vec![],
+            priority: 0,
+            is_default: false,
+            dedup: DedupOverride::default(),
+            revision: 0,
         };
 
         storage.add(broker.clone()).await.unwrap();
@@ -337,7 +609,15 @@ mod tests {
         // Update broker
         let mut updated = retrieved.clone();
         updated.port = 8883;
-        storage.update("test-1", updated).await.unwrap();
+        assert_eq!(storage.update("test-1", updated.clone()).await.unwrap(), 1);
+
+        // An update based on the previous revision is refused
+        updated.port = 1884;
+        let err = storage.update("test-1", updated).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RevisionMismatch>().unwrap().0.revision,
+            1
+        );
 
         let retrieved = storage.get("test-1").await.unwrap();
         assert_eq!(retrieved.port, 8883);
@@ -355,32 +635,83 @@ mod tests {
 
         // Create storage and add broker
         {
-            let storage = BrokerStorage::new(&store_path).unwrap();
+            let storage = FileBrokerStorage::new(&store_path).unwrap();
             let broker = BrokerConfig {
                 id: "test-1".to_string(),
                 name: "Persistent Broker".to_string(),
                 address: "localhost".to_string(),
                 port: 1883,
                 client_id_prefix: "test".to_string(),
+                client_id: None,
+                clean_session: true,
                 username: None,
                 password: None,
                 enabled: true,
                 use_tls: false,
                 insecure_skip_verify: false,
+                skip_hostname_verification: false,
                 ca_cert_path: None,
+                client_cert_path: None,
+                client_key_path: None,
+                tls_verify_hostname: None,
+                alpn_protocols: vec![],
+                protocol_version: 4,
                 bidirectional: false,
                 topics: vec![],
                 subscription_topics: vec![],
+                subscription_mode: SubscriptionMode::default(),
+                subscription_qos: None,
+                encrypt_topics: vec![],
+                sign_topics: vec![],
+                sampling: vec![],
+                batching: vec![],
+                transforms: vec![],
+                compress_topics: vec![],
+                offline_buffer: OfflineBufferConfig::default(),
+                send_queue: SendQueueConfig::default(),
+                topic_rewrites: vec![],
+                reverse_retain: RetainPolicy::default(),
+                forward_properties: true,
+                connection_count: 1,
+                payload_filters: vec![],
+                priority: 0,
+                is_default: false,
+                dedup: DedupOverride::default(),
+                revision: 0,
             };
             storage.add(broker).await.unwrap();
         }
 
         // Load storage again and verify persistence
         {
-            let storage = BrokerStorage::new(&store_path).unwrap();
+            let storage = FileBrokerStorage::new(&store_path).unwrap();
             let brokers = storage.list().await;
             assert_eq!(brokers.len(), 1);
             assert_eq!(brokers[0].name, "Persistent Broker");
         }
     }
+
+    #[test]
+    fn test_default_flag_uses_default_key() {
+        let broker: BrokerConfig = serde_json::from_str(
+            r#"{"id":"cloud","name":"Cloud","address":"cloud.example.com","port":1883,
+                "clientIdPrefix":"proxy","default":true}"#,
+        )
+        .unwrap();
+        assert!(broker.is_default);
+        assert_eq!(serde_json::to_value(&broker).unwrap()["default"], true);
+    }
+
+    #[test]
+    fn test_reverse_retain_policy() {
+        let broker: BrokerConfig = serde_json::from_str(
+            r#"{"id":"edge","name":"Edge","address":"edge.local","port":1883,
+                "clientIdPrefix":"proxy","reverseRetain":"strip"}"#,
+        )
+        .unwrap();
+        assert_eq!(broker.reverse_retain, RetainPolicy::Strip);
+        assert!(!broker.reverse_retain.apply(true));
+        assert!(RetainPolicy::Force.apply(false));
+        assert!(RetainPolicy::default().apply(true));
+    }
 }
@@ -1,10 +1,33 @@
+use crate::subscription_table::SubscriptionTable;
 use bytes::Bytes;
+use parking_lot::Mutex;
 use rumqttc::QoS;
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, RwLock};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn};
 
+/// Upper bound on retained topics, so a client can't grow the store without limit
+const MAX_RETAINED_MESSAGES: usize = 10_000;
+
+/// Highest QoS granted to client subscriptions. The QoS 2 handshake isn't implemented
+/// towards clients; MQTT allows granting less than a SUBSCRIBE asks for.
+pub const MAX_SUBSCRIPTION_QOS: QoS = QoS::AtLeastOnce;
+
+/// The lower of two QoS levels
+pub fn min_qos(a: QoS, b: QoS) -> QoS {
+    if (a as u8) <= (b as u8) {
+        a
+    } else {
+        b
+    }
+}
+
 /// Message to be sent to a client
 #[derive(Debug, Clone)]
 pub struct ClientMessage {
@@ -18,12 +41,85 @@ pub struct ClientMessage {
 struct ClientInfo {
     client_id: String,
     tx: mpsc::Sender<ClientMessage>,
-    subscriptions: HashSet<String>,
+    /// Subscription filter → QoS granted for it
+    subscriptions: HashMap<String, QoS>,
+    /// Common Name of the client's TLS certificate, when it authenticated with one
+    identity: Option<String>,
+    /// Address the client connected from (taken from the PROXY header behind a load balancer)
+    peer_addr: SocketAddr,
+    keepalive: Keepalive,
+    session: u64,
+    taken_over: CancellationToken,
+}
+
+/// PINGREQ timing and socket RTT of a client connection
+#[derive(Debug, Default)]
+struct Keepalive {
+    /// Keep alive interval the client asked for in CONNECT (0 = disabled)
+    keep_alive_secs: u16,
+    pings: u64,
+    /// Pings that arrived after the keep alive interval had passed
+    late_pings: u64,
+    last_ping: Option<Instant>,
+    /// Moving average of the time between pings (weight 1/8, like TCP's SRTT)
+    interval_avg: Option<Duration>,
+    interval_max: Option<Duration>,
+    /// Kernel's smoothed round-trip time estimate for the socket
+    rtt: Option<Duration>,
+}
+
+impl Keepalive {
+    fn ping(&mut self, now: Instant) {
+        self.pings += 1;
+        if let Some(last) = self.last_ping {
+            let interval = now.duration_since(last);
+            if self.keep_alive_secs > 0
+                && interval > Duration::from_secs(self.keep_alive_secs.into())
+            {
+                self.late_pings += 1;
+            }
+            self.interval_avg = Some(match self.interval_avg {
+                Some(avg) => (avg * 7 + interval) / 8,
+                None => interval,
+            });
+            self.interval_max = self.interval_max.max(Some(interval));
+        }
+        self.last_ping = Some(now);
+    }
+}
+
+/// A connection's registration, returned by `register_client`
+pub struct ClientSession {
+    /// Tells this connection apart from later ones with the same client ID
+    pub id: u64,
+    /// Cancelled when a newer connection takes over the client ID
+    pub taken_over: CancellationToken,
+}
+
+/// A connected client, as listed by the clients API
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectedClient {
+    pub client_id: String,
+    pub peer_address: String,
+    pub identity: Option<String>,
+    pub subscriptions: Vec<String>,
+    pub keep_alive_secs: u16,
+    pub pings: u64,
+    pub late_pings: u64,
+    pub last_ping_secs_ago: Option<u64>,
+    pub ping_interval_avg_ms: Option<u64>,
+    pub ping_interval_max_ms: Option<u64>,
+    pub rtt_ms: Option<f64>,
 }
 
 /// Registry for managing client connections and their subscriptions
 pub struct ClientRegistry {
     clients: Arc<RwLock<HashMap<String, ClientInfo>>>,
+    /// Last retained message per topic, replayed to new subscribers
+    retained: Mutex<HashMap<String, ClientMessage>>,
+    /// Which filters clients want from bidirectional brokers
+    subscriptions: Arc<SubscriptionTable>,
+    next_session: AtomicU64,
 }
 
 impl Default for ClientRegistry {
@@ -36,39 +132,151 @@ impl ClientRegistry {
     pub fn new() -> Self {
         Self {
             clients: Arc::new(RwLock::new(HashMap::new())),
+            retained: Mutex::new(HashMap::new()),
+            subscriptions: Arc::new(SubscriptionTable::new()),
+            next_session: AtomicU64::new(1),
         }
     }
 
-    /// Register a new client connection
-    pub async fn register_client(&self, client_id: String, tx: mpsc::Sender<ClientMessage>) {
+    /// Register a new client connection. As in MQTT, a connection with the client ID of
+    /// one already registered takes over: the older connection is told to disconnect
+    /// and its entry, including its subscriptions, is replaced.
+    pub async fn register_client(
+        &self,
+        client_id: String,
+        tx: mpsc::Sender<ClientMessage>,
+        identity: Option<String>,
+        peer_addr: SocketAddr,
+        keep_alive_secs: u16,
+    ) -> ClientSession {
+        let session = ClientSession {
+            id: self.next_session.fetch_add(1, Ordering::Relaxed),
+            taken_over: CancellationToken::new(),
+        };
         let mut clients = self.clients.write().await;
-        clients.insert(
+        let previous = clients.insert(
             client_id.clone(),
             ClientInfo {
-                client_id,
+                client_id: client_id.clone(),
                 tx,
-                subscriptions: HashSet::new(),
+                subscriptions: HashMap::new(),
+                identity,
+                peer_addr,
+                keepalive: Keepalive {
+                    keep_alive_secs,
+                    ..Keepalive::default()
+                },
+                session: session.id,
+                taken_over: session.taken_over.clone(),
             },
         );
-        info!("Client registered in registry");
+        match previous {
+            Some(previous) => {
+                warn!(
+                    "Client ID '{}' taken over by a new connection; disconnecting the older one",
+                    client_id
+                );
+                previous.taken_over.cancel();
+                self.release_subscriptions(&previous);
+            }
+            None => info!("Client registered in registry"),
+        }
+        session
+    }
+
+    /// Authenticated identity (certificate Common Name or CONNECT username) of a connected client
+    pub async fn client_identity(&self, client_id: &str) -> Option<String> {
+        let clients = self.clients.read().await;
+        clients.get(client_id).and_then(|c| c.identity.clone())
     }
 
-    /// Unregister a client when they disconnect
-    pub async fn unregister_client(&self, client_id: &str) {
+    /// Connected clients, sorted by client ID
+    pub async fn clients(&self) -> Vec<ConnectedClient> {
+        let clients = self.clients.read().await;
+        let mut list: Vec<ConnectedClient> = clients
+            .values()
+            .map(|client| {
+                let mut subscriptions: Vec<String> = client.subscriptions.keys().cloned().collect();
+                subscriptions.sort();
+                let keepalive = &client.keepalive;
+                ConnectedClient {
+                    client_id: client.client_id.clone(),
+                    peer_address: client.peer_addr.to_string(),
+                    identity: client.identity.clone(),
+                    subscriptions,
+                    keep_alive_secs: keepalive.keep_alive_secs,
+                    pings: keepalive.pings,
+                    late_pings: keepalive.late_pings,
+                    last_ping_secs_ago: keepalive.last_ping.map(|t| t.elapsed().as_secs()),
+                    ping_interval_avg_ms: keepalive.interval_avg.map(|d| d.as_millis() as u64),
+                    ping_interval_max_ms: keepalive.interval_max.map(|d| d.as_millis() as u64),
+                    rtt_ms: keepalive.rtt.map(|d| d.as_secs_f64() * 1000.0),
+                }
+            })
+            .collect();
+        list.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        list
+    }
+
+    /// Record a PINGREQ from a client, with the socket RTT sampled at the same time
+    pub async fn record_ping(&self, client_id: &str, session: u64, rtt: Option<Duration>) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.get_mut(client_id).filter(|c| c.session == session) {
+            client.keepalive.ping(Instant::now());
+            client.keepalive.rtt = rtt.or(client.keepalive.rtt);
+        }
+    }
+
+    /// Record the socket RTT of a client outside of pings (e.g. at CONNECT)
+    pub async fn record_rtt(&self, client_id: &str, session: u64, rtt: Option<Duration>) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.get_mut(client_id).filter(|c| c.session == session) {
+            client.keepalive.rtt = rtt.or(client.keepalive.rtt);
+        }
+    }
+
+    /// Unregister a client when they disconnect. Does nothing if another connection has
+    /// taken over the client ID since `session` was registered.
+    pub async fn unregister_client(&self, client_id: &str, session: u64) {
         let mut clients = self.clients.write().await;
-        clients.remove(client_id);
-        info!("Client '{}' unregistered from registry", client_id);
+        if clients.get(client_id).is_some_and(|c| c.session == session) {
+            if let Some(client) = clients.remove(client_id) {
+                self.release_subscriptions(&client);
+            }
+            info!("Client '{}' unregistered from registry", client_id);
+        }
+    }
+
+    fn release_subscriptions(&self, client: &ClientInfo) {
+        let filters: Vec<String> = client.subscriptions.keys().cloned().collect();
+        self.subscriptions.remove(&client.client_id, &filters);
+    }
+
+    /// The subscription demand of all clients, followed by bidirectional brokers
+    pub fn subscription_table(&self) -> Arc<SubscriptionTable> {
+        Arc::clone(&self.subscriptions)
     }
 
-    /// Add subscriptions for a client
-    pub async fn add_subscriptions(&self, client_id: &str, topics: Vec<String>) -> Vec<String> {
+    /// Add subscriptions for a client, each with the QoS granted for it. Subscribing to a
+    /// filter again replaces its QoS.
+    pub async fn add_subscriptions(
+        &self,
+        client_id: &str,
+        subscriptions: Vec<(String, QoS)>,
+    ) -> Vec<String> {
         let mut clients = self.clients.write().await;
 
         if let Some(client) = clients.get_mut(client_id) {
-            for topic in &topics {
-                client.subscriptions.insert(topic.clone());
-                info!("Client '{}' subscribed to '{}'", client_id, topic);
+            let mut topics = Vec::with_capacity(subscriptions.len());
+            for (topic, qos) in subscriptions {
+                info!(
+                    "Client '{}' subscribed to '{}' (QoS {})",
+                    client_id, topic, qos as u8
+                );
+                client.subscriptions.insert(topic.clone(), qos);
+                topics.push(topic);
             }
+            self.subscriptions.add(client_id, &topics);
             topics
         } else {
             warn!(
@@ -79,16 +287,23 @@ impl ClientRegistry {
         }
     }
 
-    /// Remove subscriptions for a client
-    pub async fn remove_subscriptions(&self, client_id: &str, topics: &[String]) {
+    /// Remove subscriptions for a client. Filters no other client wants anymore are
+    /// returned; bidirectional brokers unsubscribe from them.
+    pub async fn remove_subscriptions(&self, client_id: &str, topics: &[String]) -> Vec<String> {
         let mut clients = self.clients.write().await;
 
-        if let Some(client) = clients.get_mut(client_id) {
-            for topic in topics {
-                client.subscriptions.remove(topic);
-                info!("Client '{}' unsubscribed from '{}'", client_id, topic);
-            }
+        let Some(client) = clients.get_mut(client_id) else {
+            return Vec::new();
+        };
+        let held: Vec<String> = topics
+            .iter()
+            .filter(|topic| client.subscriptions.remove(*topic).is_some())
+            .cloned()
+            .collect();
+        for topic in &held {
+            info!("Client '{}' unsubscribed from '{}'", client_id, topic);
         }
+        self.subscriptions.remove(client_id, &held)
     }
 
     /// Get all unique topics that any client is subscribed to
@@ -97,22 +312,27 @@ impl ClientRegistry {
         let mut topics: HashSet<String> = HashSet::new();
 
         for client in clients.values() {
-            topics.extend(client.subscriptions.iter().cloned());
+            topics.extend(client.subscriptions.keys().cloned());
         }
 
         topics.into_iter().collect()
     }
 
-    /// Forward a message to all clients subscribed to the topic
+    /// Forward a message to all clients with a subscription matching the topic, at the
+    /// lower of the message's QoS and the highest QoS granted to the client's matching
+    /// subscriptions. Never waits: a client whose queue is full misses the message rather
+    /// than holding up the broker it came from.
     pub async fn forward_to_subscribers(&self, topic: &str, message: ClientMessage) {
         let clients = self.clients.read().await;
         let mut sent_count = 0;
 
         for client in clients.values() {
-            // Check if client is subscribed to this exact topic
-            // TODO: Implement wildcard matching (+, #) for full MQTT compliance
-            if client.subscriptions.contains(topic) {
-                match client.tx.send(message.clone()).await {
+            if let Some(granted) = Self::granted_qos(&client.subscriptions, topic) {
+                let message = ClientMessage {
+                    qos: min_qos(message.qos, granted),
+                    ..message.clone()
+                };
+                match client.tx.try_send(message) {
                     Ok(_) => {
                         debug!(
                             "Forwarded message on '{}' to client '{}'",
@@ -138,9 +358,66 @@ impl ClientRegistry {
         }
     }
 
+    /// Store a retained publish; an empty payload clears the topic's retained message
+    pub fn retain_message(&self, message: ClientMessage) {
+        let mut retained = self.retained.lock();
+        if message.payload.is_empty() {
+            if retained.remove(&message.topic).is_some() {
+                debug!("Cleared retained message on '{}'", message.topic);
+            }
+            return;
+        }
+        if retained.len() >= MAX_RETAINED_MESSAGES && !retained.contains_key(&message.topic) {
+            warn!(
+                "Retained message store is full ({} topics); not retaining '{}'",
+                MAX_RETAINED_MESSAGES, message.topic
+            );
+            return;
+        }
+        debug!("Retained message on '{}'", message.topic);
+        retained.insert(message.topic.clone(), message);
+    }
+
+    /// Topics with a retained message
+    pub fn retained_count(&self) -> usize {
+        self.retained.lock().len()
+    }
+
+    /// Retained messages matching any of the subscriptions (filter and granted QoS), ready
+    /// to send
+    pub fn retained_messages(&self, subscriptions: &[(String, QoS)]) -> Vec<ClientMessage> {
+        let retained = self.retained.lock();
+        let mut messages: Vec<ClientMessage> = retained
+            .values()
+            .filter_map(|msg| {
+                let granted = Self::granted_qos(
+                    subscriptions.iter().map(|(filter, qos)| (filter, qos)),
+                    &msg.topic,
+                )?;
+                Some(ClientMessage {
+                    qos: min_qos(msg.qos, granted),
+                    ..msg.clone()
+                })
+            })
+            .collect();
+        messages.sort_by(|a, b| a.topic.cmp(&b.topic));
+        messages
+    }
+
+    /// Highest QoS among the subscriptions matching `topic`, if any matches
+    fn granted_qos<'a>(
+        subscriptions: impl IntoIterator<Item = (&'a String, &'a QoS)>,
+        topic: &str,
+    ) -> Option<QoS> {
+        subscriptions
+            .into_iter()
+            .filter(|(filter, _)| Self::topic_matches(filter, topic))
+            .map(|(_, qos)| *qos)
+            .max_by_key(|qos| *qos as u8)
+    }
+
     /// Check if topic matches a subscription pattern
     /// Supports MQTT wildcards: + (single level), # (multi level)
-    #[allow(dead_code)] // Used in tests and reserved for future wildcard matching
     fn topic_matches(subscription: &str, topic: &str) -> bool {
         // Quick exact match
         if subscription == topic {
@@ -188,6 +465,160 @@ impl ClientRegistry {
 mod tests {
     use super::*;
 
+    fn retained(topic: &str, payload: &'static [u8]) -> ClientMessage {
+        ClientMessage {
+            topic: topic.to_string(),
+            payload: Bytes::from_static(payload),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }
+    }
+
+    #[test]
+    fn test_retained_messages_replay_and_clear() {
+        let registry = ClientRegistry::new();
+        registry.retain_message(retained("home/living/temp", b"21"));
+        registry.retain_message(retained("home/kitchen/temp", b"19"));
+        registry.retain_message(retained("office/temp", b"23"));
+        // A newer message replaces the previous one
+        registry.retain_message(retained("home/kitchen/temp", b"20"));
+
+        let messages = registry.retained_messages(&[("home/+/temp".to_string(), QoS::AtMostOnce)]);
+        let topics: Vec<_> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(topics, vec!["home/kitchen/temp", "home/living/temp"]);
+        assert_eq!(messages[0].payload, Bytes::from_static(b"20"));
+        assert!(messages
+            .iter()
+            .all(|m| m.retain && m.qos == QoS::AtMostOnce));
+        // The highest QoS of the matching subscriptions applies
+        let messages = registry.retained_messages(&[
+            ("home/#".to_string(), QoS::AtMostOnce),
+            ("home/kitchen/+".to_string(), QoS::AtLeastOnce),
+        ]);
+        assert_eq!(messages[0].qos, QoS::AtLeastOnce);
+        assert_eq!(messages[1].qos, QoS::AtMostOnce);
+
+        // An empty retained payload deletes the topic's retained message
+        registry.retain_message(retained("office/temp", b""));
+        assert_eq!(
+            registry
+                .retained_messages(&[("#".to_string(), QoS::AtMostOnce)])
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_client_id_takes_over() {
+        let registry = ClientRegistry::new();
+        let (tx, _rx) = mpsc::channel(1);
+        let peer: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let first = registry
+            .register_client("device".to_string(), tx.clone(), None, peer, 60)
+            .await;
+        registry
+            .add_subscriptions("device", vec![("a".to_string(), QoS::AtMostOnce)])
+            .await;
+
+        let second = registry
+            .register_client("device".to_string(), tx, None, peer, 60)
+            .await;
+        assert!(first.taken_over.is_cancelled());
+        assert!(!second.taken_over.is_cancelled());
+        assert!(registry.get_all_subscribed_topics().await.is_empty());
+        assert!(registry.subscription_table().filters().is_empty());
+        assert_eq!(
+            registry.clients().await[0].peer_address,
+            "203.0.113.7:51234"
+        );
+
+        // The older connection closing doesn't remove the newer one's entry
+        registry.unregister_client("device", first.id).await;
+        assert!(registry.clients.read().await.contains_key("device"));
+        registry.unregister_client("device", second.id).await;
+        assert!(registry.clients.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shared_filter_released_by_last_subscriber() {
+        let registry = ClientRegistry::new();
+        let peer: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        for client_id in ["a", "b"] {
+            let (tx, _rx) = mpsc::channel(1);
+            registry
+                .register_client(client_id.to_string(), tx, None, peer, 60)
+                .await;
+            registry
+                .add_subscriptions(client_id, vec![("site/#".to_string(), QoS::AtMostOnce)])
+                .await;
+        }
+        let topics = vec!["site/#".to_string()];
+
+        assert!(registry.remove_subscriptions("a", &topics).await.is_empty());
+        // Filters the client doesn't hold don't affect the other subscriber
+        assert!(registry.remove_subscriptions("a", &topics).await.is_empty());
+        assert_eq!(registry.subscription_table().count("site/#"), 1);
+        assert_eq!(registry.remove_subscriptions("b", &topics).await, topics);
+        assert!(registry.subscription_table().filters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forward_to_wildcard_subscribers() {
+        let registry = ClientRegistry::new();
+        let peer: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        registry
+            .register_client("sensor".to_string(), tx, None, peer, 60)
+            .await;
+        registry
+            .add_subscriptions("sensor", vec![("site/+/temp".to_string(), QoS::AtMostOnce)])
+            .await;
+        let (other_tx, mut other_rx) = mpsc::channel(1);
+        registry
+            .register_client("other".to_string(), other_tx, None, peer, 60)
+            .await;
+        registry
+            .add_subscriptions("other", vec![("alarms/#".to_string(), QoS::AtLeastOnce)])
+            .await;
+
+        registry
+            .forward_to_subscribers("site/kitchen/temp", retained("site/kitchen/temp", b"21"))
+            .await;
+        // A full queue drops the message instead of waiting
+        registry
+            .forward_to_subscribers("site/hall/temp", retained("site/hall/temp", b"19"))
+            .await;
+
+        // Delivered at the QoS granted to the subscription, not the message's
+        let message = rx.recv().await.unwrap();
+        assert_eq!(message.payload, Bytes::from_static(b"21"));
+        assert_eq!(message.qos, QoS::AtMostOnce);
+        assert!(rx.try_recv().is_err());
+        assert!(other_rx.try_recv().is_err());
+
+        registry
+            .forward_to_subscribers("alarms/door", retained("alarms/door", b"open"))
+            .await;
+        assert_eq!(other_rx.recv().await.unwrap().qos, QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn test_keepalive_ping_intervals() {
+        let mut keepalive = Keepalive {
+            keep_alive_secs: 30,
+            ..Keepalive::default()
+        };
+        let start = Instant::now();
+        for secs in [0, 30, 60, 100] {
+            keepalive.ping(start + Duration::from_secs(secs));
+        }
+        assert_eq!(keepalive.pings, 4);
+        assert_eq!(keepalive.late_pings, 1);
+        assert_eq!(keepalive.interval_max, Some(Duration::from_secs(40)));
+        // 30s, then 30s, then (7 * 30s + 40s) / 8
+        assert_eq!(keepalive.interval_avg, Some(Duration::from_millis(31_250)));
+    }
+
     #[test]
     fn test_topic_matching() {
         // Exact matches
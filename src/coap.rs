@@ -0,0 +1,463 @@
+//! CoAP-to-MQTT ingestion bridge
+//!
+//! Constrained devices that only speak CoAP (RFC 7252) POST or PUT to resources on a UDP
+//! port, and each request whose path matches a `[[coap.resources]]` pattern is published
+//! under the resource's topic through the same pipeline as a listener client's PUBLISH
+//! (see `ingest::Ingestor`). Confirmable requests get a piggybacked ACK once the message
+//! was forwarded: `2.04 Changed` on success, `5.00` if forwarding failed, `4.04` for paths
+//! no resource matches. Retransmissions of a confirmable request within the exchange
+//! lifetime are answered from a cache instead of being published twice.
+//!
+//! Only the parts of CoAP a telemetry bridge needs are implemented: no observe, block-wise
+//! transfers, DTLS or responses with payloads.
+
+use crate::config::{CoapConfig, CoapResource};
+use crate::ingest::Ingestor;
+use crate::topic_rewrite::{substitute, wildcard_captures};
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use rumqttc::QoS;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// How long a confirmable request's response is kept for retransmissions
+/// (EXCHANGE_LIFETIME of RFC 7252 with the default transmission parameters)
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+/// Upper bound on remembered exchanges
+const MAX_EXCHANGES: usize = 10_000;
+/// Largest datagram read; CoAP over UDP can't be larger
+const MAX_DATAGRAM: usize = 65_535;
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    /// 2.04
+    pub const CHANGED: u8 = 0x44;
+    /// 4.00
+    pub const BAD_REQUEST: u8 = 0x80;
+    /// 4.02
+    pub const BAD_OPTION: u8 = 0x82;
+    /// 4.04
+    pub const NOT_FOUND: u8 = 0x84;
+    /// 4.05
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    /// 5.00
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xA0;
+}
+
+mod option {
+    pub const URI_HOST: u16 = 3;
+    pub const URI_PORT: u16 = 7;
+    pub const URI_PATH: u16 = 11;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::Confirmable,
+            1 => Self::NonConfirmable,
+            2 => Self::Acknowledgement,
+            _ => Self::Reset,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Self::Confirmable => 0,
+            Self::NonConfirmable => 1,
+            Self::Acknowledgement => 2,
+            Self::Reset => 3,
+        }
+    }
+}
+
+/// A decoded CoAP message
+#[derive(Debug, PartialEq)]
+pub struct CoapMessage {
+    pub kind: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    /// Uri-Path options joined with `/`
+    pub path: String,
+    /// The first critical option this bridge doesn't understand, if any
+    pub unknown_critical_option: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+/// Decode a datagram. Errors carry nothing to answer with; see `message_id` for the
+/// message ID of a malformed message.
+pub fn decode(datagram: &[u8]) -> Result<CoapMessage> {
+    if datagram.len() < 4 {
+        bail!("Datagram shorter than the CoAP header");
+    }
+    if datagram[0] >> 6 != VERSION {
+        bail!("Unsupported CoAP version {}", datagram[0] >> 6);
+    }
+    let token_len = (datagram[0] & 0x0F) as usize;
+    if token_len > 8 {
+        bail!("Token length {} over 8", token_len);
+    }
+    let kind = MessageType::from_bits(datagram[0] >> 4);
+    let code = datagram[1];
+    let message_id = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let token = datagram
+        .get(4..4 + token_len)
+        .context("Datagram ends inside the token")?
+        .to_vec();
+
+    let mut rest = &datagram[4 + token_len..];
+    let mut number: u16 = 0;
+    let mut segments: Vec<String> = Vec::new();
+    let mut unknown_critical_option = None;
+    let mut payload = Vec::new();
+    while let Some((&first, tail)) = rest.split_first() {
+        if first == PAYLOAD_MARKER {
+            if tail.is_empty() {
+                bail!("Payload marker without a payload");
+            }
+            payload = tail.to_vec();
+            break;
+        }
+        rest = tail;
+        let delta = extended(first >> 4, &mut rest)?;
+        let length = extended(first & 0x0F, &mut rest)? as usize;
+        number = number
+            .checked_add(delta)
+            .context("Option number out of range")?;
+        let value = rest
+            .get(..length)
+            .context("Datagram ends inside an option")?;
+        rest = &rest[length..];
+        match number {
+            option::URI_PATH => segments
+                .push(String::from_utf8(value.to_vec()).context("Uri-Path is not valid UTF-8")?),
+            option::URI_HOST | option::URI_PORT | option::URI_QUERY | option::ACCEPT => {}
+            // Odd option numbers are critical: they must not be ignored
+            other if other % 2 == 1 => {
+                unknown_critical_option.get_or_insert(other);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(CoapMessage {
+        kind,
+        code,
+        message_id,
+        token,
+        path: segments.join("/"),
+        unknown_critical_option,
+        payload,
+    })
+}
+
+/// The message ID of a datagram too malformed to decode, so it can still be reset
+pub fn message_id(datagram: &[u8]) -> Option<u16> {
+    (datagram.len() >= 4).then(|| u16::from_be_bytes([datagram[2], datagram[3]]))
+}
+
+/// An option delta or length nibble with its extended bytes
+fn extended(nibble: u8, rest: &mut &[u8]) -> Result<u16> {
+    let (value, used) = match nibble {
+        0..=12 => (nibble as u16, 0),
+        13 => (*rest.first().context("Truncated option")? as u16 + 13, 1),
+        14 => {
+            let bytes = rest.get(..2).context("Truncated option")?;
+            let value = u16::from_be_bytes([bytes[0], bytes[1]]);
+            (value.checked_add(269).context("Option out of range")?, 2)
+        }
+        _ => bail!("Reserved option nibble 15"),
+    };
+    *rest = &rest[used..];
+    Ok(value)
+}
+
+/// Encode a response without options or payload
+pub fn encode_response(kind: MessageType, code: u8, message_id: u16, token: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(4 + token.len());
+    datagram.push(VERSION << 6 | kind.bits() << 4 | token.len() as u8);
+    datagram.push(code);
+    datagram.extend_from_slice(&message_id.to_be_bytes());
+    datagram.extend_from_slice(token);
+    datagram
+}
+
+/// The topic, QoS and retain flag for a request path: the first resource whose pattern
+/// matches, with its topic's `{n}` placeholders filled from the path
+pub fn route(resources: &[CoapResource], path: &str) -> Option<(String, QoS, bool)> {
+    resources.iter().find_map(|resource| {
+        let captures = wildcard_captures(&resource.path, path)?;
+        let topic = match &resource.topic {
+            Some(template) => substitute(template, &captures),
+            None => path.to_string(),
+        };
+        let qos = rumqttc::qos(resource.qos).unwrap_or(QoS::AtMostOnce);
+        Some((topic, qos, resource.retain))
+    })
+}
+
+/// Responses to recent confirmable requests, by peer and message ID
+#[derive(Default)]
+struct RecentExchanges {
+    responses: HashMap<(SocketAddr, u16), Vec<u8>>,
+    /// Insertion order, which is also expiry order
+    order: VecDeque<(Instant, (SocketAddr, u16))>,
+}
+
+impl RecentExchanges {
+    fn get(&self, peer: SocketAddr, message_id: u16) -> Option<&Vec<u8>> {
+        self.responses.get(&(peer, message_id))
+    }
+
+    fn insert(&mut self, peer: SocketAddr, message_id: u16, response: Vec<u8>, now: Instant) {
+        while let Some((at, key)) = self.order.front() {
+            if now.duration_since(*at) < EXCHANGE_LIFETIME && self.order.len() < MAX_EXCHANGES {
+                break;
+            }
+            self.responses.remove(key);
+            self.order.pop_front();
+        }
+        self.responses.insert((peer, message_id), response);
+        self.order.push_back((now, (peer, message_id)));
+    }
+}
+
+/// Bridge CoAP requests into the forwarding pipeline until `shutdown` is cancelled
+pub async fn run_coap_bridge(
+    config: CoapConfig,
+    ingestor: Arc<Ingestor>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    if let Some(resource) = config.resources.iter().find(|r| r.qos > 2) {
+        bail!(
+            "CoAP resource '{}' has invalid QoS {}",
+            resource.path,
+            resource.qos
+        );
+    }
+    let address = format!("{}:{}", config.bind_address, config.port);
+    let socket = UdpSocket::bind(&address)
+        .await
+        .with_context(|| format!("Failed to bind CoAP port {}", address))?;
+    info!(
+        "CoAP bridge listening on udp://{} ({} resources)",
+        address,
+        config.resources.len()
+    );
+
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    let mut exchanges = RecentExchanges::default();
+    let mut next_message_id: u16 = rand::random();
+    loop {
+        let (len, peer) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            received = socket.recv_from(&mut buffer) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("CoAP receive failed: {}", e);
+                    continue;
+                }
+            },
+        };
+        let datagram = &buffer[..len];
+        let request = match decode(datagram) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Malformed CoAP message from {}: {}", peer, e);
+                if let Some(message_id) = message_id(datagram) {
+                    let reset = encode_response(MessageType::Reset, code::EMPTY, message_id, &[]);
+                    let _ = socket.send_to(&reset, peer).await;
+                }
+                continue;
+            }
+        };
+
+        let response = match request.kind {
+            MessageType::Acknowledgement | MessageType::Reset => continue,
+            MessageType::Confirmable => {
+                if let Some(cached) = exchanges.get(peer, request.message_id) {
+                    debug!(
+                        "Answering retransmitted CoAP request {} from {}",
+                        request.message_id, peer
+                    );
+                    let _ = socket.send_to(cached, peer).await;
+                    continue;
+                }
+                // An empty confirmable message is a ping
+                if request.code == code::EMPTY {
+                    let reset =
+                        encode_response(MessageType::Reset, code::EMPTY, request.message_id, &[]);
+                    let _ = socket.send_to(&reset, peer).await;
+                    continue;
+                }
+                let code = handle_request(&config, &ingestor, &request, peer).await;
+                let ack = encode_response(
+                    MessageType::Acknowledgement,
+                    code,
+                    request.message_id,
+                    &request.token,
+                );
+                exchanges.insert(peer, request.message_id, ack.clone(), Instant::now());
+                ack
+            }
+            MessageType::NonConfirmable => {
+                let code = handle_request(&config, &ingestor, &request, peer).await;
+                next_message_id = next_message_id.wrapping_add(1);
+                encode_response(
+                    MessageType::NonConfirmable,
+                    code,
+                    next_message_id,
+                    &request.token,
+                )
+            }
+        };
+        if let Err(e) = socket.send_to(&response, peer).await {
+            debug!("Failed to answer CoAP request from {}: {}", peer, e);
+        }
+    }
+    info!("CoAP bridge stopped");
+    Ok(())
+}
+
+/// Publish a request's payload and return the response code
+async fn handle_request(
+    config: &CoapConfig,
+    ingestor: &Ingestor,
+    request: &CoapMessage,
+    peer: SocketAddr,
+) -> u8 {
+    if !matches!(request.code, code::POST | code::PUT) {
+        return code::METHOD_NOT_ALLOWED;
+    }
+    if let Some(option) = request.unknown_critical_option {
+        debug!(
+            "CoAP request from {} has unsupported critical option {}",
+            peer, option
+        );
+        return code::BAD_OPTION;
+    }
+    if request.path.is_empty() {
+        return code::BAD_REQUEST;
+    }
+    let Some((topic, qos, retain)) = route(&config.resources, &request.path) else {
+        debug!("No CoAP resource for '/{}' from {}", request.path, peer);
+        return code::NOT_FOUND;
+    };
+    debug!(
+        "CoAP request from {} on '/{}' → '{}' ({} bytes)",
+        peer,
+        request.path,
+        topic,
+        request.payload.len()
+    );
+    match ingestor
+        .publish(&topic, Bytes::from(request.payload.clone()), qos, retain)
+        .await
+    {
+        Ok(()) => code::CHANGED,
+        Err(e) => {
+            warn!(
+                "Failed to forward CoAP request on '/{}' to '{}': {}",
+                request.path, topic, e
+            );
+            code::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_post_with_path_and_payload() {
+        // CON POST, token 0xBEEF, Uri-Path "sensors" / "t1", Content-Format (elective)
+        let mut datagram = vec![0x42, code::POST, 0x12, 0x34, 0xBE, 0xEF];
+        datagram.push(0xB7);
+        datagram.extend_from_slice(b"sensors");
+        datagram.push(0x02);
+        datagram.extend_from_slice(b"t1");
+        datagram.extend_from_slice(&[0x11, 50]);
+        datagram.push(PAYLOAD_MARKER);
+        datagram.extend_from_slice(b"21.5");
+
+        let message = decode(&datagram).unwrap();
+        assert_eq!(message.kind, MessageType::Confirmable);
+        assert_eq!(message.message_id, 0x1234);
+        assert_eq!(message.token, vec![0xBE, 0xEF]);
+        assert_eq!(message.path, "sensors/t1");
+        assert_eq!(message.unknown_critical_option, None);
+        assert_eq!(message.payload, b"21.5");
+
+        assert_eq!(
+            encode_response(
+                MessageType::Acknowledgement,
+                code::CHANGED,
+                0x1234,
+                &message.token
+            ),
+            vec![0x62, 0x44, 0x12, 0x34, 0xBE, 0xEF]
+        );
+
+        // If-Match (1) is critical and unsupported
+        let message = decode(&[0x50, code::POST, 0, 1, 0x10]).unwrap();
+        assert_eq!(message.unknown_critical_option, Some(1));
+        for malformed in [
+            &[0x40, 0x02][..],
+            &[0x80, 0x02, 0, 1],
+            &[0x40, 0x02, 0, 1, 0xFF],
+        ] {
+            assert!(decode(malformed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_route_fills_topic_from_path() {
+        let resources: Vec<CoapResource> = serde_json::from_value(serde_json::json!([
+            {"path": "sensors/+/temp", "topic": "site-a/{1}/temperature", "qos": 1},
+            {"path": "raw/#"}
+        ]))
+        .unwrap();
+        assert_eq!(
+            route(&resources, "sensors/t1/temp"),
+            Some(("site-a/t1/temperature".to_string(), QoS::AtLeastOnce, false))
+        );
+        assert_eq!(
+            route(&resources, "raw/a/b"),
+            Some(("raw/a/b".to_string(), QoS::AtMostOnce, false))
+        );
+        assert_eq!(route(&resources, "other"), None);
+    }
+
+    #[test]
+    fn test_recent_exchanges_expire() {
+        let peer: SocketAddr = "192.0.2.1:5683".parse().unwrap();
+        let mut exchanges = RecentExchanges::default();
+        let now = Instant::now();
+        exchanges.insert(peer, 7, vec![1], now);
+        assert_eq!(exchanges.get(peer, 7), Some(&vec![1]));
+        exchanges.insert(peer, 8, vec![2], now + EXCHANGE_LIFETIME);
+        assert_eq!(exchanges.get(peer, 7), None);
+        assert_eq!(exchanges.get(peer, 8), Some(&vec![2]));
+    }
+}
@@ -0,0 +1,510 @@
+//! gzip/zlib handling for compressed device payloads
+//!
+//! Some devices publish gzip-compressed JSON. Payloads on configured topics are
+//! decompressed on ingest, so routing, filters, transforms and the web UI see the
+//! content; brokers can get them recompressed (`compressTopics`). A small DEFLATE
+//! decoder and a fixed-Huffman encoder are implemented here directly.
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// Largest decompressed payload accepted, against decompression bombs
+pub const DEFAULT_MAX_DECOMPRESSED_BYTES: usize = 1024 * 1024;
+
+/// Decompress a gzip or zlib payload. Returns `None` if the payload is neither.
+pub fn decompress(payload: &[u8], limit: usize) -> Result<Option<Vec<u8>>> {
+    if payload.starts_with(&[0x1f, 0x8b]) {
+        gunzip(payload, limit).map(Some)
+    } else if is_zlib(payload) {
+        unzlib(payload, limit).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Compress into the gzip format
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    out.extend(deflate(data));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn is_zlib(payload: &[u8]) -> bool {
+    match payload {
+        [cmf, flg, ..] => {
+            cmf & 0x0f == 8 && cmf >> 4 <= 7 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0
+        }
+        _ => false,
+    }
+}
+
+fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    ensure!(
+        data.len() >= 18 && data[2] == 8,
+        "Not a deflate gzip stream"
+    );
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & 0x04 != 0 {
+        let len = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+        pos += 2 + len;
+    }
+    for flag in [0x08, 0x10] {
+        if flags & flag != 0 {
+            let end = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .context("Truncated gzip header")?;
+            pos += end + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    let body = data.get(pos..).context("Truncated gzip header")?;
+    let (out, used) = inflate(body, limit)?;
+    let trailer = body.get(used..used + 8).context("Truncated gzip trailer")?;
+    ensure!(
+        trailer[..4] == crc32(&out).to_le_bytes(),
+        "gzip checksum mismatch"
+    );
+    Ok(out)
+}
+
+fn unzlib(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    ensure!(
+        data[1] & 0x20 == 0,
+        "zlib preset dictionaries are not supported"
+    );
+    let (out, used) = inflate(&data[2..], limit)?;
+    let trailer = data
+        .get(2 + used..2 + used + 4)
+        .context("Truncated zlib trailer")?;
+    ensure!(
+        trailer == adler32(&out).to_be_bytes(),
+        "zlib checksum mismatch"
+    );
+    Ok(out)
+}
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which code length code lengths are stored in a dynamic block header
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        while self.count < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .context("Truncated deflate stream")?;
+            self.buf |= u32::from(byte) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buf & ((1u32 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+/// Canonical Huffman code: number of codes per length and symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = i32::from(self.counts[len]);
+            if code - first < count {
+                return self
+                    .symbols
+                    .get((index + code - first) as usize)
+                    .copied()
+                    .context("Invalid Huffman code");
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        bail!("Invalid Huffman code")
+    }
+}
+
+/// Decode a raw DEFLATE stream; returns the data and the number of input bytes used
+fn inflate(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize)> {
+    let mut reader = BitReader {
+        data,
+        pos: 0,
+        buf: 0,
+        count: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data
+                    .get(reader.pos..reader.pos + 4)
+                    .context("Truncated stored block")?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                ensure!(
+                    len == !u16::from_le_bytes([header[2], header[3]]),
+                    "Corrupt stored block"
+                );
+                let start = reader.pos + 4;
+                let block = data
+                    .get(start..start + len as usize)
+                    .context("Truncated stored block")?;
+                ensure!(out.len() + block.len() <= limit, "Payload too large");
+                out.extend_from_slice(block);
+                reader.pos = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [8u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(&mut reader, &mut out, &literals, &distances, limit)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_tables(&mut reader)?;
+                inflate_block(&mut reader, &mut out, &literals, &distances, limit)?;
+            }
+            _ => bail!("Invalid deflate block type"),
+        }
+        if last {
+            return Ok((out, reader.pos));
+        }
+    }
+}
+
+fn read_dynamic_tables(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let clen_count = reader.bits(4)? as usize + 4;
+    let mut clen_lengths = [0u8; 19];
+    for &index in &CLEN_ORDER[..clen_count] {
+        clen_lengths[index] = reader.bits(3)? as u8;
+    }
+    let clen = Huffman::new(&clen_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match clen.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (
+                *lengths.last().context("Repeat without a previous length")?,
+                3 + reader.bits(2)?,
+            ),
+            17 => (0, 3 + reader.bits(3)?),
+            18 => (0, 11 + reader.bits(7)?),
+            _ => bail!("Invalid code length symbol"),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    ensure!(
+        lengths.len() == literal_count + distance_count,
+        "Code lengths overrun"
+    );
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    limit: usize,
+) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => {
+                ensure!(out.len() < limit, "Payload too large");
+                out.push(symbol as u8);
+            }
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                ensure!(index < LEN_BASE.len(), "Invalid length symbol");
+                let len =
+                    LEN_BASE[index] as usize + reader.bits(u32::from(LEN_EXTRA[index]))? as usize;
+                let index = distances.decode(reader)? as usize;
+                ensure!(index < DIST_BASE.len(), "Invalid distance symbol");
+                let distance =
+                    DIST_BASE[index] as usize + reader.bits(u32::from(DIST_EXTRA[index]))? as usize;
+                ensure!(distance <= out.len(), "Distance beyond start of output");
+                ensure!(out.len() + len <= limit, "Payload too large");
+                let start = out.len() - distance;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    buf: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, n: u32) {
+        self.buf |= value << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.out.push(self.buf as u8);
+            self.buf >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting with their most significant bit
+    fn code(&mut self, code: u32, n: u32) {
+        self.bits(code.reverse_bits() >> (32 - n), n);
+    }
+
+    fn literal(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buf as u8);
+        }
+        self.out
+    }
+}
+
+const WINDOW: usize = 32 * 1024;
+const HASH_BITS: u32 = 15;
+const MAX_CHAIN: usize = 64;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Compress into one fixed-Huffman DEFLATE block with greedy LZ77 matching
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 2 + 16),
+        buf: 0,
+        count: 0,
+    };
+    writer.bits(1, 1);
+    writer.bits(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let insert = |pos: usize, head: &mut [usize], prev: &mut [usize]| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash3(&data[pos..]);
+            prev[pos % WINDOW] = head[h];
+            head[h] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let mut candidate = head[hash3(&data[pos..])];
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || pos - candidate > WINDOW {
+                    break;
+                }
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, pos - candidate);
+                    if len == max_len {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW];
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+            }
+        }
+
+        let (len, distance) = best;
+        if len >= MIN_MATCH {
+            let index = LEN_BASE
+                .iter()
+                .rposition(|&base| base as usize <= len)
+                .unwrap_or(0);
+            writer.literal(257 + index as u32);
+            writer.bits(
+                (len - LEN_BASE[index] as usize) as u32,
+                u32::from(LEN_EXTRA[index]),
+            );
+            let index = DIST_BASE
+                .iter()
+                .rposition(|&base| base as usize <= distance)
+                .unwrap_or(0);
+            writer.code(index as u32, 5);
+            writer.bits(
+                (distance - DIST_BASE[index] as usize) as u32,
+                u32::from(DIST_EXTRA[index]),
+            );
+            for p in pos..pos + len {
+                insert(p, &mut head, &mut prev);
+            }
+            pos += len;
+        } else {
+            writer.literal(u32::from(data[pos]));
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    writer.literal(256);
+    writer.finish()
+}
+
+/// Hash of the next three bytes, for finding match candidates
+fn hash3(data: &[u8]) -> usize {
+    let key = u32::from(data[0]) << 16 | u32::from(data[1]) << 8 | u32::from(data[2]);
+    (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + u32::from(byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let json = br#"{"sensor":"temp","values":[21.5,21.5,21.5,21.6,21.5,21.5],"unit":"celsius","site":"plant-1","sensor_type":"temp"}"#
+            .repeat(20);
+        let compressed = gzip(&json);
+        assert!(compressed.len() < json.len() / 4);
+        assert_eq!(decompress(&compressed, 1 << 20).unwrap().unwrap(), json);
+        assert_eq!(decompress(&gzip(b""), 16).unwrap().unwrap(), b"");
+
+        // Plain payloads are left alone, oversized output is refused
+        assert!(decompress(b"{\"a\":1}", 1 << 20).unwrap().is_none());
+        assert!(decompress(&compressed, 100).is_err());
+    }
+
+    #[test]
+    fn test_decompress_reference_streams() {
+        // `printf 'hello hello hello' | gzip -n -9` (fixed Huffman block)
+        let gzipped = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00, 0x80, 0x88, 0xf9, 0xe5, 0x11, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(
+            decompress(&gzipped, 1024).unwrap().unwrap(),
+            b"hello hello hello"
+        );
+        // zlib stream with a stored block
+        let zlib = [
+            0x78, 0x01, 0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c', 0x02, 0x4d, 0x01, 0x27,
+        ];
+        assert_eq!(decompress(&zlib, 1024).unwrap().unwrap(), b"abc");
+    }
+}
@@ -1,11 +1,461 @@
+use crate::dedup::DedupOverride;
+use crate::resource_profile::ResourceProfile;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub main_broker: MainBrokerConfig,
     pub web_ui: WebUiConfig,
     pub storage: StorageConfig,
+    /// Echo detection for bidirectional brokers: window, cache limit and where state is kept
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    /// Periodic usage reports grouped by tenant or site
+    #[serde(default)]
+    pub reports: ReportsConfig,
+    /// Topics on which unchanged payloads are not forwarded again within a window
+    #[serde(default)]
+    pub duplicate_suppression: Vec<DuplicateRule>,
+    /// JSON state topics forwarded only when they change
+    #[serde(default)]
+    pub delta_forwarding: Vec<DeltaRule>,
+    /// Topics whose gzip/zlib payloads are decompressed on ingest
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// JSON timestamp fields checked against the proxy's clock
+    #[serde(default)]
+    pub timestamp_checks: Vec<TimestampRule>,
+    /// What happens to messages no broker's filters match
+    #[serde(default)]
+    pub unrouted: UnroutedConfig,
+    /// Topics delivered to a set of brokers all-or-nothing
+    #[serde(default)]
+    pub delivery_groups: DeliveryGroupsConfig,
+    /// Plain TCP status port for monitoring systems without HTTP
+    #[serde(default)]
+    pub tcp_health: Option<TcpHealthConfig>,
+    /// Origin tagging of messages forwarded to bidirectional brokers
+    #[serde(default)]
+    pub loop_prevention: LoopPreventionConfig,
+    /// `low_resource` shrinks queues and buffers for small devices
+    #[serde(default)]
+    pub profile: ResourceProfile,
+    /// External script that can inspect, modify or drop messages
+    #[serde(default)]
+    pub scripting: Option<ScriptingConfig>,
+    /// Compiled WebAssembly filters and authenticators loaded at startup
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    /// Restarts broker connections whose eventloop stopped making progress
+    #[serde(default)]
+    pub broker_watchdog: BrokerWatchdogConfig,
+    /// How broker `priority` affects forwarding (primary/backup destinations)
+    #[serde(default)]
+    pub priority_mode: PriorityMode,
+    /// Periodic probe publishes that measure each broker's round trip
+    #[serde(default)]
+    pub health_checks: HealthCheckConfig,
+    /// Online/offline messages published for listener clients on connect and disconnect
+    #[serde(default)]
+    pub availability: AvailabilityConfig,
+    /// QoS of the subscriptions the proxy makes on the main and bidirectional brokers
+    #[serde(default)]
+    pub subscriptions: SubscriptionConfig,
+    /// Drops replayed commands that bidirectional brokers relay back
+    #[serde(default)]
+    pub replay_protection: Option<ReplayProtectionConfig>,
+    /// UDP port bridging CoAP POSTs from constrained devices into the forwarding path
+    #[serde(default)]
+    pub coap: Option<CoapConfig>,
+    /// Sources polled for messages to publish, e.g. HTTP endpoints returning JSON
+    #[serde(default)]
+    pub sources: Vec<SourceConfig>,
+    /// Prometheus message counters per topic prefix
+    #[serde(default)]
+    pub topic_metrics: Option<TopicMetricsConfig>,
+    /// OTLP export of forwarding spans and `traceparent` propagation to MQTT 5.0 brokers
+    #[serde(default)]
+    pub opentelemetry: Option<OpenTelemetryConfig>,
+    /// Log output format
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    /// `pretty` for people, `json` for log collectors (Loki, ELK); `LOG_FORMAT` overrides it
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl LoggingConfig {
+    /// The configured format, unless the `LOG_FORMAT` environment variable sets another
+    pub fn effective_format(&self) -> Result<LogFormat> {
+        match std::env::var("LOG_FORMAT") {
+            Ok(format) => match format.to_ascii_lowercase().as_str() {
+                "pretty" => Ok(LogFormat::Pretty),
+                "json" => Ok(LogFormat::Json),
+                other => anyhow::bail!("Invalid LOG_FORMAT '{}' (pretty or json)", other),
+            },
+            Err(_) => Ok(self.format),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenTelemetryConfig {
+    /// OTLP/HTTP traces endpoint (`http://` only), e.g. a collector or Jaeger/Tempo
+    #[serde(default = "default_otlp_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_otel_service_name")]
+    pub service_name: String,
+    /// Share of messages traced that arrive without a `traceparent`
+    #[serde(default = "default_otel_sample_ratio")]
+    pub sample_ratio: f64,
+    /// Add a `traceparent` user property to messages forwarded to MQTT 5.0 brokers
+    #[serde(default = "default_true")]
+    pub propagate: bool,
+    /// Extra request headers, e.g. for authentication
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_otlp_endpoint() -> String {
+    "http://localhost:4318/v1/traces".to_string()
+}
+
+fn default_otel_service_name() -> String {
+    "mqtt-proxy".to_string()
+}
+
+fn default_otel_sample_ratio() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicMetricsConfig {
+    /// Topic levels in the `prefix` label (`fleet/meters/m1/power` → `fleet/meters`)
+    #[serde(default = "default_topic_metrics_depth")]
+    pub depth: usize,
+    /// Distinct prefixes counted; later ones are counted as `(other)`
+    #[serde(default = "default_topic_metrics_max_prefixes")]
+    pub max_prefixes: usize,
+}
+
+fn default_topic_metrics_depth() -> usize {
+    2
+}
+
+fn default_topic_metrics_max_prefixes() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoapConfig {
+    #[serde(default = "default_coap_bind_address")]
+    pub bind_address: String,
+    #[serde(default = "default_coap_port")]
+    pub port: u16,
+    /// Client ID bridged messages are published under (script hooks, UI stream)
+    #[serde(default = "default_coap_client_id")]
+    pub client_id: String,
+    /// Resources devices may post to; the first matching path wins
+    pub resources: Vec<CoapResource>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoapResource {
+    /// Request path pattern without the leading `/` (`+`/`#` wildcards)
+    pub path: String,
+    /// Topic to publish under, with `{n}` for the n-th wildcard's levels; defaults to the
+    /// request path
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn default_coap_bind_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_coap_port() -> u16 {
+    5683
+}
+
+fn default_coap_client_id() -> String {
+    "coap-bridge".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceConfig {
+    HttpJson(HttpJsonSourceConfig),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpJsonSourceConfig {
+    /// Unique name, also the client ID its messages are published under
+    pub name: String,
+    /// `http://` URL fetched with GET
+    pub url: String,
+    #[serde(default = "default_source_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_source_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Extra request headers, e.g. `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub topic: String,
+    /// Path (`$.data.reading`) of the part of the response to publish; the whole response
+    /// by default
+    #[serde(default)]
+    pub select: Option<String>,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn default_source_interval_secs() -> u64 {
+    60
+}
+
+fn default_source_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayProtectionConfig {
+    /// Command topic patterns (`+`/`#` wildcards) checked for replays
+    pub topics: Vec<String>,
+    /// How long a command's (origin, correlation ID) pair is remembered
+    #[serde(default = "default_replay_window_secs")]
+    pub window_secs: u64,
+    /// Top-level JSON field holding the correlation ID of messages without MQTT 5.0
+    /// correlation data
+    #[serde(default = "default_correlation_field")]
+    pub correlation_field: String,
+    /// Drop commands without a correlation ID instead of relaying them unchecked
+    #[serde(default)]
+    pub require_correlation_id: bool,
+    /// File the remembered commands are kept in across restarts
+    #[serde(default = "default_replay_store_path")]
+    pub store_path: String,
+}
+
+fn default_replay_window_secs() -> u64 {
+    300
+}
+
+fn default_correlation_field() -> String {
+    "id".to_string()
+}
+
+fn default_replay_store_path() -> String {
+    "./data/replay_protection.json".to_string()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubscriptionConfig {
+    /// 0 (default), 1 or 2. With 1, messages lost to a broker or proxy restart while in
+    /// flight are redelivered; brokers and the main broker can override it.
+    #[serde(default)]
+    pub qos: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AvailabilityConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Topic of the messages; `{client_id}` is replaced with the client's ID
+    #[serde(default = "default_availability_topic")]
+    pub topic: String,
+    #[serde(default = "default_online_payload")]
+    pub online_payload: String,
+    #[serde(default = "default_offline_payload")]
+    pub offline_payload: String,
+    #[serde(default = "default_availability_qos")]
+    pub qos: u8,
+    /// Retained, so consumers that subscribe later still see the current state
+    #[serde(default = "default_true")]
+    pub retain: bool,
+}
+
+impl Default for AvailabilityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            topic: default_availability_topic(),
+            online_payload: default_online_payload(),
+            offline_payload: default_offline_payload(),
+            qos: default_availability_qos(),
+            retain: true,
+        }
+    }
+}
+
+fn default_availability_topic() -> String {
+    "clients/{client_id}/status".to_string()
+}
+
+fn default_online_payload() -> String {
+    "online".to_string()
+}
+
+fn default_offline_payload() -> String {
+    "offline".to_string()
+}
+
+fn default_availability_qos() -> u8 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    /// Topic of the QoS 1 probe messages (the payload is a timestamp)
+    #[serde(default = "default_probe_topic")]
+    pub probe_topic: String,
+    /// A probe not acknowledged within this time counts as failed
+    #[serde(default = "default_probe_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Round trip above which a broker is marked degraded
+    #[serde(default = "default_degraded_latency_ms")]
+    pub degraded_latency_ms: u64,
+    /// Failed probes in a row before a broker is marked degraded
+    #[serde(default = "default_degraded_failures")]
+    pub degraded_failures: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: default_health_check_interval_secs(),
+            probe_topic: default_probe_topic(),
+            timeout_ms: default_probe_timeout_ms(),
+            degraded_latency_ms: default_degraded_latency_ms(),
+            degraded_failures: default_degraded_failures(),
+        }
+    }
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    30
+}
+
+fn default_probe_topic() -> String {
+    "mqtt-proxy/probe".to_string()
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_degraded_latency_ms() -> u64 {
+    1000
+}
+
+fn default_degraded_failures() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriorityMode {
+    /// Forward to every matching broker; priority is ignored
+    #[default]
+    All,
+    /// Forward to every matching broker, highest priority first
+    Ordered,
+    /// Forward only to the matching brokers of the highest priority that is connected
+    HighestConnected,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrokerWatchdogConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Seconds without any eventloop event before the connection is recreated; keep it
+    /// above the 60 s keep-alive
+    #[serde(default = "default_watchdog_stall_secs")]
+    pub stall_secs: u64,
+}
+
+impl Default for BrokerWatchdogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            stall_secs: default_watchdog_stall_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptingConfig {
+    /// Rhai script defining the hook functions
+    pub script: String,
+    /// Steps one hook call may take before it is stopped
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+    /// Forward messages unchanged while the script fails, instead of dropping them
+    #[serde(default = "default_true")]
+    pub fail_open: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginConfig {
+    /// WebAssembly module implementing the plugin ABI (see `plugins`)
+    pub path: String,
+    /// Topics a filter plugin sees (`+`/`#` wildcards); empty for all topics
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// Wall time one call may take before it is stopped
+    #[serde(default = "default_plugin_timeout_ms")]
+    pub timeout_ms: u64,
+    /// WebAssembly instructions one call may execute (wasmtime fuel)
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+    /// Memory one plugin instance may grow to
+    #[serde(default = "default_plugin_max_memory_mb")]
+    pub max_memory_mb: usize,
+    /// Forward messages unchanged while a filter fails, instead of dropping them
+    #[serde(default = "default_true")]
+    pub fail_open: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoopPreventionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Identifies this proxy in tags; defaults to the main broker client ID. Proxies
+    /// bridging the same brokers need distinct origins.
+    #[serde(default)]
+    pub origin: Option<String>,
+    /// Wrap payloads for MQTT 3.1.1 brokers, which have no user properties
+    #[serde(default)]
+    pub wrap_payloads: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TcpHealthConfig {
+    pub port: u16,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +469,28 @@ pub struct MainBrokerConfig {
     pub username: Option<String>,
     #[serde(default)]
     pub password: Option<String>,
+    /// MQTT protocol level: 4 (3.1.1, default) or 5 (5.0). With 5, the properties of
+    /// messages exchanged with MQTT 5.0 brokers and clients are kept end-to-end.
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u8,
+    /// Duplicate detection of messages received from the main broker; unset fields use
+    /// the global `[dedup]` settings, except the window, which defaults to 1000 ms
+    #[serde(default = "default_main_broker_dedup")]
+    pub dedup: DedupOverride,
+    /// QoS of the proxy's subscriptions on the main broker; `[subscriptions] qos` if unset
+    #[serde(default)]
+    pub subscription_qos: Option<u8>,
+}
+
+fn default_protocol_version() -> u8 {
+    crate::broker_client::PROTOCOL_V4
+}
+
+fn default_main_broker_dedup() -> DedupOverride {
+    DedupOverride {
+        window_ms: Some(1000),
+        ..DedupOverride::default()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,6 +506,9 @@ pub struct ProxyConfig {
     pub username: Option<String>,
     #[serde(default)]
     pub password: Option<String>,
+    /// Additional accounts accepted when `require_auth` is set
+    #[serde(default)]
+    pub users: Vec<ListenerUser>,
     /// TLS settings for incoming connections
     #[serde(default)]
     pub use_tls: bool,
@@ -41,13 +516,240 @@ pub struct ProxyConfig {
     pub tls_cert_path: Option<String>,
     #[serde(default)]
     pub tls_key_path: Option<String>,
+    /// CA bundle used to verify client certificates (enables mutual TLS)
+    #[serde(default)]
+    pub tls_client_ca_path: Option<String>,
+    /// Reject clients that don't present a certificate signed by `tls_client_ca_path`
+    #[serde(default)]
+    pub require_client_cert: bool,
+    /// Generate a self-signed certificate when `use_tls` is set without cert and key paths
+    #[serde(default)]
+    pub tls_self_signed: Option<SelfSignedConfig>,
+}
+
+/// Where a self-signed certificate is kept and the names it is valid for (see
+/// `self_signed`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelfSignedConfig {
+    #[serde(default = "default_self_signed_dir")]
+    pub dir: String,
+    /// DNS names and IP addresses in the certificate's subject alternative names
+    #[serde(default = "default_self_signed_hostnames")]
+    pub hostnames: Vec<String>,
+}
+
+impl Default for SelfSignedConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_self_signed_dir(),
+            hostnames: default_self_signed_hostnames(),
+        }
+    }
+}
+
+fn default_self_signed_dir() -> String {
+    "./data/tls".to_string()
+}
+
+fn default_self_signed_hostnames() -> Vec<String> {
+    vec!["localhost".to_string(), "127.0.0.1".to_string()]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerUser {
+    pub username: String,
+    pub password: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WebUiConfig {
     pub port: u16,
+    /// Address the API port binds to, e.g. `127.0.0.1` for local automation only
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    /// Serve the Web UI's static files on this port instead of `port`, which then serves
+    /// the API only; the UI calls the API on `port` of the same host
+    #[serde(default)]
+    pub ui_port: Option<u16>,
+    #[serde(default = "default_bind_address")]
+    pub ui_bind_address: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Attach per-broker delivery results to messages on the WebSocket stream
+    #[serde(default)]
+    pub debug_deliveries: bool,
+    /// Number of recent messages kept for the history search API (0 disables)
+    #[serde(default = "default_message_history_size")]
+    pub message_history_size: usize,
+    /// Optionally spill message history to rotating on-disk segments
+    #[serde(default)]
+    pub history_disk: Option<HistoryDiskConfig>,
+    /// Pause the message stream to the UI while forwarding is under load
+    #[serde(default)]
+    pub load_shedding: LoadSheddingConfig,
+    /// Accept messages over HTTP (`/api/ingest`) as if published by a listener client
+    #[serde(default)]
+    pub ingest: IngestConfig,
+    /// Serve the Web UI's static files; the API is served either way
     #[serde(default = "default_true")]
+    pub serve_ui: bool,
+    /// Directory of the built Web UI
+    #[serde(default = "default_ui_dir")]
+    pub ui_dir: String,
+    /// Append-only log of broker and main broker changes made through the API
+    #[serde(default = "default_audit_log_path")]
+    pub audit_log_path: String,
+    /// Serve HTTPS instead of HTTP on both ports
+    #[serde(default)]
+    pub use_tls: bool,
+    #[serde(default)]
+    pub tls_cert_path: Option<String>,
+    #[serde(default)]
+    pub tls_key_path: Option<String>,
+    /// Generate a self-signed certificate when `use_tls` is set without cert and key paths
+    #[serde(default)]
+    pub tls_self_signed: Option<SelfSignedConfig>,
+    /// Obtain and renew the HTTPS certificate from an ACME CA (Let's Encrypt); implies
+    /// `use_tls` and replaces the certificate settings above
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcmeConfig {
+    /// Names the certificate is issued for; all must resolve to this host
+    pub domains: Vec<String>,
+    /// Contact address registered with the account (expiry notices)
+    #[serde(default)]
+    pub email: Option<String>,
+    /// ACME directory; Let's Encrypt production by default
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    /// Plain HTTP port answering `http-01` challenges; the CA always connects to port 80,
+    /// so another value needs a port forward
+    #[serde(default = "default_acme_http_port")]
+    pub http_port: u16,
+    /// Directory holding the account key and the certificate
+    #[serde(default = "default_acme_dir")]
+    pub dir: String,
+    /// Renew the certificate this many days before it expires
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AcmeChallenge {
+    /// The CA fetches `/.well-known/acme-challenge/<token>` from `http_port`
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    /// The CA connects with TLS ALPN `acme-tls/1` to the Web UI port, which must be 443
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+fn default_acme_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+fn default_acme_http_port() -> u16 {
+    80
+}
+
+fn default_acme_dir() -> String {
+    "./data/acme".to_string()
+}
+
+fn default_acme_renew_before_days() -> u32 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestConfig {
+    #[serde(default)]
     pub enabled: bool,
+    /// Client ID ingested messages are published under (script hooks, UI stream)
+    #[serde(default = "default_ingest_client_id")]
+    pub client_id: String,
+    /// Most records accepted in one `POST /api/ingest` batch
+    #[serde(default = "default_ingest_max_batch")]
+    pub max_batch: usize,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            client_id: default_ingest_client_id(),
+            max_batch: default_ingest_max_batch(),
+        }
+    }
+}
+
+fn default_ingest_client_id() -> String {
+    "http-ingest".to_string()
+}
+
+fn default_ingest_max_batch() -> usize {
+    1000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadSheddingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Pause once the smoothed forwarding latency exceeds this
+    #[serde(default = "default_max_latency_ms")]
+    pub max_latency_ms: f64,
+    /// Pause once this many messages wait in the broadcast channel
+    #[serde(default = "default_max_queue_depth")]
+    pub max_queue_depth: usize,
+    /// Resume once neither threshold has been exceeded for this long
+    #[serde(default = "default_resume_after_secs")]
+    pub resume_after_secs: u64,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_latency_ms: default_max_latency_ms(),
+            max_queue_depth: default_max_queue_depth(),
+            resume_after_secs: default_resume_after_secs(),
+        }
+    }
+}
+
+fn default_max_latency_ms() -> f64 {
+    50.0
+}
+
+fn default_max_queue_depth() -> usize {
+    500
+}
+
+fn default_resume_after_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryDiskConfig {
+    /// Directory holding the history segment files
+    pub path: String,
+    /// Start a new segment once the current one reaches this size
+    #[serde(default = "default_segment_max_bytes")]
+    pub segment_max_bytes: u64,
+    /// Start a new segment once the current one is this old
+    #[serde(default = "default_segment_max_age_secs")]
+    pub segment_max_age_secs: u64,
+    /// Delete the oldest segments when all segments together exceed this size
+    #[serde(default = "default_history_max_total_bytes")]
+    pub max_total_bytes: u64,
+    /// Delete segments last written longer ago than this
+    #[serde(default = "default_history_max_age_secs")]
+    pub max_age_secs: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,12 +759,363 @@ pub struct StorageConfig {
     /// Path to settings storage file
     #[serde(default = "default_settings_store_path")]
     pub settings_store_path: String,
+    /// Batch store changes and write them at most this often (0 writes every change)
+    #[serde(default)]
+    pub flush_interval_ms: u64,
+    /// Fetch brokers and settings from a central management service; the store files then
+    /// only cache them
+    #[serde(default)]
+    pub remote: Option<RemoteStorageConfig>,
+}
+
+/// Central configuration of many proxies (see `remote_config`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteStorageConfig {
+    /// URL returning the brokers, in the format of the broker store file
+    #[serde(default)]
+    pub brokers_url: Option<String>,
+    /// URL returning the settings, in the format of the settings store file
+    #[serde(default)]
+    pub settings_url: Option<String>,
+    /// Extra request headers, e.g. `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default = "default_remote_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+    #[serde(default = "default_source_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_remote_refresh_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    /// Topic patterns whose compressed payloads are decompressed before routing
+    #[serde(default)]
+    pub decompress_topics: Vec<String>,
+    /// Larger decompressed payloads are forwarded still compressed
+    #[serde(default = "default_max_decompressed_bytes")]
+    pub max_decompressed_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            decompress_topics: Vec::new(),
+            max_decompressed_bytes: default_max_decompressed_bytes(),
+        }
+    }
+}
+
+fn default_max_decompressed_bytes() -> usize {
+    crate::compression::DEFAULT_MAX_DECOMPRESSED_BYTES
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DedupConfig {
+    /// Echo detection for bidirectional brokers; brokers can override it
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// How long forwarded messages are remembered
+    #[serde(default = "default_dedup_window_ms")]
+    pub window_ms: u64,
+    /// Forwarded messages remembered per broker (memory backend)
+    #[serde(default = "default_dedup_max_entries")]
+    pub max_entries: usize,
+    #[serde(default)]
+    pub backend: DedupBackend,
+    /// `redis://[[user]:password@]host[:port][/db]`, required for the redis backend
+    #[serde(default)]
+    pub redis_url: Option<String>,
+    /// Prefix for the keys written to Redis
+    #[serde(default = "default_dedup_key_prefix")]
+    pub key_prefix: String,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_ms: default_dedup_window_ms(),
+            max_entries: default_dedup_max_entries(),
+            backend: DedupBackend::default(),
+            redis_url: None,
+            key_prefix: default_dedup_key_prefix(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupBackend {
+    /// Per-process state (single instance)
+    #[default]
+    Memory,
+    /// Shared between proxy instances bridging the same brokers
+    Redis,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportsConfig {
+    /// Zero-based topic level that identifies the tenant or site
+    #[serde(default)]
+    pub group_level: usize,
+    /// Length of a reporting period
+    #[serde(default = "default_report_interval_secs")]
+    pub interval_secs: u64,
+    /// Busiest topics listed per group
+    #[serde(default = "default_report_top_topics")]
+    pub top_topics: usize,
+    /// Publish each finished report as JSON to this topic on the main broker
+    #[serde(default)]
+    pub publish_topic: Option<String>,
+}
+
+impl Default for ReportsConfig {
+    fn default() -> Self {
+        Self {
+            group_level: 0,
+            interval_secs: default_report_interval_secs(),
+            top_topics: default_report_top_topics(),
+            publish_topic: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateRule {
+    /// Topic pattern the rule applies to (`+`/`#` wildcards)
+    pub topic: String,
+    /// How long an unchanged payload is suppressed after it was last forwarded
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaRule {
+    /// Topic pattern the rule applies to (`+`/`#` wildcards)
+    pub topic: String,
+    #[serde(default)]
+    pub mode: DeltaMode,
+    /// Dotted paths of the fields that trigger forwarding in `changed` mode (all if empty)
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeltaMode {
+    /// Forward the full payload when a watched field changed
+    #[default]
+    Changed,
+    /// Forward only the top-level keys that changed
+    Diff,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnroutedConfig {
+    #[serde(default)]
+    pub action: UnroutedAction,
+    /// ID of the broker that receives unrouted messages with `action = "broker"`
+    #[serde(default)]
+    pub broker: Option<String>,
+    /// Topic prefix unrouted messages are republished under on the main broker with
+    /// `action = "dead_letter"`
+    #[serde(default = "default_dead_letter_topic")]
+    pub dead_letter_topic: String,
+}
+
+impl Default for UnroutedConfig {
+    fn default() -> Self {
+        Self {
+            action: UnroutedAction::default(),
+            broker: None,
+            dead_letter_topic: default_dead_letter_topic(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnroutedAction {
+    /// Count the message and drop it
+    #[default]
+    Ignore,
+    /// Count the message and drop it with a warning
+    Warn,
+    /// Send the message to the `broker` catch-all broker
+    Broker,
+    /// Republish the message on the main broker under `dead_letter_topic`
+    DeadLetter,
+}
+
+fn default_dead_letter_topic() -> String {
+    "proxy/unrouted".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryGroupsConfig {
+    /// File holding the messages waiting for their group, kept across restarts
+    #[serde(default = "default_delivery_groups_state_path")]
+    pub state_path: String,
+    /// How often waiting messages are retried
+    #[serde(default = "default_delivery_groups_retry_interval_secs")]
+    pub retry_interval_secs: u64,
+    /// Waiting messages per group; beyond this the oldest is dropped
+    #[serde(default = "default_delivery_groups_max_pending")]
+    pub max_pending: usize,
+    #[serde(default)]
+    pub groups: Vec<DeliveryGroup>,
+}
+
+impl Default for DeliveryGroupsConfig {
+    fn default() -> Self {
+        Self {
+            state_path: default_delivery_groups_state_path(),
+            retry_interval_secs: default_delivery_groups_retry_interval_secs(),
+            max_pending: default_delivery_groups_max_pending(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryGroup {
+    pub name: String,
+    /// Topic patterns delivered through this group
+    pub topics: Vec<String>,
+    /// IDs of the brokers that all have to acknowledge each message
+    pub brokers: Vec<String>,
+}
+
+fn default_delivery_groups_state_path() -> String {
+    "./data/delivery_groups.json".to_string()
+}
+
+fn default_delivery_groups_retry_interval_secs() -> u64 {
+    10
+}
+
+fn default_delivery_groups_max_pending() -> usize {
+    10_000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimestampRule {
+    /// Topic pattern the rule applies to (`+`/`#` wildcards)
+    pub topic: String,
+    /// Dotted path of the timestamp field (epoch seconds, epoch milliseconds or RFC 3339)
+    #[serde(default = "default_timestamp_field")]
+    pub field: String,
+    /// Largest accepted difference from the proxy's clock, in either direction
+    #[serde(default = "default_max_skew_secs")]
+    pub max_skew_secs: u64,
+    #[serde(default)]
+    pub action: TimestampAction,
+    /// Top-level field set to `true` on tagged messages
+    #[serde(default = "default_timestamp_tag_field")]
+    pub tag_field: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampAction {
+    /// Forward the message with `tag_field` set
+    #[default]
+    Tag,
+    /// Don't forward the message
+    Drop,
+}
+
+fn default_timestamp_field() -> String {
+    "timestamp".to_string()
+}
+
+fn default_max_skew_secs() -> u64 {
+    300
+}
+
+fn default_timestamp_tag_field() -> String {
+    "timestamp_invalid".to_string()
+}
+
+fn default_report_interval_secs() -> u64 {
+    3600
+}
+
+fn default_report_top_topics() -> usize {
+    10
+}
+
+fn default_dedup_window_ms() -> u64 {
+    crate::dedup::ECHO_WINDOW.as_millis() as u64
+}
+
+fn default_dedup_max_entries() -> usize {
+    crate::dedup::DEFAULT_MAX_ENTRIES
+}
+
+fn default_dedup_key_prefix() -> String {
+    "mqtt-proxy:echo".to_string()
 }
 
 fn default_settings_store_path() -> String {
     "./data/settings.json".to_string()
 }
 
+fn default_message_history_size() -> usize {
+    10_000
+}
+
+fn default_bind_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_ui_dir() -> String {
+    "web-ui/dist".to_string()
+}
+
+fn default_audit_log_path() -> String {
+    "./data/audit.jsonl".to_string()
+}
+
+fn default_segment_max_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_segment_max_age_secs() -> u64 {
+    3600
+}
+
+fn default_history_max_total_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_history_max_age_secs() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+fn default_plugin_timeout_ms() -> u64 {
+    20
+}
+
+fn default_plugin_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_plugin_max_memory_mb() -> usize {
+    16
+}
+
+fn default_watchdog_stall_secs() -> u64 {
+    150
+}
+
 fn default_true() -> bool {
     true
 }
@@ -107,15 +1160,60 @@ impl Default for Config {
                 client_id: "mqtt-proxy".to_string(),
                 username: None,
                 password: None,
+                protocol_version: default_protocol_version(),
+                dedup: default_main_broker_dedup(),
+                subscription_qos: None,
             },
             web_ui: WebUiConfig {
                 port: 3000,
+                bind_address: default_bind_address(),
+                ui_port: None,
+                ui_bind_address: default_bind_address(),
                 enabled: true,
+                debug_deliveries: false,
+                message_history_size: default_message_history_size(),
+                history_disk: None,
+                load_shedding: LoadSheddingConfig::default(),
+                ingest: IngestConfig::default(),
+                serve_ui: true,
+                ui_dir: default_ui_dir(),
+                audit_log_path: default_audit_log_path(),
+                use_tls: false,
+                tls_cert_path: None,
+                tls_key_path: None,
+                tls_self_signed: None,
+                acme: None,
             },
             storage: StorageConfig {
                 broker_store_path: "./data/brokers.json".to_string(),
                 settings_store_path: default_settings_store_path(),
+                flush_interval_ms: 0,
+                remote: None,
             },
+            dedup: DedupConfig::default(),
+            reports: ReportsConfig::default(),
+            duplicate_suppression: Vec::new(),
+            delta_forwarding: Vec::new(),
+            compression: CompressionConfig::default(),
+            timestamp_checks: Vec::new(),
+            unrouted: UnroutedConfig::default(),
+            delivery_groups: DeliveryGroupsConfig::default(),
+            tcp_health: None,
+            loop_prevention: LoopPreventionConfig::default(),
+            profile: ResourceProfile::default(),
+            scripting: None,
+            plugins: Vec::new(),
+            broker_watchdog: BrokerWatchdogConfig::default(),
+            priority_mode: PriorityMode::default(),
+            health_checks: HealthCheckConfig::default(),
+            availability: AvailabilityConfig::default(),
+            subscriptions: SubscriptionConfig::default(),
+            replay_protection: None,
+            coap: None,
+            sources: Vec::new(),
+            topic_metrics: None,
+            opentelemetry: None,
+            logging: LoggingConfig::default(),
         }
     }
 }
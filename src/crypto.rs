@@ -2,9 +2,13 @@
 //!
 //! Uses AES-256-GCM encryption with a key derived from the MQTT_PROXY_SECRET environment variable.
 //! Encrypted passwords are prefixed with "ENC:" and base64 encoded.
+//!
+//! Message payloads on designated topics can also be encrypted end to end between proxies,
+//! with a key derived from MQTT_PROXY_PAYLOAD_SECRET that all peers share, and signed
+//! with HMAC-SHA256 using MQTT_PROXY_SIGNING_SECRET.
 
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
@@ -16,6 +20,14 @@ use tracing::warn;
 const ENCRYPTED_PREFIX: &str = "ENC:";
 const NONCE_SIZE: usize = 12; // 96 bits for AES-GCM
 const ENV_SECRET_KEY: &str = "MQTT_PROXY_SECRET";
+const ENV_PAYLOAD_SECRET_KEY: &str = "MQTT_PROXY_PAYLOAD_SECRET";
+/// Marks (and versions) an encrypted payload: magic + nonce + ciphertext
+const PAYLOAD_MAGIC: &[u8] = b"MPE1";
+const ENV_SIGNING_SECRET_KEY: &str = "MQTT_PROXY_SIGNING_SECRET";
+/// Marks (and versions) a signed payload: magic + HMAC-SHA256 + payload
+const SIGNATURE_MAGIC: &[u8] = b"MPS1";
+const SIGNATURE_SIZE: usize = 32;
+const HMAC_BLOCK_SIZE: usize = 64;
 
 /// Derives a 256-bit key from the secret using SHA-256
 fn derive_key(secret: &str) -> [u8; 32] {
@@ -126,6 +138,120 @@ pub fn decrypt_password(encrypted: &str) -> Option<String> {
     }
 }
 
+/// Gets the payload encryption key shared between proxies
+pub fn payload_key() -> Option<[u8; 32]> {
+    env::var(ENV_PAYLOAD_SECRET_KEY).ok().map(|secret| {
+        let mut hasher = Sha256::new();
+        hasher.update(secret.as_bytes());
+        hasher.update(b"mqtt-proxy-payload-encryption"); // Salt
+        hasher.finalize().into()
+    })
+}
+
+/// Encrypts a message payload using AES-256-GCM
+///
+/// The topic is authenticated along with the payload, so a ciphertext replayed onto
+/// another topic fails to decrypt.
+pub fn encrypt_payload(key: &[u8; 32], topic: &str, payload: &[u8]) -> Option<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(key).expect("Invalid key length");
+
+    let mut nonce_bytes = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce_bytes),
+            Payload {
+                msg: payload,
+                aad: topic.as_bytes(),
+            },
+        )
+        .map_err(|e| warn!("Failed to encrypt payload on '{}': {}", topic, e))
+        .ok()?;
+
+    let mut combined = Vec::with_capacity(PAYLOAD_MAGIC.len() + NONCE_SIZE + ciphertext.len());
+    combined.extend_from_slice(PAYLOAD_MAGIC);
+    combined.extend_from_slice(&nonce_bytes);
+    combined.extend(ciphertext);
+    Some(combined)
+}
+
+/// Decrypts a payload produced by encrypt_payload for the same topic
+///
+/// Returns None for plaintext, tampered or foreign payloads.
+pub fn decrypt_payload(key: &[u8; 32], topic: &str, data: &[u8]) -> Option<Vec<u8>> {
+    let rest = data.strip_prefix(PAYLOAD_MAGIC)?;
+    if rest.len() < NONCE_SIZE {
+        return None;
+    }
+    let (nonce_bytes, ciphertext) = rest.split_at(NONCE_SIZE);
+    let cipher = Aes256Gcm::new_from_slice(key).expect("Invalid key length");
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce_bytes),
+            Payload {
+                msg: ciphertext,
+                aad: topic.as_bytes(),
+            },
+        )
+        .ok()
+}
+
+/// Gets the payload signing secret shared between proxies
+pub fn signing_key() -> Option<Vec<u8>> {
+    env::var(ENV_SIGNING_SECRET_KEY)
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .map(String::into_bytes)
+}
+
+/// HMAC-SHA256 (RFC 2104) over the concatenation of `parts`
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; SIGNATURE_SIZE] {
+    let mut block = [0u8; HMAC_BLOCK_SIZE];
+    if key.len() > HMAC_BLOCK_SIZE {
+        block[..SIGNATURE_SIZE].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// Prefixes a payload with an HMAC over topic and payload
+pub fn sign_payload(key: &[u8], topic: &str, payload: &[u8]) -> Vec<u8> {
+    // Topic names can't contain NUL, so it separates topic and payload unambiguously
+    let signature = hmac_sha256(key, &[topic.as_bytes(), &[0], payload]);
+    let mut signed = Vec::with_capacity(SIGNATURE_MAGIC.len() + SIGNATURE_SIZE + payload.len());
+    signed.extend_from_slice(SIGNATURE_MAGIC);
+    signed.extend_from_slice(&signature);
+    signed.extend_from_slice(payload);
+    signed
+}
+
+/// Checks a payload produced by sign_payload for the same topic and returns the
+/// original payload, or None if it is unsigned or the signature doesn't match
+pub fn verify_payload<'a>(key: &[u8], topic: &str, data: &'a [u8]) -> Option<&'a [u8]> {
+    let rest = data.strip_prefix(SIGNATURE_MAGIC)?;
+    if rest.len() < SIGNATURE_SIZE {
+        return None;
+    }
+    let (signature, payload) = rest.split_at(SIGNATURE_SIZE);
+    let expected = hmac_sha256(key, &[topic.as_bytes(), &[0], payload]);
+    constant_time_eq(&expected, signature).then_some(payload)
+}
+
+/// Compare without an early exit so the timing doesn't reveal the matching prefix
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// Checks if password encryption is configured (MQTT_PROXY_SECRET is set)
 pub fn is_encryption_configured() -> bool {
     env::var(ENV_SECRET_KEY).is_ok()
@@ -175,6 +301,50 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_payload_encryption_roundtrip() {
+        let key = [7u8; 32];
+        let encrypted = encrypt_payload(&key, "site/cmd", b"{\"on\":true}").unwrap();
+
+        assert!(encrypted.starts_with(PAYLOAD_MAGIC));
+        assert_eq!(
+            decrypt_payload(&key, "site/cmd", &encrypted).unwrap(),
+            b"{\"on\":true}"
+        );
+        // Bound to the topic and the key; plaintext is rejected
+        assert!(decrypt_payload(&key, "site/other", &encrypted).is_none());
+        assert!(decrypt_payload(&[8u8; 32], "site/cmd", &encrypted).is_none());
+        assert!(decrypt_payload(&key, "site/cmd", b"{\"on\":true}").is_none());
+    }
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        // RFC 4231 test case 2
+        let mac = hmac_sha256(b"Jefe", &[b"what do ya want ", b"for nothing?"]);
+        let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_payload_signature_roundtrip() {
+        let signed = sign_payload(b"shared", "site/cmd", b"reboot");
+
+        assert_eq!(
+            verify_payload(b"shared", "site/cmd", &signed),
+            Some(&b"reboot"[..])
+        );
+        assert_eq!(verify_payload(b"shared", "site/other", &signed), None);
+        assert_eq!(verify_payload(b"forged", "site/cmd", &signed), None);
+        assert_eq!(verify_payload(b"shared", "site/cmd", b"reboot"), None);
+
+        let mut tampered = signed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(verify_payload(b"shared", "site/cmd", &tampered), None);
+    }
+
     #[test]
     fn test_empty_password() {
         with_test_secret(|| {
@@ -0,0 +1,155 @@
+//! Dead-letter store for forwards that failed for good
+//!
+//! A message a broker task rejected, didn't take in time, or that a broker dependency
+//! held back is kept here instead of vanishing with a warning. Through `/api/deadletter`
+//! it can be inspected, re-driven to its broker or purged, per broker or one by one. The
+//! store is in memory and bounded; beyond `DEAD_LETTER_CAPACITY` the oldest entries go.
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rumqttc::QoS;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Failed forwards kept across all brokers
+pub const DEAD_LETTER_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedForward {
+    pub id: u64,
+    pub broker_id: String,
+    pub broker_name: String,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    /// Why the last attempt failed
+    pub reason: String,
+    pub failed_at: DateTime<Utc>,
+    /// Re-drive attempts that failed as well
+    pub redrives: u32,
+}
+
+#[derive(Default)]
+pub struct DeadLetterStore {
+    entries: Mutex<VecDeque<FailedForward>>,
+    next_id: AtomicU64,
+    /// Entries dropped to stay within the capacity
+    evicted: AtomicU64,
+}
+
+impl DeadLetterStore {
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &self,
+        broker_id: &str,
+        broker_name: &str,
+        topic: &str,
+        payload: &Bytes,
+        qos: QoS,
+        retain: bool,
+        reason: String,
+    ) {
+        let entry = FailedForward {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            broker_id: broker_id.to_string(),
+            broker_name: broker_name.to_string(),
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos: qos as u8,
+            retain,
+            reason,
+            failed_at: Utc::now(),
+            redrives: 0,
+        };
+        self.push(entry);
+    }
+
+    /// Put back an entry whose re-drive failed
+    pub fn restore(&self, mut entry: FailedForward, reason: String) {
+        entry.redrives += 1;
+        entry.reason = reason;
+        entry.failed_at = Utc::now();
+        self.push(entry);
+    }
+
+    fn push(&self, entry: FailedForward) {
+        let mut entries = self.entries.lock();
+        if entries.len() >= DEAD_LETTER_CAPACITY {
+            entries.pop_front();
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        entries.push_back(entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Entries for `broker` (all brokers if `None`), newest first
+    pub fn list(&self, broker: Option<&str>) -> Vec<FailedForward> {
+        self.entries
+            .lock()
+            .iter()
+            .rev()
+            .filter(|entry| broker.is_none_or(|id| entry.broker_id == id))
+            .cloned()
+            .collect()
+    }
+
+    /// Remove and return the matching entries, oldest first. `ids` narrows the
+    /// selection down to those entries.
+    pub fn take(&self, broker: Option<&str>, ids: Option<&[u64]>) -> Vec<FailedForward> {
+        let mut entries = self.entries.lock();
+        let (taken, kept): (VecDeque<_>, VecDeque<_>) = entries.drain(..).partition(|entry| {
+            broker.is_none_or(|id| entry.broker_id == id)
+                && ids.is_none_or(|ids| ids.contains(&entry.id))
+        });
+        *entries = kept;
+        taken.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_by_broker_and_id() {
+        let store = DeadLetterStore::default();
+        let payload = Bytes::from_static(b"42");
+        for broker in ["cloud", "edge", "cloud"] {
+            store.record(
+                broker,
+                broker,
+                "sensors/a",
+                &payload,
+                QoS::AtLeastOnce,
+                false,
+                "timeout".to_string(),
+            );
+        }
+        let listed: Vec<u64> = store.list(Some("cloud")).iter().map(|e| e.id).collect();
+        assert_eq!(listed, vec![3, 1]);
+
+        let taken = store.take(None, Some(&[2]));
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].broker_id, "edge");
+        store.restore(taken.into_iter().next().unwrap(), "failed".to_string());
+        assert_eq!(store.list(Some("edge"))[0].redrives, 1);
+
+        let cloud = store.take(Some("cloud"), None);
+        assert_eq!(cloud.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(store.list(None).len(), 1);
+    }
+}
@@ -0,0 +1,130 @@
+//! Debounced JSON store writes
+//!
+//! The broker and settings stores rewrite their whole file on every change. With a flush
+//! interval set (`[storage] flush_interval_ms`), a change only marks the file dirty and
+//! the latest contents are written once the interval has passed, so a burst of toggles
+//! and edits costs one write instead of many; this spares the flash of SD-card based
+//! edge devices. `flush` writes pending contents right away and runs on shutdown.
+//! Delivery group queues use `write_in_background`, which keeps the file I/O off the
+//! forwarding path.
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+struct Shared {
+    path: PathBuf,
+    /// Contents not written yet, and whether a delayed flush is scheduled
+    pending: Mutex<(Option<String>, bool)>,
+    /// Held while writing, so an older flush can't overwrite newer contents
+    writing: Mutex<()>,
+}
+
+pub struct DebouncedWriter {
+    shared: Arc<Shared>,
+    flush_interval: Duration,
+}
+
+impl DebouncedWriter {
+    /// A zero `flush_interval` writes every change immediately
+    pub fn new(path: PathBuf, flush_interval: Duration) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                path,
+                pending: Mutex::new((None, false)),
+                writing: Mutex::new(()),
+            }),
+            flush_interval,
+        }
+    }
+
+    /// Replace the file's contents, now or after the flush interval
+    pub fn write(&self, contents: String) -> Result<()> {
+        if self.flush_interval.is_zero() {
+            *self.shared.pending.lock() = (Some(contents), false);
+            return self.shared.flush();
+        }
+        let mut pending = self.shared.pending.lock();
+        pending.0 = Some(contents);
+        if !pending.1 {
+            pending.1 = true;
+            let shared = Arc::clone(&self.shared);
+            let delay = self.flush_interval;
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                shared.pending.lock().1 = false;
+                if let Err(e) = shared.flush() {
+                    error!("{:#}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Replace the file's contents on the blocking thread pool, for callers on async hot
+    /// paths; a write that runs late finds the newer contents, so the file never goes back
+    pub fn write_in_background(&self, contents: String) {
+        self.shared.pending.lock().0 = Some(contents);
+        let shared = Arc::clone(&self.shared);
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = shared.flush() {
+                error!("{:#}", e);
+            }
+        });
+    }
+
+    /// Write pending contents now
+    pub fn flush(&self) -> Result<()> {
+        self.shared.flush()
+    }
+}
+
+impl Shared {
+    fn flush(&self) -> Result<()> {
+        let _writing = self.writing.lock();
+        let Some(contents) = self.pending.lock().0.take() else {
+            return Ok(());
+        };
+        write_atomic(&self.path, &contents)
+    }
+}
+
+/// Write to a temp file first, then rename (atomic operation)
+fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, contents)
+        .with_context(|| format!("Failed to write temp file: {:?}", temp_path))?;
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to save store: {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_writes_are_batched_until_flushed() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("store.json");
+        let writer = DebouncedWriter::new(path.clone(), Duration::from_millis(50));
+
+        writer.write("1".to_string()).unwrap();
+        writer.write("2".to_string()).unwrap();
+        assert!(!path.exists());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "2");
+
+        writer.write("3".to_string()).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "3");
+
+        let immediate = DebouncedWriter::new(path.clone(), Duration::ZERO);
+        immediate.write("4".to_string()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "4");
+    }
+}
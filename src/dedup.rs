@@ -0,0 +1,485 @@
+//! Echo-suppression state shared between broker tasks
+//!
+//! Bidirectional brokers send back the messages the proxy forwarded to them. Each broker
+//! task records what it forwarded and skips matching messages on the way back. The
+//! default backend keeps that state in memory. The Redis backend lets several proxy
+//! instances bridging the same brokers (active-active) see each other's forwards, so an
+//! echo of a message forwarded by one instance is not relayed again by another.
+
+use crate::config::{DedupBackend, DedupConfig};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+/// How long forwarded messages are remembered for echo detection, unless configured
+pub const ECHO_WINDOW: Duration = Duration::from_millis(500);
+
+/// Forwarded messages remembered per broker, unless configured
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Upper bound for a single Redis round trip; the broker task waits on it
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Per-broker overrides of the global `[dedup]` settings; unset fields use those
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DedupOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_entries: Option<usize>,
+}
+
+impl DedupOverride {
+    /// The settings a broker with these overrides uses
+    pub fn resolve(&self, global: &DedupConfig) -> DedupSettings {
+        DedupSettings {
+            enabled: self.enabled.unwrap_or(global.enabled),
+            window: Duration::from_millis(self.window_ms.unwrap_or(global.window_ms)),
+            max_entries: self.max_entries.unwrap_or(global.max_entries).max(1),
+        }
+    }
+}
+
+/// Effective echo detection settings of one broker connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupSettings {
+    pub enabled: bool,
+    /// How long a forwarded message is remembered
+    pub window: Duration,
+    /// Forwarded messages remembered at most; the oldest are forgotten first
+    pub max_entries: usize,
+}
+
+impl Default for DedupSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: ECHO_WINDOW,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+/// Where broker tasks record forwarded messages and look up echoes
+///
+/// `scope` identifies the downstream broker (its address and port, which unlike the
+/// broker ID is the same on every proxy instance); `hash` covers topic and payload.
+/// `settings` are the broker's window and cache limit.
+#[async_trait]
+pub trait DedupStore: Send + Sync {
+    /// Remember that a message was forwarded to the broker
+    async fn record(&self, scope: &str, hash: u64, settings: &DedupSettings) -> Result<()>;
+
+    /// Whether a message received from the broker is an echo of a recent forward
+    async fn is_echo(&self, scope: &str, hash: u64, settings: &DedupSettings) -> Result<bool>;
+}
+
+/// Create the store selected in the configuration
+pub fn build_dedup_store(config: &DedupConfig) -> Result<Arc<dyn DedupStore>> {
+    match config.backend {
+        DedupBackend::Memory => Ok(Arc::new(MemoryDedupStore::new())),
+        DedupBackend::Redis => {
+            let url = config
+                .redis_url
+                .as_deref()
+                .context("The redis dedup backend requires redis_url")?;
+            let store = RedisDedupStore::new(url, &config.key_prefix)?;
+            info!(
+                "Sharing echo detection state through Redis at {}",
+                store.address
+            );
+            Ok(Arc::new(store))
+        }
+    }
+}
+
+/// Lock shards of the memory store. Each broker's cache lives in one shard, so forwards
+/// to different brokers rarely wait on each other.
+const SHARDS: usize = 16;
+
+type EchoCache = HashMap<String, VecDeque<(u64, Instant)>>;
+
+/// Process-local store; an echo consumes its entry so identical messages sent
+/// deliberately right after still get through
+pub struct MemoryDedupStore {
+    shards: Vec<Mutex<EchoCache>>,
+}
+
+impl Default for MemoryDedupStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryDedupStore {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, scope: &str) -> &Mutex<EchoCache> {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        scope.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+}
+
+#[async_trait]
+impl DedupStore for MemoryDedupStore {
+    async fn record(&self, scope: &str, hash: u64, settings: &DedupSettings) -> Result<()> {
+        let now = Instant::now();
+        let mut entries = self.shard(scope).lock();
+        // Look up before inserting, so the common case doesn't allocate the key
+        if !entries.contains_key(scope) {
+            entries.insert(scope.to_string(), VecDeque::new());
+        }
+        let cache = entries.get_mut(scope).expect("cache was just inserted");
+        prune(cache, now, settings.window);
+        while cache.len() >= settings.max_entries {
+            cache.pop_front();
+        }
+        cache.push_back((hash, now));
+        Ok(())
+    }
+
+    async fn is_echo(&self, scope: &str, hash: u64, settings: &DedupSettings) -> Result<bool> {
+        let mut entries = self.shard(scope).lock();
+        let Some(cache) = entries.get_mut(scope) else {
+            return Ok(false);
+        };
+        prune(cache, Instant::now(), settings.window);
+        let found = match cache.iter().position(|(h, _)| *h == hash) {
+            Some(index) => {
+                cache.remove(index);
+                true
+            }
+            None => false,
+        };
+        if cache.is_empty() {
+            entries.remove(scope);
+        }
+        Ok(found)
+    }
+}
+
+fn prune(cache: &mut VecDeque<(u64, Instant)>, now: Instant, window: Duration) {
+    while cache
+        .front()
+        .is_some_and(|(_, t)| now.duration_since(*t) >= window)
+    {
+        cache.pop_front();
+    }
+}
+
+/// Store backed by Redis keys that expire after the echo window
+///
+/// Entries are not consumed: every instance subscribed to the broker receives the
+/// echo, and each of them has to drop it. Expiry bounds the key count, so the
+/// `max_entries` limit does not apply.
+pub struct RedisDedupStore {
+    address: String,
+    username: Option<String>,
+    password: Option<String>,
+    database: u32,
+    key_prefix: String,
+    connection: tokio::sync::Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl RedisDedupStore {
+    /// Parse `redis://[[user]:password@]host[:port][/db]`; connects lazily
+    pub fn new(url: &str, key_prefix: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("redis://")
+            .with_context(|| format!("Unsupported Redis URL '{}'", url))?;
+        let (credentials, rest) = match rest.rsplit_once('@') {
+            Some((credentials, rest)) => (Some(credentials), rest),
+            None => (None, rest),
+        };
+        let (host, database) = match rest.split_once('/') {
+            Some((host, "")) => (host, 0),
+            Some((host, db)) => (
+                host,
+                db.parse()
+                    .with_context(|| format!("Invalid Redis database '{}'", db))?,
+            ),
+            None => (rest, 0),
+        };
+        anyhow::ensure!(!host.is_empty(), "Redis URL '{}' has no host", url);
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:6379", host)
+        };
+        let (username, password) = match credentials.map(|c| c.split_once(':')) {
+            Some(Some((user, password))) => (
+                Some(user.to_string()).filter(|u| !u.is_empty()),
+                Some(password.to_string()),
+            ),
+            Some(None) => (None, credentials.map(str::to_string)),
+            None => (None, None),
+        };
+
+        Ok(Self {
+            address,
+            username,
+            password,
+            database,
+            key_prefix: key_prefix.to_string(),
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    fn key(&self, scope: &str, hash: u64) -> String {
+        format!("{}:{}:{:016x}", self.key_prefix, scope, hash)
+    }
+
+    /// Run one command, reconnecting first if needed. The connection is dropped on
+    /// any error so the next command starts from a clean state.
+    async fn command(&self, args: &[&[u8]]) -> Result<Reply> {
+        let mut connection = self.connection.lock().await;
+        let result = tokio::time::timeout(REDIS_TIMEOUT, async {
+            if connection.is_none() {
+                *connection = Some(self.connect().await?);
+            }
+            let stream = connection
+                .as_mut()
+                .expect("connection was just established");
+            request(stream, args).await
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Redis request timed out")));
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    async fn connect(&self) -> Result<BufStream<TcpStream>> {
+        let stream = TcpStream::connect(&self.address)
+            .await
+            .with_context(|| format!("Failed to connect to Redis at {}", self.address))?;
+        let _ = stream.set_nodelay(true);
+        let mut stream = BufStream::new(stream);
+        if let Some(password) = &self.password {
+            match &self.username {
+                Some(username) => {
+                    request(
+                        &mut stream,
+                        &[b"AUTH", username.as_bytes(), password.as_bytes()],
+                    )
+                    .await?
+                }
+                None => request(&mut stream, &[b"AUTH", password.as_bytes()]).await?,
+            };
+        }
+        if self.database != 0 {
+            let database = self.database.to_string();
+            request(&mut stream, &[b"SELECT", database.as_bytes()]).await?;
+        }
+        Ok(stream)
+    }
+}
+
+#[async_trait]
+impl DedupStore for RedisDedupStore {
+    async fn record(&self, scope: &str, hash: u64, settings: &DedupSettings) -> Result<()> {
+        let key = self.key(scope, hash);
+        let ttl = settings.window.as_millis().max(1).to_string();
+        self.command(&[b"SET", key.as_bytes(), b"1", b"PX", ttl.as_bytes()])
+            .await?;
+        Ok(())
+    }
+
+    async fn is_echo(&self, scope: &str, hash: u64, _settings: &DedupSettings) -> Result<bool> {
+        let key = self.key(scope, hash);
+        match self.command(&[b"EXISTS", key.as_bytes()]).await? {
+            Reply::Integer(count) => Ok(count > 0),
+            other => {
+                warn!("Unexpected Redis reply to EXISTS: {:?}", other);
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// The subset of RESP replies the store's commands produce
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+async fn request(stream: &mut BufStream<TcpStream>, args: &[&[u8]]) -> Result<Reply> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    read_reply(stream).await
+}
+
+async fn read_reply(stream: &mut BufStream<TcpStream>) -> Result<Reply> {
+    let mut line = String::new();
+    anyhow::ensure!(
+        stream.read_line(&mut line).await? > 0,
+        "Redis closed the connection"
+    );
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (kind, value) = line.split_at(line.len().min(1));
+    match kind {
+        "+" => Ok(Reply::Status(value.to_string())),
+        "-" => anyhow::bail!("Redis error: {}", value),
+        ":" => Ok(Reply::Integer(
+            value.parse().context("Invalid Redis integer")?,
+        )),
+        "$" => {
+            let len: i64 = value.parse().context("Invalid Redis bulk length")?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut data = vec![0; len as usize + 2];
+            stream.read_exact(&mut data).await?;
+            data.truncate(len as usize);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => anyhow::bail!("Unsupported Redis reply '{}'", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_memory_store_consumes_echoes_per_scope() {
+        let store = MemoryDedupStore::new();
+        let settings = DedupSettings {
+            window: Duration::from_millis(50),
+            ..DedupSettings::default()
+        };
+        store.record("a:1883", 7, &settings).await.unwrap();
+
+        assert!(!store.is_echo("b:1883", 7, &settings).await.unwrap());
+        assert!(store.is_echo("a:1883", 7, &settings).await.unwrap());
+        assert!(!store.is_echo("a:1883", 7, &settings).await.unwrap());
+
+        store.record("a:1883", 8, &settings).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(!store.is_echo("a:1883", 8, &settings).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_forgets_oldest_beyond_max_entries() {
+        let store = MemoryDedupStore::new();
+        let settings = DedupSettings {
+            max_entries: 2,
+            ..DedupSettings::default()
+        };
+        for hash in 1..=3 {
+            store.record("a:1883", hash, &settings).await.unwrap();
+        }
+
+        assert!(!store.is_echo("a:1883", 1, &settings).await.unwrap());
+        assert!(store.is_echo("a:1883", 2, &settings).await.unwrap());
+        assert!(store.is_echo("a:1883", 3, &settings).await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_memory_store_shards_brokers_independently() {
+        let store = Arc::new(MemoryDedupStore::new());
+        let settings = DedupSettings::default();
+        let tasks: Vec<_> = (0..32)
+            .map(|broker| {
+                let store = Arc::clone(&store);
+                tokio::spawn(async move {
+                    let scope = format!("broker-{}:1883", broker);
+                    for hash in 0..100 {
+                        store.record(&scope, hash, &settings).await.unwrap();
+                    }
+                    for hash in 0..100 {
+                        assert!(store.is_echo(&scope, hash, &settings).await.unwrap());
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_override_falls_back_to_global_settings() {
+        let global = DedupConfig::default();
+        let settings = DedupOverride {
+            window_ms: Some(2000),
+            ..DedupOverride::default()
+        }
+        .resolve(&global);
+
+        assert!(settings.enabled);
+        assert_eq!(settings.window, Duration::from_millis(2000));
+        assert_eq!(settings.max_entries, DEFAULT_MAX_ENTRIES);
+    }
+
+    #[tokio::test]
+    async fn test_redis_store_speaks_resp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://:secret@{}/2", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufStream::new(socket);
+            let replies: [&[u8]; 4] = [b"+OK\r\n", b"+OK\r\n", b"+OK\r\n", b":1\r\n"];
+            let mut commands = Vec::new();
+            for reply in replies {
+                // Each request is an array header followed by (length, value) line pairs
+                let mut header = String::new();
+                socket.read_line(&mut header).await.unwrap();
+                let count: usize = header.trim()[1..].parse().unwrap();
+                let mut args = Vec::new();
+                for _ in 0..count {
+                    let mut line = String::new();
+                    socket.read_line(&mut line).await.unwrap();
+                    line.clear();
+                    socket.read_line(&mut line).await.unwrap();
+                    args.push(line.trim_end().to_string());
+                }
+                commands.push(args.join(" "));
+                socket.write_all(reply).await.unwrap();
+                socket.flush().await.unwrap();
+            }
+            commands
+        });
+
+        let store = RedisDedupStore::new(&url, "proxy").unwrap();
+        let settings = DedupSettings::default();
+        store.record("broker:1883", 255, &settings).await.unwrap();
+        assert!(store.is_echo("broker:1883", 255, &settings).await.unwrap());
+
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                "AUTH secret".to_string(),
+                "SELECT 2".to_string(),
+                "SET proxy:broker:1883:00000000000000ff 1 PX 500".to_string(),
+                "EXISTS proxy:broker:1883:00000000000000ff".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,503 @@
+//! All-or-nothing delivery groups for critical topics
+//!
+//! A message on a group's topics goes to the group's brokers only, and only while all of
+//! them are connected: until then it waits in the group's queue rather than reaching some
+//! members and not others. Once published, every member has to acknowledge it; if one
+//! doesn't, the whole group is retried. MQTT has no way to take a message back, so a
+//! member that acknowledged an attempt receives the message again on the retry.
+//!
+//! Queues are delivered in order by `run_delivery_group_retries`, one message per group at
+//! a time, and written to `state_path` on every change (in the background), so messages
+//! waiting for a group survive a restart.
+
+use crate::broker_actor::{BrokerHandle, PendingPublish};
+use crate::config::{DeliveryGroup, DeliveryGroupsConfig};
+use crate::connection_manager::ConnectionManager;
+use crate::debounced_write::DebouncedWriter;
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// A message waiting for every member of its group to acknowledge it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GroupMessage {
+    /// Identifies the message while it is being delivered
+    #[serde(default)]
+    id: u64,
+    group: String,
+    topic: String,
+    /// Base64 encoded
+    payload: String,
+    qos: u8,
+    retain: bool,
+    /// Delivery attempts that didn't reach every member
+    attempts: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupCounters {
+    pub name: String,
+    pub brokers: Vec<String>,
+    /// Messages waiting for the whole group
+    pub pending: usize,
+    /// Messages every member acknowledged since startup
+    pub delivered: u64,
+}
+
+#[derive(Default)]
+struct GroupState {
+    pending: Vec<GroupMessage>,
+    delivered: HashMap<String, u64>,
+    /// ID of the next queued message
+    next_id: u64,
+}
+
+pub struct DeliveryGroups {
+    config: DeliveryGroupsConfig,
+    state: Mutex<GroupState>,
+    writer: DebouncedWriter,
+    /// Woken when a message is queued, so delivery doesn't wait for the retry interval
+    queued: Notify,
+}
+
+impl Default for DeliveryGroups {
+    fn default() -> Self {
+        let config = DeliveryGroupsConfig::default();
+        Self {
+            writer: DebouncedWriter::new(PathBuf::from(&config.state_path), Duration::ZERO),
+            config,
+            state: Mutex::new(GroupState::default()),
+            queued: Notify::new(),
+        }
+    }
+}
+
+/// The first waiting message of a group, handed to every member; `finish` waits for their
+/// acknowledgements
+pub struct Delivery {
+    id: u64,
+    group: String,
+    topic: String,
+    publishes: Vec<(String, PendingPublish)>,
+}
+
+impl DeliveryGroups {
+    /// Load the messages a previous run left waiting from `config.state_path`
+    pub fn new(config: DeliveryGroupsConfig) -> Result<Self> {
+        let path = PathBuf::from(&config.state_path);
+        let mut pending: Vec<GroupMessage> = if config.groups.is_empty() || !path.exists() {
+            Vec::new()
+        } else {
+            let json = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read delivery group state: {:?}", path))?;
+            serde_json::from_str(&json)
+                .with_context(|| format!("Failed to parse delivery group state: {:?}", path))?
+        };
+        pending.retain(|message| {
+            let known = config.groups.iter().any(|g| g.name == message.group);
+            if !known {
+                warn!(
+                    "Dropping waiting message on '{}' of removed delivery group '{}'",
+                    message.topic, message.group
+                );
+            }
+            known
+        });
+        if !pending.is_empty() {
+            info!(
+                "{} messages waiting for delivery groups from the previous run",
+                pending.len()
+            );
+        }
+        for (id, message) in pending.iter_mut().enumerate() {
+            message.id = id as u64;
+        }
+        Ok(Self {
+            writer: DebouncedWriter::new(path, Duration::ZERO),
+            config,
+            state: Mutex::new(GroupState {
+                next_id: pending.len() as u64,
+                pending,
+                delivered: HashMap::new(),
+            }),
+            queued: Notify::new(),
+        })
+    }
+
+    /// The group whose topics match `topic`
+    pub fn group_for(&self, topic: &str) -> Option<&DeliveryGroup> {
+        self.config.groups.iter().find(|group| {
+            group
+                .topics
+                .iter()
+                .any(|pattern| ConnectionManager::topic_matches_pattern(pattern, topic))
+        })
+    }
+
+    /// Check that every group has members and that they are all in `enabled`: a group
+    /// with a missing member never delivers, its messages just wait
+    pub fn validate(&self, enabled: &[String]) -> Result<()> {
+        for group in &self.config.groups {
+            if group.brokers.is_empty() {
+                bail!("Delivery group '{}' has no brokers", group.name);
+            }
+            if let Some(id) = group.brokers.iter().find(|id| !enabled.contains(id)) {
+                bail!(
+                    "Delivery group '{}' includes broker '{}', which is not an enabled broker",
+                    group.name,
+                    id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Topic patterns of all groups
+    pub fn topics(&self) -> impl Iterator<Item = &String> {
+        self.config.groups.iter().flat_map(|group| &group.topics)
+    }
+
+    /// Queue a message for `group` behind the ones already waiting; it is delivered by
+    /// `run_delivery_group_retries`
+    pub async fn enqueue(
+        &self,
+        group: &DeliveryGroup,
+        topic: &str,
+        payload: &Bytes,
+        qos: QoS,
+        retain: bool,
+    ) {
+        let mut state = self.state.lock().await;
+        let queued = state
+            .pending
+            .iter()
+            .filter(|message| message.group == group.name)
+            .count();
+        if queued >= self.config.max_pending {
+            if let Some(oldest) = state.pending.iter().position(|m| m.group == group.name) {
+                let dropped = state.pending.remove(oldest);
+                warn!(
+                    "Delivery group '{}' has {} messages waiting; dropped the oldest on '{}'",
+                    group.name, queued, dropped.topic
+                );
+            }
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pending.push(GroupMessage {
+            id,
+            group: group.name.clone(),
+            topic: topic.to_string(),
+            payload: BASE64.encode(payload),
+            qos: qos as u8,
+            retain,
+            attempts: 0,
+        });
+        self.save(&state.pending);
+        drop(state);
+        self.queued.notify_one();
+    }
+
+    /// Hand the first waiting message of each group to its members, for the groups whose
+    /// members are all connected (except those in `skip`). Doesn't wait for
+    /// acknowledgements, so it can run under the connection manager's lock.
+    pub async fn start_deliveries(
+        &self,
+        brokers: &HashMap<String, BrokerHandle>,
+        skip: &HashSet<String>,
+    ) -> Vec<Delivery> {
+        let mut state = self.state.lock().await;
+        let mut deliveries = Vec::new();
+        let mut changed = false;
+        for group in &self.config.groups {
+            if skip.contains(&group.name) {
+                continue;
+            }
+            let members: Option<Vec<&BrokerHandle>> = group
+                .brokers
+                .iter()
+                .map(|id| brokers.get(id).filter(|broker| broker.is_connected()))
+                .collect();
+            let Some(members) = members.filter(|members| !members.is_empty()) else {
+                continue;
+            };
+            while let Some(index) = state.pending.iter().position(|m| m.group == group.name) {
+                let message = &state.pending[index];
+                let Ok(payload) = BASE64.decode(&message.payload) else {
+                    warn!("Dropping undecodable message on '{}'", message.topic);
+                    state.pending.remove(index);
+                    changed = true;
+                    continue;
+                };
+                let payload = Bytes::from(payload);
+                let qos = rumqttc::qos(message.qos).unwrap_or(QoS::AtLeastOnce);
+                let publishes = members
+                    .iter()
+                    .map(|broker| {
+                        let publish = broker.publish_acked(
+                            &broker.rewrite_topic(&message.topic),
+                            payload.clone(),
+                            qos,
+                            message.retain,
+                            None,
+                        );
+                        (broker.config.name.clone(), publish)
+                    })
+                    .collect();
+                deliveries.push(Delivery {
+                    id: message.id,
+                    group: group.name.clone(),
+                    topic: message.topic.clone(),
+                    publishes,
+                });
+                break;
+            }
+        }
+        if changed {
+            self.save(&state.pending);
+        }
+        deliveries
+    }
+
+    /// Wait for every member to acknowledge `delivery`; the message leaves the queue if
+    /// they all did. Returns whether they did.
+    pub async fn finish(&self, delivery: Delivery) -> bool {
+        let mut failed = Vec::new();
+        for (name, publish) in delivery.publishes {
+            if publish.outcome().await.is_err() {
+                failed.push(name);
+            }
+        }
+        let mut state = self.state.lock().await;
+        // Dropped meanwhile to make room for newer messages
+        let Some(index) = state.pending.iter().position(|m| m.id == delivery.id) else {
+            return failed.is_empty();
+        };
+        if failed.is_empty() {
+            debug!(
+                "Delivered '{}' to all of delivery group '{}'",
+                delivery.topic, delivery.group
+            );
+            state.pending.remove(index);
+            *state.delivered.entry(delivery.group).or_default() += 1;
+        } else {
+            let message = &mut state.pending[index];
+            message.attempts += 1;
+            warn!(
+                "Delivery group '{}': {} did not acknowledge '{}' (attempt {}); retrying the group",
+                delivery.group,
+                failed.join(", "),
+                delivery.topic,
+                message.attempts
+            );
+        }
+        self.save(&state.pending);
+        failed.is_empty()
+    }
+
+    pub async fn counters(&self) -> Vec<GroupCounters> {
+        let state = self.state.lock().await;
+        self.config
+            .groups
+            .iter()
+            .map(|group| GroupCounters {
+                name: group.name.clone(),
+                brokers: group.brokers.clone(),
+                pending: state
+                    .pending
+                    .iter()
+                    .filter(|message| message.group == group.name)
+                    .count(),
+                delivered: state.delivered.get(&group.name).copied().unwrap_or(0),
+            })
+            .collect()
+    }
+
+    /// Write the waiting messages now instead of in the background, e.g. on shutdown
+    pub fn flush_state(&self) -> Result<()> {
+        self.writer.flush()
+    }
+
+    fn save(&self, pending: &[GroupMessage]) {
+        match serde_json::to_string(pending) {
+            Ok(json) => self.writer.write_in_background(json),
+            Err(e) => warn!("Failed to serialize delivery group state: {}", e),
+        }
+    }
+}
+
+/// Deliver waiting group messages as they are queued, and retry them every
+/// `retry_interval_secs`, until `shutdown` is cancelled. Acknowledgements are awaited
+/// without holding the connection manager's lock, so a slow member doesn't hold up
+/// forwarding.
+pub async fn run_delivery_group_retries(
+    connection_manager: Arc<RwLock<ConnectionManager>>,
+    config: DeliveryGroupsConfig,
+    shutdown: CancellationToken,
+) {
+    if config.groups.is_empty() {
+        return;
+    }
+    let period = Duration::from_secs(config.retry_interval_secs.max(1));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        let groups = connection_manager.read().await.delivery_groups();
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+            _ = groups.queued.notified() => {}
+        }
+        // Groups whose message wasn't acknowledged wait for the next retry
+        let mut failed = HashSet::new();
+        loop {
+            let deliveries = connection_manager
+                .read()
+                .await
+                .start_group_deliveries(&failed)
+                .await;
+            if deliveries.is_empty() {
+                break;
+            }
+            for delivery in deliveries {
+                let group = delivery.group.clone();
+                if !groups.finish(delivery).await {
+                    failed.insert(group);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn config(state_path: &std::path::Path) -> DeliveryGroupsConfig {
+        DeliveryGroupsConfig {
+            state_path: state_path.to_string_lossy().into_owned(),
+            retry_interval_secs: 10,
+            max_pending: 2,
+            groups: vec![DeliveryGroup {
+                name: "billing".to_string(),
+                topics: vec!["billing/#".to_string()],
+                brokers: vec!["historian".to_string(), "cloud".to_string()],
+            }],
+        }
+    }
+
+    fn broker(id: &str, acks: &Arc<AtomicBool>) -> (String, BrokerHandle) {
+        let config = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": id,
+            "address": "127.0.0.1",
+            "port": 1,
+            "clientIdPrefix": "test"
+        }))
+        .unwrap();
+        (id.to_string(), BrokerHandle::stub(config, Arc::clone(acks)))
+    }
+
+    #[test]
+    fn test_validate_requires_enabled_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let groups = DeliveryGroups::new(config(&dir.path().join("groups.json"))).unwrap();
+        let known = ["historian".to_string(), "cloud".to_string()];
+        assert!(groups.validate(&known).is_ok());
+        assert!(groups.validate(&known[..1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_group_is_retried_until_every_member_acks() {
+        let dir = tempfile::tempdir().unwrap();
+        let groups = DeliveryGroups::new(config(&dir.path().join("groups.json"))).unwrap();
+        let group = groups.group_for("billing/invoice").unwrap().clone();
+        for payload in ["1", "2"] {
+            groups
+                .enqueue(
+                    &group,
+                    "billing/invoice",
+                    &Bytes::from(payload),
+                    QoS::AtLeastOnce,
+                    false,
+                )
+                .await;
+        }
+        let historian_acks = Arc::new(AtomicBool::new(true));
+        let cloud_acks = Arc::new(AtomicBool::new(false));
+        let brokers: HashMap<_, _> = [
+            broker("historian", &historian_acks),
+            broker("cloud", &cloud_acks),
+        ]
+        .into_iter()
+        .collect();
+
+        // One member fails: the first message stays at the front for the next attempt
+        let deliveries = groups.start_deliveries(&brokers, &HashSet::new()).await;
+        assert_eq!(deliveries.len(), 1);
+        for delivery in deliveries {
+            assert!(!groups.finish(delivery).await);
+        }
+        let counters = groups.counters().await;
+        assert_eq!((counters[0].pending, counters[0].delivered), (2, 0));
+        assert_eq!(groups.state.lock().await.pending[0].attempts, 1);
+        let skip = HashSet::from(["billing".to_string()]);
+        assert!(groups.start_deliveries(&brokers, &skip).await.is_empty());
+
+        // Both ack: the message leaves the queue, the next one is up
+        cloud_acks.store(true, Ordering::Relaxed);
+        for delivery in groups.start_deliveries(&brokers, &HashSet::new()).await {
+            assert!(groups.finish(delivery).await);
+        }
+        let counters = groups.counters().await;
+        assert_eq!((counters[0].pending, counters[0].delivered), (1, 1));
+        let state = groups.state.lock().await;
+        assert_eq!(BASE64.decode(&state.pending[0].payload).unwrap(), b"2");
+    }
+
+    #[tokio::test]
+    async fn test_waiting_messages_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir.path().join("groups.json"));
+        let groups = DeliveryGroups::new(config.clone()).unwrap();
+        let group = groups.group_for("billing/invoice").unwrap().clone();
+        assert!(groups.group_for("sensors/a").is_none());
+        for payload in ["1", "2", "3"] {
+            groups
+                .enqueue(
+                    &group,
+                    "billing/invoice",
+                    &Bytes::from(payload),
+                    QoS::AtLeastOnce,
+                    false,
+                )
+                .await;
+        }
+        // Members aren't connected: nothing is delivered, nothing is lost
+        assert!(groups
+            .start_deliveries(&HashMap::new(), &HashSet::new())
+            .await
+            .is_empty());
+        groups.flush_state().unwrap();
+
+        let restarted = DeliveryGroups::new(config).unwrap();
+        let counters = restarted.counters().await;
+        assert_eq!(counters[0].pending, 2);
+        let state = restarted.state.lock().await;
+        let payloads: Vec<_> = state
+            .pending
+            .iter()
+            .map(|m| BASE64.decode(&m.payload).unwrap())
+            .collect();
+        assert_eq!(payloads, vec![b"2".to_vec(), b"3".to_vec()]);
+    }
+}
@@ -0,0 +1,198 @@
+//! Delta-only forwarding for JSON state topics
+//!
+//! Devices that publish their full state as JSON often change one field at a time. For
+//! topics matching a rule, the proxy compares each payload with the last state seen on the
+//! topic and either forwards the full payload only when one of the watched fields changed
+//! (`changed`), or forwards an object with just the top-level keys that changed (`diff`,
+//! removed keys are sent as `null`). The first message on a topic is always forwarded in
+//! full; payloads that aren't JSON objects are forwarded unchanged.
+
+use crate::config::{DeltaMode, DeltaRule};
+use crate::connection_manager::ConnectionManager;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Topics remembered at once; further topics are forwarded in full
+const MAX_TRACKED_TOPICS: usize = 10_000;
+
+/// Last JSON state per topic for the configured delta rules
+#[derive(Default)]
+pub struct DeltaFilter {
+    rules: Vec<DeltaRule>,
+    states: Mutex<HashMap<String, TopicState>>,
+}
+
+struct TopicState {
+    rule: usize,
+    /// Last state forwarded (`changed`) or received (`diff`)
+    last: Map<String, Value>,
+}
+
+impl DeltaFilter {
+    pub fn new(rules: Vec<DeltaRule>) -> Self {
+        Self {
+            rules: rules
+                .into_iter()
+                .filter(|rule| !rule.topic.is_empty())
+                .collect(),
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The payload to forward, or `None` if nothing relevant changed
+    pub fn apply(&self, topic: &str, payload: Bytes) -> Option<Bytes> {
+        if self.rules.is_empty() {
+            return Some(payload);
+        }
+        let mut states = self.states.lock();
+        let rule = match states.get(topic) {
+            Some(state) => state.rule,
+            None => match self
+                .rules
+                .iter()
+                .position(|rule| ConnectionManager::topic_matches_pattern(&rule.topic, topic))
+            {
+                Some(rule) => rule,
+                None => return Some(payload),
+            },
+        };
+        let Ok(Value::Object(state)) = serde_json::from_slice::<Value>(&payload) else {
+            return Some(payload);
+        };
+
+        let Some(TopicState { last: previous, .. }) = states.get_mut(topic) else {
+            if states.len() < MAX_TRACKED_TOPICS {
+                states.insert(topic.to_string(), TopicState { rule, last: state });
+            }
+            return Some(payload);
+        };
+        let rule = &self.rules[rule];
+        let forward = match rule.mode {
+            DeltaMode::Changed => {
+                let changed = if rule.fields.is_empty() {
+                    *previous != state
+                } else {
+                    rule.fields
+                        .iter()
+                        .any(|field| lookup(previous, field) != lookup(&state, field))
+                };
+                changed.then_some(payload)
+            }
+            DeltaMode::Diff => {
+                let diff = diff(previous, &state);
+                if diff.is_empty() {
+                    None
+                } else {
+                    serde_json::to_vec(&Value::Object(diff))
+                        .ok()
+                        .map(Bytes::from)
+                }
+            }
+        };
+        // `changed` compares against the last forwarded state; `diff` against the latest
+        if forward.is_some() || rule.mode == DeltaMode::Diff {
+            *previous = state;
+        }
+        forward
+    }
+}
+
+/// Value at a dotted path (`battery.level`)
+fn lookup<'a>(object: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let mut parts = path.split('.');
+    let mut value = object.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_object()?.get(part)?;
+    }
+    Some(value)
+}
+
+/// Top-level keys that differ between two states; removed keys map to `null`
+fn diff(previous: &Map<String, Value>, current: &Map<String, Value>) -> Map<String, Value> {
+    let mut diff: Map<String, Value> = current
+        .iter()
+        .filter(|(key, value)| previous.get(*key) != Some(*value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    for key in previous.keys() {
+        if !current.contains_key(key) {
+            diff.insert(key.clone(), Value::Null);
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(topic: &str, mode: DeltaMode, fields: &[&str]) -> DeltaRule {
+        DeltaRule {
+            topic: topic.to_string(),
+            mode,
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    fn forwarded(filter: &DeltaFilter, topic: &str, payload: &str) -> Option<Value> {
+        filter
+            .apply(topic, Bytes::from(payload.to_string()))
+            .map(|p| serde_json::from_slice(&p).unwrap())
+    }
+
+    #[test]
+    fn test_changed_mode_watches_fields() {
+        let filter = DeltaFilter::new(vec![rule(
+            "devices/+/state",
+            DeltaMode::Changed,
+            &["power", "battery.level"],
+        )]);
+        let topic = "devices/d1/state";
+
+        assert!(forwarded(
+            &filter,
+            topic,
+            r#"{"power":"on","battery":{"level":90},"rssi":-60}"#
+        )
+        .is_some());
+        // Unwatched field changed
+        assert!(forwarded(
+            &filter,
+            topic,
+            r#"{"power":"on","battery":{"level":90},"rssi":-61}"#
+        )
+        .is_none());
+        assert!(forwarded(
+            &filter,
+            topic,
+            r#"{"power":"on","battery":{"level":89},"rssi":-61}"#
+        )
+        .is_some());
+        // Non-JSON and unmatched topics pass through
+        assert!(filter
+            .apply(topic, Bytes::from_static(b"offline"))
+            .is_some());
+        assert!(forwarded(&filter, "other", r#"{"power":"on"}"#).is_some());
+    }
+
+    #[test]
+    fn test_diff_mode_forwards_changed_keys() {
+        let filter = DeltaFilter::new(vec![rule("devices/#", DeltaMode::Diff, &[])]);
+        let topic = "devices/d1/state";
+
+        assert_eq!(
+            forwarded(&filter, topic, r#"{"power":"on","temp":21}"#),
+            Some(serde_json::json!({"power": "on", "temp": 21}))
+        );
+        assert_eq!(
+            forwarded(&filter, topic, r#"{"power":"on","temp":21}"#),
+            None
+        );
+        assert_eq!(
+            forwarded(&filter, topic, r#"{"temp":22,"mode":"eco"}"#),
+            Some(serde_json::json!({"temp": 22, "mode": "eco", "power": null}))
+        );
+    }
+}
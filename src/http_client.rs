@@ -0,0 +1,313 @@
+//! Minimal HTTP/1.1 client for `http://` and `https://` endpoints
+//!
+//! Enough for polled sources (`sources`), the OTLP span exporter (`otel`), the ACME
+//! client (`acme`) and remote configuration (`remote_config`): one request per connection
+//! (`Connection: close`), with the response read to the end and its body taken by
+//! Content-Length or chunked encoding. `https://` servers are verified against the
+//! platform roots (`SSL_CERT_FILE` adds others). Callers add their own timeouts.
+
+use crate::connection_manager::load_root_store;
+use anyhow::{bail, Context, Result};
+use rustls_pki_types::ServerName;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// Largest HTTP response read
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+/// Send a request and return the body of a 2xx response. `body` is the content type and
+/// content of the request body, if any.
+pub async fn request<'a>(
+    method: &str,
+    url: &HttpUrl,
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    body: Option<(&str, &[u8])>,
+) -> Result<Vec<u8>> {
+    send(method, url, headers, body).await?.into_body()
+}
+
+/// Send a request and return the response, whatever its status
+pub async fn send<'a>(
+    method: &str,
+    url: &HttpUrl,
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    body: Option<(&str, &[u8])>,
+) -> Result<HttpResponse> {
+    let stream = TcpStream::connect((url.host.as_str(), url.port))
+        .await
+        .with_context(|| format!("Failed to connect to {}", url.authority))?;
+    if !url.tls {
+        return exchange(stream, method, url, headers, body).await;
+    }
+    let server_name = ServerName::try_from(url.host.clone())
+        .with_context(|| format!("Invalid server name '{}'", url.host))?;
+    let stream = TlsConnector::from(tls_config()?)
+        .connect(server_name, stream)
+        .await
+        .with_context(|| format!("TLS handshake with {} failed", url.authority))?;
+    exchange(stream, method, url, headers, body).await
+}
+
+/// Client configuration for `https://`, built on first use
+fn tls_config() -> Result<Arc<rustls::ClientConfig>> {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    if let Some(config) = CONFIG.get() {
+        return Ok(Arc::clone(config));
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(load_root_store(None)?)
+        .with_no_client_auth();
+    Ok(Arc::clone(CONFIG.get_or_init(|| Arc::new(config))))
+}
+
+async fn exchange<'a, S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    method: &str,
+    url: &HttpUrl,
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    body: Option<(&str, &[u8])>,
+) -> Result<HttpResponse> {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        method, url.target, url.authority
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some((content_type, content)) = body {
+        request.push_str(&format!(
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            content_type,
+            content.len()
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    if let Some((_, content)) = body {
+        stream.write_all(content).await?;
+    }
+
+    let mut response = Vec::new();
+    match (&mut stream)
+        .take(MAX_RESPONSE_BYTES + 1)
+        .read_to_end(&mut response)
+        .await
+    {
+        // Servers that close without a TLS close_notify
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !response.is_empty() => {}
+        result => {
+            result?;
+        }
+    }
+    if response.len() as u64 > MAX_RESPONSE_BYTES {
+        bail!("Response larger than {} bytes", MAX_RESPONSE_BYTES);
+    }
+    parse_response(&response)
+}
+
+/// A response with its body decoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    /// Header names and values as received
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Value of the first header called `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The body of a 2xx response
+    pub fn into_body(self) -> Result<Vec<u8>> {
+        if !self.is_success() {
+            bail!("HTTP status {}", self.status);
+        }
+        Ok(self.body)
+    }
+}
+
+/// The parts of an `http://` or `https://` URL needed for a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpUrl {
+    tls: bool,
+    /// `host[:port]` for the Host header
+    authority: String,
+    host: String,
+    port: u16,
+    /// Path and query
+    target: String,
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let (tls, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => bail!("Only http:// and https:// URLs are supported"),
+        };
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // An IPv6 address without a port has colons but ends with `]`
+            Some((host, port)) if !port.ends_with(']') => {
+                (host, port.parse().context("Invalid port")?)
+            }
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            bail!("URL has no host");
+        }
+        let target = if target.starts_with('?') {
+            format!("/{}", target)
+        } else {
+            target.to_string()
+        };
+        Ok(Self {
+            tls,
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            target,
+        })
+    }
+
+    /// `host[:port]`, for messages that shouldn't show the path and query
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+}
+
+/// Status, headers and decoded body of an HTTP/1.x response
+fn parse_response(response: &[u8]) -> Result<HttpResponse> {
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .context("Incomplete HTTP response")?;
+    let head = std::str::from_utf8(&response[..end]).context("Invalid HTTP response head")?;
+    let body = &response[end + 4..];
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .with_context(|| format!("Invalid HTTP status line '{}'", status_line))?;
+
+    let mut chunked = false;
+    let mut content_length = None;
+    let mut headers = Vec::new();
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.to_ascii_lowercase().contains("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse::<usize>().context("Invalid Content-Length")?);
+        }
+        headers.push((name.trim().to_string(), value.to_string()));
+    }
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        match content_length {
+            Some(length) => body
+                .get(..length)
+                .context("Response shorter than its Content-Length")?
+                .to_vec(),
+            None => body.to_vec(),
+        }
+    };
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Join the chunks of a `Transfer-Encoding: chunked` body
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut joined = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .context("Truncated chunk size")?;
+        let size_line = std::str::from_utf8(&body[..line_end]).context("Invalid chunk size")?;
+        let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_hex, 16).context("Invalid chunk size")?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(joined);
+        }
+        joined.extend_from_slice(body.get(..size).context("Truncated chunk")?);
+        body = body.get(size + 2..).context("Truncated chunk")?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url_and_response() {
+        assert_eq!(
+            HttpUrl::parse("http://10.0.0.5:8080/api/status?unit=c").unwrap(),
+            HttpUrl {
+                tls: false,
+                authority: "10.0.0.5:8080".to_string(),
+                host: "10.0.0.5".to_string(),
+                port: 8080,
+                target: "/api/status?unit=c".to_string(),
+            }
+        );
+        let url = HttpUrl::parse("http://[::1]").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 80));
+        assert_eq!(url.target, "/");
+        let url = HttpUrl::parse("https://acme.example.com/directory").unwrap();
+        assert_eq!((url.tls, url.port), (true, 443));
+        assert!(HttpUrl::parse("ftp://example.com/").is_err());
+
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}trailing")
+                .and_then(HttpResponse::into_body)
+                .unwrap(),
+            b"{}"
+        );
+        assert_eq!(
+            parse_response(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3;x=y\r\n:1}\r\n0\r\n\r\n"
+            )
+            .and_then(HttpResponse::into_body)
+            .unwrap(),
+            b"{\"a\":1}"
+        );
+        let created = parse_response(
+            b"HTTP/1.1 201 Created\r\nReplay-Nonce: n1\r\nContent-Length: 0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            (created.status, created.header("replay-nonce")),
+            (201, Some("n1"))
+        );
+        assert!(parse_response(b"HTTP/1.1 503 Service Unavailable\r\n\r\n")
+            .and_then(HttpResponse::into_body)
+            .is_err());
+    }
+}
@@ -0,0 +1,326 @@
+//! Ingestion of messages over HTTP, for services that can't speak MQTT
+//!
+//! `POST /api/ingest` takes a JSON array of records and `POST /api/ingest/stream` takes
+//! newline-delimited JSON records, handled as they arrive. Each record goes through the
+//! same path as a PUBLISH from a listener client: ingest decompression, the client
+//! publish script hook, retained messages, rules, routing and the Web UI stream, with
+//! `[web_ui.ingest] client_id` as the publishing client. MQTT client ACLs don't apply;
+//! keep the API port private when ingestion is enabled. The CoAP bridge (`coap`) publishes
+//! through the same `Ingestor`.
+
+use crate::client_registry::ClientMessage;
+use crate::connection_manager::ConnectionManager;
+use crate::metrics::Metrics;
+use crate::script_hooks::ScriptHook;
+use crate::web_server::MqttMessage;
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use bytes::Bytes;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{broadcast, RwLock};
+use tracing::debug;
+
+/// Longest line accepted on the streaming endpoint
+pub const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+/// Errors listed in a summary; the rest are only counted
+const MAX_REPORTED_ERRORS: usize = 100;
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IngestRecord {
+    pub topic: String,
+    /// Text, or base64 with `"encoding": "base64"`
+    #[serde(default)]
+    pub payload: String,
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl IngestRecord {
+    /// The payload and QoS to publish with, or why the record can't be published
+    pub fn decode(&self) -> Result<(Bytes, QoS)> {
+        if self.topic.is_empty() || self.topic.contains(['+', '#']) {
+            bail!("Invalid topic '{}'", self.topic);
+        }
+        let qos = match self.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            other => bail!("Invalid QoS {}", other),
+        };
+        let payload = match self.encoding {
+            PayloadEncoding::Utf8 => Bytes::from(self.payload.clone()),
+            PayloadEncoding::Base64 => Bytes::from(
+                base64::engine::general_purpose::STANDARD
+                    .decode(&self.payload)
+                    .context("Invalid base64 payload")?,
+            ),
+        };
+        Ok((payload, qos))
+    }
+}
+
+/// Outcome of a batch or stream
+#[derive(Debug, Default, Serialize)]
+pub struct IngestSummary {
+    pub accepted: usize,
+    pub rejected: usize,
+    /// The first rejected records, by position in the batch or stream (from 0)
+    pub errors: Vec<RecordError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecordError {
+    pub index: usize,
+    pub error: String,
+}
+
+impl IngestSummary {
+    pub fn record(&mut self, index: usize, result: Result<()>) {
+        match result {
+            Ok(()) => self.accepted += 1,
+            Err(e) => {
+                self.rejected += 1;
+                if self.errors.len() < MAX_REPORTED_ERRORS {
+                    self.errors.push(RecordError {
+                        index,
+                        error: format!("{:#}", e),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Split the complete lines off the front of `buffer`, leaving a trailing partial line.
+/// Blank lines are skipped.
+pub fn take_lines(buffer: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let Some(end) = buffer.iter().rposition(|byte| *byte == b'\n') else {
+        return Vec::new();
+    };
+    let rest = buffer.split_off(end + 1);
+    let complete = std::mem::replace(buffer, rest);
+    complete
+        .split(|byte| *byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+/// Publishes ingested records into the forwarding pipeline
+pub struct Ingestor {
+    connection_manager: Arc<RwLock<ConnectionManager>>,
+    /// The Web UI message stream, if the UI is enabled
+    message_tx: Option<broadcast::Sender<MqttMessage>>,
+    metrics: Arc<Metrics>,
+    client_id: String,
+    debug_deliveries: bool,
+}
+
+impl Ingestor {
+    pub fn new(
+        connection_manager: Arc<RwLock<ConnectionManager>>,
+        message_tx: Option<broadcast::Sender<MqttMessage>>,
+        client_id: String,
+        debug_deliveries: bool,
+    ) -> Self {
+        Self {
+            connection_manager,
+            message_tx,
+            metrics: Metrics::global(),
+            client_id,
+            debug_deliveries,
+        }
+    }
+
+    /// Publish one record as if a listener client had sent it
+    pub async fn publish_record(&self, record: &IngestRecord) -> Result<()> {
+        let (payload, qos) = record.decode()?;
+        self.publish(&record.topic, payload, qos, record.retain)
+            .await
+    }
+
+    /// Publish a message as if a listener client had sent it
+    pub async fn publish(&self, topic: &str, payload: Bytes, qos: QoS, retain: bool) -> Result<()> {
+        let start = Instant::now();
+        self.metrics.messages_received.inc();
+
+        let manager = self.connection_manager.read().await;
+        let payload = manager.decode_ingest(topic, payload);
+        let Some((topic, payload)) = manager.run_script_hook(
+            ScriptHook::OnClientPublish,
+            topic,
+            payload,
+            retain,
+            Some(&self.client_id),
+        ) else {
+            debug!("Script hook dropped ingested message to '{}'", topic);
+            return Ok(());
+        };
+
+        if retain {
+            manager.client_registry().retain_message(ClientMessage {
+                topic: topic.clone(),
+                payload: payload.clone(),
+                qos,
+                retain: true,
+            });
+        }
+        let deliveries = manager
+            .forward_message(
+                &topic,
+                payload.clone(),
+                qos,
+                retain,
+                None,
+                self.debug_deliveries,
+            )
+            .await?;
+
+        let broadcast = self.message_tx.as_ref().filter(|tx| {
+            manager
+                .load_shedder()
+                .admit(start.elapsed(), tx.len(), Instant::now())
+        });
+        if let Some(tx) = broadcast {
+            let _ = tx.send(MqttMessage {
+                timestamp: chrono::Utc::now(),
+                client_id: self.client_id.clone(),
+                topic,
+                payload: payload.to_vec(),
+                qos: qos as u8,
+                retain,
+                deliveries: self.debug_deliveries.then_some(deliveries),
+            });
+        }
+        self.metrics
+            .processing_latency
+            .observe(start.elapsed().as_secs_f64());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(json: serde_json::Value) -> IngestRecord {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_records_decode_payload_and_qos() {
+        let (payload, qos) = record(serde_json::json!({"topic": "a/b", "payload": "21.5"}))
+            .decode()
+            .unwrap();
+        assert_eq!(payload, Bytes::from_static(b"21.5"));
+        assert_eq!(qos, QoS::AtMostOnce);
+
+        let (payload, qos) = record(serde_json::json!(
+            {"topic": "a/b", "payload": "AAEC", "encoding": "base64", "qos": 1}
+        ))
+        .decode()
+        .unwrap();
+        assert_eq!(payload, Bytes::from_static(&[0, 1, 2]));
+        assert_eq!(qos, QoS::AtLeastOnce);
+
+        for invalid in [
+            serde_json::json!({"topic": "a/+"}),
+            serde_json::json!({"topic": ""}),
+            serde_json::json!({"topic": "a", "qos": 3}),
+            serde_json::json!({"topic": "a", "payload": "%", "encoding": "base64"}),
+        ] {
+            assert!(record(invalid).decode().is_err());
+        }
+    }
+
+    #[test]
+    fn test_take_lines_keeps_partial_line() {
+        let mut buffer = b"{\"a\":1}\r\n\n{\"b\":2}\n{\"c\"".to_vec();
+        assert_eq!(
+            take_lines(&mut buffer),
+            vec![b"{\"a\":1}".to_vec(), b"{\"b\":2}".to_vec()]
+        );
+        assert_eq!(buffer, b"{\"c\"");
+        assert!(take_lines(&mut buffer).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_message_is_counted_in_metrics() {
+        let mut manager = ConnectionManager::new(
+            Vec::new(),
+            Arc::new(crate::client_registry::ClientRegistry::new()),
+            "127.0.0.1".to_string(),
+            1,
+            4,
+            &crate::config::DedupConfig::default(),
+            0,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "id": "metrics-test",
+            "name": "metrics-test",
+            "address": "127.0.0.1",
+            "port": 1,
+            "clientIdPrefix": "test"
+        }))
+        .unwrap();
+        let acks = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        manager.insert_broker(crate::broker_actor::BrokerHandle::stub(config, acks));
+        let ingestor = Ingestor::new(
+            Arc::new(RwLock::new(manager)),
+            None,
+            "ingest".to_string(),
+            false,
+        );
+
+        // The registry is global and shared with other tests, so compare against the
+        // counts before this message rather than absolute values
+        let metrics = Metrics::global();
+        let received = metrics.messages_received.get();
+        let forwarded = metrics.messages_forwarded.get();
+        ingestor
+            .publish(
+                "metrics/power",
+                Bytes::from_static(b"120"),
+                QoS::AtLeastOnce,
+                false,
+            )
+            .await
+            .unwrap();
+        assert!(metrics.messages_received.get() > received);
+        assert!(metrics.messages_forwarded.get() > forwarded);
+
+        let (_, body) = crate::metrics::render();
+        let body = String::from_utf8(body).unwrap();
+        let value = |name: &str| {
+            body.lines().find_map(|line| {
+                line.strip_prefix(name)?
+                    .strip_prefix(' ')?
+                    .parse::<u64>()
+                    .ok()
+            })
+        };
+        assert!(value("mqtt_messages_received_total") > Some(received));
+        assert!(value("mqtt_messages_forwarded_total") > Some(forwarded));
+    }
+}
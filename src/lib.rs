@@ -1,18 +1,73 @@
+pub mod acl;
+pub mod acme;
+pub mod aggregation;
+pub mod audit;
+pub mod availability;
+pub mod batching;
+pub mod broker_actor;
+pub mod broker_client;
+pub mod broker_diff;
+pub mod broker_probe;
 pub mod broker_storage;
 pub mod client_registry;
+pub mod coap;
+pub mod codec;
+pub mod compression;
 pub mod config;
 pub mod connection_manager;
 pub mod crypto;
+pub mod dead_letters;
+pub mod debounced_write;
+pub mod dedup;
+pub mod delivery_groups;
+pub mod delta;
+pub mod http_client;
+pub mod ingest;
+pub mod listener_auth;
+pub mod listener_tls;
+pub mod load_shedding;
+pub mod loop_prevention;
 pub mod main_broker_client;
+pub mod message_history;
 pub mod metrics;
 pub mod mqtt_listener;
+pub mod mqtt_v5;
+pub mod offline_buffer;
+pub mod otel;
+pub mod payload_filter;
+pub mod plugins;
 pub mod proxy;
+pub mod proxy_protocol;
+pub mod remote_config;
+pub mod replay_protection;
+pub mod reports;
+pub mod resource_profile;
+pub mod routing;
+pub mod rules;
+pub mod runtime_stats;
+pub mod sampling;
+pub mod script_hooks;
+pub mod self_signed;
+pub mod send_queue;
 pub mod settings_storage;
+pub mod sources;
+pub mod stats;
+pub mod stream_filter;
+pub mod subscription_table;
+pub mod suppression;
+pub mod tcp_health;
+pub mod template;
+pub mod timestamp_check;
+pub mod topic_rewrite;
+pub mod topology;
+pub mod transform;
+pub mod unrouted;
 pub mod web_server;
+pub mod web_tls;
 
-pub use broker_storage::{BrokerConfig, BrokerStorage};
+pub use broker_storage::{BrokerConfig, BrokerStorage, FileBrokerStorage};
 pub use client_registry::ClientRegistry;
 pub use config::Config;
 pub use main_broker_client::MainBrokerClient;
 pub use proxy::MqttProxy;
-pub use settings_storage::SettingsStorage;
+pub use settings_storage::{FileSettingsStorage, SettingsStorage};
@@ -0,0 +1,137 @@
+//! Username/password authentication for clients connecting to the listener
+
+use crate::config::ProxyConfig;
+use crate::crypto::constant_time_eq;
+use crate::mqtt_v5;
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Why a CONNECT was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectRefusal {
+    /// Unknown user or wrong password
+    BadCredentials,
+    /// No credentials were sent
+    NotAuthorized,
+}
+
+impl ConnectRefusal {
+    /// MQTT 3.1.1 CONNACK return code
+    pub fn return_code(self) -> u8 {
+        match self {
+            ConnectRefusal::BadCredentials => 4,
+            ConnectRefusal::NotAuthorized => 5,
+        }
+    }
+
+    /// MQTT 5.0 CONNACK reason code
+    pub fn reason_code(self) -> u8 {
+        match self {
+            ConnectRefusal::BadCredentials => mqtt_v5::reason::BAD_USERNAME_OR_PASSWORD,
+            ConnectRefusal::NotAuthorized => mqtt_v5::reason::NOT_AUTHORIZED,
+        }
+    }
+}
+
+/// Credentials accepted by the listener
+pub struct ListenerAuth {
+    users: HashMap<String, String>,
+}
+
+impl ListenerAuth {
+    /// Credentials from `username`/`password` and `users`, or `None` unless `require_auth` is set
+    pub fn from_config(config: &ProxyConfig) -> Result<Option<Self>> {
+        if !config.require_auth {
+            return Ok(None);
+        }
+
+        let mut users: HashMap<String, String> = config
+            .users
+            .iter()
+            .map(|user| (user.username.clone(), user.password.clone()))
+            .collect();
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            users.insert(username.clone(), password.clone());
+        }
+        anyhow::ensure!(
+            !users.is_empty(),
+            "require_auth is enabled but no users are configured"
+        );
+        Ok(Some(Self { users }))
+    }
+
+    pub fn check(
+        &self,
+        username: Option<&str>,
+        password: Option<&[u8]>,
+    ) -> std::result::Result<(), ConnectRefusal> {
+        let Some(username) = username else {
+            return Err(ConnectRefusal::NotAuthorized);
+        };
+        match (self.users.get(username), password) {
+            (Some(expected), Some(password)) if constant_time_eq(expected.as_bytes(), password) => {
+                Ok(())
+            }
+            _ => Err(ConnectRefusal::BadCredentials),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ListenerUser;
+
+    fn proxy_config() -> ProxyConfig {
+        toml::from_str(
+            r#"
+            listen_address = "0.0.0.0:1883"
+            max_packet_size = 65536
+            connection_timeout_secs = 30
+            require_auth = true
+            username = "admin"
+            password = "secret"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check_credentials() {
+        let mut config = proxy_config();
+        config.users.push(ListenerUser {
+            username: "sensor".to_string(),
+            password: "s3nsor".to_string(),
+        });
+        let auth = ListenerAuth::from_config(&config).unwrap().unwrap();
+
+        assert_eq!(auth.check(Some("admin"), Some(b"secret")), Ok(()));
+        assert_eq!(auth.check(Some("sensor"), Some(b"s3nsor")), Ok(()));
+        assert_eq!(
+            auth.check(Some("admin"), Some(b"wrong")),
+            Err(ConnectRefusal::BadCredentials)
+        );
+        assert_eq!(
+            auth.check(Some("nobody"), Some(b"secret")),
+            Err(ConnectRefusal::BadCredentials)
+        );
+        assert_eq!(
+            auth.check(Some("admin"), None),
+            Err(ConnectRefusal::BadCredentials)
+        );
+        assert_eq!(auth.check(None, None), Err(ConnectRefusal::NotAuthorized));
+        assert_eq!(ConnectRefusal::BadCredentials.return_code(), 4);
+        assert_eq!(ConnectRefusal::NotAuthorized.return_code(), 5);
+    }
+
+    #[test]
+    fn test_from_config() {
+        let mut config = proxy_config();
+        config.require_auth = false;
+        assert!(ListenerAuth::from_config(&config).unwrap().is_none());
+
+        config.require_auth = true;
+        config.username = None;
+        assert!(ListenerAuth::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! TLS for the MQTT listener, including client certificate (mutual TLS) authentication
+//!
+//! With `tls_client_ca_path` set, clients present a certificate signed by that CA and the
+//! certificate's subject Common Name becomes the client's authenticated identity.
+
+use crate::config::ProxyConfig;
+use crate::self_signed;
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rustls::server::WebPkiClientVerifier;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+
+/// Build the acceptor for incoming TLS connections, or `None` when TLS is disabled
+pub fn build_tls_acceptor(config: &ProxyConfig) -> Result<Option<TlsAcceptor>> {
+    if !config.use_tls {
+        return Ok(None);
+    }
+
+    let (cert_path, key_path) = self_signed::cert_and_key(
+        config.tls_cert_path.as_deref(),
+        config.tls_key_path.as_deref(),
+        config.tls_self_signed.as_ref(),
+        "listener",
+    )?;
+    let certs = load_certs(&cert_path)?;
+    let key = load_private_key(&key_path)?;
+
+    let builder = rustls::ServerConfig::builder();
+    let builder = match &config.tls_client_ca_path {
+        Some(ca_path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid CA certificate in {}", ca_path))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if config.require_client_cert {
+                verifier.build()
+            } else {
+                verifier.allow_unauthenticated().build()
+            }
+            .context("Failed to build client certificate verifier")?;
+            info!(
+                "Listener verifies client certificates against {} ({})",
+                ca_path,
+                if config.require_client_cert {
+                    "required"
+                } else {
+                    "optional"
+                }
+            );
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => {
+            anyhow::ensure!(
+                !config.require_client_cert,
+                "require_client_cert needs tls_client_ca_path"
+            );
+            builder.with_no_client_auth()
+        }
+    };
+
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .context("Invalid listener certificate or key")?;
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+pub(crate) fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {}", path))?;
+    anyhow::ensure!(!certs.is_empty(), "No certificates found in {}", path);
+    Ok(certs)
+}
+
+pub(crate) fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse private key in {}", path))?
+        .with_context(|| format!("No private key found in {}", path))
+}
+
+/// DER object identifier of the X.520 commonName attribute (2.5.4.3)
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Fields of the TBSCertificate of a DER-encoded X.509 certificate, from serialNumber on
+fn tbs_fields(cert: &[u8]) -> Option<&[u8]> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+    let (_, certificate, _) = read_tlv(cert)?;
+    let (_, tbs, _) = read_tlv(certificate)?;
+
+    // TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature,
+    //                               issuer, validity, subject, ... }
+    let (tag, _, rest) = read_tlv(tbs)?;
+    Some(if tag == 0xA0 { rest } else { tbs })
+}
+
+/// Expiry (notAfter) of a DER-encoded X.509 certificate
+pub fn cert_not_after(cert: &[u8]) -> Option<DateTime<Utc>> {
+    let mut tbs = tbs_fields(cert)?;
+    for _ in 0..3 {
+        // serialNumber, signature, issuer
+        tbs = read_tlv(tbs)?.2;
+    }
+    // Validity ::= SEQUENCE { notBefore Time, notAfter Time }
+    let (_, validity, _) = read_tlv(tbs)?;
+    let (_, _, not_after) = read_tlv(validity)?;
+    let (tag, time, _) = read_tlv(not_after)?;
+    let time = std::str::from_utf8(time).ok()?;
+    let time = match tag {
+        // UTCTime: YYMMDDHHMMSSZ, years 50-99 are 19xx
+        0x17 => {
+            let year: u32 = time.get(..2)?.parse().ok()?;
+            format!("{}{}", if year >= 50 { "19" } else { "20" }, time)
+        }
+        // GeneralizedTime: YYYYMMDDHHMMSSZ
+        0x18 => time.to_string(),
+        _ => return None,
+    };
+    NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Subject Common Name of a DER-encoded X.509 certificate
+pub fn peer_common_name(cert: &[u8]) -> Option<String> {
+    let mut tbs = tbs_fields(cert)?;
+    for _ in 0..4 {
+        // serialNumber, signature, issuer, validity
+        tbs = read_tlv(tbs)?.2;
+    }
+    let (_, mut subject, _) = read_tlv(tbs)?;
+
+    // Name ::= SEQUENCE OF SET OF AttributeTypeAndValue
+    let mut common_name = None;
+    while !subject.is_empty() {
+        let (_, mut rdn, rest) = read_tlv(subject)?;
+        subject = rest;
+        while !rdn.is_empty() {
+            let (_, attribute, rest) = read_tlv(rdn)?;
+            rdn = rest;
+            let (oid_tag, oid, value) = read_tlv(attribute)?;
+            if oid_tag == 0x06 && oid == OID_COMMON_NAME {
+                let (_, value, _) = read_tlv(value)?;
+                // The most specific (last) CN wins
+                common_name = Some(String::from_utf8_lossy(value).into_owned());
+            }
+        }
+    }
+    common_name
+}
+
+/// Split one DER element into (tag, contents, remaining input)
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let octets = (first & 0x7F) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let len = rest[..octets]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, &rest[octets..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Client certificate with subject "O=Fleet, CN=device-42"
+    const DEVICE_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBMjCB2QIUCG3G8PTgU3ImR/JbNhEaPB5IWpIwCgYIKoZIzj0EAwIwEjEQMA4G
+A1UEAwwHVGVzdCBDQTAgFw0yNjEwMTYwMDU0MzhaGA8yMTI2MDkyMjAwNTQzOFow
+JDEOMAwGA1UECgwFRmxlZXQxEjAQBgNVBAMMCWRldmljZS00MjBZMBMGByqGSM49
+AgEGCCqGSM49AwEHA0IABFkUPRyvDnMowBLZpd4G9TadWyv+uHDTG8mwshUA2e3M
++LL7MrrnqUuWU7UAuMslhB3e8MCrOWhoP5CC758SeR8wCgYIKoZIzj0EAwIDSAAw
+RQIgKmt4Dw7FBEJbpdog7/Yg7gEVI1t0cwHbF1EEqP4Fs8ICIQDegLWHol40YBr/
+kw9/9FJsSrtQ3QjRkMnqoBHUaoEb4g==
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_peer_common_name() {
+        let cert = rustls_pemfile::certs(&mut DEVICE_CERT.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer_common_name(&cert), Some("device-42".to_string()));
+        assert_eq!(peer_common_name(&cert[..40]), None);
+        assert_eq!(
+            cert_not_after(&cert).unwrap().to_rfc3339(),
+            "2126-09-22T00:54:38+00:00"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Load shedding for the Web UI message stream
+//!
+//! Every message forwarded also gets cloned into the WebSocket broadcast channel, which
+//! feeds the live view and the message history. Under load that work competes with the
+//! data path, so with `[web_ui.load_shedding]` enabled the proxy stops broadcasting while
+//! the smoothed forwarding latency or the broadcast queue depth is over its threshold,
+//! and resumes once both have stayed below for `resume_after_secs`. `/api/status` reports
+//! the pause so the UI can show that the live view is incomplete.
+
+use crate::config::LoadSheddingConfig;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Weight of the latest sample in the smoothed latency
+const LATENCY_SMOOTHING: f64 = 0.1;
+
+struct LoadState {
+    avg_latency_ms: f64,
+    overloaded_at: Option<Instant>,
+}
+
+pub struct LoadShedder {
+    config: LoadSheddingConfig,
+    state: Mutex<LoadState>,
+    shedding: AtomicBool,
+    /// Messages not broadcast while shedding
+    shed: AtomicU64,
+}
+
+impl Default for LoadShedder {
+    fn default() -> Self {
+        Self::new(LoadSheddingConfig::default())
+    }
+}
+
+impl LoadShedder {
+    pub fn new(config: LoadSheddingConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(LoadState {
+                avg_latency_ms: 0.0,
+                overloaded_at: None,
+            }),
+            shedding: AtomicBool::new(false),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Record how long a message took to forward and how many messages wait for the UI;
+    /// returns whether the message should be broadcast
+    pub fn admit(&self, latency: Duration, queue_depth: usize, now: Instant) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+        let mut state = self.state.lock();
+        state.avg_latency_ms +=
+            (latency.as_secs_f64() * 1000.0 - state.avg_latency_ms) * LATENCY_SMOOTHING;
+        let overloaded = state.avg_latency_ms > self.config.max_latency_ms
+            || queue_depth > self.config.max_queue_depth;
+        if overloaded {
+            state.overloaded_at = Some(now);
+            if !self.shedding.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Pausing the Web UI message stream under load (latency {:.1} ms, {} queued)",
+                    state.avg_latency_ms, queue_depth
+                );
+            }
+        } else if self.shedding.load(Ordering::Relaxed)
+            && state.overloaded_at.is_none_or(|at| {
+                now.duration_since(at) >= Duration::from_secs(self.config.resume_after_secs)
+            })
+        {
+            self.shedding.store(false, Ordering::Relaxed);
+            info!(
+                "Resuming the Web UI message stream ({} messages not shown)",
+                self.shed.load(Ordering::Relaxed)
+            );
+        }
+        if self.shedding.load(Ordering::Relaxed) {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            false
+        } else {
+            true
+        }
+    }
+
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    /// Messages kept from the UI since startup
+    pub fn shed_messages(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pauses_and_resumes_with_hysteresis() {
+        let shedder = LoadShedder::new(LoadSheddingConfig {
+            enabled: true,
+            max_latency_ms: 50.0,
+            max_queue_depth: 100,
+            resume_after_secs: 10,
+        });
+        let fast = Duration::from_millis(1);
+        let start = Instant::now();
+        assert!(shedder.admit(fast, 0, start));
+        // A full broadcast queue pauses immediately
+        assert!(!shedder.admit(fast, 101, start));
+        assert!(shedder.is_shedding());
+        assert!(!shedder.admit(fast, 0, start + Duration::from_secs(5)));
+        assert!(shedder.admit(fast, 0, start + Duration::from_secs(10)));
+        assert_eq!(shedder.shed_messages(), 2);
+
+        // Slow forwarding pauses once the smoothed latency is over the threshold
+        let slow = Duration::from_millis(400);
+        let later = start + Duration::from_secs(20);
+        assert!(shedder.admit(slow, 0, later));
+        assert!(!shedder.admit(slow, 0, later));
+
+        let disabled = LoadShedder::default();
+        assert!(disabled.admit(slow, 10_000, later));
+    }
+}
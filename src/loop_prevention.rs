@@ -0,0 +1,174 @@
+//! Loop prevention by origin tagging
+//!
+//! Hash-based echo detection only recognises a message that comes back within
+//! the dedup window (`[dedup] window_ms`); bridges through intermediate brokers that take longer let it loop. With
+//! `[loop_prevention]` enabled, every message forwarded to a bidirectional broker carries
+//! this proxy's origin and a sequence number, and anything received back with our own
+//! origin is dropped however late it arrives.
+//!
+//! MQTT 5.0 brokers get the tag as the `x-proxy-origin` user property, and for them the tag
+//! replaces echo detection altogether: hashing misses slow echoes and also drops a device's
+//! legitimate repeats of a message. MQTT 3.1.1 has no properties, so there the payload is
+//! wrapped (magic bytes, origin, sequence, original payload) when `wrap_payloads` is set;
+//! those brokers keep echo detection as well.
+
+use crate::config::LoopPreventionConfig;
+use crate::mqtt_v5::{self, PropertyValue};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// User property holding `<origin>:<sequence>`
+pub const ORIGIN_PROPERTY: &str = "x-proxy-origin";
+/// Start of a wrapped payload; the NUL byte keeps it apart from JSON and text payloads
+const WRAPPER_MAGIC: &[u8; 4] = b"\0MPO";
+
+/// Create the tagger if loop prevention is enabled; `default_origin` applies when no
+/// origin is configured
+pub fn build_origin_tagger(
+    config: &LoopPreventionConfig,
+    default_origin: &str,
+) -> Option<Arc<OriginTagger>> {
+    if !config.enabled {
+        return None;
+    }
+    let origin = config
+        .origin
+        .as_deref()
+        .filter(|origin| !origin.is_empty())
+        .unwrap_or(default_origin);
+    Some(Arc::new(OriginTagger::new(origin, config.wrap_payloads)))
+}
+
+/// Stamps forwarded messages with this proxy's origin
+pub struct OriginTagger {
+    origin: String,
+    sequence: AtomicU64,
+    /// Tag MQTT 3.1.1 messages by wrapping their payloads
+    wrap_payloads: bool,
+}
+
+impl OriginTagger {
+    pub fn new(origin: impl Into<String>, wrap_payloads: bool) -> Self {
+        let mut origin: String = origin.into();
+        // The length has to fit the wrapper's one-byte length field
+        while origin.len() > u8::MAX as usize {
+            origin.pop();
+        }
+        Self {
+            origin,
+            sequence: AtomicU64::new(0),
+            wrap_payloads,
+        }
+    }
+
+    pub fn wraps_payloads(&self) -> bool {
+        self.wrap_payloads
+    }
+
+    fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Value for the `x-proxy-origin` user property
+    pub fn next_tag(&self) -> String {
+        format!("{}:{}", self.origin, self.next_sequence())
+    }
+
+    /// `payload` wrapped with our origin and the next sequence number
+    pub fn wrap(&self, payload: &[u8]) -> Bytes {
+        let mut wrapped = BytesMut::with_capacity(payload.len() + self.origin.len() + 13);
+        wrapped.put_slice(WRAPPER_MAGIC);
+        wrapped.put_u8(self.origin.len() as u8);
+        wrapped.put_slice(self.origin.as_bytes());
+        wrapped.put_u64(self.next_sequence());
+        wrapped.put_slice(payload);
+        wrapped.freeze()
+    }
+
+    /// Whether a `x-proxy-origin` value was set by this proxy
+    pub fn is_own_tag(&self, tag: &str) -> bool {
+        tag.rsplit_once(':')
+            .is_some_and(|(origin, _)| origin == self.origin)
+    }
+
+    /// Whether a payload was wrapped by this proxy
+    pub fn is_own_payload(&self, payload: &[u8]) -> bool {
+        unwrap(payload).is_some_and(|(origin, _, _)| origin == self.origin.as_bytes())
+    }
+}
+
+/// Remove the origin tags from a message's properties and return the first one. Tags only
+/// mean something between a proxy and its brokers, so they are not passed on.
+pub fn take_origin(properties: &mut Option<mqtt_v5::Properties>) -> Option<String> {
+    let props = properties.as_mut()?;
+    let mut origin = None;
+    props.0.retain(|(id, value)| match (*id, value) {
+        (mqtt_v5::property::USER_PROPERTY, PropertyValue::Utf8StringPair(key, tag))
+            if key == ORIGIN_PROPERTY =>
+        {
+            origin.get_or_insert_with(|| tag.clone());
+            false
+        }
+        _ => true,
+    });
+    if props.is_empty() {
+        *properties = None;
+    }
+    origin
+}
+
+/// Origin, sequence number and original payload of a wrapped payload
+pub fn unwrap(payload: &[u8]) -> Option<(&[u8], u64, &[u8])> {
+    let rest = payload.strip_prefix(WRAPPER_MAGIC)?;
+    let (&len, rest) = rest.split_first()?;
+    let len = len as usize;
+    if rest.len() < len + 8 {
+        return None;
+    }
+    let (origin, rest) = rest.split_at(len);
+    let (sequence, inner) = rest.split_at(8);
+    let sequence = u64::from_be_bytes(sequence.try_into().ok()?);
+    Some((origin, sequence, inner))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recognises_own_tags_and_wrapped_payloads() {
+        let tagger = OriginTagger::new("proxy-a", true);
+        let other = OriginTagger::new("proxy-b", true);
+
+        let tag = tagger.next_tag();
+        assert_eq!(tag, "proxy-a:1");
+        assert!(tagger.is_own_tag(&tag));
+        assert!(!other.is_own_tag(&tag));
+        assert!(!tagger.is_own_tag("proxy-a"));
+
+        let wrapped = tagger.wrap(b"{\"t\":21.5}");
+        assert_eq!(
+            unwrap(&wrapped),
+            Some((&b"proxy-a"[..], 2, &b"{\"t\":21.5}"[..]))
+        );
+        assert!(tagger.is_own_payload(&wrapped));
+        assert!(!other.is_own_payload(&wrapped));
+        assert!(!tagger.is_own_payload(b"{\"t\":21.5}"));
+        // Truncated wrapper
+        assert!(unwrap(&wrapped[..10]).is_none());
+
+        let mut properties = Some(mqtt_v5::Properties::default());
+        for (key, value) in [(ORIGIN_PROPERTY, tag.as_str()), ("site", "north")] {
+            properties.as_mut().unwrap().push(
+                mqtt_v5::property::USER_PROPERTY,
+                PropertyValue::Utf8StringPair(key.into(), value.into()),
+            );
+        }
+        assert_eq!(take_origin(&mut properties), Some(tag));
+        assert_eq!(
+            properties.unwrap().user_properties().collect::<Vec<_>>(),
+            vec![("site", "north")]
+        );
+    }
+}
@@ -1,22 +1,35 @@
 use anyhow::Result;
-use mqtt_proxy::{config::Config, proxy::MqttProxy};
+use mqtt_proxy::{
+    config::{Config, LogFormat},
+    proxy::MqttProxy,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+// Heap usage for /api/debug/runtime
+#[global_allocator]
+static ALLOCATOR: mqtt_proxy::runtime_stats::CountingAllocator =
+    mqtt_proxy::runtime_stats::CountingAllocator;
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Load configuration first: it chooses the log format
+    let config = Config::from_env()?;
+
     // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "mqtt_proxy=info,rumqttc=warn".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| "mqtt_proxy=info,rumqttc=warn".into()),
+    );
+    match config.logging.effective_format()? {
+        // One object per line; fields of the `client`, `broker` and `message` spans
+        // (client_id, broker, topic) are in `spans`
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json().flatten_event(true))
+            .init(),
+        LogFormat::Pretty => registry.with(tracing_subscriber::fmt::layer()).init(),
+    }
 
     tracing::info!("Starting MQTT Proxy");
-
-    // Load configuration
-    let config = Config::from_env()?;
     tracing::info!("Configuration loaded: {:?}", config);
 
     // Create and start proxy
@@ -1,10 +1,13 @@
+use crate::broker_client::{self, BrokerClient, BrokerEvent, BrokerEventLoop};
 use crate::config::MainBrokerConfig;
 use crate::connection_manager::ConnectionManager;
+use crate::metrics::Metrics;
+use crate::resource_profile;
+use crate::script_hooks::ScriptHook;
 use anyhow::Result;
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
-use std::collections::HashSet;
+use rumqttc::QoS;
+use std::collections::{HashSet, VecDeque};
 use std::hash::{Hash, Hasher};
-use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::{watch, RwLock};
@@ -24,15 +27,98 @@ struct MessageCacheEntry {
     timestamp: Instant,
 }
 
+/// How long shutdown waits for the DISCONNECT to reach the main broker
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// First reconnect delay after a connection error
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for the reconnect delay
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// A connection that lasted this long resets the reconnect delay
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+/// Minimum time between two subscribe requests to the main broker
+const MIN_RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Exponential reconnect delay. Only a connection that stayed up for a while resets it,
+/// so a broker that accepts and then drops connections still gets backed off.
+struct ReconnectBackoff {
+    delay: Duration,
+    connected_at: Option<Instant>,
+}
+
+impl ReconnectBackoff {
+    fn new() -> Self {
+        Self {
+            delay: INITIAL_RECONNECT_DELAY,
+            connected_at: None,
+        }
+    }
+
+    fn on_connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+    }
+
+    /// Delay before the next connection attempt
+    fn on_error(&mut self, now: Instant) -> Duration {
+        if let Some(connected_at) = self.connected_at.take() {
+            if now.duration_since(connected_at) >= STABLE_CONNECTION {
+                self.delay = INITIAL_RECONNECT_DELAY;
+            }
+        }
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+        delay
+    }
+}
+
+/// Decides when to (re)subscribe: once per new session, at most once per
+/// `MIN_RESUBSCRIBE_INTERVAL`; a subscription that comes too soon is deferred
+#[derive(Default)]
+struct ResubscribeGate {
+    last: Option<Instant>,
+    pending: Option<Instant>,
+}
+
+impl ResubscribeGate {
+    /// Called on ConnAck; returns true when the subscription should be sent now
+    fn on_connected(&mut self, now: Instant, session_present: bool) -> bool {
+        if session_present && self.last.is_some() {
+            // The broker kept our subscriptions
+            self.pending = None;
+            return false;
+        }
+        match self.last {
+            Some(last) if now.duration_since(last) < MIN_RESUBSCRIBE_INTERVAL => {
+                self.pending = Some(last + MIN_RESUBSCRIBE_INTERVAL);
+                false
+            }
+            _ => {
+                self.on_subscribed(now);
+                true
+            }
+        }
+    }
+
+    fn on_disconnected(&mut self) {
+        self.pending = None;
+    }
+
+    /// When a deferred subscription is due
+    fn due(&self) -> Option<Instant> {
+        self.pending
+    }
+
+    fn on_subscribed(&mut self, now: Instant) {
+        self.last = Some(now);
+        self.pending = None;
+    }
+}
+
 pub struct MainBrokerClient {
     config: MainBrokerConfig,
-    #[allow(dead_code)] // Client is recreated in run() for proper eventloop handling
-    client: AsyncClient,
     connection_manager: Arc<RwLock<ConnectionManager>>,
     message_tx: Option<tokio::sync::broadcast::Sender<crate::web_server::MqttMessage>>,
-    messages_received: Option<Arc<AtomicU64>>,
-    messages_forwarded: Option<Arc<AtomicU64>>,
-    total_latency_ns: Option<Arc<AtomicU64>>,
+    metrics: Arc<Metrics>,
+    debug_deliveries: bool,
 }
 
 impl MainBrokerClient {
@@ -40,109 +126,135 @@ impl MainBrokerClient {
         config: MainBrokerConfig,
         connection_manager: Arc<RwLock<ConnectionManager>>,
         message_tx: Option<tokio::sync::broadcast::Sender<crate::web_server::MqttMessage>>,
-        messages_received: Option<Arc<AtomicU64>>,
-        messages_forwarded: Option<Arc<AtomicU64>>,
-        total_latency_ns: Option<Arc<AtomicU64>>,
+        debug_deliveries: bool,
     ) -> Result<Self> {
-        let mut mqtt_options = MqttOptions::new(&config.client_id, &config.address, config.port);
-        mqtt_options.set_keep_alive(std::time::Duration::from_secs(60));
-
-        if let (Some(username), Some(password)) = (&config.username, &config.password) {
-            mqtt_options.set_credentials(username, password);
-        }
-
-        let (client, _eventloop) = AsyncClient::new(mqtt_options, 10000);
-
         Ok(Self {
             config,
-            client,
             connection_manager,
             message_tx,
-            messages_received,
-            messages_forwarded,
-            total_latency_ns,
+            metrics: Metrics::global(),
+            debug_deliveries,
         })
     }
 
+    /// Connect and forward messages until `shutdown_rx` fires (or its sender is dropped).
+    /// Returns only after the connection is closed, so a replacement client can't overlap
+    /// with this one.
     pub async fn run(self, mut shutdown_rx: watch::Receiver<bool>) -> Result<()> {
         info!(
-            "Starting main broker client, connecting to {}:{}",
-            self.config.address, self.config.port
+            "Starting main broker client, connecting to {}:{} (MQTT v{})",
+            self.config.address, self.config.port, self.config.protocol_version
         );
 
-        let mut mqtt_options = MqttOptions::new(
+        // Over MQTT 5.0, the properties of received messages are forwarded downstream
+        let (client, mut eventloop) = broker_client::plain_connection(
+            self.config.protocol_version,
             &self.config.client_id,
             &self.config.address,
             self.config.port,
+            self.config
+                .username
+                .as_deref()
+                .zip(self.config.password.as_deref()),
         );
-        mqtt_options.set_keep_alive(std::time::Duration::from_secs(60));
 
-        if let (Some(username), Some(password)) = (&self.config.username, &self.config.password) {
-            mqtt_options.set_credentials(username, password);
-        }
-
-        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10000);
-
-        // Subscribe to all topics from all downstream brokers
-        let subscribed_topics = self.subscribe_to_all_topics(&client).await;
-        info!("Subscribed to {} unique topics", subscribed_topics.len());
+        // Subscriptions are sent on ConnAck, see ResubscribeGate, and on request via the API
+        let resubscribe_requests = self
+            .connection_manager
+            .read()
+            .await
+            .main_resubscribe_requests();
+        let mut backoff = ReconnectBackoff::new();
+        let mut resubscribe = ResubscribeGate::default();
 
         // Message deduplication cache - prevents forwarding echoed messages
         // Key: hash, Value: timestamp of when we last forwarded this message
-        let mut message_cache: Vec<MessageCacheEntry> = Vec::new();
-        const DEDUP_WINDOW_MS: u64 = 1000; // Ignore duplicates within 1 second
+        let mut message_cache: VecDeque<MessageCacheEntry> = VecDeque::new();
+        let dedup = self
+            .connection_manager
+            .read()
+            .await
+            .dedup_settings(&self.config.dedup);
 
         // Process incoming messages
         loop {
             tokio::select! {
                 _ = shutdown_rx.changed() => {
                     info!("Main broker client received shutdown signal");
+                    Self::disconnect(&client, &mut eventloop).await;
                     return Ok(());
                 }
+                _ = tokio::time::sleep_until(
+                    resubscribe.due().unwrap_or_else(Instant::now).into()
+                ), if resubscribe.due().is_some() => {
+                    resubscribe.on_subscribed(Instant::now());
+                    let subscribed = self.subscribe_to_all_topics(&client).await;
+                    info!("Subscribed to {} topics (deferred)", subscribed.len());
+                }
+                _ = resubscribe_requests.notified() => {
+                    let subscribed = self.subscribe_to_all_topics(&client).await;
+                    info!("Resubscribed to {} topics on request", subscribed.len());
+                }
                 poll_result = eventloop.poll() => {
             match poll_result {
-                Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                Ok(BrokerEvent::ConnAck { session_present }) => {
                     info!(
                         "Connected to main broker at {}:{}",
                         self.config.address, self.config.port
                     );
+                    let now = Instant::now();
+                    backoff.on_connected(now);
 
-                    // Re-subscribe after reconnection
-                    let subscribed = self.subscribe_to_all_topics(&client).await;
-                    info!(
-                        "Re-subscribed to {} topics after reconnection",
-                        subscribed.len()
-                    );
+                    if resubscribe.on_connected(now, session_present) {
+                        let subscribed = self.subscribe_to_all_topics(&client).await;
+                        info!("Subscribed to {} topics", subscribed.len());
+                    } else if let Some(due) = resubscribe.due() {
+                        info!(
+                            "Reconnected too soon after the last subscribe; subscribing in {:.1}s",
+                            due.saturating_duration_since(now).as_secs_f64()
+                        );
+                    }
                 }
-                Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                Ok(BrokerEvent::Publish {
+                    topic,
+                    payload,
+                    qos,
+                    retain,
+                    properties,
+                    ..
+                }) => {
                     let start = Instant::now();
 
-                    let topic = publish.topic.clone();
-                    let payload = bytes::Bytes::from(publish.payload.to_vec());
-                    let qos = publish.qos;
-                    let retain = publish.retain;
+                    let payload = self
+                        .connection_manager
+                        .read()
+                        .await
+                        .decode_ingest(&topic, payload);
 
-                    // Compute message hash for deduplication
-                    let hash = message_hash(&topic, &payload);
+                    if dedup.enabled {
+                        // Compute message hash for deduplication
+                        let hash = message_hash(&topic, &payload);
 
-                    // Clean old entries from cache
-                    let now = Instant::now();
-                    message_cache.retain(|e| {
-                        now.duration_since(e.timestamp) < Duration::from_millis(DEDUP_WINDOW_MS)
-                    });
+                        // Clean old entries from cache
+                        let now = Instant::now();
+                        message_cache.retain(|e| now.duration_since(e.timestamp) < dedup.window);
 
-                    // Check if this is a duplicate (echoed message)
-                    let is_duplicate = message_cache.iter().any(|e| e.hash == hash);
-                    if is_duplicate {
-                        debug!("🔄 Skipping duplicate message: topic='{}' (already forwarded recently)", topic);
-                        continue;
-                    }
+                        // Check if this is a duplicate (echoed message)
+                        let is_duplicate = message_cache.iter().any(|e| e.hash == hash);
+                        if is_duplicate {
+                            debug!("🔄 Skipping duplicate message: topic='{}' (already forwarded recently)", topic);
+                            continue;
+                        }
 
-                    // Add to cache
-                    message_cache.push(MessageCacheEntry {
-                        hash,
-                        timestamp: now,
-                    });
+                        // Add to cache, forgetting the oldest entries beyond the limit
+                        while message_cache.len() >= dedup.max_entries {
+                            message_cache.pop_front();
+                        }
+                        message_cache.push_back(MessageCacheEntry {
+                            hash,
+                            timestamp: now,
+                        });
+                    }
 
                     debug!(
                         "📥 Received from main broker: topic='{}', {} bytes",
@@ -150,13 +262,42 @@ impl MainBrokerClient {
                         payload.len()
                     );
 
-                    // Increment received counter
-                    if let Some(counter) = &self.messages_received {
-                        counter.fetch_add(1, Ordering::Relaxed);
-                    }
+                    self.metrics.messages_received.inc();
 
-                    // Broadcast to Web UI
-                    if let Some(tx) = &self.message_tx {
+                    let manager = self.connection_manager.read().await;
+                    let Some((topic, payload)) = manager
+                        .run_script_hook(ScriptHook::OnBrokerMessage, &topic, payload, retain, None)
+                    else {
+                        debug!("Script hook dropped message on '{}'", topic);
+                        continue;
+                    };
+
+                    // Forward to matching downstream brokers
+                    let deliveries = match manager
+                        .forward_message(
+                            &topic,
+                            payload.clone(),
+                            qos,
+                            retain,
+                            properties.as_ref(),
+                            self.debug_deliveries,
+                        )
+                        .await
+                    {
+                        Ok(deliveries) => deliveries,
+                        Err(e) => {
+                            error!("Failed to forward message: {}", e);
+                            Vec::new()
+                        }
+                    };
+
+                    // Broadcast to Web UI, unless forwarding is under load
+                    let broadcast = self.message_tx.as_ref().filter(|tx| {
+                        manager
+                            .load_shedder()
+                            .admit(start.elapsed(), tx.len(), Instant::now())
+                    });
+                    if let Some(tx) = broadcast {
                         let mqtt_msg = crate::web_server::MqttMessage {
                             timestamp: chrono::Utc::now(),
                             client_id: "main-broker".to_string(),
@@ -168,31 +309,33 @@ impl MainBrokerClient {
                                 QoS::ExactlyOnce => 2,
                             },
                             retain,
+                            deliveries: self.debug_deliveries.then_some(deliveries),
                         };
                         let _ = tx.send(mqtt_msg);
                     }
 
-                    // Forward to matching downstream brokers
-                    let manager = self.connection_manager.read().await;
-                    if let Err(e) = manager
-                        .forward_message(&topic, payload, qos, retain, &self.messages_forwarded)
-                        .await
-                    {
-                        error!("Failed to forward message: {}", e);
-                    }
-
-                    // Record latency
-                    let elapsed = start.elapsed();
-                    if let Some(latency_counter) = &self.total_latency_ns {
-                        latency_counter.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
-                    }
+                    self.metrics
+                        .processing_latency
+                        .observe(start.elapsed().as_secs_f64());
                 }
                 Ok(_) => {
                     // Other events
                 }
                 Err(e) => {
-                    error!("Main broker connection error: {}", e);
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    resubscribe.on_disconnected();
+                    let delay = backoff.on_error(Instant::now());
+                    error!(
+                        "Main broker connection error: {} (retrying in {}s)",
+                        e,
+                        delay.as_secs()
+                    );
+                    tokio::select! {
+                        _ = shutdown_rx.changed() => {
+                            info!("Main broker client received shutdown signal");
+                            return Ok(());
+                        }
+                        _ = tokio::time::sleep(delay) => {}
+                    }
                 }
             }
                 }
@@ -200,13 +343,48 @@ impl MainBrokerClient {
         }
     }
 
-    async fn subscribe_to_all_topics(&self, client: &AsyncClient) -> HashSet<String> {
-        // Always subscribe to all topics (#) so the WebUI can monitor everything
+    /// Send DISCONNECT and drive the eventloop until it is written (or the broker is gone)
+    async fn disconnect(client: &BrokerClient, eventloop: &mut BrokerEventLoop) {
+        if client.try_disconnect().is_err() {
+            return;
+        }
+        let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, async {
+            loop {
+                match eventloop.poll().await {
+                    Ok(BrokerEvent::Disconnected) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        })
+        .await;
+        info!("Disconnected from main broker");
+    }
+
+    async fn subscribe_to_all_topics(&self, client: &BrokerClient) -> HashSet<String> {
+        let qos = self
+            .connection_manager
+            .read()
+            .await
+            .subscription_qos(self.config.subscription_qos);
+        // The low-resource profile only takes what can be forwarded; the filters are
+        // computed per connection, so broker changes apply after the next reconnect
+        if !resource_profile::limits().monitor_all_topics {
+            if let Some(filters) = self.connection_manager.read().await.forwarding_filters() {
+                for filter in &filters {
+                    if let Err(e) = client.subscribe(filter, qos).await {
+                        error!("Failed to subscribe to {}: {}", filter, e);
+                    }
+                }
+                return filters.into_iter().collect();
+            }
+        }
+
+        // Subscribe to all topics (#) so the WebUI can monitor everything
         // Message filtering for downstream brokers happens in forward_message()
         let mut all_topics = HashSet::new();
         all_topics.insert("#".to_string());
 
-        match client.subscribe("#", QoS::AtMostOnce).await {
+        match client.subscribe("#", qos).await {
             Ok(_) => info!("Subscribed to all topics (#) for monitoring"),
             Err(e) => error!("Failed to subscribe to #: {}", e),
         }
@@ -214,3 +392,95 @@ impl MainBrokerClient {
         all_topics
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_registry::ClientRegistry;
+    use crate::config::DedupConfig;
+    use crate::dedup::DedupOverride;
+
+    #[test]
+    fn test_reconnect_backoff_grows_until_stable_connection() {
+        let mut backoff = ReconnectBackoff::new();
+        let start = Instant::now();
+
+        let delays: Vec<_> = (0..8).map(|_| backoff.on_error(start).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+
+        // A connection that drops right away doesn't reset the delay
+        backoff.on_connected(start);
+        assert_eq!(
+            backoff.on_error(start + Duration::from_secs(1)).as_secs(),
+            60
+        );
+
+        backoff.on_connected(start);
+        assert_eq!(backoff.on_error(start + STABLE_CONNECTION).as_secs(), 1);
+    }
+
+    #[test]
+    fn test_resubscribe_gate_limits_frequency() {
+        let mut gate = ResubscribeGate::default();
+        let start = Instant::now();
+
+        assert!(gate.on_connected(start, false));
+
+        // Reconnecting within the interval defers the subscription
+        gate.on_disconnected();
+        let t = start + Duration::from_secs(2);
+        assert!(!gate.on_connected(t, false));
+        assert_eq!(gate.due(), Some(start + MIN_RESUBSCRIBE_INTERVAL));
+
+        // A resumed session keeps its subscriptions
+        gate.on_disconnected();
+        assert!(!gate.on_connected(t, true));
+        assert_eq!(gate.due(), None);
+
+        gate.on_disconnected();
+        assert!(gate.on_connected(start + MIN_RESUBSCRIBE_INTERVAL, false));
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_on_shutdown_while_reconnecting() {
+        let manager = ConnectionManager::new(
+            Vec::new(),
+            Arc::new(ClientRegistry::new()),
+            "127.0.0.1".to_string(),
+            1,
+            4,
+            &DedupConfig::default(),
+            0,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        // Nothing listens on port 1, so the client sits in its reconnect delay
+        let config = MainBrokerConfig {
+            address: "127.0.0.1".to_string(),
+            port: 1,
+            client_id: "main-broker-client-test".to_string(),
+            username: None,
+            password: None,
+            protocol_version: 4,
+            dedup: DedupOverride::default(),
+            subscription_qos: None,
+        };
+        let client = MainBrokerClient::new(config, Arc::new(RwLock::new(manager)), None, false)
+            .await
+            .unwrap();
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let run = tokio::spawn(client.run(shutdown_rx));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!run.is_finished());
+
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), run)
+            .await
+            .expect("client should stop well before the reconnect delay ends")
+            .unwrap()
+            .unwrap();
+    }
+}
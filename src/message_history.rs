@@ -0,0 +1,739 @@
+//! Bounded in-memory history of observed messages
+//!
+//! Records everything broadcast to the Web UI so operators can search recent traffic
+//! (topic filter, payload substring/regex, client ID, time range). A per-topic index keeps
+//! topic-filtered queries from scanning the whole buffer.
+//!
+//! Optionally the history is also spilled to rotating JSON-lines segments on disk, turning
+//! the proxy into a short-term flight recorder; searches fall through to the segments once
+//! the in-memory buffer is exhausted. Segments are written, rotated and pruned by a
+//! dedicated thread, so recording a message never waits for the disk.
+
+use crate::config::HistoryDiskConfig;
+use crate::connection_manager::ConnectionManager;
+use crate::web_server::MqttMessage;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+const SEGMENT_PREFIX: &str = "history-";
+const SEGMENT_EXTENSION: &str = "jsonl";
+/// Messages waiting for the segment writer; beyond that they are left out of the segments
+const WRITE_QUEUE_SIZE: usize = 4096;
+/// How often the segment writer flushes buffered writes
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the segment writer applies the limits while no segment is rotated
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Search criteria for the history buffer; all set fields must match
+#[derive(Default)]
+pub struct HistoryQuery {
+    /// MQTT topic filter (supports `+` and `#`)
+    pub topic: Option<String>,
+    pub payload_contains: Option<String>,
+    pub payload_regex: Option<Regex>,
+    pub client_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    fn matches(&self, msg: &MqttMessage) -> bool {
+        if self.since.is_some_and(|since| msg.timestamp < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| msg.timestamp > until) {
+            return false;
+        }
+        if let Some(client_id) = &self.client_id {
+            if &msg.client_id != client_id {
+                return false;
+            }
+        }
+        if self.payload_contains.is_none() && self.payload_regex.is_none() {
+            return true;
+        }
+        let payload = String::from_utf8_lossy(&msg.payload);
+        if let Some(needle) = &self.payload_contains {
+            if !payload.contains(needle.as_str()) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.payload_regex {
+            if !regex.is_match(&payload) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+struct HistoryInner {
+    messages: VecDeque<MqttMessage>,
+    /// Sequence number of `messages[0]`
+    first_seq: u64,
+    /// Topic -> sequence numbers of its messages, oldest first
+    topic_index: HashMap<String, VecDeque<u64>>,
+}
+
+pub struct MessageHistory {
+    capacity: usize,
+    inner: RwLock<HistoryInner>,
+    disk: Option<DiskSegments>,
+}
+
+impl MessageHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: RwLock::new(HistoryInner {
+                messages: VecDeque::new(),
+                first_seq: 0,
+                topic_index: HashMap::new(),
+            }),
+            disk: None,
+        }
+    }
+
+    /// Create a history that also spills every message to rotating on-disk segments;
+    /// with a zero `capacity` the history is disabled, on disk too
+    pub fn with_disk(capacity: usize, config: HistoryDiskConfig) -> Result<Self> {
+        let mut history = Self::new(capacity);
+        if capacity == 0 {
+            return Ok(history);
+        }
+        let disk = DiskSegments::new(config)?;
+        // Sequence numbers continue from the segments of earlier runs
+        history.inner.get_mut().first_seq = disk.next_seq()?;
+        history.disk = Some(disk);
+        Ok(history)
+    }
+
+    /// Apply the segment size and age limits soon; the segment writer otherwise checks
+    /// them when a message starts a new segment, and every minute
+    pub fn prune(&self) {
+        if let Some(disk) = &self.disk {
+            disk.prune();
+        }
+    }
+
+    /// Wait until the messages recorded so far are written to disk
+    pub fn flush(&self) {
+        if let Some(disk) = &self.disk {
+            if let Err(e) = disk.flush() {
+                warn!("Failed to flush message history segment: {}", e);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&self, msg: MqttMessage) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.write();
+        let seq = inner.first_seq + inner.messages.len() as u64;
+        // Queued under the lock, so segments hold messages in sequence order
+        if let Some(disk) = &self.disk {
+            disk.append(seq, msg.clone());
+        }
+
+        if inner.messages.len() >= self.capacity {
+            if let Some(evicted) = inner.messages.pop_front() {
+                let evicted_seq = inner.first_seq;
+                inner.first_seq += 1;
+                if let Some(seqs) = inner.topic_index.get_mut(&evicted.topic) {
+                    if seqs.front() == Some(&evicted_seq) {
+                        seqs.pop_front();
+                    }
+                    if seqs.is_empty() {
+                        inner.topic_index.remove(&evicted.topic);
+                    }
+                }
+            }
+        }
+
+        inner
+            .topic_index
+            .entry(msg.topic.clone())
+            .or_default()
+            .push_back(seq);
+        inner.messages.push_back(msg);
+    }
+
+    /// Return matching messages, newest first, up to `query.limit`.
+    /// Falls through to on-disk segments for messages older than the in-memory buffer.
+    pub fn search(&self, query: &HistoryQuery) -> Vec<MqttMessage> {
+        let (mut results, first_in_memory) = self.search_memory(query);
+
+        if let Some(disk) = &self.disk {
+            if results.len() < query.limit {
+                let remaining = query.limit - results.len();
+                match disk.search(query, first_in_memory, remaining) {
+                    Ok(older) => results.extend(older),
+                    Err(e) => warn!("Failed to search message history segments: {}", e),
+                }
+            }
+        }
+
+        results
+    }
+
+    /// Matches in memory, and the sequence number the buffer starts at
+    fn search_memory(&self, query: &HistoryQuery) -> (Vec<MqttMessage>, u64) {
+        let inner = self.inner.read();
+        (Self::search_inner(&inner, query), inner.first_seq)
+    }
+
+    fn search_inner(inner: &HistoryInner, query: &HistoryQuery) -> Vec<MqttMessage> {
+        let get = |seq: u64| &inner.messages[(seq - inner.first_seq) as usize];
+
+        match &query.topic {
+            Some(filter) => {
+                let mut seqs: Vec<u64> = inner
+                    .topic_index
+                    .iter()
+                    .filter(|(topic, _)| ConnectionManager::topic_matches_pattern(filter, topic))
+                    .flat_map(|(_, seqs)| seqs.iter().copied())
+                    .collect();
+                seqs.sort_unstable_by(|a, b| b.cmp(a));
+                seqs.into_iter()
+                    .map(get)
+                    .filter(|msg| query.matches(msg))
+                    .take(query.limit)
+                    .cloned()
+                    .collect()
+            }
+            None => inner
+                .messages
+                .iter()
+                .rev()
+                .filter(|msg| query.matches(msg))
+                .take(query.limit)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+struct OpenSegment {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    started: SystemTime,
+    bytes: u64,
+}
+
+/// A message in a segment file, with its history sequence number
+#[derive(Serialize, Deserialize)]
+struct SegmentLine<M = MqttMessage> {
+    seq: u64,
+    #[serde(flatten)]
+    message: M,
+}
+
+/// Rotating JSON-lines segment files, one message per line
+struct DiskSegments {
+    dir: PathBuf,
+    /// To the segment writer thread; taken on drop so the thread finishes
+    commands: Option<SyncSender<DiskCommand>>,
+    /// Messages left out of the segments because the writer fell behind
+    dropped: Arc<AtomicU64>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// Work for the segment writer thread, done in order
+enum DiskCommand {
+    Append(u64, MqttMessage),
+    Prune,
+    /// Flush buffered writes, then signal
+    Flush(SyncSender<()>),
+}
+
+impl DiskSegments {
+    fn new(config: HistoryDiskConfig) -> Result<Self> {
+        let dir = PathBuf::from(&config.path);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create history directory: {:?}", dir))?;
+        info!("Spilling message history to {:?}", dir);
+        let (commands, commands_rx) = mpsc::sync_channel(WRITE_QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer = SegmentWriter {
+            config,
+            dir: dir.clone(),
+            current: None,
+        };
+        let writer = std::thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn({
+                let dropped = Arc::clone(&dropped);
+                move || writer.run(commands_rx, &dropped)
+            })
+            .context("Failed to start the message history writer")?;
+        Ok(Self {
+            dir,
+            commands: Some(commands),
+            dropped,
+            writer: Some(writer),
+        })
+    }
+
+    fn append(&self, seq: u64, msg: MqttMessage) {
+        let Some(commands) = &self.commands else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = commands.try_send(DiskCommand::Append(seq, msg)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn prune(&self) {
+        if let Some(commands) = &self.commands {
+            // A prune already waiting does the same
+            let _ = commands.try_send(DiskCommand::Prune);
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        let (done, done_rx) = mpsc::sync_channel(1);
+        self.commands
+            .as_ref()
+            .context("Message history writer has stopped")?
+            .send(DiskCommand::Flush(done))
+            .ok()
+            .context("Message history writer has stopped")?;
+        done_rx
+            .recv()
+            .ok()
+            .context("Message history writer has stopped")
+    }
+
+    /// The sequence number after the last message on disk
+    fn next_seq(&self) -> Result<u64> {
+        for path in segments(&self.dir)?.iter().rev() {
+            let file = File::open(path)
+                .with_context(|| format!("Failed to open history segment: {:?}", path))?;
+            let last = BufReader::new(file)
+                .lines()
+                .map_while(|line| line.ok())
+                .filter_map(|line| serde_json::from_str::<SegmentLine>(&line).ok())
+                .last();
+            if let Some(line) = last {
+                return Ok(line.seq + 1);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Segment files, oldest first
+    fn segments(&self) -> Result<Vec<PathBuf>> {
+        segments(&self.dir)
+    }
+
+    /// Search segments newest first for messages with a sequence number below `before`,
+    /// the ones no longer in memory
+    fn search(&self, query: &HistoryQuery, before: u64, limit: usize) -> Result<Vec<MqttMessage>> {
+        self.flush()?;
+        let mut results = Vec::new();
+
+        for path in self.segments()?.iter().rev() {
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(_) => continue, // Rotated away while searching
+            };
+            let mut matches: Vec<MqttMessage> = BufReader::new(file)
+                .lines()
+                .map_while(|line| line.ok())
+                .filter_map(|line| serde_json::from_str::<SegmentLine>(&line).ok())
+                .filter(|line| line.seq < before)
+                .map(|line| line.message)
+                .filter(|msg| {
+                    query.topic.as_ref().is_none_or(|filter| {
+                        ConnectionManager::topic_matches_pattern(filter, &msg.topic)
+                    })
+                })
+                .filter(|msg| query.matches(msg))
+                .collect();
+            matches.reverse();
+            results.extend(matches.into_iter().take(limit - results.len()));
+            if results.len() >= limit {
+                break;
+            }
+        }
+        Ok(results)
+    }
+}
+
+impl Drop for DiskSegments {
+    /// Let the writer finish what is queued and close the segment
+    fn drop(&mut self) {
+        self.commands.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// The segment writer thread's side of `DiskSegments`
+struct SegmentWriter {
+    config: HistoryDiskConfig,
+    dir: PathBuf,
+    current: Option<OpenSegment>,
+}
+
+impl SegmentWriter {
+    fn run(mut self, commands: Receiver<DiskCommand>, dropped: &AtomicU64) {
+        let mut flushed = Instant::now();
+        let mut pruned = Instant::now();
+        loop {
+            let command = match commands.recv_timeout(FLUSH_INTERVAL) {
+                Ok(command) => Some(command),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let prune = matches!(command, Some(DiskCommand::Prune));
+            let skipped = dropped.swap(0, Ordering::Relaxed);
+            if skipped > 0 {
+                warn!(
+                    "Message history writer fell behind; {} messages were not written to disk",
+                    skipped
+                );
+            }
+            match command {
+                Some(DiskCommand::Append(seq, msg)) => {
+                    if let Err(e) = self.append(seq, &msg) {
+                        warn!("Failed to write message history segment: {}", e);
+                    }
+                }
+                Some(DiskCommand::Flush(done)) => {
+                    self.flush();
+                    flushed = Instant::now();
+                    let _ = done.send(());
+                }
+                Some(DiskCommand::Prune) | None => {}
+            }
+            if flushed.elapsed() >= FLUSH_INTERVAL {
+                self.flush();
+                flushed = Instant::now();
+            }
+            if prune || pruned.elapsed() >= PRUNE_INTERVAL {
+                if let Err(e) = self.prune() {
+                    warn!("Failed to prune message history segments: {}", e);
+                }
+                pruned = Instant::now();
+            }
+        }
+        self.flush();
+    }
+
+    fn append(&mut self, seq: u64, msg: &MqttMessage) -> Result<()> {
+        let mut line = serde_json::to_vec(&SegmentLine { seq, message: msg })
+            .context("Failed to serialize message")?;
+        line.push(b'\n');
+
+        if self.rotate_if_due()? {
+            self.enforce_limits(None)?;
+        }
+
+        if self.current.is_none() {
+            self.current = Some(self.open_segment()?);
+        }
+        let segment = self.current.as_mut().expect("segment was just opened");
+        segment.writer.write_all(&line)?;
+        segment.bytes += line.len() as u64;
+        Ok(())
+    }
+
+    fn open_segment(&self) -> Result<OpenSegment> {
+        // Segment names sort chronologically; bump the stamp if one was opened this microsecond
+        let mut stamp = Utc::now().timestamp_micros();
+        let path = loop {
+            let path = self.dir.join(format!(
+                "{}{:020}.{}",
+                SEGMENT_PREFIX, stamp, SEGMENT_EXTENSION
+            ));
+            if !path.exists() {
+                break path;
+            }
+            stamp += 1;
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open history segment: {:?}", path))?;
+        Ok(OpenSegment {
+            path,
+            writer: BufWriter::new(file),
+            started: SystemTime::now(),
+            bytes: 0,
+        })
+    }
+
+    /// Close the current segment if it reached the size or age limit
+    fn rotate_if_due(&mut self) -> Result<bool> {
+        let due = self.current.as_ref().is_some_and(|segment| {
+            segment.bytes >= self.config.segment_max_bytes
+                || segment.started.elapsed().unwrap_or_default()
+                    >= Duration::from_secs(self.config.segment_max_age_secs)
+        });
+        if !due {
+            return Ok(false);
+        }
+        if let Some(mut segment) = self.current.take() {
+            segment.writer.flush()?;
+        }
+        Ok(true)
+    }
+
+    /// Rotate and delete segments by the limits even while no messages are written
+    fn prune(&mut self) -> Result<()> {
+        self.rotate_if_due()?;
+        self.enforce_limits(self.current.as_ref().map(|segment| segment.path.as_path()))
+    }
+
+    fn flush(&mut self) {
+        if let Some(segment) = &mut self.current {
+            if let Err(e) = segment.writer.flush() {
+                warn!("Failed to flush message history segment: {}", e);
+            }
+        }
+    }
+
+    /// Delete the oldest closed segments beyond the size and age limits; `open` is the
+    /// segment being written, which is kept
+    fn enforce_limits(&self, open: Option<&Path>) -> Result<()> {
+        let mut segments = segments(&self.dir)?;
+        segments.retain(|path| Some(path.as_path()) != open);
+        let sizes: Vec<u64> = segments
+            .iter()
+            .map(|p| std::fs::metadata(p).map(|m| m.len()).unwrap_or(0))
+            .collect();
+        let mut total: u64 = sizes.iter().sum();
+        let max_age = Duration::from_secs(self.config.max_age_secs);
+
+        for (path, size) in segments.iter().zip(sizes) {
+            let expired = std::fs::metadata(path)
+                .and_then(|m| m.modified())
+                .map(|modified| modified.elapsed().unwrap_or_default() > max_age)
+                .unwrap_or(false);
+            if total <= self.config.max_total_bytes && !expired {
+                break;
+            }
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove history segment: {:?}", path))?;
+            total = total.saturating_sub(size);
+        }
+        Ok(())
+    }
+}
+
+/// Segment files in `dir`, oldest first
+fn segments(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut segments: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| is_segment(path))
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+fn is_segment(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION)
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(SEGMENT_PREFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(client_id: &str, topic: &str, payload: &str) -> MqttMessage {
+        MqttMessage {
+            timestamp: Utc::now(),
+            client_id: client_id.to_string(),
+            topic: topic.to_string(),
+            payload: payload.as_bytes().to_vec(),
+            qos: 0,
+            retain: false,
+            deliveries: None,
+        }
+    }
+
+    #[test]
+    fn test_search_by_topic_and_payload() {
+        let history = MessageHistory::new(100);
+        history.push(message("dev-1", "home/kitchen/temp", "21.5"));
+        history.push(message("dev-2", "home/garage/temp", "12.0"));
+        history.push(message("dev-1", "home/kitchen/humidity", "40"));
+        history.push(message("dev-1", "home/kitchen/temp", "22.0"));
+
+        let results = history.search(&HistoryQuery {
+            topic: Some("home/+/temp".to_string()),
+            limit: 10,
+            ..Default::default()
+        });
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].payload, b"22.0");
+
+        let results = history.search(&HistoryQuery {
+            payload_regex: Some(Regex::new(r"^2\d\.").unwrap()),
+            client_id: Some("dev-1".to_string()),
+            limit: 10,
+            ..Default::default()
+        });
+        assert_eq!(results.len(), 2);
+
+        let results = history.search(&HistoryQuery {
+            payload_contains: Some("12".to_string()),
+            limit: 10,
+            ..Default::default()
+        });
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].client_id, "dev-2");
+    }
+
+    #[test]
+    fn test_eviction_keeps_index_consistent() {
+        let history = MessageHistory::new(2);
+        history.push(message("a", "t/1", "1"));
+        history.push(message("a", "t/2", "2"));
+        history.push(message("a", "t/1", "3"));
+
+        assert_eq!(history.len(), 2);
+        let results = history.search(&HistoryQuery {
+            topic: Some("t/1".to_string()),
+            limit: 10,
+            ..Default::default()
+        });
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].payload, b"3");
+    }
+
+    #[test]
+    fn test_disk_segments_rotate_and_search() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = HistoryDiskConfig {
+            path: temp_dir.path().to_string_lossy().to_string(),
+            segment_max_bytes: 1,
+            segment_max_age_secs: 3600,
+            max_total_bytes: u64::MAX,
+            max_age_secs: 3600,
+        };
+        let history = MessageHistory::with_disk(1, config).unwrap();
+
+        history.push(message("dev-1", "a/1", "first"));
+        history.push(message("dev-1", "a/2", "second"));
+        history.push(message("dev-1", "a/3", "third"));
+        history.flush();
+
+        let segments = history.disk.as_ref().unwrap().segments().unwrap();
+        assert_eq!(segments.len(), 3);
+
+        // Newest comes from memory, the older two from disk without duplicates
+        let results = history.search(&HistoryQuery {
+            limit: 10,
+            ..Default::default()
+        });
+        let payloads: Vec<&[u8]> = results.iter().map(|m| m.payload.as_slice()).collect();
+        assert_eq!(payloads, vec![&b"third"[..], b"second", b"first"]);
+    }
+
+    fn disk_config(temp_dir: &tempfile::TempDir) -> HistoryDiskConfig {
+        HistoryDiskConfig {
+            path: temp_dir.path().to_string_lossy().to_string(),
+            segment_max_bytes: u64::MAX,
+            segment_max_age_secs: 3600,
+            max_total_bytes: u64::MAX,
+            max_age_secs: 3600,
+        }
+    }
+
+    #[test]
+    fn test_disk_search_pages_by_sequence() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let timestamp = Utc::now();
+        let push = |history: &MessageHistory, payload: &str| {
+            history.push(MqttMessage {
+                timestamp,
+                ..message("dev-1", "a", payload)
+            })
+        };
+        let payloads = |history: &MessageHistory| -> Vec<Vec<u8>> {
+            history
+                .search(&HistoryQuery {
+                    limit: 10,
+                    ..Default::default()
+                })
+                .into_iter()
+                .map(|m| m.payload)
+                .collect()
+        };
+
+        // Same timestamp throughout: only the sequence tells memory and disk apart
+        let history = MessageHistory::with_disk(1, disk_config(&temp_dir)).unwrap();
+        push(&history, "1");
+        push(&history, "2");
+        assert_eq!(payloads(&history), vec![b"2".to_vec(), b"1".to_vec()]);
+        history.flush();
+        drop(history);
+
+        // After a restart, new messages continue the sequence of those on disk
+        let history = MessageHistory::with_disk(1, disk_config(&temp_dir)).unwrap();
+        push(&history, "3");
+        assert_eq!(
+            payloads(&history),
+            vec![b"3".to_vec(), b"2".to_vec(), b"1".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_prune_applies_limits_without_new_messages() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = HistoryDiskConfig {
+            segment_max_age_secs: 0,
+            max_age_secs: 0,
+            ..disk_config(&temp_dir)
+        };
+        let history = MessageHistory::with_disk(10, config).unwrap();
+        history.push(message("dev-1", "a", "1"));
+        history.flush();
+        let disk = history.disk.as_ref().unwrap();
+        assert_eq!(disk.segments().unwrap().len(), 1);
+
+        std::thread::sleep(Duration::from_millis(20));
+        history.prune();
+        history.flush();
+        assert!(disk.segments().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_disabled_history_writes_no_segments() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let history = MessageHistory::with_disk(0, disk_config(&temp_dir)).unwrap();
+        history.push(message("dev-1", "a", "1"));
+        history.flush();
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+}
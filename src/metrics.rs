@@ -1,14 +1,47 @@
+use crate::config::TopicMetricsConfig;
+use parking_lot::Mutex;
 use prometheus::{
-    register_histogram, register_int_counter, register_int_gauge, Histogram, IntCounter, IntGauge,
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
 };
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+use tracing::warn;
+
+/// `direction` label of messages from the proxy to a downstream broker
+pub const OUTBOUND: &str = "outbound";
+/// `direction` label of messages relayed from a bidirectional broker to the main broker
+pub const INBOUND: &str = "inbound";
+/// `prefix` label of messages whose topic prefix arrived after the cardinality cap was hit
+pub const OTHER_PREFIX: &str = "(other)";
+
+static GLOBAL: OnceLock<Arc<Metrics>> = OnceLock::new();
 
 pub struct Metrics {
     pub messages_received: IntCounter,
     pub messages_forwarded: IntCounter,
-    pub message_latency: Histogram,
+    /// Time from receiving a message (from a listener client, the main broker or HTTP
+    /// ingestion) until it was forwarded
+    pub processing_latency: Histogram,
+    /// Payload size by `broker` and `direction`
+    pub message_size: HistogramVec,
+    /// Time from handing a message to a broker until it was accepted (outbound) or from
+    /// receiving it until it went to the main broker (inbound), by `broker` and `direction`
+    pub publish_latency: HistogramVec,
+    /// Round trip of health-check probes by `broker`
+    pub probe_latency: HistogramVec,
+    /// 1 while health checks mark a `broker` degraded
+    pub broker_degraded: IntGaugeVec,
     pub active_connections: IntGauge,
     pub broker_connections: IntGauge,
+    /// Messages by topic `prefix`, counted only with `[topic_metrics]` configured
+    pub topic_messages: IntCounterVec,
+    /// Topic and payload bytes by `broker`, `direction` and first-level topic `prefix`
+    /// (empty unless `[topic_metrics]` is configured; see `BandwidthStats`)
+    pub broker_bytes: IntCounterVec,
+    /// Messages `/ws/messages` subscribers missed because they fell behind the broadcast
+    pub websocket_dropped: IntCounter,
 }
 
 impl Metrics {
@@ -24,9 +57,35 @@ impl Metrics {
                 "Total number of messages forwarded to brokers"
             )
             .unwrap(),
-            message_latency: register_histogram!(
-                "mqtt_message_latency_seconds",
-                "Message forwarding latency in seconds"
+            processing_latency: register_histogram!(
+                "mqtt_processing_latency_seconds",
+                "Time from receiving a message until it was forwarded in seconds"
+            )
+            .unwrap(),
+            message_size: register_histogram_vec!(
+                "mqtt_message_size_bytes",
+                "Payload size of forwarded messages in bytes",
+                &["broker", "direction"],
+                // 64 B to 1 MiB
+                exponential_buckets(64.0, 4.0, 8).unwrap()
+            )
+            .unwrap(),
+            publish_latency: register_histogram_vec!(
+                "mqtt_publish_latency_seconds",
+                "Message publish latency in seconds",
+                &["broker", "direction"]
+            )
+            .unwrap(),
+            probe_latency: register_histogram_vec!(
+                "mqtt_broker_probe_latency_seconds",
+                "Round trip of broker health-check probes in seconds",
+                &["broker"]
+            )
+            .unwrap(),
+            broker_degraded: register_int_gauge_vec!(
+                "mqtt_broker_degraded",
+                "Whether health checks mark the broker degraded",
+                &["broker"]
             )
             .unwrap(),
             active_connections: register_int_gauge!(
@@ -39,13 +98,128 @@ impl Metrics {
                 "Number of active broker connections"
             )
             .unwrap(),
+            topic_messages: register_int_counter_vec!(
+                "mqtt_topic_messages_total",
+                "Messages received for forwarding by topic prefix",
+                &["prefix"]
+            )
+            .unwrap(),
+            broker_bytes: register_int_counter_vec!(
+                "mqtt_broker_bytes_total",
+                "Topic and payload bytes exchanged with downstream brokers",
+                &["broker", "direction", "prefix"]
+            )
+            .unwrap(),
+            websocket_dropped: register_int_counter!(
+                "mqtt_websocket_dropped_messages_total",
+                "Messages WebSocket subscribers missed because they fell behind"
+            )
+            .unwrap(),
         })
     }
+
+    /// The metrics registered in the default Prometheus registry
+    pub fn global() -> Arc<Self> {
+        Arc::clone(GLOBAL.get_or_init(Self::new))
+    }
+
+    /// Record a message published to (`OUTBOUND`) or relayed from (`INBOUND`) a broker
+    pub fn observe_publish(&self, broker: &str, direction: &str, size: usize, latency: f64) {
+        self.message_size
+            .with_label_values(&[broker, direction])
+            .observe(size as f64);
+        self.publish_latency
+            .with_label_values(&[broker, direction])
+            .observe(latency);
+    }
+
+    /// Average of `processing_latency` since startup in milliseconds
+    pub fn average_latency_ms(&self) -> f64 {
+        let count = self.processing_latency.get_sample_count();
+        if count == 0 {
+            return 0.0;
+        }
+        self.processing_latency.get_sample_sum() / count as f64 * 1000.0
+    }
+
+    /// Drop the series of a removed (or renamed) broker
+    pub fn remove_broker(&self, broker: &str) {
+        let _ = self.probe_latency.remove_label_values(&[broker]);
+        let _ = self.broker_degraded.remove_label_values(&[broker]);
+        for direction in [OUTBOUND, INBOUND] {
+            let _ = self.message_size.remove_label_values(&[broker, direction]);
+            let _ = self
+                .publish_latency
+                .remove_label_values(&[broker, direction]);
+        }
+    }
+}
+
+/// Everything in the default Prometheus registry in the text exposition format, with its
+/// content type
+pub fn render() -> (String, Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        warn!("Failed to encode metrics: {}", e);
+    }
+    (encoder.format_type().to_string(), buffer)
+}
+
+/// Counts messages under the first levels of their topic, with a cap on distinct prefixes
+/// so that device IDs in the counted levels can't blow up the number of series
+pub struct TopicPrefixCounter {
+    depth: usize,
+    max_prefixes: usize,
+    prefixes: Mutex<HashSet<String>>,
+    counter: IntCounterVec,
+}
+
+impl TopicPrefixCounter {
+    pub fn new(config: &TopicMetricsConfig) -> Self {
+        Self {
+            depth: config.depth.max(1),
+            max_prefixes: config.max_prefixes,
+            prefixes: Mutex::new(HashSet::new()),
+            counter: Metrics::global().topic_messages.clone(),
+        }
+    }
+
+    /// Count a message on `topic`
+    pub fn record(&self, topic: &str) {
+        let prefix = self.prefix(topic);
+        let label = {
+            let mut prefixes = self.prefixes.lock();
+            if prefixes.contains(prefix) {
+                prefix
+            } else if prefixes.len() < self.max_prefixes {
+                prefixes.insert(prefix.to_string());
+                prefix
+            } else {
+                if prefixes.insert(OTHER_PREFIX.to_string()) {
+                    warn!(
+                        "More than {} topic prefixes; further prefixes are counted as '{}'",
+                        self.max_prefixes, OTHER_PREFIX
+                    );
+                }
+                OTHER_PREFIX
+            }
+        };
+        self.counter.with_label_values(&[label]).inc();
+    }
+
+    /// The first `depth` levels of `topic`
+    fn prefix<'a>(&self, topic: &'a str) -> &'a str {
+        match topic.match_indices('/').nth(self.depth - 1) {
+            Some((end, _)) => &topic[..end],
+            None => topic,
+        }
+    }
 }
 
 impl Default for Metrics {
     fn default() -> Self {
-        Self::new().as_ref().clone()
+        Self::global().as_ref().clone()
     }
 }
 
@@ -54,9 +228,78 @@ impl Clone for Metrics {
         Self {
             messages_received: self.messages_received.clone(),
             messages_forwarded: self.messages_forwarded.clone(),
-            message_latency: self.message_latency.clone(),
+            processing_latency: self.processing_latency.clone(),
+            message_size: self.message_size.clone(),
+            publish_latency: self.publish_latency.clone(),
+            probe_latency: self.probe_latency.clone(),
+            broker_degraded: self.broker_degraded.clone(),
             active_connections: self.active_connections.clone(),
             broker_connections: self.broker_connections.clone(),
+            topic_messages: self.topic_messages.clone(),
+            websocket_dropped: self.websocket_dropped.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histograms_are_labeled_per_broker() {
+        let metrics = Metrics::global();
+        metrics.observe_publish("test-cloud", OUTBOUND, 100, 0.002);
+        metrics.observe_publish("test-cloud", OUTBOUND, 5000, 0.010);
+        metrics.observe_publish("test-edge", INBOUND, 10, 0.001);
+
+        let cloud = metrics
+            .message_size
+            .with_label_values(&["test-cloud", OUTBOUND]);
+        assert_eq!(cloud.get_sample_count(), 2);
+        assert_eq!(cloud.get_sample_sum(), 5100.0);
+        assert_eq!(
+            metrics
+                .publish_latency
+                .with_label_values(&["test-edge", INBOUND])
+                .get_sample_count(),
+            1
+        );
+
+        metrics.remove_broker("test-cloud");
+        assert_eq!(
+            metrics
+                .message_size
+                .with_label_values(&["test-cloud", OUTBOUND])
+                .get_sample_count(),
+            0
+        );
+
+        let (content_type, body) = render();
+        assert!(content_type.starts_with("text/plain"));
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.contains("mqtt_message_size_bytes_count{broker=\"test-edge\""));
+    }
+
+    #[test]
+    fn test_topic_prefixes_are_capped() {
+        let counter = TopicPrefixCounter::new(&TopicMetricsConfig {
+            depth: 2,
+            max_prefixes: 2,
+        });
+        counter.record("test-fleet/meters/m1/power");
+        counter.record("test-fleet/meters/m2/power");
+        counter.record("test-fleet");
+        counter.record("test-fleet/valves/v1");
+
+        let count = |prefix: &str| {
+            Metrics::global()
+                .topic_messages
+                .with_label_values(&[prefix])
+                .get()
+        };
+        assert_eq!(count("test-fleet/meters"), 2);
+        assert_eq!(count("test-fleet"), 1);
+        assert_eq!(count("test-fleet/valves"), 0);
+        assert!(count(OTHER_PREFIX) >= 1);
+    }
+}
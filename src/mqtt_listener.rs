@@ -1,16 +1,29 @@
 use anyhow::{Context, Result};
 use bytes::{Buf, Bytes, BytesMut};
 use mqttrs::*;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, RwLock};
-use tracing::{debug, error, info, warn};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
 
-use crate::client_registry::{ClientMessage, ClientRegistry};
+use crate::acl::AclTable;
+use crate::client_registry::{self, ClientMessage, ClientRegistry, ClientSession};
 use crate::connection_manager::ConnectionManager;
+use crate::listener_auth::ListenerAuth;
+use crate::listener_tls;
+use crate::metrics::Metrics;
+use crate::mqtt_v5::{self, PropertyValue, V5Packet};
+use crate::plugins::Plugins;
+use crate::proxy_protocol;
+use crate::script_hooks::ScriptHook;
 
 /// Context for handling MQTT packets - groups related parameters to reduce function argument count
 struct PacketHandlerContext<'a> {
@@ -19,17 +32,110 @@ struct PacketHandlerContext<'a> {
     client_registry: &'a Arc<ClientRegistry>,
     mqtt_msg_tx: &'a mpsc::Sender<ClientMessage>,
     message_tx: &'a Option<tokio::sync::broadcast::Sender<crate::web_server::MqttMessage>>,
-    messages_received: &'a Option<Arc<AtomicU64>>,
-    messages_forwarded: &'a Option<Arc<AtomicU64>>,
-    total_latency_ns: &'a Option<Arc<AtomicU64>>,
+    metrics: &'a Metrics,
+    debug_deliveries: bool,
+    /// Set once the client connected with MQTT 5.0
+    v5: &'a AtomicBool,
+    /// QoS 1 messages the client accepts unacknowledged (its v5 Receive Maximum)
+    receive_maximum: &'a AtomicUsize,
+    /// Common Name of the client certificate (mutual TLS)
+    identity: Option<&'a str>,
+    /// Original client address (from the PROXY header when enabled)
+    peer_addr: SocketAddr,
+    rtt_probe: RttProbe,
+    auth: Option<&'a ListenerAuth>,
+    /// Authenticator plugins deciding on CONNECT after `auth`
+    plugins: Option<&'a Plugins>,
+    acl: Option<&'a AclTable>,
 }
 
-/// Messages that can be sent to a client
+/// Time allowed for a load balancer to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time allowed for a client to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a closing connection waits for queued writes to reach the client
+const WRITER_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time a client refused for the connection limit has to send its CONNECT
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Refusals answered at once; beyond this, excess connections are closed right away
+const MAX_PENDING_REFUSALS: usize = 64;
+/// QoS 1 messages sent to a client without a PUBACK before delivery to it pauses
+const MAX_INFLIGHT: usize = 32;
+
+/// Work for a client's writer task
 enum ClientWrite {
     /// MQTT message from bidirectional broker
     Message(ClientMessage),
     /// Raw MQTT packet bytes (for protocol responses)
     RawPacket(Vec<u8>),
+    /// The client acknowledged the QoS 1 message with this packet ID
+    Acked(u16),
+}
+
+/// Packet IDs of the QoS 1 messages sent to a client and not acknowledged yet. Sessions
+/// aren't kept across connections, so unacknowledged messages are not redelivered.
+#[derive(Default)]
+struct Inflight {
+    last_pid: u16,
+    pids: HashSet<u16>,
+}
+
+impl Inflight {
+    fn len(&self) -> usize {
+        self.pids.len()
+    }
+
+    /// Take the next free packet ID; IDs run from 1 to 65535 and then wrap around
+    fn take_pid(&mut self) -> u16 {
+        loop {
+            self.last_pid = self.last_pid.checked_add(1).unwrap_or(1);
+            if self.pids.insert(self.last_pid) {
+                return self.last_pid;
+            }
+        }
+    }
+
+    /// Release the packet ID of an acknowledged message; false if it wasn't in flight
+    fn ack(&mut self, pid: u16) -> bool {
+        self.pids.remove(&pid)
+    }
+}
+
+/// PUBLISH of a message to a client, at QoS 1 if it has a packet ID and QoS 0 otherwise
+fn encode_client_publish(msg: &ClientMessage, pid: Option<u16>, v5: bool) -> Option<Vec<u8>> {
+    if v5 {
+        return Some(mqtt_v5::encode_publish(
+            &msg.topic,
+            &msg.payload,
+            pid.map_or(0, |_| 1),
+            pid,
+            msg.retain,
+            &mqtt_v5::Properties::default(),
+        ));
+    }
+    let qospid = match pid {
+        Some(pid) => QosPid::AtLeastOnce(Pid::try_from(pid).ok()?),
+        None => QosPid::AtMostOnce,
+    };
+    let publish = Packet::Publish(Publish {
+        dup: false,
+        qospid,
+        retain: msg.retain,
+        topic_name: &msg.topic,
+        payload: &msg.payload,
+    });
+    let mut buf = vec![0u8; msg.topic.len() + msg.payload.len() + 16];
+    let bytes_written = encode_slice(&publish, &mut buf).ok()?;
+    buf.truncate(bytes_written);
+    Some(buf)
+}
+
+/// QoS granted for a subscription: as requested, up to `MAX_SUBSCRIPTION_QOS`
+fn granted_qos(requested: QoS) -> rumqttc::QoS {
+    match requested {
+        QoS::AtMostOnce => rumqttc::QoS::AtMostOnce,
+        QoS::AtLeastOnce | QoS::ExactlyOnce => client_registry::MAX_SUBSCRIPTION_QOS,
+    }
 }
 
 pub struct MqttListenerServer {
@@ -37,9 +143,76 @@ pub struct MqttListenerServer {
     connection_manager: Arc<RwLock<ConnectionManager>>,
     client_registry: Arc<ClientRegistry>,
     message_tx: Option<tokio::sync::broadcast::Sender<crate::web_server::MqttMessage>>,
-    messages_received: Option<Arc<AtomicU64>>,
-    messages_forwarded: Option<Arc<AtomicU64>>,
-    total_latency_ns: Option<Arc<AtomicU64>>,
+    metrics: Arc<Metrics>,
+    debug_deliveries: bool,
+    /// Terminate TLS (and optionally verify client certificates) on accepted connections
+    tls: Option<TlsAcceptor>,
+    /// Credentials required in CONNECT
+    auth: Option<Arc<ListenerAuth>>,
+    /// Authenticator plugins (see `plugins`)
+    plugins: Option<Arc<Plugins>>,
+    /// Topics each client may publish and subscribe to
+    acl: Option<Arc<AclTable>>,
+    /// Concurrent client connections accepted before new ones are refused
+    max_connections: Option<usize>,
+    /// Expect a PROXY protocol header (v1 or v2) before anything else on each connection
+    proxy_protocol: bool,
+}
+
+/// Reads the kernel's round-trip time estimate for a client's TCP socket (Linux only).
+/// Only valid while the connection's socket is open.
+#[derive(Clone, Copy)]
+struct RttProbe {
+    #[cfg(target_os = "linux")]
+    fd: std::os::fd::RawFd,
+}
+
+impl RttProbe {
+    fn new(stream: &TcpStream) -> Self {
+        #[cfg(not(target_os = "linux"))]
+        let _ = stream;
+        Self {
+            #[cfg(target_os = "linux")]
+            fd: std::os::fd::AsRawFd::as_raw_fd(stream),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sample(&self) -> Option<Duration> {
+        // SAFETY: tcp_info is plain data and getsockopt writes at most `len` bytes into it
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (ret == 0 && info.tcpi_rtt > 0).then(|| Duration::from_micros(info.tcpi_rtt.into()))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn sample(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// A client connection, plain TCP or TLS
+trait ClientStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ClientStream for T {}
+
+/// PUBACK for `pid`; MQTT 3.1.1 has no reason codes, so `reason` only goes to v5 clients
+fn encode_puback(v5: bool, pid: u16, reason: u8) -> Vec<u8> {
+    if v5 {
+        mqtt_v5::encode_puback(pid, reason)
+    } else {
+        // PUBACK: Fixed header (0x40) + Remaining length (0x02) + Packet ID (2 bytes, big-endian)
+        vec![0x40u8, 0x02, (pid >> 8) as u8, (pid & 0xFF) as u8]
+    }
 }
 
 // Parse MQTT packet length from variable header
@@ -82,51 +255,155 @@ impl MqttListenerServer {
         connection_manager: Arc<RwLock<ConnectionManager>>,
         client_registry: Arc<ClientRegistry>,
         message_tx: Option<tokio::sync::broadcast::Sender<crate::web_server::MqttMessage>>,
-        messages_received: Option<Arc<AtomicU64>>,
-        messages_forwarded: Option<Arc<AtomicU64>>,
-        total_latency_ns: Option<Arc<AtomicU64>>,
+        debug_deliveries: bool,
     ) -> Self {
         Self {
             listen_address,
             connection_manager,
             client_registry,
             message_tx,
-            messages_received,
-            messages_forwarded,
-            total_latency_ns,
+            metrics: Metrics::global(),
+            debug_deliveries,
+            tls: None,
+            auth: None,
+            plugins: None,
+            acl: None,
+            max_connections: None,
+            proxy_protocol: false,
         }
     }
 
-    pub async fn run(self) -> Result<()> {
+    /// Require valid credentials in CONNECT (see `listener_auth::ListenerAuth::from_config`)
+    pub fn with_auth(mut self, auth: ListenerAuth) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Let authenticator plugins decide on CONNECT too (see `plugins::Plugins::authenticate`)
+    pub fn with_plugins(mut self, plugins: Arc<Plugins>) -> Self {
+        self.plugins = Some(plugins);
+        self
+    }
+
+    /// Enforce per-client topic ACLs (see `SettingsStorage::acl_table`)
+    pub fn with_acl(mut self, acl: Arc<AclTable>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Refuse clients with CONNACK "server unavailable" while `max` connections are open
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Read the client's original address from a PROXY protocol header sent by a load
+    /// balancer; connections without one are rejected
+    pub fn with_proxy_protocol(mut self) -> Self {
+        self.proxy_protocol = true;
+        self
+    }
+
+    /// Accept TLS connections only (see `listener_tls::build_tls_acceptor`)
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Accept clients until `shutdown` is cancelled, then wait for every client
+    /// connection to close
+    pub async fn run(self, shutdown: CancellationToken) -> Result<()> {
         let listener = TcpListener::bind(&self.listen_address)
             .await
             .context(format!("Failed to bind to {}", self.listen_address))?;
 
         info!("MQTT Listener started on {}", self.listen_address);
 
+        let mut clients = JoinSet::new();
+        let mut refusals = JoinSet::new();
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+                // Reap finished connections so the set doesn't grow
+                Some(_) = clients.join_next(), if !clients.is_empty() => continue,
+                Some(_) = refusals.join_next(), if !refusals.is_empty() => continue,
+            };
+            match accepted {
+                Ok((stream, addr))
+                    if self.max_connections.is_some_and(|max| clients.len() >= max) =>
+                {
+                    if refusals.len() >= MAX_PENDING_REFUSALS {
+                        warn!("Connection limit reached; closing connection from {}", addr);
+                        continue;
+                    }
+                    warn!("Connection limit reached; refusing client from {}", addr);
+                    refusals.spawn(refuse_connection(
+                        stream,
+                        self.tls.clone(),
+                        self.proxy_protocol,
+                    ));
+                }
                 Ok((stream, addr)) => {
                     info!("New client connection from {}", addr);
                     let connection_manager = Arc::clone(&self.connection_manager);
                     let client_registry = Arc::clone(&self.client_registry);
                     let message_tx = self.message_tx.clone();
-                    let messages_received = self.messages_received.clone();
-                    let messages_forwarded = self.messages_forwarded.clone();
-                    let total_latency_ns = self.total_latency_ns.clone();
-
-                    tokio::spawn(async move {
-                        if let Err(e) = handle_client(
+                    let metrics = Arc::clone(&self.metrics);
+                    let debug_deliveries = self.debug_deliveries;
+                    let client_shutdown = shutdown.clone();
+                    let tls = self.tls.clone();
+                    let auth = self.auth.clone();
+                    let plugins = self.plugins.clone();
+                    let acl = self.acl.clone();
+                    let proxy_protocol = self.proxy_protocol;
+
+                    clients.spawn(async move {
+                        let mut stream = stream;
+                        let addr = if proxy_protocol {
+                            match read_proxy_header(&mut stream).await {
+                                Ok(Some(original)) => {
+                                    info!("Connection from {} is proxied for {}", addr, original);
+                                    original
+                                }
+                                Ok(None) => addr,
+                                Err(e) => {
+                                    warn!("Rejected connection from {}: {}", addr, e);
+                                    return;
+                                }
+                            }
+                        } else {
+                            addr
+                        };
+                        let rtt_probe = RttProbe::new(&stream);
+                        let (stream, identity) = match accept_stream(stream, tls).await {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                warn!("Rejected connection from {}: {}", addr, e);
+                                return;
+                            }
+                        };
+                        metrics.active_connections.inc();
+                        let result = handle_client(
                             stream,
+                            addr,
+                            rtt_probe,
+                            identity,
                             connection_manager,
                             client_registry,
                             message_tx,
-                            messages_received,
-                            messages_forwarded,
-                            total_latency_ns,
+                            Arc::clone(&metrics),
+                            debug_deliveries,
+                            auth,
+                            plugins,
+                            acl,
+                            &client_shutdown,
                         )
-                        .await
-                        {
+                        // The client ID is recorded once the CONNECT arrives
+                        .instrument(info_span!("client", client_id = field::Empty))
+                        .await;
+                        metrics.active_connections.dec();
+                        if let Err(e) = result {
                             error!("Client connection error from {}: {}", addr, e);
                         }
                     });
@@ -136,22 +413,100 @@ impl MqttListenerServer {
                 }
             }
         }
+
+        info!("MQTT Listener stopped accepting; closing client connections");
+        drop(listener);
+        refusals.shutdown().await;
+        while clients.join_next().await.is_some() {}
+        Ok(())
     }
 }
 
-async fn handle_client(
+/// Answer the client's CONNECT with "server unavailable" and close the connection
+async fn refuse_connection(mut stream: TcpStream, tls: Option<TlsAcceptor>, proxy_protocol: bool) {
+    let _ = tokio::time::timeout(REFUSAL_TIMEOUT, async {
+        if proxy_protocol {
+            proxy_protocol::read_header(&mut stream).await?;
+        }
+        let (mut stream, _) = accept_stream(stream, tls).await?;
+        // Only the protocol level is needed, which sits within the first bytes of CONNECT
+        let mut buffer = BytesMut::with_capacity(64);
+        let level = loop {
+            if stream.read_buf(&mut buffer).await? == 0 {
+                anyhow::bail!("Client closed the connection");
+            }
+            if let Some(level) = mqtt_v5::connect_protocol_level(&buffer) {
+                break level;
+            }
+            if buffer.len() >= 16 {
+                break 4;
+            }
+        };
+        let connack = if level == mqtt_v5::PROTOCOL_LEVEL {
+            mqtt_v5::encode_connack(
+                false,
+                mqtt_v5::reason::SERVER_UNAVAILABLE,
+                &mqtt_v5::Properties::default(),
+            )
+        } else {
+            // Return code 3: server unavailable
+            vec![0x20u8, 0x02, 0x00, 0x03]
+        };
+        stream.write_all(&connack).await?;
+        stream.shutdown().await?;
+        Ok(())
+    })
+    .await;
+}
+
+/// Read the PROXY protocol header, which comes before the TLS handshake
+async fn read_proxy_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(stream))
+        .await
+        .context("Timed out waiting for the PROXY header")?
+}
+
+/// Run the TLS handshake when enabled. Returns the stream and the Common Name of the
+/// client certificate, if one was presented.
+async fn accept_stream(
     stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+) -> Result<(Box<dyn ClientStream>, Option<String>)> {
+    let Some(acceptor) = tls else {
+        return Ok((Box::new(stream), None));
+    };
+    let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("TLS handshake timed out")?
+        .context("TLS handshake failed")?;
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| listener_tls::peer_common_name(cert));
+    Ok((Box::new(stream), identity))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_client(
+    stream: Box<dyn ClientStream>,
+    peer_addr: SocketAddr,
+    rtt_probe: RttProbe,
+    identity: Option<String>,
     connection_manager: Arc<RwLock<ConnectionManager>>,
     client_registry: Arc<ClientRegistry>,
     message_tx: Option<tokio::sync::broadcast::Sender<crate::web_server::MqttMessage>>,
-    messages_received: Option<Arc<AtomicU64>>,
-    messages_forwarded: Option<Arc<AtomicU64>>,
-    total_latency_ns: Option<Arc<AtomicU64>>,
+    metrics: Arc<Metrics>,
+    debug_deliveries: bool,
+    auth: Option<Arc<ListenerAuth>>,
+    plugins: Option<Arc<Plugins>>,
+    acl: Option<Arc<AclTable>>,
+    shutdown: &CancellationToken,
 ) -> Result<()> {
-    let peer_addr = stream.peer_addr()?;
     let mut buffer = BytesMut::with_capacity(4096);
     let mut client_id = String::from("unknown");
-    let mut client_registered = false;
+    let mut session: Option<ClientSession> = None;
 
     // Create channel for sending to this client (both messages and protocol responses)
     let (to_client_tx, mut to_client_rx) = mpsc::channel::<ClientWrite>(100);
@@ -159,182 +514,381 @@ async fn handle_client(
     // Create a separate channel for bidirectional MQTT messages
     let (mqtt_msg_tx, mut mqtt_msg_rx) = mpsc::channel::<ClientMessage>(100);
 
-    // Clone the sender for use in the main loop (sender is Clone)
-    let to_client_tx_clone = to_client_tx.clone();
+    // Protocol level is only known after CONNECT; the writer needs it to encode PUBLISH
+    let v5 = Arc::new(AtomicBool::new(false));
+    let writer_v5 = Arc::clone(&v5);
+    // Lowered by a v5 client's Receive Maximum in CONNECT
+    let receive_maximum = Arc::new(AtomicUsize::new(MAX_INFLIGHT));
+    let writer_receive_maximum = Arc::clone(&receive_maximum);
 
     // Split the stream for concurrent read/write
-    let (mut read_half, mut write_half) = stream.into_split();
+    let (mut read_half, mut write_half) = tokio::io::split(stream);
 
     // Spawn task to send to client - handles both protocol responses and MQTT messages
-    let _client_writer = tokio::spawn(async move {
+    let mut client_writer = tokio::spawn(async move {
+        let mut inflight = Inflight::default();
         loop {
-            tokio::select! {
-                Some(write) = to_client_rx.recv() => {
-                    match write {
-                        ClientWrite::RawPacket(bytes) => {
-                            if write_half.write_all(&bytes).await.is_err() {
-                                break; // Connection closed
-                            }
-                        }
-                        ClientWrite::Message(msg) => {
-                            // Convert QoS to mqttrs QosPid
-                            let qospid = match msg.qos {
-                                rumqttc::QoS::AtMostOnce => QosPid::AtMostOnce,
-                                rumqttc::QoS::AtLeastOnce => QosPid::AtLeastOnce(Pid::try_from(1).unwrap()),
-                                rumqttc::QoS::ExactlyOnce => QosPid::ExactlyOnce(Pid::try_from(1).unwrap()),
-                            };
-
-                            let publish = Packet::Publish(Publish {
-                                dup: false,
-                                qospid,
-                                retain: msg.retain,
-                                topic_name: &msg.topic,
-                                payload: &msg.payload,
-                            });
-
-                            // Encode and send packet
-                            let mut buf = vec![0u8; 4096];
-                            if let Ok(bytes_written) = encode_slice(&publish, &mut buf) {
-                                if write_half.write_all(&buf[..bytes_written]).await.is_err() {
-                                    break; // Connection closed
-                                }
-                                debug!("Sent PUBLISH to client: topic='{}'", msg.topic);
-                            }
-                        }
-                    }
+            let window = writer_receive_maximum.load(Ordering::Relaxed);
+            let write = tokio::select! {
+                Some(write) = to_client_rx.recv() => write,
+                // Forward MQTT message from bidirectional broker, while the client has room
+                Some(msg) = mqtt_msg_rx.recv(), if inflight.len() < window => {
+                    ClientWrite::Message(msg)
                 }
-                Some(msg) = mqtt_msg_rx.recv() => {
-                    // Forward MQTT message from bidirectional broker
-                    if to_client_tx.send(ClientWrite::Message(msg)).await.is_err() {
-                        break;
+                else => break,
+            };
+            let bytes = match write {
+                ClientWrite::RawPacket(bytes) => bytes,
+                ClientWrite::Acked(pid) => {
+                    if !inflight.ack(pid) {
+                        debug!("PUBACK from client for unknown packet {}", pid);
                     }
+                    continue;
                 }
-                else => break,
+                ClientWrite::Message(msg) => {
+                    // Retained replays don't wait for the window; past it they go out at QoS 0
+                    let pid = (msg.qos != rumqttc::QoS::AtMostOnce && inflight.len() < window)
+                        .then(|| inflight.take_pid());
+                    let Some(bytes) =
+                        encode_client_publish(&msg, pid, writer_v5.load(Ordering::Relaxed))
+                    else {
+                        warn!("Failed to encode PUBLISH on '{}' for client", msg.topic);
+                        continue;
+                    };
+                    debug!(
+                        "Sending PUBLISH to client: topic='{}', packet={:?}",
+                        msg.topic, pid
+                    );
+                    bytes
+                }
+            };
+            if write_half.write_all(&bytes).await.is_err() {
+                break; // Connection closed
             }
         }
-    });
-
-    loop {
-        // Read data from the stream
-        let n = read_half.read_buf(&mut buffer).await?;
-
-        if n == 0 {
-            info!("Client {} disconnected", client_id);
-            if client_registered {
-                client_registry.unregister_client(&client_id).await;
-            }
-            break;
+        if inflight.len() > 0 {
+            debug!(
+                "{} QoS 1 message(s) to client unacknowledged at disconnect",
+                inflight.len()
+            );
         }
+    });
 
-        // Try to decode MQTT packets from buffer
-        // Create context for packet handling
-        let ctx = PacketHandlerContext {
-            to_client_tx: &to_client_tx_clone,
-            connection_manager: &connection_manager,
-            client_registry: &client_registry,
-            mqtt_msg_tx: &mqtt_msg_tx,
-            message_tx: &message_tx,
-            messages_received: &messages_received,
-            messages_forwarded: &messages_forwarded,
-            total_latency_ns: &total_latency_ns,
-        };
-
-        #[allow(clippy::while_let_loop)]
-        // Complex break conditions make while-let less readable here
+    let result: Result<()> = async {
         loop {
-            // First, check if we can determine the packet length
-            let packet_len = match parse_packet_length(&buffer[..]) {
-                Some(len) => len,
-                None => {
-                    // Need more data to determine packet length
+            // Read data from the stream
+            let n = tokio::select! {
+                _ = taken_over(&session) => {
+                    info!("Closing connection of client {} after takeover", client_id);
+                    if v5.load(Ordering::Relaxed) {
+                        let _ = to_client_tx.try_send(ClientWrite::RawPacket(
+                            mqtt_v5::encode_disconnect(mqtt_v5::reason::SESSION_TAKEN_OVER),
+                        ));
+                    }
+                    break;
+                }
+                _ = shutdown.cancelled() => {
+                    info!("Closing connection of client {} for shutdown", client_id);
+                    if let Some(session) = &session {
+                        client_registry.unregister_client(&client_id, session.id).await;
+                    }
                     break;
                 }
+                result = read_half.read_buf(&mut buffer) => match result {
+                    Ok(n) => n,
+                    Err(e) => {
+                        if let Some(session) = &session {
+                            client_registry.unregister_client(&client_id, session.id).await;
+                        }
+                        return Err(e.into());
+                    }
+                },
             };
 
-            // Make sure we have the complete packet
-            if buffer.len() < packet_len {
-                // Need more data
+            if n == 0 {
+                info!("Client {} disconnected", client_id);
+                if let Some(session) = &session {
+                    client_registry
+                        .unregister_client(&client_id, session.id)
+                        .await;
+                }
                 break;
             }
 
-            // Clone the packet data for decoding
-            let packet_data = buffer[..packet_len].to_vec();
+            // Try to decode MQTT packets from buffer
+            // Create context for packet handling
+            let ctx = PacketHandlerContext {
+                to_client_tx: &to_client_tx,
+                connection_manager: &connection_manager,
+                client_registry: &client_registry,
+                mqtt_msg_tx: &mqtt_msg_tx,
+                message_tx: &message_tx,
+                metrics: &metrics,
+                debug_deliveries,
+                v5: &v5,
+                receive_maximum: &receive_maximum,
+                identity: identity.as_deref(),
+                peer_addr,
+                rtt_probe,
+                auth: auth.as_deref(),
+                plugins: plugins.as_deref().filter(|plugins| plugins.authenticates()),
+                acl: acl.as_deref(),
+            };
 
-            match decode_slice(&packet_data) {
-                Ok(Some(packet)) => {
-                    // Handle the packet
-                    match handle_packet(&ctx, &packet, &mut client_id, &mut client_registered).await
-                    {
-                        Ok(should_continue) => {
-                            if !should_continue {
-                                info!("Client {} requested disconnect", client_id);
-                                if client_registered {
-                                    client_registry.unregister_client(&client_id).await;
+            #[allow(clippy::while_let_loop)]
+            // Complex break conditions make while-let less readable here
+            loop {
+                // First, check if we can determine the packet length
+                let packet_len = match parse_packet_length(&buffer[..]) {
+                    Some(len) => len,
+                    None => {
+                        // Need more data to determine packet length
+                        break;
+                    }
+                };
+
+                // Make sure we have the complete packet
+                if buffer.len() < packet_len {
+                    // Need more data
+                    break;
+                }
+
+                // Clone the packet data for decoding
+                let packet_data = buffer[..packet_len].to_vec();
+
+                // The CONNECT protocol level decides which codec the rest of the session uses
+                if mqtt_v5::connect_protocol_level(&packet_data) == Some(mqtt_v5::PROTOCOL_LEVEL) {
+                    v5.store(true, Ordering::Relaxed);
+                }
+
+                let decoded = if v5.load(Ordering::Relaxed) {
+                    mqtt_v5::decode(&packet_data).map(Some)
+                } else {
+                    decode_slice(&packet_data)
+                        .map(|packet| {
+                            packet.map(|packet| V5Packet {
+                                packet,
+                                properties: Default::default(),
+                                reason_code: None,
+                            })
+                        })
+                        .map_err(|e| anyhow::anyhow!("{:?}", e))
+                };
+
+                match decoded {
+                    Ok(Some(decoded)) => {
+                        // Handle the packet
+                        match handle_packet(&ctx, &decoded, &mut client_id, &mut session).await {
+                            Ok(should_continue) => {
+                                if !should_continue {
+                                    info!("Client {} requested disconnect", client_id);
+                                    if let Some(session) = &session {
+                                        client_registry
+                                            .unregister_client(&client_id, session.id)
+                                            .await;
+                                    }
+                                    return Ok(());
                                 }
-                                return Ok(());
                             }
-                        }
-                        Err(e) => {
-                            error!("Error handling packet from {}: {}", client_id, e);
-                            if client_registered {
-                                client_registry.unregister_client(&client_id).await;
+                            Err(e) => {
+                                error!("Error handling packet from {}: {}", client_id, e);
+                                if let Some(session) = &session {
+                                    client_registry
+                                        .unregister_client(&client_id, session.id)
+                                        .await;
+                                }
+                                return Err(e);
                             }
-                            return Err(e);
                         }
-                    }
 
-                    // Remove processed bytes from buffer
-                    buffer.advance(packet_len);
-                }
-                Ok(None) => {
-                    // This shouldn't happen since we have the complete packet
-                    error!("Failed to decode complete packet");
-                    buffer.advance(1);
-                }
-                Err(e) => {
-                    error!("Failed to decode MQTT packet from {}: {:?}", peer_addr, e);
-                    // Try to recover by advancing past this packet
-                    buffer.advance(packet_len.min(buffer.len()));
+                        // Remove processed bytes from buffer
+                        buffer.advance(packet_len);
+                    }
+                    Ok(None) => {
+                        // This shouldn't happen since we have the complete packet
+                        error!("Failed to decode complete packet");
+                        buffer.advance(1);
+                    }
+                    Err(e) => {
+                        error!("Failed to decode MQTT packet from {}: {}", peer_addr, e);
+                        // Try to recover by advancing past this packet
+                        buffer.advance(packet_len.min(buffer.len()));
+                    }
                 }
             }
         }
+
+        Ok(())
+    }
+    .await;
+
+    // A connection taken over by the same client ID leaves the client online
+    if session
+        .as_ref()
+        .is_some_and(|session| !session.taken_over.is_cancelled())
+    {
+        connection_manager
+            .read()
+            .await
+            .publish_availability(&client_id, false)
+            .await;
     }
 
-    Ok(())
+    // The writer stops once both channels are closed; give it a moment to flush
+    drop(to_client_tx);
+    drop(mqtt_msg_tx);
+    if tokio::time::timeout(WRITER_FLUSH_TIMEOUT, &mut client_writer)
+        .await
+        .is_err()
+    {
+        client_writer.abort();
+    }
+
+    result
+}
+
+/// Resolves once another connection takes over the client's ID
+async fn taken_over(session: &Option<ClientSession>) {
+    match session {
+        Some(session) => session.taken_over.cancelled().await,
+        None => std::future::pending().await,
+    }
 }
 
 async fn handle_packet<'a>(
     ctx: &PacketHandlerContext<'_>,
-    packet: &Packet<'a>,
+    decoded: &V5Packet<'a>,
     client_id: &mut String,
-    client_registered: &mut bool,
+    session: &mut Option<ClientSession>,
 ) -> Result<bool> {
-    match packet {
+    let v5 = ctx.v5.load(Ordering::Relaxed);
+    match &decoded.packet {
         Packet::Connect(connect) => {
             *client_id = connect.client_id.to_string();
+
+            // MQTT 5.0 clients may leave the client ID empty and expect one to be assigned
+            let mut connack_properties = mqtt_v5::Properties::default();
+            if v5 && client_id.is_empty() {
+                *client_id = format!("auto-{}", uuid::Uuid::new_v4());
+                connack_properties.push(
+                    mqtt_v5::property::ASSIGNED_CLIENT_IDENTIFIER,
+                    PropertyValue::Utf8String(client_id.clone()),
+                );
+            }
+            tracing::Span::current().record("client_id", client_id.as_str());
+
             info!(
-                "CONNECT from client '{}' (protocol: {:?}, clean_session: {})",
-                client_id, connect.protocol, connect.clean_session
+                "CONNECT from client '{}' at {} (protocol: {}, clean_session: {})",
+                client_id,
+                ctx.peer_addr,
+                if v5 {
+                    "MQTT 5.0".to_string()
+                } else {
+                    format!("{:?}", connect.protocol)
+                },
+                connect.clean_session
             );
+            // Certificate Common Name, or the username once checked against the configured accounts
+            let mut identity = ctx.identity.map(str::to_string);
+            if let Some(identity) = ctx.identity {
+                info!(
+                    "Client '{}' authenticated by certificate as '{}'",
+                    client_id, identity
+                );
+            } else if ctx.auth.is_some() || ctx.plugins.is_some() {
+                // A verified client certificate already authenticates the client
+                let checked = ctx
+                    .auth
+                    .map_or(Ok(()), |auth| {
+                        auth.check(connect.username, connect.password)
+                    })
+                    .and_then(|()| {
+                        ctx.plugins.map_or(Ok(()), |plugins| {
+                            plugins.authenticate(
+                                client_id.as_str(),
+                                connect.username,
+                                connect.password,
+                            )
+                        })
+                    });
+                if let Err(refusal) = checked {
+                    warn!(
+                        "Refusing CONNECT from client '{}' (user: {:?}): {:?}",
+                        client_id, connect.username, refusal
+                    );
+                    let connack_bytes = if v5 {
+                        mqtt_v5::encode_connack(
+                            false,
+                            refusal.reason_code(),
+                            &mqtt_v5::Properties::default(),
+                        )
+                    } else {
+                        vec![0x20u8, 0x02, 0x00, refusal.return_code()]
+                    };
+                    ctx.to_client_tx
+                        .send(ClientWrite::RawPacket(connack_bytes))
+                        .await
+                        .context("Failed to send CONNACK")?;
+                    // Closing the connection after the CONNACK is flushed
+                    return Ok(false);
+                }
+                identity = connect.username.map(str::to_string);
+            }
+
+            if let Some(PropertyValue::TwoByteInteger(max)) = decoded
+                .properties
+                .get(mqtt_v5::property::RECEIVE_MAXIMUM)
+                .filter(|_| v5)
+            {
+                if *max > 0 {
+                    ctx.receive_maximum
+                        .store(usize::from(*max).min(MAX_INFLIGHT), Ordering::Relaxed);
+                }
+            }
 
             // Register client with registry (use mqtt_msg_tx for bidirectional messages)
+            let registered = ctx
+                .client_registry
+                .register_client(
+                    client_id.clone(),
+                    ctx.mqtt_msg_tx.clone(),
+                    identity,
+                    ctx.peer_addr,
+                    connect.keep_alive,
+                )
+                .await;
             ctx.client_registry
-                .register_client(client_id.clone(), ctx.mqtt_msg_tx.clone())
+                .record_rtt(client_id, registered.id, ctx.rtt_probe.sample())
                 .await;
-            *client_registered = true;
+            *session = Some(registered);
             info!(
                 "✅ Client '{}' registered for bidirectional message forwarding",
                 client_id
             );
 
             // Send CONNACK - manually constructed for reliability
-            // CONNACK: Fixed header (0x20) + Remaining length (0x02) + Session present (0x00) + Return code (0x00 = accepted)
-            let connack_bytes = vec![0x20u8, 0x02, 0x00, 0x00];
+            let connack_bytes = if v5 {
+                // QoS 2 handshakes and shared subscriptions aren't implemented; tell v5 clients up front
+                connack_properties.push(mqtt_v5::property::MAXIMUM_QOS, PropertyValue::Byte(1));
+                connack_properties.push(
+                    mqtt_v5::property::SHARED_SUBSCRIPTION_AVAILABLE,
+                    PropertyValue::Byte(0),
+                );
+                mqtt_v5::encode_connack(false, mqtt_v5::reason::SUCCESS, &connack_properties)
+            } else {
+                // CONNACK: Fixed header (0x20) + Remaining length (0x02) + Session present (0x00) + Return code (0x00 = accepted)
+                vec![0x20u8, 0x02, 0x00, 0x00]
+            };
             ctx.to_client_tx
                 .send(ClientWrite::RawPacket(connack_bytes))
                 .await
                 .context("Failed to send CONNACK")?;
             debug!("Sent CONNACK to client '{}'", client_id);
+            ctx.connection_manager
+                .read()
+                .await
+                .publish_availability(client_id, true)
+                .await;
             Ok(true)
         }
 
@@ -343,7 +897,11 @@ async fn handle_packet<'a>(
             let start = Instant::now();
 
             let topic = &publish.topic_name;
-            let payload = Bytes::copy_from_slice(publish.payload);
+            let payload = ctx
+                .connection_manager
+                .read()
+                .await
+                .decode_ingest(topic, Bytes::copy_from_slice(publish.payload));
 
             // Extract QoS and packet ID from QosPid enum
             let (qos, pkid) = match &publish.qospid {
@@ -352,10 +910,7 @@ async fn handle_packet<'a>(
                 QosPid::ExactlyOnce(pid) => (rumqttc::QoS::ExactlyOnce, Some(*pid)),
             };
 
-            // Increment received message counter
-            if let Some(counter) = ctx.messages_received {
-                counter.fetch_add(1, Ordering::Relaxed);
-            }
+            ctx.metrics.messages_received.inc();
 
             info!(
                 "📨 PUBLISH from '{}': topic='{}', payload_size={} bytes, qos={:?}, retain={}",
@@ -365,6 +920,9 @@ async fn handle_packet<'a>(
                 qos,
                 publish.retain
             );
+            if !decoded.properties.is_empty() {
+                debug!("PUBLISH properties: {:?}", decoded.properties);
+            }
 
             // Debug: Log payload content (first 100 bytes)
             if !payload.is_empty() {
@@ -379,8 +937,90 @@ async fn handle_packet<'a>(
                 debug!("📄 Payload preview: {}", preview);
             }
 
-            // Broadcast to WebSocket clients
-            if let Some(tx) = ctx.message_tx {
+            if let Some(acl) = ctx.acl {
+                let identity = ctx.client_registry.client_identity(client_id).await;
+                if !acl.can_publish(identity.as_deref(), topic) {
+                    warn!(
+                        "🚫 PUBLISH from '{}' to '{}' denied by ACL (identity: {:?})",
+                        client_id, topic, identity
+                    );
+                    // MQTT 3.1.1 has no way to refuse a publish; acknowledge and drop it
+                    if let (Some(pid), rumqttc::QoS::AtLeastOnce) = (pkid, qos) {
+                        let puback_bytes =
+                            encode_puback(v5, pid.get(), mqtt_v5::reason::NOT_AUTHORIZED);
+                        ctx.to_client_tx
+                            .send(ClientWrite::RawPacket(puback_bytes))
+                            .await
+                            .context("Failed to send PUBACK")?;
+                    }
+                    return Ok(true);
+                }
+            }
+
+            let Some((topic, payload)) = ctx.connection_manager.read().await.run_script_hook(
+                ScriptHook::OnClientPublish,
+                topic,
+                payload,
+                publish.retain,
+                Some(client_id),
+            ) else {
+                debug!(
+                    "Script hook dropped PUBLISH from '{}' to '{}'",
+                    client_id, topic
+                );
+                if let (Some(pid), rumqttc::QoS::AtLeastOnce) = (pkid, qos) {
+                    ctx.to_client_tx
+                        .send(ClientWrite::RawPacket(encode_puback(
+                            v5,
+                            pid.get(),
+                            mqtt_v5::reason::SUCCESS,
+                        )))
+                        .await
+                        .context("Failed to send PUBACK")?;
+                }
+                return Ok(true);
+            };
+            let topic = topic.as_str();
+
+            if publish.retain {
+                ctx.client_registry.retain_message(ClientMessage {
+                    topic: topic.to_string(),
+                    payload: payload.clone(),
+                    qos,
+                    retain: true,
+                });
+            }
+
+            // Forward to all downstream brokers
+            let manager = ctx.connection_manager.read().await;
+            let deliveries = match manager
+                .forward_message(
+                    topic,
+                    payload.clone(),
+                    qos,
+                    publish.retain,
+                    Some(&decoded.properties),
+                    ctx.debug_deliveries,
+                )
+                .await
+            {
+                Ok(deliveries) => {
+                    info!("✅ Message forwarded to all brokers: topic='{}'", topic);
+                    deliveries
+                }
+                Err(e) => {
+                    warn!("⚠️  Failed to forward message: {}", e);
+                    Vec::new()
+                }
+            };
+
+            // Broadcast to WebSocket clients, unless forwarding is under load
+            let broadcast = ctx.message_tx.as_ref().filter(|tx| {
+                manager
+                    .load_shedder()
+                    .admit(start.elapsed(), tx.len(), Instant::now())
+            });
+            if let Some(tx) = broadcast {
                 let qos_u8 = match qos {
                     rumqttc::QoS::AtMostOnce => 0,
                     rumqttc::QoS::AtLeastOnce => 1,
@@ -394,57 +1034,32 @@ async fn handle_packet<'a>(
                     payload: payload.to_vec(),
                     qos: qos_u8,
                     retain: publish.retain,
+                    deliveries: ctx.debug_deliveries.then_some(deliveries),
                 };
 
                 // Send to WebSocket subscribers (ignore if no subscribers)
                 let _ = tx.send(mqtt_msg);
             }
 
-            // Forward to all downstream brokers
-            let manager = ctx.connection_manager.read().await;
-            match manager
-                .forward_message(topic, payload, qos, publish.retain, ctx.messages_forwarded)
-                .await
-            {
-                Ok(_) => {
-                    info!("✅ Message forwarded to all brokers: topic='{}'", topic);
-                }
-                Err(e) => {
-                    warn!("⚠️  Failed to forward message: {}", e);
-                }
-            }
-
-            // Record latency
-            let elapsed = start.elapsed();
-            if let Some(latency_counter) = ctx.total_latency_ns {
-                latency_counter.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
-            }
+            ctx.metrics
+                .processing_latency
+                .observe(start.elapsed().as_secs_f64());
 
             // Send PUBACK if QoS 1
             if let Some(pid) = pkid {
                 if matches!(qos, rumqttc::QoS::AtLeastOnce) {
-                    // Get the packet ID as u16
-                    let pid_bytes = format!("{:?}", pid); // Format: "Pid(123)"
-                    if let Some(num_str) = pid_bytes
-                        .strip_prefix("Pid(")
-                        .and_then(|s| s.strip_suffix(")"))
+                    let pid_u16 = pid.get();
+                    let puback_bytes = encode_puback(v5, pid_u16, mqtt_v5::reason::SUCCESS);
+                    if ctx
+                        .to_client_tx
+                        .send(ClientWrite::RawPacket(puback_bytes))
+                        .await
+                        .is_ok()
                     {
-                        if let Ok(pid_u16) = num_str.parse::<u16>() {
-                            // PUBACK: Fixed header (0x40) + Remaining length (0x02) + Packet ID (2 bytes, big-endian)
-                            let puback_bytes =
-                                vec![0x40u8, 0x02, (pid_u16 >> 8) as u8, (pid_u16 & 0xFF) as u8];
-                            if ctx
-                                .to_client_tx
-                                .send(ClientWrite::RawPacket(puback_bytes))
-                                .await
-                                .is_ok()
-                            {
-                                debug!(
-                                    "Sent PUBACK to client '{}' for packet {}",
-                                    client_id, pid_u16
-                                );
-                            }
-                        }
+                        debug!(
+                            "Sent PUBACK to client '{}' for packet {}",
+                            client_id, pid_u16
+                        );
                     }
                 }
             }
@@ -454,6 +1069,11 @@ async fn handle_packet<'a>(
 
         Packet::Pingreq => {
             debug!("PINGREQ from client '{}'", client_id);
+            if let Some(session) = session {
+                ctx.client_registry
+                    .record_ping(client_id, session.id, ctx.rtt_probe.sample())
+                    .await;
+            }
             // PINGRESP: Fixed header (0xD0) + Remaining length (0x00)
             let pingresp_bytes = vec![0xD0u8, 0x00];
             ctx.to_client_tx
@@ -464,38 +1084,107 @@ async fn handle_packet<'a>(
             Ok(true)
         }
 
+        Packet::Puback(pid) => {
+            ctx.to_client_tx
+                .send(ClientWrite::Acked(pid.get()))
+                .await
+                .context("Failed to pass on PUBACK")?;
+            Ok(true)
+        }
+
         Packet::Subscribe(subscribe) => {
             let topics: Vec<String> = subscribe
                 .topics
                 .iter()
                 .map(|t| t.topic_path.to_string())
                 .collect();
+            let qos: Vec<rumqttc::QoS> = subscribe
+                .topics
+                .iter()
+                .map(|t| granted_qos(t.qos))
+                .collect();
             info!("SUBSCRIBE from client '{}': topics={:?}", client_id, topics);
 
-            // Add subscriptions to client registry
-            let subscribed_topics = ctx
-                .client_registry
-                .add_subscriptions(client_id, topics.clone())
-                .await;
+            // Filters the client's ACL doesn't allow are refused in the SUBACK
+            let granted: Vec<bool> = match ctx.acl {
+                Some(acl) => {
+                    let identity = ctx.client_registry.client_identity(client_id).await;
+                    topics
+                        .iter()
+                        .map(|topic| {
+                            let allowed = acl.can_subscribe(identity.as_deref(), topic);
+                            if !allowed {
+                                warn!(
+                                    "🚫 SUBSCRIBE from '{}' to '{}' denied by ACL (identity: {:?})",
+                                    client_id, topic, identity
+                                );
+                            }
+                            allowed
+                        })
+                        .collect()
+                }
+                None => vec![true; topics.len()],
+            };
+            let subscriptions: Vec<(String, rumqttc::QoS)> = topics
+                .into_iter()
+                .zip(qos.iter().copied())
+                .zip(&granted)
+                .filter_map(|(subscription, granted)| granted.then_some(subscription))
+                .collect();
 
-            // Subscribe to these topics on all bidirectional brokers
-            if !subscribed_topics.is_empty() {
-                let manager = ctx.connection_manager.read().await;
-                manager.subscribe_to_topics(&subscribed_topics).await;
-            }
+            // Bidirectional brokers follow the registry's subscription table
+            ctx.client_registry
+                .add_subscriptions(client_id, subscriptions.clone())
+                .await;
 
             // Send SUBACK
-            let suback = Packet::Suback(Suback {
-                pid: subscribe.pid,
-                return_codes: subscribe
-                    .topics
+            if v5 {
+                let reason_codes: Vec<u8> = granted
                     .iter()
-                    .map(|_| SubscribeReturnCodes::Success(QoS::AtMostOnce))
-                    .collect(),
-            });
-
-            send_packet(ctx.to_client_tx, &suback).await?;
+                    .zip(&qos)
+                    .map(|(granted, qos)| match (granted, qos) {
+                        (false, _) => mqtt_v5::reason::NOT_AUTHORIZED,
+                        (true, rumqttc::QoS::AtMostOnce) => mqtt_v5::reason::GRANTED_QOS_0,
+                        (true, _) => mqtt_v5::reason::GRANTED_QOS_1,
+                    })
+                    .collect();
+                ctx.to_client_tx
+                    .send(ClientWrite::RawPacket(mqtt_v5::encode_suback(
+                        subscribe.pid.get(),
+                        &reason_codes,
+                    )))
+                    .await
+                    .context("Failed to send SUBACK")?;
+            } else {
+                let suback = Packet::Suback(Suback {
+                    pid: subscribe.pid,
+                    return_codes: granted
+                        .iter()
+                        .zip(&qos)
+                        .map(|(granted, qos)| match (granted, qos) {
+                            (false, _) => SubscribeReturnCodes::Failure,
+                            (true, rumqttc::QoS::AtMostOnce) => {
+                                SubscribeReturnCodes::Success(QoS::AtMostOnce)
+                            }
+                            (true, _) => SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                        })
+                        .collect(),
+                });
+                send_packet(ctx.to_client_tx, &suback).await?;
+            }
             debug!("Sent SUBACK to client '{}'", client_id);
+
+            // Replay retained messages for the new subscriptions
+            for message in ctx.client_registry.retained_messages(&subscriptions) {
+                debug!(
+                    "Sending retained message on '{}' to client '{}'",
+                    message.topic, client_id
+                );
+                ctx.to_client_tx
+                    .send(ClientWrite::Message(message))
+                    .await
+                    .context("Failed to send retained message")?;
+            }
             Ok(true)
         }
 
@@ -506,22 +1195,35 @@ async fn handle_packet<'a>(
                 client_id, topics
             );
 
-            // Remove subscriptions from client registry
+            // Brokers unsubscribe once no client wants a filter anymore
             ctx.client_registry
                 .remove_subscriptions(client_id, &topics)
                 .await;
 
-            // Unsubscribe from brokers (only if no other clients are subscribed)
-            // Note: For simplicity, we'll keep broker subscriptions active
-            // A more advanced implementation would track subscription counts
-
-            let unsuback = Packet::Unsuback(unsubscribe.pid);
-            send_packet(ctx.to_client_tx, &unsuback).await?;
+            if v5 {
+                let reason_codes = vec![mqtt_v5::reason::SUCCESS; unsubscribe.topics.len()];
+                ctx.to_client_tx
+                    .send(ClientWrite::RawPacket(mqtt_v5::encode_unsuback(
+                        unsubscribe.pid.get(),
+                        &reason_codes,
+                    )))
+                    .await
+                    .context("Failed to send UNSUBACK")?;
+            } else {
+                let unsuback = Packet::Unsuback(unsubscribe.pid);
+                send_packet(ctx.to_client_tx, &unsuback).await?;
+            }
             Ok(true)
         }
 
         Packet::Disconnect => {
-            info!("DISCONNECT from client '{}'", client_id);
+            match decoded.reason_code {
+                Some(code) if code != mqtt_v5::reason::NORMAL_DISCONNECTION => info!(
+                    "DISCONNECT from client '{}' (reason code 0x{:02X})",
+                    client_id, code
+                ),
+                _ => info!("DISCONNECT from client '{}'", client_id),
+            }
             Ok(false)
         }
 
@@ -549,3 +1251,307 @@ async fn send_packet<'a>(
         .context("Failed to send packet")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DedupConfig;
+
+    #[tokio::test]
+    async fn test_run_returns_after_shutdown_with_open_client() {
+        // Reserve a free port for the listener
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let registry = Arc::new(ClientRegistry::new());
+        let manager = ConnectionManager::new(
+            Vec::new(),
+            Arc::clone(&registry),
+            "127.0.0.1".to_string(),
+            1,
+            4,
+            &DedupConfig::default(),
+            0,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let server = MqttListenerServer::new(
+            format!("127.0.0.1:{}", port),
+            Arc::new(RwLock::new(manager)),
+            registry,
+            None,
+            false,
+        );
+
+        let shutdown = CancellationToken::new();
+        let server_task = tokio::spawn(server.run(shutdown.clone()));
+
+        // Keep a client connected across the shutdown
+        let mut client = None;
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await {
+                client = Some(stream);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut client = client.expect("listener should accept connections");
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server_task)
+            .await
+            .expect("listener should stop")
+            .unwrap()
+            .unwrap();
+
+        // The proxy closed the client's connection
+        let mut buf = [0u8; 1];
+        assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_refuses_with_server_unavailable() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let registry = Arc::new(ClientRegistry::new());
+        let manager = ConnectionManager::new(
+            Vec::new(),
+            Arc::clone(&registry),
+            "127.0.0.1".to_string(),
+            1,
+            4,
+            &DedupConfig::default(),
+            0,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let server = MqttListenerServer::new(
+            format!("127.0.0.1:{}", port),
+            Arc::new(RwLock::new(manager)),
+            registry,
+            None,
+            false,
+        )
+        .with_max_connections(1);
+
+        let shutdown = CancellationToken::new();
+        let server_task = tokio::spawn(server.run(shutdown.clone()));
+
+        let mut first = None;
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await {
+                first = Some(stream);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let _first = first.expect("listener should accept connections");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // MQTT 3.1.1 CONNECT with client ID "b"
+        let mut second = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        second
+            .write_all(&[
+                0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, 1, b'b',
+            ])
+            .await
+            .unwrap();
+        let mut connack = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(5), second.read_exact(&mut connack))
+            .await
+            .expect("refusal should be answered")
+            .unwrap();
+        assert_eq!(connack, [0x20, 0x02, 0x00, 0x03]);
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server_task)
+            .await
+            .expect("listener should stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_list_reports_pings_and_rtt() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let registry = Arc::new(ClientRegistry::new());
+        let manager = ConnectionManager::new(
+            Vec::new(),
+            Arc::clone(&registry),
+            "127.0.0.1".to_string(),
+            1,
+            4,
+            &DedupConfig::default(),
+            0,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let server = MqttListenerServer::new(
+            format!("127.0.0.1:{}", port),
+            Arc::new(RwLock::new(manager)),
+            Arc::clone(&registry),
+            None,
+            false,
+        );
+
+        let shutdown = CancellationToken::new();
+        let server_task = tokio::spawn(server.run(shutdown.clone()));
+
+        let mut client = None;
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await {
+                client = Some(stream);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut client = client.expect("listener should accept connections");
+
+        // MQTT 3.1.1 CONNECT with client ID "a" and a 60s keep alive, then PINGREQ
+        client
+            .write_all(&[
+                0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, 1, b'a',
+            ])
+            .await
+            .unwrap();
+        let mut connack = [0u8; 4];
+        client.read_exact(&mut connack).await.unwrap();
+        client.write_all(&[0xC0, 0x00]).await.unwrap();
+        let mut pingresp = [0u8; 2];
+        client.read_exact(&mut pingresp).await.unwrap();
+        assert_eq!(pingresp, [0xD0, 0x00]);
+
+        let clients = registry.clients().await;
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].keep_alive_secs, 60);
+        assert_eq!(clients[0].pings, 1);
+        assert_eq!(clients[0].rtt_ms.is_some(), cfg!(target_os = "linux"));
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server_task)
+            .await
+            .expect("listener should stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_inflight_packet_ids_skip_unacknowledged() {
+        let mut inflight = Inflight::default();
+        assert_eq!(inflight.take_pid(), 1);
+        assert_eq!(inflight.take_pid(), 2);
+        assert!(inflight.ack(1));
+        assert!(!inflight.ack(1));
+
+        // IDs wrap around after 65535 and skip those still in flight
+        inflight.last_pid = u16::MAX - 1;
+        assert_eq!(inflight.take_pid(), u16::MAX);
+        assert_eq!(inflight.take_pid(), 1);
+        assert_eq!(inflight.take_pid(), 3);
+        assert_eq!(inflight.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_qos1_subscription_gets_packet_ids() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let registry = Arc::new(ClientRegistry::new());
+        let manager = ConnectionManager::new(
+            Vec::new(),
+            Arc::clone(&registry),
+            "127.0.0.1".to_string(),
+            1,
+            4,
+            &DedupConfig::default(),
+            0,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let server = MqttListenerServer::new(
+            format!("127.0.0.1:{}", port),
+            Arc::new(RwLock::new(manager)),
+            Arc::clone(&registry),
+            None,
+            false,
+        );
+
+        let shutdown = CancellationToken::new();
+        let server_task = tokio::spawn(server.run(shutdown.clone()));
+
+        let mut client = None;
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await {
+                client = Some(stream);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut client = client.expect("listener should accept connections");
+
+        // MQTT 3.1.1 CONNECT as "q", then SUBSCRIBE to "t" at QoS 2
+        client
+            .write_all(&[
+                0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, 1, b'q',
+            ])
+            .await
+            .unwrap();
+        let mut connack = [0u8; 4];
+        client.read_exact(&mut connack).await.unwrap();
+        client
+            .write_all(&[0x82, 6, 0, 7, 0, 1, b't', 2])
+            .await
+            .unwrap();
+        // Granted QoS 1
+        let mut suback = [0u8; 5];
+        client.read_exact(&mut suback).await.unwrap();
+        assert_eq!(suback, [0x90, 3, 0, 7, 1]);
+
+        let message = |payload: &'static [u8]| ClientMessage {
+            topic: "t".to_string(),
+            payload: Bytes::from_static(payload),
+            qos: rumqttc::QoS::ExactlyOnce,
+            retain: false,
+        };
+        for payload in [&b"a"[..], &b"b"[..]] {
+            registry.forward_to_subscribers("t", message(payload)).await;
+        }
+        let mut publishes = [0u8; 16];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut publishes))
+            .await
+            .expect("messages should be delivered")
+            .unwrap();
+        assert_eq!(
+            publishes,
+            [0x32, 6, 0, 1, b't', 0, 1, b'a', 0x32, 6, 0, 1, b't', 0, 2, b'b']
+        );
+        client.write_all(&[0x40, 2, 0, 1]).await.unwrap();
+        client.write_all(&[0x40, 2, 0, 2]).await.unwrap();
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server_task)
+            .await
+            .expect("listener should stop")
+            .unwrap()
+            .unwrap();
+    }
+}
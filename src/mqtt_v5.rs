@@ -0,0 +1,589 @@
+//! MQTT 5.0 wire format for the client listener
+//!
+//! mqttrs only understands MQTT 3.1.1, so v5 packets are decoded here into the same
+//! `mqttrs::Packet` shapes (plus their properties) and responses are encoded with v5
+//! reason codes and property sections.
+
+use anyhow::{bail, Context, Result};
+use mqttrs::{Connect, LastWill, Packet, Pid, Protocol, Publish, QoS, QosPid};
+use mqttrs::{Subscribe, SubscribeTopic, Unsubscribe};
+
+/// Protocol level sent in the CONNECT variable header by MQTT 5.0 clients
+pub const PROTOCOL_LEVEL: u8 = 5;
+
+/// Property identifiers (MQTT 5.0 section 2.2.2.2)
+pub mod property {
+    pub const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+    pub const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+    pub const CONTENT_TYPE: u8 = 0x03;
+    pub const RESPONSE_TOPIC: u8 = 0x08;
+    pub const CORRELATION_DATA: u8 = 0x09;
+    pub const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+    pub const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+    pub const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+    pub const SERVER_KEEP_ALIVE: u8 = 0x13;
+    pub const AUTHENTICATION_METHOD: u8 = 0x15;
+    pub const AUTHENTICATION_DATA: u8 = 0x16;
+    pub const REQUEST_PROBLEM_INFORMATION: u8 = 0x17;
+    pub const WILL_DELAY_INTERVAL: u8 = 0x18;
+    pub const REQUEST_RESPONSE_INFORMATION: u8 = 0x19;
+    pub const RESPONSE_INFORMATION: u8 = 0x1A;
+    pub const SERVER_REFERENCE: u8 = 0x1C;
+    pub const REASON_STRING: u8 = 0x1F;
+    pub const RECEIVE_MAXIMUM: u8 = 0x21;
+    pub const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+    pub const TOPIC_ALIAS: u8 = 0x23;
+    pub const MAXIMUM_QOS: u8 = 0x24;
+    pub const RETAIN_AVAILABLE: u8 = 0x25;
+    pub const USER_PROPERTY: u8 = 0x26;
+    pub const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+    pub const WILDCARD_SUBSCRIPTION_AVAILABLE: u8 = 0x28;
+    pub const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+    pub const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2A;
+}
+
+/// Reason codes used in responses (MQTT 5.0 section 2.4)
+pub mod reason {
+    pub const SUCCESS: u8 = 0x00;
+    pub const GRANTED_QOS_0: u8 = 0x00;
+    pub const GRANTED_QOS_1: u8 = 0x01;
+    pub const NORMAL_DISCONNECTION: u8 = 0x00;
+    pub const NO_SUBSCRIPTION_EXISTED: u8 = 0x11;
+    pub const UNSPECIFIED_ERROR: u8 = 0x80;
+    pub const PROTOCOL_ERROR: u8 = 0x82;
+    pub const BAD_USERNAME_OR_PASSWORD: u8 = 0x86;
+    pub const NOT_AUTHORIZED: u8 = 0x87;
+    pub const SERVER_UNAVAILABLE: u8 = 0x88;
+    pub const SESSION_TAKEN_OVER: u8 = 0x8E;
+}
+
+/// A single property value, typed by its identifier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropertyValue {
+    Byte(u8),
+    TwoByteInteger(u16),
+    FourByteInteger(u32),
+    VariableByteInteger(u32),
+    Utf8String(String),
+    BinaryData(Vec<u8>),
+    Utf8StringPair(String, String),
+}
+
+/// Properties attached to a v5 packet, in wire order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Properties(pub Vec<(u8, PropertyValue)>);
+
+impl Properties {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn push(&mut self, id: u8, value: PropertyValue) {
+        self.0.push((id, value));
+    }
+
+    /// First value for a property identifier
+    pub fn get(&self, id: u8) -> Option<&PropertyValue> {
+        self.0.iter().find(|(i, _)| *i == id).map(|(_, v)| v)
+    }
+
+    /// User properties as name/value pairs
+    pub fn user_properties(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().filter_map(|(id, value)| match value {
+            PropertyValue::Utf8StringPair(k, v) if *id == property::USER_PROPERTY => {
+                Some((k.as_str(), v.as_str()))
+            }
+            _ => None,
+        })
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self> {
+        let len = reader.varint()? as usize;
+        let mut props = Reader::new(reader.take(len)?);
+        let mut properties = Properties::default();
+        while !props.is_empty() {
+            let id = props.varint()? as u8;
+            let value = match id {
+                property::PAYLOAD_FORMAT_INDICATOR
+                | property::REQUEST_PROBLEM_INFORMATION
+                | property::REQUEST_RESPONSE_INFORMATION
+                | property::MAXIMUM_QOS
+                | property::RETAIN_AVAILABLE
+                | property::WILDCARD_SUBSCRIPTION_AVAILABLE
+                | property::SUBSCRIPTION_IDENTIFIER_AVAILABLE
+                | property::SHARED_SUBSCRIPTION_AVAILABLE => PropertyValue::Byte(props.u8()?),
+                property::SERVER_KEEP_ALIVE
+                | property::RECEIVE_MAXIMUM
+                | property::TOPIC_ALIAS_MAXIMUM
+                | property::TOPIC_ALIAS => PropertyValue::TwoByteInteger(props.u16()?),
+                property::MESSAGE_EXPIRY_INTERVAL
+                | property::SESSION_EXPIRY_INTERVAL
+                | property::WILL_DELAY_INTERVAL
+                | property::MAXIMUM_PACKET_SIZE => PropertyValue::FourByteInteger(props.u32()?),
+                property::SUBSCRIPTION_IDENTIFIER => {
+                    PropertyValue::VariableByteInteger(props.varint()?)
+                }
+                property::CONTENT_TYPE
+                | property::RESPONSE_TOPIC
+                | property::ASSIGNED_CLIENT_IDENTIFIER
+                | property::AUTHENTICATION_METHOD
+                | property::RESPONSE_INFORMATION
+                | property::SERVER_REFERENCE
+                | property::REASON_STRING => PropertyValue::Utf8String(props.str()?.to_string()),
+                property::CORRELATION_DATA | property::AUTHENTICATION_DATA => {
+                    PropertyValue::BinaryData(props.binary()?.to_vec())
+                }
+                property::USER_PROPERTY => {
+                    let key = props.str()?.to_string();
+                    let value = props.str()?.to_string();
+                    PropertyValue::Utf8StringPair(key, value)
+                }
+                other => bail!("Unknown MQTT 5 property identifier 0x{:02X}", other),
+            };
+            properties.push(id, value);
+        }
+        Ok(properties)
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        for (id, value) in &self.0 {
+            write_varint(&mut body, *id as u32);
+            match value {
+                PropertyValue::Byte(b) => body.push(*b),
+                PropertyValue::TwoByteInteger(v) => body.extend_from_slice(&v.to_be_bytes()),
+                PropertyValue::FourByteInteger(v) => body.extend_from_slice(&v.to_be_bytes()),
+                PropertyValue::VariableByteInteger(v) => write_varint(&mut body, *v),
+                PropertyValue::Utf8String(s) => write_binary(&mut body, s.as_bytes()),
+                PropertyValue::BinaryData(d) => write_binary(&mut body, d),
+                PropertyValue::Utf8StringPair(k, v) => {
+                    write_binary(&mut body, k.as_bytes());
+                    write_binary(&mut body, v.as_bytes());
+                }
+            }
+        }
+        write_varint(out, body.len() as u32);
+        out.extend_from_slice(&body);
+    }
+}
+
+/// A decoded v5 packet: the common packet shape plus the properties mqttrs has no room for
+#[derive(Debug)]
+pub struct V5Packet<'a> {
+    pub packet: Packet<'a>,
+    pub properties: Properties,
+    /// Reason code carried by DISCONNECT and acknowledgement packets
+    pub reason_code: Option<u8>,
+}
+
+/// Peek at a complete CONNECT packet and return its protocol level
+pub fn connect_protocol_level(packet: &[u8]) -> Option<u8> {
+    if packet.first()? >> 4 != 1 {
+        return None;
+    }
+    let mut reader = Reader::new(packet.get(1..)?);
+    reader.varint().ok()?;
+    reader.str().ok()?;
+    reader.u8().ok()
+}
+
+/// Decode one complete MQTT 5.0 packet
+pub fn decode(packet: &[u8]) -> Result<V5Packet<'_>> {
+    let mut reader = Reader::new(packet);
+    let header = reader.u8()?;
+    let remaining = reader.varint()? as usize;
+    let mut body = Reader::new(reader.take(remaining)?);
+
+    let mut properties = Properties::default();
+    let mut reason_code = None;
+    let packet = match header >> 4 {
+        1 => {
+            let protocol_name = body.str()?;
+            let level = body.u8()?;
+            if protocol_name != "MQTT" || level != PROTOCOL_LEVEL {
+                bail!(
+                    "Not an MQTT 5 CONNECT (protocol '{}', level {})",
+                    protocol_name,
+                    level
+                );
+            }
+            let flags = body.u8()?;
+            let keep_alive = body.u16()?;
+            properties = Properties::decode(&mut body)?;
+            let client_id = body.str()?;
+            let last_will = if flags & 0x04 != 0 {
+                // Will properties are not forwarded anywhere; skip them
+                Properties::decode(&mut body)?;
+                let topic = body.str()?;
+                let message = body.binary()?;
+                Some(LastWill {
+                    topic,
+                    message,
+                    qos: qos_from_bits((flags >> 3) & 0x03)?,
+                    retain: flags & 0x20 != 0,
+                })
+            } else {
+                None
+            };
+            let username = if flags & 0x80 != 0 {
+                Some(body.str()?)
+            } else {
+                None
+            };
+            let password = if flags & 0x40 != 0 {
+                Some(body.binary()?)
+            } else {
+                None
+            };
+            Packet::Connect(Connect {
+                // mqttrs has no v5 variant; callers track the protocol level separately
+                protocol: Protocol::MQTT311,
+                keep_alive,
+                client_id,
+                clean_session: flags & 0x02 != 0,
+                last_will,
+                username,
+                password,
+            })
+        }
+        3 => {
+            let qos = qos_from_bits((header >> 1) & 0x03)?;
+            let topic_name = body.str()?;
+            let qospid = match qos {
+                QoS::AtMostOnce => QosPid::AtMostOnce,
+                QoS::AtLeastOnce => QosPid::AtLeastOnce(body.pid()?),
+                QoS::ExactlyOnce => QosPid::ExactlyOnce(body.pid()?),
+            };
+            properties = Properties::decode(&mut body)?;
+            Packet::Publish(Publish {
+                dup: header & 0x08 != 0,
+                qospid,
+                retain: header & 0x01 != 0,
+                topic_name,
+                payload: body.rest(),
+            })
+        }
+        kind @ 4..=7 => {
+            let pid = body.pid()?;
+            if !body.is_empty() {
+                reason_code = Some(body.u8()?);
+            }
+            if !body.is_empty() {
+                properties = Properties::decode(&mut body)?;
+            }
+            match kind {
+                4 => Packet::Puback(pid),
+                5 => Packet::Pubrec(pid),
+                6 => Packet::Pubrel(pid),
+                _ => Packet::Pubcomp(pid),
+            }
+        }
+        8 => {
+            let pid = body.pid()?;
+            properties = Properties::decode(&mut body)?;
+            let mut topics = Vec::new();
+            while !body.is_empty() {
+                let topic_path = body.str()?.to_string();
+                let options = body.u8()?;
+                topics.push(SubscribeTopic {
+                    topic_path,
+                    qos: qos_from_bits(options & 0x03)?,
+                });
+            }
+            if topics.is_empty() {
+                bail!("SUBSCRIBE without topic filters");
+            }
+            Packet::Subscribe(Subscribe { pid, topics })
+        }
+        10 => {
+            let pid = body.pid()?;
+            properties = Properties::decode(&mut body)?;
+            let mut topics = Vec::new();
+            while !body.is_empty() {
+                topics.push(body.str()?.to_string());
+            }
+            Packet::Unsubscribe(Unsubscribe { pid, topics })
+        }
+        12 => Packet::Pingreq,
+        14 => {
+            if !body.is_empty() {
+                reason_code = Some(body.u8()?);
+            }
+            if !body.is_empty() {
+                properties = Properties::decode(&mut body)?;
+            }
+            Packet::Disconnect
+        }
+        other => bail!("Unsupported MQTT 5 packet type {}", other),
+    };
+
+    Ok(V5Packet {
+        packet,
+        properties,
+        reason_code,
+    })
+}
+
+/// CONNACK with a reason code and properties
+pub fn encode_connack(session_present: bool, reason_code: u8, properties: &Properties) -> Vec<u8> {
+    let mut body = vec![session_present as u8, reason_code];
+    properties.encode(&mut body);
+    frame(0x20, &body)
+}
+
+/// PUBACK for a QoS 1 publish
+pub fn encode_puback(pid: u16, reason_code: u8) -> Vec<u8> {
+    let mut body = pid.to_be_bytes().to_vec();
+    body.push(reason_code);
+    Properties::default().encode(&mut body);
+    frame(0x40, &body)
+}
+
+/// SUBACK with one reason code per topic filter
+pub fn encode_suback(pid: u16, reason_codes: &[u8]) -> Vec<u8> {
+    let mut body = pid.to_be_bytes().to_vec();
+    Properties::default().encode(&mut body);
+    body.extend_from_slice(reason_codes);
+    frame(0x90, &body)
+}
+
+/// UNSUBACK with one reason code per topic filter
+pub fn encode_unsuback(pid: u16, reason_codes: &[u8]) -> Vec<u8> {
+    let mut body = pid.to_be_bytes().to_vec();
+    Properties::default().encode(&mut body);
+    body.extend_from_slice(reason_codes);
+    frame(0xB0, &body)
+}
+
+/// PUBLISH to a v5 client
+pub fn encode_publish(
+    topic: &str,
+    payload: &[u8],
+    qos: u8,
+    pid: Option<u16>,
+    retain: bool,
+    properties: &Properties,
+) -> Vec<u8> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 8);
+    write_binary(&mut body, topic.as_bytes());
+    if qos > 0 {
+        body.extend_from_slice(&pid.unwrap_or(1).to_be_bytes());
+    }
+    properties.encode(&mut body);
+    body.extend_from_slice(payload);
+    frame(0x30 | (qos << 1) | retain as u8, &body)
+}
+
+/// Server-initiated DISCONNECT
+pub fn encode_disconnect(reason_code: u8) -> Vec<u8> {
+    let mut body = vec![reason_code];
+    Properties::default().encode(&mut body);
+    frame(0xE0, &body)
+}
+
+fn frame(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 5);
+    out.push(header);
+    write_varint(&mut out, body.len() as u32);
+    out.extend_from_slice(body);
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn write_binary(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+fn qos_from_bits(bits: u8) -> Result<QoS> {
+    match bits {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        n => bail!("Invalid QoS {}", n),
+    }
+}
+
+/// Cursor over a packet buffer that hands out borrowed strings and slices
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            bail!("Packet truncated");
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn pid(&mut self) -> Result<Pid> {
+        Pid::try_from(self.u16()?).map_err(|_| anyhow::anyhow!("Packet identifier 0"))
+    }
+
+    fn varint(&mut self) -> Result<u32> {
+        let mut value = 0u32;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u32) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Malformed variable byte integer")
+    }
+
+    fn binary(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        std::str::from_utf8(self.binary()?).context("Invalid UTF-8 string")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect_packet(client_id: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        write_binary(&mut body, b"MQTT");
+        body.push(PROTOCOL_LEVEL);
+        body.push(0x02 | 0x80); // clean start + username
+        body.extend_from_slice(&30u16.to_be_bytes());
+        let mut props = Properties::default();
+        props.push(
+            property::SESSION_EXPIRY_INTERVAL,
+            PropertyValue::FourByteInteger(60),
+        );
+        props.encode(&mut body);
+        write_binary(&mut body, client_id.as_bytes());
+        write_binary(&mut body, b"user");
+        frame(0x10, &body)
+    }
+
+    #[test]
+    fn test_decode_connect() {
+        let bytes = connect_packet("sensor-1");
+        assert_eq!(connect_protocol_level(&bytes), Some(PROTOCOL_LEVEL));
+
+        let decoded = decode(&bytes).unwrap();
+        let Packet::Connect(connect) = decoded.packet else {
+            panic!("expected CONNECT");
+        };
+        assert_eq!(connect.client_id, "sensor-1");
+        assert_eq!(connect.keep_alive, 30);
+        assert!(connect.clean_session);
+        assert_eq!(connect.username, Some("user"));
+        assert_eq!(connect.password, None);
+        assert_eq!(
+            decoded.properties.get(property::SESSION_EXPIRY_INTERVAL),
+            Some(&PropertyValue::FourByteInteger(60))
+        );
+    }
+
+    #[test]
+    fn test_publish_roundtrip_with_properties() {
+        let mut props = Properties::default();
+        props.push(
+            property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair("site".into(), "north".into()),
+        );
+        props.push(
+            property::MESSAGE_EXPIRY_INTERVAL,
+            PropertyValue::FourByteInteger(120),
+        );
+        let bytes = encode_publish("a/b", b"hello", 1, Some(7), true, &props);
+
+        let decoded = decode(&bytes).unwrap();
+        let Packet::Publish(publish) = decoded.packet else {
+            panic!("expected PUBLISH");
+        };
+        assert_eq!(publish.topic_name, "a/b");
+        assert_eq!(publish.payload, b"hello");
+        assert!(publish.retain);
+        assert_eq!(
+            publish.qospid,
+            QosPid::AtLeastOnce(Pid::try_from(7).unwrap())
+        );
+        assert_eq!(decoded.properties, props);
+        assert_eq!(
+            decoded.properties.user_properties().collect::<Vec<_>>(),
+            vec![("site", "north")]
+        );
+    }
+
+    #[test]
+    fn test_decode_subscribe_and_disconnect() {
+        let mut body = 3u16.to_be_bytes().to_vec();
+        Properties::default().encode(&mut body);
+        write_binary(&mut body, b"sensors/#");
+        body.push(0x01 | 0x04); // QoS 1, no-local
+        let bytes = frame(0x82, &body);
+        let decoded = decode(&bytes).unwrap();
+        let Packet::Subscribe(subscribe) = decoded.packet else {
+            panic!("expected SUBSCRIBE");
+        };
+        assert_eq!(subscribe.pid.get(), 3);
+        assert_eq!(subscribe.topics[0].topic_path, "sensors/#");
+        assert_eq!(subscribe.topics[0].qos, QoS::AtLeastOnce);
+
+        let bytes = encode_disconnect(reason::NORMAL_DISCONNECTION);
+        let decoded = decode(&bytes).unwrap();
+        assert!(matches!(decoded.packet, Packet::Disconnect));
+        assert_eq!(decoded.reason_code, Some(reason::NORMAL_DISCONNECTION));
+    }
+
+    #[test]
+    fn test_v311_connect_is_not_v5() {
+        // CONNECT with protocol level 4
+        let bytes = [
+            0x10, 0x0C, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3C, 0x00, 0x00,
+        ];
+        assert_eq!(connect_protocol_level(&bytes), Some(4));
+        assert!(decode(&bytes).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Buffering of messages for a disconnected broker
+//!
+//! While a broker is down its task keeps the messages forwarded to it, up to
+//! `maxMessages` (the oldest is evicted to make room), and publishes them in order once
+//! the broker is back. Messages older than `maxAgeSecs` by then are dropped instead.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfflineBufferConfig {
+    /// Messages kept while disconnected; `0` turns buffering off
+    #[serde(default = "default_max_messages")]
+    pub max_messages: usize,
+    /// Buffered messages older than this are dropped instead of sent on reconnect
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+impl Default for OfflineBufferConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: default_max_messages(),
+            max_age_secs: default_max_age_secs(),
+        }
+    }
+}
+
+fn default_max_messages() -> usize {
+    1000
+}
+
+fn default_max_age_secs() -> u64 {
+    300
+}
+
+/// Messages held for one broker while it is disconnected
+pub struct OfflineBuffer<T> {
+    max_messages: usize,
+    max_age: Duration,
+    queue: VecDeque<(Instant, T)>,
+}
+
+impl<T> OfflineBuffer<T> {
+    pub fn new(config: &OfflineBufferConfig) -> Self {
+        Self {
+            max_messages: config.max_messages,
+            max_age: Duration::from_secs(config.max_age_secs),
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_messages > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Keep `message`; returns the oldest message if it had to make room
+    pub fn push(&mut self, message: T, now: Instant) -> Option<T> {
+        let evicted = if self.queue.len() >= self.max_messages {
+            self.queue.pop_front().map(|(_, message)| message)
+        } else {
+            None
+        };
+        self.queue.push_back((now, message));
+        evicted
+    }
+
+    /// Take the buffered messages, oldest first, and how many had expired
+    pub fn drain(&mut self, now: Instant) -> (Vec<T>, usize) {
+        let buffered = self.queue.len();
+        let fresh: Vec<T> = self
+            .queue
+            .drain(..)
+            .filter(|(buffered_at, _)| now.duration_since(*buffered_at) <= self.max_age)
+            .map(|(_, message)| message)
+            .collect();
+        let expired = buffered - fresh.len();
+        (fresh, expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_evicts_oldest_and_drops_expired() {
+        let mut buffer = OfflineBuffer::new(&OfflineBufferConfig {
+            max_messages: 3,
+            max_age_secs: 60,
+        });
+        let start = Instant::now();
+        assert_eq!(buffer.push(1, start), None);
+        buffer.push(2, start + Duration::from_secs(30));
+        buffer.push(3, start + Duration::from_secs(40));
+        assert_eq!(buffer.push(4, start + Duration::from_secs(50)), Some(1));
+        assert_eq!(buffer.len(), 3);
+
+        // Message 2 was buffered at +30s and is too old at +95s
+        assert_eq!(
+            buffer.drain(start + Duration::from_secs(95)),
+            (vec![3, 4], 1)
+        );
+        assert!(buffer.is_empty());
+
+        let off = OfflineBuffer::<u8>::new(&OfflineBufferConfig {
+            max_messages: 0,
+            max_age_secs: 60,
+        });
+        assert!(!off.is_enabled());
+    }
+}
@@ -0,0 +1,599 @@
+//! OpenTelemetry tracing of forwarded messages
+//!
+//! With `[opentelemetry]` configured, every sampled message received for forwarding gets a
+//! `receive` span, with a `dedup` child covering the duplicate, timestamp and delta checks
+//! of each copy forwarded and a `publish <broker>` child per broker it is handed to (until
+//! the broker task accepted it, or the broker acknowledged it where that is awaited). A
+//! message from an MQTT 5.0 client with a W3C `traceparent` user property continues that
+//! trace; other messages start one, sampled at `sample_ratio`. Messages forwarded to MQTT
+//! 5.0 brokers carry the `traceparent` of their publish span, so consumers can join the
+//! trace: all of them with `propagate`, or those of rules with an `inject_traceparent`
+//! action, which can also put it in a field of JSON object payloads.
+//!
+//! Finished spans are batched and exported as OTLP/HTTP JSON by `run_span_exporter`. When
+//! the exporter falls behind, spans are dropped rather than slowing down forwarding.
+
+use crate::config::OpenTelemetryConfig;
+use crate::http_client::{self, HttpUrl};
+use crate::mqtt_v5::{self, PropertyValue};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// User property carrying the W3C trace context
+pub const TRACEPARENT_PROPERTY: &str = "traceparent";
+/// Finished spans waiting for export; more are dropped
+const SPAN_QUEUE_SIZE: usize = 4096;
+const MAX_EXPORT_BATCH: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Trace and span ID of a `traceparent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Parse a W3C `traceparent` (`00-<trace ID>-<parent ID>-<flags>`)
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        if version.len() != 2 || version == "ff" {
+            return None;
+        }
+        let trace_id = decode_hex::<16>(parts.next()?)?;
+        let span_id = decode_hex::<8>(parts.next()?)?;
+        let [flags] = decode_hex::<1>(parts.next()?)?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// OTLP span kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Producer = 4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<usize> for AttributeValue {
+    fn from(value: usize) -> Self {
+        AttributeValue::Int(value as i64)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+/// A finished span
+#[derive(Debug)]
+struct SpanData {
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    error: Option<String>,
+}
+
+/// Creates the spans of sampled messages and queues them for export
+pub struct Tracer {
+    sample_ratio: f64,
+    propagate: bool,
+    spans: mpsc::Sender<SpanData>,
+    dropped: AtomicU64,
+}
+
+/// Receiving end of the finished spans; pass to `run_span_exporter`
+pub struct SpanExporter {
+    spans: mpsc::Receiver<SpanData>,
+    url: HttpUrl,
+    config: OpenTelemetryConfig,
+}
+
+impl Tracer {
+    pub fn new(config: &OpenTelemetryConfig) -> Result<(Arc<Self>, SpanExporter)> {
+        let url = HttpUrl::parse(&config.endpoint).context("Invalid OTLP endpoint")?;
+        let (tx, rx) = mpsc::channel(SPAN_QUEUE_SIZE);
+        let tracer = Arc::new(Self {
+            sample_ratio: config.sample_ratio.clamp(0.0, 1.0),
+            propagate: config.propagate,
+            spans: tx,
+            dropped: AtomicU64::new(0),
+        });
+        let exporter = SpanExporter {
+            spans: rx,
+            url,
+            config: config.clone(),
+        };
+        Ok((tracer, exporter))
+    }
+
+    /// Start the span of a message received for forwarding, continuing the trace of its
+    /// `traceparent` user property if it has one. `None` if the message isn't sampled.
+    pub fn start_receive(
+        self: &Arc<Self>,
+        topic: &str,
+        properties: Option<&mqtt_v5::Properties>,
+    ) -> Option<Span> {
+        let parent = properties
+            .and_then(|properties| {
+                properties
+                    .user_properties()
+                    .find(|(name, _)| *name == TRACEPARENT_PROPERTY)
+            })
+            .and_then(|(_, traceparent)| TraceContext::parse(traceparent));
+        let trace_id = match parent {
+            Some(parent) if !parent.sampled => return None,
+            Some(parent) => parent.trace_id,
+            None if rand::random::<f64>() < self.sample_ratio => rand::random(),
+            None => return None,
+        };
+        let mut span = Span::start(
+            Arc::clone(self),
+            trace_id,
+            parent.map(|parent| parent.span_id),
+            "receive".to_string(),
+            SpanKind::Server,
+        );
+        span.set("messaging.system", "mqtt");
+        span.set("messaging.destination.name", topic);
+        Some(span)
+    }
+
+    fn export(&self, span: SpanData) {
+        if self.spans.try_send(span).is_err() && self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+            warn!("Span export is falling behind; dropping spans");
+        }
+    }
+}
+
+/// A span in progress; exported when dropped
+pub struct Span {
+    tracer: Arc<Tracer>,
+    data: Option<SpanData>,
+}
+
+impl Span {
+    fn start(
+        tracer: Arc<Tracer>,
+        trace_id: [u8; 16],
+        parent_span_id: Option<[u8; 8]>,
+        name: String,
+        kind: SpanKind,
+    ) -> Self {
+        let now = SystemTime::now();
+        Self {
+            tracer,
+            data: Some(SpanData {
+                context: TraceContext {
+                    trace_id,
+                    span_id: rand::random(),
+                    sampled: true,
+                },
+                parent_span_id,
+                name,
+                kind,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+
+    pub fn child(&self, name: impl Into<String>, kind: SpanKind) -> Span {
+        let context = self.context();
+        Span::start(
+            Arc::clone(&self.tracer),
+            context.trace_id,
+            Some(context.span_id),
+            name.into(),
+            kind,
+        )
+    }
+
+    pub fn context(&self) -> TraceContext {
+        self.data.as_ref().expect("span is live").context
+    }
+
+    pub fn set(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    /// Mark the span as failed
+    pub fn fail(&mut self, message: impl Into<String>) {
+        if let Some(data) = &mut self.data {
+            data.error = Some(message.into());
+        }
+    }
+
+    /// `properties` with this span's `traceparent` in place of any the publisher sent, if
+    /// the tracer propagates trace context or a rule asks for it (`force`)
+    pub fn propagate(
+        &self,
+        properties: Option<&mqtt_v5::Properties>,
+        force: bool,
+    ) -> Option<mqtt_v5::Properties> {
+        if !self.tracer.propagate && !force {
+            return None;
+        }
+        let mut properties = properties.cloned().unwrap_or_default();
+        properties.0.retain(|(id, value)| {
+            !matches!(
+                (*id, value),
+                (mqtt_v5::property::USER_PROPERTY, PropertyValue::Utf8StringPair(name, _))
+                    if name == TRACEPARENT_PROPERTY
+            )
+        });
+        properties.push(
+            mqtt_v5::property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair(
+                TRACEPARENT_PROPERTY.to_string(),
+                self.context().traceparent(),
+            ),
+        );
+        Some(properties)
+    }
+
+    /// A JSON object payload with this span's `traceparent` in each of `fields`; other
+    /// payloads are returned as they are
+    pub fn inject_fields(&self, payload: &Bytes, fields: &[String]) -> Bytes {
+        let Ok(Value::Object(mut object)) = serde_json::from_slice::<Value>(payload) else {
+            return payload.clone();
+        };
+        let traceparent = self.context().traceparent();
+        for field in fields {
+            object.insert(field.clone(), Value::String(traceparent.clone()));
+        }
+        Bytes::from(Value::Object(object).to_string())
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end = SystemTime::now();
+            self.tracer.export(data);
+        }
+    }
+}
+
+/// Export finished spans in batches until `shutdown` is cancelled
+pub async fn run_span_exporter(mut exporter: SpanExporter, shutdown: CancellationToken) {
+    info!("Exporting spans to {}", exporter.config.endpoint);
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            Some(span) = exporter.spans.recv() => {
+                batch.push(span);
+                if batch.len() < MAX_EXPORT_BATCH {
+                    continue;
+                }
+            }
+            _ = interval.tick() => {}
+        }
+        if !batch.is_empty() {
+            exporter.export(std::mem::take(&mut batch)).await;
+        }
+    }
+    // Spans of the messages forwarded before shutdown
+    while let Ok(span) = exporter.spans.try_recv() {
+        batch.push(span);
+    }
+    if !batch.is_empty() {
+        exporter.export(batch).await;
+    }
+}
+
+impl SpanExporter {
+    async fn export(&self, spans: Vec<SpanData>) {
+        let body = encode_spans(&self.config.service_name, &spans);
+        let headers = self
+            .config
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+        let sent = tokio::time::timeout(
+            EXPORT_TIMEOUT,
+            http_client::request(
+                "POST",
+                &self.url,
+                headers,
+                Some(("application/json", body.as_slice())),
+            ),
+        )
+        .await;
+        match sent {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Failed to export {} spans: {:#}", spans.len(), e),
+            Err(_) => warn!(
+                "Failed to export {} spans: no response within {:?}",
+                spans.len(),
+                EXPORT_TIMEOUT
+            ),
+        }
+    }
+}
+
+/// An OTLP/HTTP JSON `ExportTraceServiceRequest`
+fn encode_spans(service_name: &str, spans: &[SpanData]) -> Vec<u8> {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut encoded = json!({
+                "traceId": hex(&span.context.trace_id),
+                "spanId": hex(&span.context.span_id),
+                "name": span.name,
+                "kind": span.kind as u8,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect::<Vec<_>>(),
+                "status": match &span.error {
+                    Some(message) => json!({"code": 2, "message": message}),
+                    None => json!({"code": 0}),
+                },
+            });
+            if let Some(parent) = &span.parent_span_id {
+                encoded["parentSpanId"] = json!(hex(parent));
+            }
+            encoded
+        })
+        .collect();
+    let request = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", &AttributeValue::from(service_name))],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "mqtt-proxy", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    });
+    serde_json::to_vec(&request).unwrap_or_default()
+}
+
+fn attribute(key: &str, value: &AttributeValue) -> Value {
+    let value = match value {
+        AttributeValue::String(value) => json!({"stringValue": value}),
+        // OTLP JSON encodes 64-bit integers as strings
+        AttributeValue::Int(value) => json!({"intValue": value.to_string()}),
+        AttributeValue::Bool(value) => json!({"boolValue": value}),
+    };
+    json!({"key": key, "value": value})
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OpenTelemetryConfig {
+        serde_json::from_value(json!({})).unwrap()
+    }
+
+    #[test]
+    fn test_traceparent_round_trip() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(traceparent).unwrap();
+        assert!(context.sampled);
+        assert_eq!(
+            context.span_id,
+            [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]
+        );
+        assert_eq!(context.traceparent(), traceparent);
+
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert!(TraceContext::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spans_continue_and_propagate_the_publishers_trace() {
+        let (tracer, mut exporter) = Tracer::new(&config()).unwrap();
+        let mut properties = mqtt_v5::Properties::default();
+        properties.push(
+            mqtt_v5::property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair(
+                TRACEPARENT_PROPERTY.to_string(),
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string(),
+            ),
+        );
+
+        let receive = tracer.start_receive("a/b", Some(&properties)).unwrap();
+        let publish = receive.child("publish cloud", SpanKind::Producer);
+        let forwarded = publish.propagate(Some(&properties), false).unwrap();
+        let traceparents: Vec<_> = forwarded
+            .user_properties()
+            .filter(|(name, _)| *name == TRACEPARENT_PROPERTY)
+            .collect();
+        assert_eq!(
+            traceparents,
+            vec![(
+                TRACEPARENT_PROPERTY,
+                publish.context().traceparent().as_str()
+            )]
+        );
+        assert_eq!(
+            serde_json::from_slice::<Value>(&publish.inject_fields(
+                &Bytes::from_static(br#"{"power": 120}"#),
+                &["trace".to_string()]
+            ))
+            .unwrap(),
+            json!({"power": 120, "trace": publish.context().traceparent()})
+        );
+        assert_eq!(
+            publish.inject_fields(&Bytes::from_static(b"[1]"), &["trace".to_string()]),
+            Bytes::from_static(b"[1]")
+        );
+        drop(publish);
+        drop(receive);
+
+        let publish = exporter.spans.recv().await.unwrap();
+        let receive = exporter.spans.recv().await.unwrap();
+        assert_eq!(receive.context.trace_id, publish.context.trace_id);
+        assert_eq!(
+            hex(&receive.context.trace_id),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(
+            receive.parent_span_id,
+            Some([0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7])
+        );
+        assert_eq!(publish.parent_span_id, Some(receive.context.span_id));
+
+        let encoded: Value = serde_json::from_slice(&encode_spans("proxy", &[receive])).unwrap();
+        let span = &encoded["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "receive");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+
+        // An unsampled parent isn't traced
+        let mut unsampled = mqtt_v5::Properties::default();
+        unsampled.push(
+            mqtt_v5::property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair(
+                TRACEPARENT_PROPERTY.to_string(),
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00".to_string(),
+            ),
+        );
+        assert!(tracer.start_receive("a/b", Some(&unsampled)).is_none());
+    }
+
+    #[test]
+    fn test_inject_traceparent_into_payload_fields_and_properties() {
+        // `propagate` is off, as when only rules ask for the traceparent
+        let config = serde_json::from_value(json!({"propagate": false})).unwrap();
+        let (tracer, _exporter) = Tracer::new(&config).unwrap();
+        let span = tracer.start_receive("orders/42", None).unwrap();
+        let traceparent = span.context().traceparent();
+        let fields = ["traceparent".to_string(), "meta".to_string()];
+
+        let injected = span.inject_fields(
+            &Bytes::from_static(br#"{"id": 42, "meta": {"source": "erp"}}"#),
+            &fields,
+        );
+        assert_eq!(
+            serde_json::from_slice::<Value>(&injected).unwrap(),
+            json!({"id": 42, "traceparent": traceparent, "meta": traceparent})
+        );
+        for untouched in [
+            &b"[1, 2]"[..],
+            b"42",
+            br#""text""#,
+            b"21.5 C",
+            b"{\"truncated\": ",
+            b"\xff\xfe",
+            b"",
+        ] {
+            let payload = Bytes::copy_from_slice(untouched);
+            assert_eq!(span.inject_fields(&payload, &fields), payload);
+        }
+
+        // The user-property form replaces the publisher's traceparent and keeps the rest
+        let mut properties = mqtt_v5::Properties::default();
+        properties.push(
+            mqtt_v5::property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair("site".to_string(), "north".to_string()),
+        );
+        properties.push(
+            mqtt_v5::property::USER_PROPERTY,
+            PropertyValue::Utf8StringPair(
+                TRACEPARENT_PROPERTY.to_string(),
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string(),
+            ),
+        );
+        assert!(span.propagate(Some(&properties), false).is_none());
+        let forwarded = span.propagate(Some(&properties), true).unwrap();
+        assert_eq!(
+            forwarded.user_properties().collect::<Vec<_>>(),
+            vec![
+                ("site", "north"),
+                (TRACEPARENT_PROPERTY, traceparent.as_str())
+            ]
+        );
+        let added = span.propagate(None, true).unwrap();
+        assert_eq!(
+            added.user_properties().collect::<Vec<_>>(),
+            vec![(TRACEPARENT_PROPERTY, traceparent.as_str())]
+        );
+    }
+}
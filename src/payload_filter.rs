@@ -0,0 +1,257 @@
+//! Content-based filtering per broker
+//!
+//! Beyond topic filters, a broker's `payloadFilters` forward only messages whose JSON
+//! payload satisfies a condition such as `$.battery < 20`. A condition is a JSONPath-style
+//! path (`$.a.b`, `$.readings[0]`) compared with a number, a quoted string, `true`, `false`
+//! or `null`; a path on its own requires the field to exist and not be `false` or `null`.
+//! Every filter whose topic pattern matches must hold. Payloads that aren't JSON pass, so
+//! those messages are filtered by topic only.
+
+use crate::connection_manager::ConnectionManager;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PayloadFilter {
+    /// Topic pattern the condition applies to (`+`/`#` wildcards)
+    pub topic: String,
+    pub condition: Condition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+/// A parsed `<path> [<operator> <literal>]` condition; (de)serialized as its source text
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Condition {
+    source: String,
+    path: Vec<Segment>,
+    comparison: Option<(Operator, Value)>,
+}
+
+impl TryFrom<String> for Condition {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        Condition::parse(&source)
+    }
+}
+
+impl From<Condition> for String {
+    fn from(condition: Condition) -> Self {
+        condition.source
+    }
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self> {
+        let text = source.trim();
+        let rest = text
+            .strip_prefix('$')
+            .ok_or_else(|| anyhow!("condition '{}' must start with a '$' path", source))?;
+        // The path ends at the first whitespace or operator character
+        let end = rest
+            .find(|c: char| c.is_whitespace() || "=!<>".contains(c))
+            .unwrap_or(rest.len());
+        let path = parse_path(&rest[..end])
+            .ok_or_else(|| anyhow!("invalid path in condition '{}'", source))?;
+        let rest = rest[end..].trim_start();
+        let comparison = if rest.is_empty() {
+            None
+        } else {
+            let (operator, literal) = [
+                ("==", Operator::Eq),
+                ("!=", Operator::Ne),
+                ("<=", Operator::Le),
+                (">=", Operator::Ge),
+                ("<", Operator::Lt),
+                (">", Operator::Gt),
+            ]
+            .into_iter()
+            .find_map(|(token, operator)| rest.strip_prefix(token).map(|lit| (operator, lit)))
+            .ok_or_else(|| anyhow!("unknown operator in condition '{}'", source))?;
+            let literal = parse_literal(literal.trim())
+                .ok_or_else(|| anyhow!("invalid value in condition '{}'", source))?;
+            Some((operator, literal))
+        };
+        Ok(Self {
+            source: source.to_string(),
+            path,
+            comparison,
+        })
+    }
+
+    /// Whether the condition is a bare path, usable with `select`
+    pub fn is_path(&self) -> bool {
+        self.comparison.is_none()
+    }
+
+    /// The value the path points to in `document`, if it exists
+    pub fn select<'a>(&self, document: &'a Value) -> Option<&'a Value> {
+        self.path
+            .iter()
+            .try_fold(document, |value, segment| match segment {
+                Segment::Field(name) => value.get(name),
+                Segment::Index(index) => value.get(index),
+            })
+    }
+
+    pub fn matches(&self, document: &Value) -> bool {
+        let Some(value) = self.select(document) else {
+            return false;
+        };
+        let Some((operator, literal)) = &self.comparison else {
+            return !matches!(value, Value::Null | Value::Bool(false));
+        };
+        let ordering = match (value, literal) {
+            (Value::Number(a), Value::Number(b)) => a
+                .as_f64()
+                .zip(b.as_f64())
+                .and_then(|(a, b)| a.partial_cmp(&b)),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (a, b) if a == b => Some(Ordering::Equal),
+            _ => None,
+        };
+        match operator {
+            Operator::Eq => ordering == Some(Ordering::Equal),
+            Operator::Ne => ordering != Some(Ordering::Equal),
+            Operator::Lt => ordering == Some(Ordering::Less),
+            Operator::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Operator::Gt => ordering == Some(Ordering::Greater),
+            Operator::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+/// `.field`, `['field']` and `[index]` segments after the `$`
+fn parse_path(mut path: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    while !path.is_empty() {
+        if let Some(rest) = path.strip_prefix('.') {
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(Segment::Field(rest[..end].to_string()));
+            path = &rest[end..];
+        } else if let Some(rest) = path.strip_prefix('[') {
+            let end = rest.find(']')?;
+            let inner = &rest[..end];
+            let quoted = inner
+                .strip_prefix('\'')
+                .and_then(|s| s.strip_suffix('\''))
+                .or_else(|| inner.strip_prefix('"').and_then(|s| s.strip_suffix('"')));
+            segments.push(match quoted {
+                Some(name) => Segment::Field(name.to_string()),
+                None => Segment::Index(inner.parse().ok()?),
+            });
+            path = &rest[end + 1..];
+        } else {
+            return None;
+        }
+    }
+    Some(segments)
+}
+
+fn parse_literal(literal: &str) -> Option<Value> {
+    if let Some(text) = literal
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+    {
+        return Some(Value::String(text.to_string()));
+    }
+    match serde_json::from_str::<Value>(literal).ok()? {
+        value @ (Value::Number(_) | Value::String(_) | Value::Bool(_) | Value::Null) => Some(value),
+        _ => None,
+    }
+}
+
+/// Whether `payload` on `topic` passes every filter whose topic matches
+pub fn allows(filters: &[PayloadFilter], topic: &str, payload: &[u8]) -> bool {
+    let mut applicable = filters.iter().filter(|filter| {
+        !filter.topic.is_empty() && ConnectionManager::topic_matches_pattern(&filter.topic, topic)
+    });
+    let Some(first) = applicable.next() else {
+        return true;
+    };
+    let Ok(document) = serde_json::from_slice::<Value>(payload) else {
+        return true;
+    };
+    first.condition.matches(&document)
+        && applicable.all(|filter| filter.condition.matches(&document))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(topic: &str, condition: &str) -> PayloadFilter {
+        PayloadFilter {
+            topic: topic.to_string(),
+            condition: Condition::parse(condition).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_conditions() {
+        let document = serde_json::json!({
+            "battery": 15,
+            "state": "ON",
+            "readings": [{"t": 21.5}],
+            "offline": false
+        });
+        let holds = |condition: &str| Condition::parse(condition).unwrap().matches(&document);
+        assert!(holds("$.battery < 20"));
+        assert!(!holds("$.battery >= 20"));
+        assert!(holds("$.state == 'ON'"));
+        assert!(holds("$.state != \"OFF\""));
+        assert!(holds("$.readings[0].t > 21"));
+        assert!(holds("$['state']"));
+        assert!(!holds("$.offline"));
+        assert!(!holds("$.missing"));
+        // A value of another type never compares
+        assert!(!holds("$.state < 20"));
+
+        assert!(Condition::parse("battery < 20").is_err());
+        assert!(Condition::parse("$.battery ~ 20").is_err());
+        assert!(Condition::parse("$.battery < [1]").is_err());
+    }
+
+    #[test]
+    fn test_allows_by_topic_and_payload() {
+        let filters = vec![filter("sensors/#", "$.battery < 20")];
+        assert!(allows(&filters, "sensors/door", br#"{"battery": 10}"#));
+        assert!(!allows(&filters, "sensors/door", br#"{"battery": 80}"#));
+        // Not JSON: topic filtering only
+        assert!(allows(&filters, "sensors/door", b"low"));
+        // No filter for the topic
+        assert!(allows(&filters, "lights/hall", br#"{"battery": 80}"#));
+
+        let parsed: PayloadFilter =
+            serde_json::from_str(r#"{"topic": "a/#", "condition": "$.x == 1"}"#).unwrap();
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap()["condition"],
+            "$.x == 1"
+        );
+        assert!(
+            serde_json::from_str::<PayloadFilter>(r#"{"topic": "a", "condition": "x"}"#).is_err()
+        );
+    }
+}
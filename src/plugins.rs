@@ -0,0 +1,599 @@
+//! WASM plugins for message filters and client authentication
+//!
+//! Third parties ship compiled filters and authenticators as WebAssembly modules, listed
+//! under `[[plugins]]` and loaded at startup. Modules run in wasmtime without WASI, and
+//! every call gets a fresh instance limited to `max_memory_mb` of memory, `fuel`
+//! instructions and `timeout_ms` of wall time, so a plugin can neither reach the host
+//! nor hold up forwarding, and keeps no state between calls.
+//!
+//! ABI version 1 (e.g. `wasm32-unknown-unknown` modules):
+//!
+//! - import `env.log(level: i32, ptr: i32, len: i32)` (optional): log a UTF-8 message at
+//!   level 0 error, 1 warn, 2 info or otherwise debug; nothing else may be imported
+//! - export `memory`, `plugin_abi_version() -> i32` returning `1`, and
+//!   `alloc(len: i32) -> i32`, which the host calls to copy inputs into the module
+//! - export `filter(topic_ptr, topic_len, payload_ptr, payload_len: i32) -> i64`: `0`
+//!   forwards the message unchanged, `-1` drops it, anything else is `(ptr << 32) | len`
+//!   of a JSON `{"topic": ..., "payload": <base64>}` replacement; a missing field keeps
+//!   the original
+//! - export `authenticate(client_id_ptr, client_id_len, username_ptr, username_len,
+//!   password_ptr, password_len: i32) -> i32`: `0` accepts the client, `1` refuses it as
+//!   bad credentials, `2` as not authorized; a missing username or password has length `-1`
+//!
+//! A module exports `filter`, `authenticate` or both. Filters run on messages entering
+//! the proxy after the script hooks, in configuration order; a failing filter forwards
+//! the message unchanged, or drops it if `fail_open` is off. Authenticators run on CONNECT
+//! after the listener's own credential check and all of them must accept the client; a
+//! failing authenticator refuses it.
+
+use crate::config::PluginConfig;
+use crate::connection_manager::ConnectionManager;
+use crate::listener_auth::ConnectRefusal;
+use anyhow::{bail, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+use wasmtime::{
+    Caller, Config, Engine, Instance, InstancePre, Linker, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+/// Plugin ABI version this proxy implements
+const ABI_VERSION: i32 = 1;
+/// How often the engine's epoch advances, the resolution of `timeout_ms`
+const EPOCH_TICK_MS: u64 = 5;
+
+/// What a plugin instance can reach while it runs
+struct PluginState {
+    name: String,
+    limits: StoreLimits,
+}
+
+struct Plugin {
+    name: String,
+    instance: InstancePre<PluginState>,
+    topics: Vec<String>,
+    fuel: u64,
+    /// Epoch ticks one call may take
+    deadline: u64,
+    max_memory: usize,
+    fail_open: bool,
+    filters: bool,
+    authenticates: bool,
+}
+
+/// Plugins loaded from `[[plugins]]`
+pub struct Plugins {
+    plugins: Vec<Plugin>,
+    /// Stops the thread advancing the epoch
+    stopped: Arc<AtomicBool>,
+}
+
+impl Plugins {
+    /// Compile and check every plugin, so a missing file or ABI mismatch shows at startup
+    pub fn load(configs: &[PluginConfig]) -> Result<Option<Self>> {
+        if configs.is_empty() {
+            return Ok(None);
+        }
+        let modules = configs
+            .iter()
+            .map(|config| {
+                std::fs::read(&config.path)
+                    .with_context(|| format!("Failed to read plugin '{}'", config.path))
+                    .map(|bytes| (bytes, config))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let plugins = Self::compile(
+            modules
+                .iter()
+                .map(|(bytes, config)| (bytes.as_slice(), *config)),
+        )?;
+        for plugin in &plugins.plugins {
+            info!(
+                "Loaded plugin '{}' (filter: {}, authenticate: {})",
+                plugin.name, plugin.filters, plugin.authenticates
+            );
+        }
+        Ok(Some(plugins))
+    }
+
+    fn compile<'a>(
+        modules: impl IntoIterator<Item = (&'a [u8], &'a PluginConfig)>,
+    ) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true).epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+        let plugins = modules
+            .into_iter()
+            .map(|(bytes, config)| {
+                Plugin::new(&engine, bytes, config)
+                    .with_context(|| format!("Failed to load plugin '{}'", config.path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let ticker_stopped = Arc::clone(&stopped);
+        std::thread::Builder::new()
+            .name("plugin-epoch".to_string())
+            .spawn(move || {
+                while !ticker_stopped.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(EPOCH_TICK_MS));
+                    engine.increment_epoch();
+                }
+            })
+            .context("Failed to start the plugin timeout thread")?;
+        Ok(Self { plugins, stopped })
+    }
+
+    /// Whether any plugin decides on client CONNECTs
+    pub fn authenticates(&self) -> bool {
+        self.plugins.iter().any(|plugin| plugin.authenticates)
+    }
+
+    /// Pass a message through the filter plugins: the topic and payload to continue with,
+    /// or `None` if it is dropped
+    pub fn filter(&self, topic: &str, payload: Bytes) -> Option<(String, Bytes)> {
+        let mut message = (topic.to_string(), payload);
+        for plugin in &self.plugins {
+            if !plugin.filters || !plugin.applies_to(&message.0) {
+                continue;
+            }
+            match plugin.filter(&message.0, &message.1) {
+                Ok(Some(filtered)) => message = filtered,
+                Ok(None) => {
+                    debug!(
+                        "Plugin '{}' dropped a message on '{}'",
+                        plugin.name, message.0
+                    );
+                    return None;
+                }
+                Err(e) => {
+                    warn!(
+                        "Plugin '{}' failed on '{}': {:#}",
+                        plugin.name, message.0, e
+                    );
+                    if !plugin.fail_open {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(message)
+    }
+
+    /// Whether every authenticator plugin accepts the client
+    pub fn authenticate(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&[u8]>,
+    ) -> std::result::Result<(), ConnectRefusal> {
+        for plugin in self.plugins.iter().filter(|plugin| plugin.authenticates) {
+            match plugin.authenticate(client_id, username, password) {
+                Ok(Ok(())) => {}
+                Ok(Err(refusal)) => return Err(refusal),
+                Err(e) => {
+                    warn!(
+                        "Plugin '{}' failed to authenticate client '{}': {:#}",
+                        plugin.name, client_id, e
+                    );
+                    return Err(ConnectRefusal::NotAuthorized);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Plugins {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Plugin {
+    fn new(engine: &Engine, bytes: &[u8], config: &PluginConfig) -> Result<Self> {
+        let module = Module::new(engine, bytes)?;
+        let mut linker = Linker::new(engine);
+        linker.func_wrap("env", "log", host_log)?;
+        // Fails for modules importing anything but `env.log`, e.g. WASI
+        let instance = linker.instantiate_pre(&module)?;
+        let exports = |name: &str| module.exports().any(|export| export.name() == name);
+        for export in ["memory", "plugin_abi_version", "alloc"] {
+            ensure!(exports(export), "Plugin doesn't export '{}'", export);
+        }
+        let filters = exports("filter");
+        let authenticates = exports("authenticate");
+        ensure!(
+            filters || authenticates,
+            "Plugin exports neither 'filter' nor 'authenticate'"
+        );
+
+        let plugin = Self {
+            name: Path::new(&config.path)
+                .file_stem()
+                .map_or_else(|| config.path.clone(), |stem| stem.to_string_lossy().into()),
+            instance,
+            topics: config.topics.clone(),
+            fuel: config.fuel,
+            deadline: config.timeout_ms.div_ceil(EPOCH_TICK_MS).max(1),
+            max_memory: config.max_memory_mb.saturating_mul(1024 * 1024),
+            fail_open: config.fail_open,
+            filters,
+            authenticates,
+        };
+        let version = plugin.call(|store, instance| {
+            let version = instance.get_typed_func::<(), i32>(&mut *store, "plugin_abi_version")?;
+            version.call(&mut *store, ())
+        })?;
+        ensure!(
+            version == ABI_VERSION,
+            "Plugin implements ABI version {}, the proxy version {}",
+            version,
+            ABI_VERSION
+        );
+        Ok(plugin)
+    }
+
+    fn applies_to(&self, topic: &str) -> bool {
+        self.topics.is_empty()
+            || self
+                .topics
+                .iter()
+                .any(|pattern| ConnectionManager::topic_matches_pattern(pattern, topic))
+    }
+
+    /// Run `f` on a fresh instance within the plugin's limits
+    fn call<R>(
+        &self,
+        f: impl FnOnce(&mut Store<PluginState>, &Instance) -> Result<R>,
+    ) -> Result<R> {
+        let mut store = Store::new(
+            self.instance.module().engine(),
+            PluginState {
+                name: self.name.clone(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.max_memory)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.fuel)?;
+        store.set_epoch_deadline(self.deadline);
+        let instance = self.instance.instantiate(&mut store)?;
+        f(&mut store, &instance)
+    }
+
+    fn filter(&self, topic: &str, payload: &Bytes) -> Result<Option<(String, Bytes)>> {
+        self.call(|store, instance| {
+            let (topic_ptr, topic_len) = write(store, instance, topic.as_bytes())?;
+            let (payload_ptr, payload_len) = write(store, instance, payload)?;
+            let filter =
+                instance.get_typed_func::<(i32, i32, i32, i32), i64>(&mut *store, "filter")?;
+            match filter.call(
+                &mut *store,
+                (topic_ptr, topic_len, payload_ptr, payload_len),
+            )? {
+                0 => Ok(Some((topic.to_string(), payload.clone()))),
+                -1 => Ok(None),
+                packed => {
+                    let json = read(store, instance, packed)?;
+                    replacement(&json, topic, payload).map(Some)
+                }
+            }
+        })
+    }
+
+    fn authenticate(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&[u8]>,
+    ) -> Result<std::result::Result<(), ConnectRefusal>> {
+        self.call(|store, instance| {
+            let (client_id_ptr, client_id_len) = write(store, instance, client_id.as_bytes())?;
+            let (username_ptr, username_len) = match username {
+                Some(username) => write(store, instance, username.as_bytes())?,
+                None => (0, -1),
+            };
+            let (password_ptr, password_len) = match password {
+                Some(password) => write(store, instance, password)?,
+                None => (0, -1),
+            };
+            let authenticate = instance.get_typed_func::<(i32, i32, i32, i32, i32, i32), i32>(
+                &mut *store,
+                "authenticate",
+            )?;
+            match authenticate.call(
+                &mut *store,
+                (
+                    client_id_ptr,
+                    client_id_len,
+                    username_ptr,
+                    username_len,
+                    password_ptr,
+                    password_len,
+                ),
+            )? {
+                0 => Ok(Ok(())),
+                1 => Ok(Err(ConnectRefusal::BadCredentials)),
+                2 => Ok(Err(ConnectRefusal::NotAuthorized)),
+                other => bail!("Plugin returned unknown result {}", other),
+            }
+        })
+    }
+}
+
+/// `env.log`, the only host function plugins can import
+fn host_log(mut caller: Caller<'_, PluginState>, level: i32, ptr: i32, len: i32) {
+    let Some(memory) = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+    else {
+        return;
+    };
+    let data = memory.data(&caller);
+    let Some(text) = usize::try_from(ptr)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(start, len)| data.get(start..start.checked_add(len)?))
+    else {
+        return;
+    };
+    let text = String::from_utf8_lossy(text);
+    let name = &caller.data().name;
+    match level {
+        0 => error!("Plugin '{}': {}", name, text),
+        1 => warn!("Plugin '{}': {}", name, text),
+        2 => info!("Plugin '{}': {}", name, text),
+        _ => debug!("Plugin '{}': {}", name, text),
+    }
+}
+
+/// Copy `bytes` into the instance through its `alloc`
+fn write(store: &mut Store<PluginState>, instance: &Instance, bytes: &[u8]) -> Result<(i32, i32)> {
+    let len = i32::try_from(bytes.len()).context("Input too large for the plugin")?;
+    let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "alloc")?;
+    let ptr = alloc.call(&mut *store, len)?;
+    memory(store, instance)?
+        .write(&mut *store, ptr as u32 as usize, bytes)
+        .context("Plugin allocated memory outside its memory")?;
+    Ok((ptr, len))
+}
+
+/// The bytes at a `(ptr << 32) | len` the plugin returned
+fn read(store: &mut Store<PluginState>, instance: &Instance, packed: i64) -> Result<Vec<u8>> {
+    let start = (packed >> 32) as u32 as usize;
+    let len = packed as u32 as usize;
+    let memory = memory(store, instance)?;
+    memory
+        .data(&*store)
+        .get(start..start + len)
+        .map(<[u8]>::to_vec)
+        .context("Plugin returned a result outside its memory")
+}
+
+fn memory(store: &mut Store<PluginState>, instance: &Instance) -> Result<wasmtime::Memory> {
+    instance
+        .get_memory(&mut *store, "memory")
+        .context("Plugin doesn't export 'memory'")
+}
+
+/// Message a filter replaced the original with
+#[derive(Deserialize)]
+struct Replacement {
+    topic: Option<String>,
+    /// Base64
+    payload: Option<String>,
+}
+
+fn replacement(json: &[u8], topic: &str, payload: &Bytes) -> Result<(String, Bytes)> {
+    let replacement: Replacement =
+        serde_json::from_slice(json).context("Plugin returned an invalid replacement")?;
+    let topic = replacement.topic.unwrap_or_else(|| topic.to_string());
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        bail!("Plugin returned an invalid topic '{}'", topic);
+    }
+    let payload = match replacement.payload {
+        Some(encoded) => Bytes::from(
+            BASE64
+                .decode(encoded)
+                .context("Plugin returned a payload that isn't base64")?,
+        ),
+        None => payload.clone(),
+    };
+    Ok((topic, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// A module implementing the ABI around `functions`; `alloc` hands out memory from 1024
+    fn module(imports: &str, version: i32, functions: &str) -> String {
+        format!(
+            r#"(module
+                {}
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                (func (export "plugin_abi_version") (result i32) (i32.const {}))
+                (func (export "alloc") (param $len i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $next))
+                    (global.set $next (i32.add (global.get $next) (local.get $len)))
+                    (local.get $ptr))
+                {})"#,
+            imports, version, functions
+        )
+    }
+
+    fn config() -> PluginConfig {
+        PluginConfig {
+            path: "test.wasm".to_string(),
+            topics: vec![],
+            timeout_ms: 1_000,
+            fuel: 1_000_000,
+            max_memory_mb: 1,
+            fail_open: true,
+        }
+    }
+
+    fn plugins(wat: &str, config: &PluginConfig) -> Result<Plugins> {
+        Plugins::compile([(wat.as_bytes(), config)])
+    }
+
+    #[test]
+    fn test_filter_plugins_replace_and_drop_messages() {
+        // Drops empty payloads and moves everything else to "renamed"
+        let wat = module(
+            "",
+            1,
+            r#"(data (i32.const 16) "{\"topic\":\"renamed\"}")
+            (func (export "filter") (param i32 i32 i32 i32) (result i64)
+                (if (i32.eqz (local.get 3)) (then (return (i64.const -1))))
+                (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 19)))"#,
+        );
+        let plugins = plugins(
+            &wat,
+            &PluginConfig {
+                topics: vec!["sensors/#".to_string()],
+                ..config()
+            },
+        )
+        .unwrap();
+        assert!(!plugins.authenticates());
+
+        let payload = Bytes::from_static(b"21.5");
+        assert_eq!(
+            plugins.filter("sensors/1", payload.clone()),
+            Some(("renamed".to_string(), payload.clone()))
+        );
+        assert_eq!(plugins.filter("sensors/1", Bytes::new()), None);
+        // Topics the plugin isn't configured for pass unchanged
+        assert_eq!(
+            plugins.filter("other", Bytes::new()),
+            Some(("other".to_string(), Bytes::new()))
+        );
+    }
+
+    #[test]
+    fn test_plugins_are_stopped_at_their_limits() {
+        let runaway = module(
+            "",
+            1,
+            r#"(func (export "filter") (param i32 i32 i32 i32) (result i64)
+                (loop $forever (br $forever))
+                (i64.const 0))"#,
+        );
+        let payload = Bytes::from_static(b"1");
+        let unchanged = Some(("a".to_string(), payload.clone()));
+
+        let out_of_fuel = plugins(&runaway, &config()).unwrap();
+        assert_eq!(out_of_fuel.filter("a", payload.clone()), unchanged);
+        let closed = plugins(
+            &runaway,
+            &PluginConfig {
+                fail_open: false,
+                ..config()
+            },
+        )
+        .unwrap();
+        assert_eq!(closed.filter("a", payload.clone()), None);
+
+        let timed_out = plugins(
+            &runaway,
+            &PluginConfig {
+                fuel: 1 << 50,
+                timeout_ms: 20,
+                ..config()
+            },
+        )
+        .unwrap();
+        let started = Instant::now();
+        assert_eq!(timed_out.filter("a", payload.clone()), unchanged);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Growing the memory by 100 pages (6.4 MB) fails within 1 MB; the plugin drops
+        // the message when it can't
+        let greedy = module(
+            "",
+            1,
+            r#"(func (export "filter") (param i32 i32 i32 i32) (result i64)
+                (if (i32.eq (memory.grow (i32.const 100)) (i32.const -1))
+                    (then (return (i64.const -1))))
+                (i64.const 0))"#,
+        );
+        let limited = plugins(&greedy, &config()).unwrap();
+        assert_eq!(limited.filter("a", payload.clone()), None);
+        let roomy = plugins(
+            &greedy,
+            &PluginConfig {
+                max_memory_mb: 16,
+                ..config()
+            },
+        )
+        .unwrap();
+        assert_eq!(roomy.filter("a", payload), unchanged);
+    }
+
+    #[test]
+    fn test_authenticator_plugins() {
+        // Accepts passwords starting with "s"
+        let wat = module(
+            r#"(import "env" "log" (func $log (param i32 i32 i32)))"#,
+            1,
+            r#"(data (i32.const 16) "checking")
+            (func (export "authenticate")
+                (param $client_ptr i32) (param $client_len i32)
+                (param $user_ptr i32) (param $user_len i32)
+                (param $pass_ptr i32) (param $pass_len i32)
+                (result i32)
+                (call $log (i32.const 2) (i32.const 16) (i32.const 8))
+                (if (i32.lt_s (local.get $user_len) (i32.const 0)) (then (return (i32.const 2))))
+                (if (i32.lt_s (local.get $pass_len) (i32.const 1)) (then (return (i32.const 1))))
+                (if (i32.eq (i32.load8_u (local.get $pass_ptr)) (i32.const 115))
+                    (then (return (i32.const 0))))
+                (i32.const 1))"#,
+        );
+        let plugins = plugins(&wat, &config()).unwrap();
+        assert!(plugins.authenticates());
+
+        assert_eq!(
+            plugins.authenticate("dev", Some("admin"), Some(b"secret")),
+            Ok(())
+        );
+        assert_eq!(
+            plugins.authenticate("dev", Some("admin"), Some(b"wrong")),
+            Err(ConnectRefusal::BadCredentials)
+        );
+        assert_eq!(
+            plugins.authenticate("dev", None, None),
+            Err(ConnectRefusal::NotAuthorized)
+        );
+        // Authenticators don't filter messages
+        assert!(plugins.filter("a", Bytes::new()).is_some());
+    }
+
+    #[test]
+    fn test_plugins_outside_the_abi_are_rejected() {
+        let filter = r#"(func (export "filter") (param i32 i32 i32 i32) (result i64)
+            (i64.const 0))"#;
+        assert!(plugins(&module("", 1, filter), &config()).is_ok());
+        // WASI or other host access isn't available
+        let wasi = module(
+            r#"(import "wasi_snapshot_preview1" "fd_write"
+                (func (param i32 i32 i32 i32) (result i32)))"#,
+            1,
+            filter,
+        );
+        assert!(plugins(&wasi, &config()).is_err());
+        assert!(plugins(&module("", 2, filter), &config()).is_err());
+        assert!(plugins(&module("", 1, ""), &config()).is_err());
+    }
+}
@@ -1,38 +1,88 @@
-use crate::broker_storage::BrokerStorage;
-use crate::config::{Config, MainBrokerConfig};
-use crate::connection_manager::ConnectionManager;
+use crate::aggregation::run_aggregate_flush;
+use crate::availability::Availability;
+use crate::broker_storage::{build_broker_storage, BrokerStorage};
+use crate::coap::run_coap_bridge;
+use crate::codec::CodecRegistry;
+use crate::config::{Config, MainBrokerConfig, UnroutedAction};
+use crate::connection_manager::{run_broker_watchdog, run_health_checks, ConnectionManager};
+use crate::delivery_groups::{run_delivery_group_retries, DeliveryGroups};
+use crate::delta::DeltaFilter;
+use crate::ingest::Ingestor;
+use crate::load_shedding::LoadShedder;
+use crate::loop_prevention::build_origin_tagger;
 use crate::main_broker_client::MainBrokerClient;
-use crate::settings_storage::SettingsStorage;
+use crate::message_history::MessageHistory;
+use crate::metrics::TopicPrefixCounter;
+use crate::otel::{run_span_exporter, SpanExporter, Tracer};
+use crate::plugins::Plugins;
+use crate::remote_config::run_config_refresh;
+use crate::replay_protection::ReplayGuard;
+use crate::reports::{run_usage_reports, UsageTracker};
+use crate::resource_profile::{self, ResourceProfile};
+use crate::script_hooks::ScriptHooks;
+use crate::settings_storage::{build_settings_storage, SettingsStorage};
+use crate::sources::{build_sources, run_source};
+use crate::subscription_table::MAIN_BROKER_DEMAND;
+use crate::suppression::DuplicateSuppressor;
+use crate::tcp_health::run_tcp_health;
+use crate::timestamp_check::TimestampChecker;
+use crate::unrouted::{
+    run_dead_letter_publisher, DeadLetter, UnroutedHandler, DEAD_LETTER_QUEUE_SIZE,
+};
 use crate::web_server::WebServer;
-use anyhow::Result;
-use std::sync::atomic::AtomicU64;
+use anyhow::{Context, Result};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, watch, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 
 pub struct MqttProxy {
     config: Config,
     connection_manager: Arc<RwLock<ConnectionManager>>,
-    #[allow(dead_code)] // Storage is managed by WebServer, kept for potential direct access
-    broker_storage: Arc<BrokerStorage>,
-    settings_storage: Arc<SettingsStorage>,
+    /// Managed by the WebServer; flushed here on shutdown
+    broker_storage: Arc<dyn BrokerStorage>,
+    settings_storage: Arc<dyn SettingsStorage>,
     web_server: Option<WebServer>,
+    /// Also handed to the web server and the remote configuration refresh
+    main_broker_restart_tx: mpsc::Sender<()>,
     main_broker_restart_rx: mpsc::Receiver<()>,
     message_tx: Option<tokio::sync::broadcast::Sender<crate::web_server::MqttMessage>>,
-    messages_received: Option<Arc<AtomicU64>>,
-    messages_forwarded: Option<Arc<AtomicU64>>,
-    total_latency_ns: Option<Arc<AtomicU64>>,
+    /// Long-running tasks that `run` waits for before returning
+    tasks: JoinSet<()>,
+    /// Cancelled on Ctrl-C or through `shutdown_token`
+    shutdown: CancellationToken,
+    usage_tracker: Arc<UsageTracker>,
+    /// Unrouted messages to republish on the main broker (`[unrouted] action = "dead_letter"`)
+    dead_letters: Option<mpsc::Receiver<DeadLetter>>,
+    /// Seen commands of `[replay_protection]`; flushed here on shutdown
+    replay_guard: Option<Arc<ReplayGuard>>,
+    /// Spans to export to the `[opentelemetry]` endpoint
+    span_exporter: Option<SpanExporter>,
 }
 
 impl MqttProxy {
     pub async fn new(config: Config) -> Result<Self> {
         info!("Initializing MQTT Proxy Forwarder");
+        resource_profile::activate(config.profile);
+        if config.profile != ResourceProfile::Standard {
+            info!("Using the {:?} resource profile", config.profile);
+        }
+
+        let flush_interval = Duration::from_millis(config.storage.flush_interval_ms);
 
-        // Initialize broker storage
-        let broker_storage = Arc::new(BrokerStorage::new(&config.storage.broker_store_path)?);
+        // Initialize broker and settings storage (local files, or fetched from
+        // `[storage.remote]`)
+        let broker_storage = build_broker_storage(&config.storage).await?;
+        let settings_storage = build_settings_storage(&config.storage).await?;
 
-        // Initialize settings storage
-        let settings_storage = Arc::new(SettingsStorage::new(&config.storage.settings_store_path)?);
+        let replay_guard = config
+            .replay_protection
+            .clone()
+            .map(|replay_config| ReplayGuard::load(replay_config, flush_interval))
+            .transpose()?
+            .map(Arc::new);
 
         // Initialize with default test brokers if empty
         broker_storage.init_defaults().await?;
@@ -43,10 +93,15 @@ impl MqttProxy {
             "Loaded {} downstream broker configurations",
             broker_configs.len()
         );
+        let enabled_brokers: Vec<String> = broker_configs
+            .iter()
+            .filter(|broker| broker.enabled)
+            .map(|broker| broker.id.clone())
+            .collect();
 
         // Resolve main broker config: settings.json > config.toml/env > defaults
         let main_broker_config =
-            Self::resolve_main_broker_config(&settings_storage, &config.main_broker).await;
+            Self::resolve_main_broker_config(settings_storage.as_ref(), &config.main_broker).await;
 
         // Initialize connection manager (connects to downstream brokers)
         let connection_manager = Arc::new(RwLock::new(
@@ -55,34 +110,141 @@ impl MqttProxy {
                 Arc::new(crate::client_registry::ClientRegistry::new()),
                 main_broker_config.address.clone(),
                 main_broker_config.port,
+                main_broker_config.protocol_version,
+                &config.dedup,
+                config.subscriptions.qos,
+                build_origin_tagger(&config.loop_prevention, &main_broker_config.client_id),
+                replay_guard.clone(),
             )
             .await?,
         ));
+        connection_manager
+            .write()
+            .await
+            .set_routing_table(settings_storage.get_routing().await);
+        connection_manager
+            .write()
+            .await
+            .set_rules(settings_storage.get_rules().await);
+        connection_manager
+            .read()
+            .await
+            .client_registry()
+            .subscription_table()
+            .set(
+                MAIN_BROKER_DEMAND,
+                &settings_storage.get_main_demand().await,
+            );
+        let usage_tracker = Arc::new(UsageTracker::new(&config.reports));
+        connection_manager
+            .write()
+            .await
+            .set_usage_tracker(Arc::clone(&usage_tracker));
+        connection_manager.write().await.set_topic_prefix_counter(
+            config
+                .topic_metrics
+                .as_ref()
+                .map(|topic_metrics| Arc::new(TopicPrefixCounter::new(topic_metrics))),
+        );
+        let (tracer, span_exporter) = match &config.opentelemetry {
+            Some(opentelemetry) => {
+                let (tracer, exporter) = Tracer::new(opentelemetry)?;
+                (Some(tracer), Some(exporter))
+            }
+            None => (None, None),
+        };
+        connection_manager.write().await.set_tracer(tracer);
+        connection_manager
+            .write()
+            .await
+            .set_duplicate_suppressor(Arc::new(DuplicateSuppressor::new(
+                config.duplicate_suppression.clone(),
+            )));
+        connection_manager
+            .write()
+            .await
+            .set_load_shedder(Arc::new(LoadShedder::new(
+                config.web_ui.load_shedding.clone(),
+            )));
+        connection_manager
+            .write()
+            .await
+            .set_availability(Arc::new(Availability::new(config.availability.clone())));
+        let script_hooks = match &config.scripting {
+            Some(scripting) => Some(Arc::new(ScriptHooks::start(scripting.clone())?)),
+            None => None,
+        };
+        connection_manager
+            .write()
+            .await
+            .set_script_hooks(script_hooks);
+        let plugins = Plugins::load(&config.plugins)?.map(Arc::new);
+        connection_manager.write().await.set_plugins(plugins);
+        connection_manager
+            .write()
+            .await
+            .set_delta_filter(Arc::new(DeltaFilter::new(config.delta_forwarding.clone())));
+        connection_manager
+            .write()
+            .await
+            .set_timestamp_checker(Arc::new(TimestampChecker::new(
+                config.timestamp_checks.clone(),
+            )));
+        connection_manager
+            .write()
+            .await
+            .set_compression_config(config.compression.clone());
+        connection_manager
+            .write()
+            .await
+            .set_priority_mode(config.priority_mode);
+        let delivery_groups = DeliveryGroups::new(config.delivery_groups.clone())?;
+        delivery_groups
+            .validate(&enabled_brokers)
+            .context("Invalid [delivery_groups] configuration")?;
+        connection_manager
+            .write()
+            .await
+            .set_delivery_groups(Arc::new(delivery_groups));
+        let (dead_letter_tx, dead_letters) = if config.unrouted.action == UnroutedAction::DeadLetter
+        {
+            let (tx, rx) = mpsc::channel(DEAD_LETTER_QUEUE_SIZE);
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+        connection_manager
+            .write()
+            .await
+            .set_unrouted_handler(Arc::new(UnroutedHandler::new(
+                config.unrouted.clone(),
+                dead_letter_tx,
+            )));
 
         // Create restart channel for main broker client
         let (restart_tx, restart_rx) = mpsc::channel(1);
 
         // Initialize web server if enabled
-        let (web_server, message_tx, messages_received, messages_forwarded, total_latency_ns) =
-            if config.web_ui.enabled {
-                let (web_server, msg_tx, recv_counter, fwd_counter, latency_counter) =
-                    WebServer::new(
-                        config.web_ui.port,
-                        Arc::clone(&connection_manager),
-                        Arc::clone(&broker_storage),
-                        Arc::clone(&settings_storage),
-                        restart_tx,
-                    );
-                (
-                    Some(web_server),
-                    Some(msg_tx),
-                    Some(recv_counter),
-                    Some(fwd_counter),
-                    Some(latency_counter),
-                )
-            } else {
-                (None, None, None, None, None)
-            };
+        let message_history = Arc::new(match &config.web_ui.history_disk {
+            _ if !resource_profile::limits().message_history => MessageHistory::new(0),
+            Some(disk_config) => {
+                MessageHistory::with_disk(config.web_ui.message_history_size, disk_config.clone())?
+            }
+            None => MessageHistory::new(config.web_ui.message_history_size),
+        });
+        let (web_server, message_tx) = if config.web_ui.enabled {
+            let (web_server, msg_tx) = WebServer::new(
+                config.web_ui.clone(),
+                Arc::clone(&connection_manager),
+                Arc::clone(&broker_storage),
+                Arc::clone(&settings_storage),
+                restart_tx.clone(),
+                message_history,
+            );
+            (Some(web_server), Some(msg_tx))
+        } else {
+            (None, None)
+        };
 
         Ok(Self {
             config,
@@ -90,17 +252,31 @@ impl MqttProxy {
             broker_storage,
             settings_storage,
             web_server,
+            main_broker_restart_tx: restart_tx,
             main_broker_restart_rx: restart_rx,
             message_tx,
-            messages_received,
-            messages_forwarded,
-            total_latency_ns,
+            tasks: JoinSet::new(),
+            shutdown: CancellationToken::new(),
+            usage_tracker,
+            dead_letters,
+            replay_guard,
+            span_exporter,
         })
     }
 
+    /// Token that stops the proxy when cancelled; `run` returns once every task has ended
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Codec registry of the forwarding rules, for registering custom codecs
+    pub async fn codecs(&self) -> Arc<CodecRegistry> {
+        self.connection_manager.read().await.codecs()
+    }
+
     /// Resolve main broker config with priority: settings.json > config.toml/env > defaults
     async fn resolve_main_broker_config(
-        settings_storage: &SettingsStorage,
+        settings_storage: &dyn SettingsStorage,
         fallback: &MainBrokerConfig,
     ) -> MainBrokerConfig {
         if let Some(saved) = settings_storage.get_main_broker().await {
@@ -114,6 +290,10 @@ impl MqttProxy {
                 client_id: saved.client_id,
                 username: saved.username,
                 password: saved.password,
+                protocol_version: saved.protocol_version,
+                // Not part of the stored settings
+                dedup: fallback.dedup.clone(),
+                subscription_qos: fallback.subscription_qos,
             }
         } else {
             info!(
@@ -124,13 +304,28 @@ impl MqttProxy {
         }
     }
 
+    /// Signal the main broker client to stop and wait until it has disconnected
+    async fn stop_main_client(
+        shutdown_tx: watch::Sender<bool>,
+        client_task: JoinHandle<Result<()>>,
+    ) {
+        let _ = shutdown_tx.send(true);
+        match client_task.await {
+            Ok(Ok(())) => info!("Main broker client stopped"),
+            Ok(Err(e)) => error!("Main broker client stopped with error: {}", e),
+            Err(e) => error!("Main broker client task failed: {}", e),
+        }
+    }
+
     pub async fn run(mut self) -> Result<()> {
         info!("Starting MQTT Proxy Forwarder");
 
         // Resolve initial main broker config
-        let initial_config =
-            Self::resolve_main_broker_config(&self.settings_storage, &self.config.main_broker)
-                .await;
+        let initial_config = Self::resolve_main_broker_config(
+            self.settings_storage.as_ref(),
+            &self.config.main_broker,
+        )
+        .await;
         info!(
             "Main broker: {}:{}",
             initial_config.address, initial_config.port
@@ -139,13 +334,96 @@ impl MqttProxy {
         // Start web server
         if let Some(web_server) = self.web_server {
             info!("Starting Web UI on port {}", self.config.web_ui.port);
-            tokio::spawn(async move {
-                if let Err(e) = web_server.run().await {
+            let shutdown = self.shutdown.clone();
+            self.tasks.spawn(async move {
+                if let Err(e) = web_server.run(shutdown).await {
                     error!("Web server error: {}", e);
                 }
             });
         }
 
+        self.tasks.spawn(run_usage_reports(
+            Arc::clone(&self.usage_tracker),
+            self.config.reports.clone(),
+            initial_config.clone(),
+            self.shutdown.clone(),
+        ));
+        if let Some(tcp_health) = &self.config.tcp_health {
+            let connection_manager = Arc::clone(&self.connection_manager);
+            let port = tcp_health.port;
+            let shutdown = self.shutdown.clone();
+            self.tasks.spawn(async move {
+                if let Err(e) = run_tcp_health(port, connection_manager, shutdown).await {
+                    error!("TCP health port error: {:#}", e);
+                }
+            });
+        }
+        self.tasks.spawn(run_broker_watchdog(
+            Arc::clone(&self.connection_manager),
+            self.config.broker_watchdog.clone(),
+            self.shutdown.clone(),
+        ));
+        self.tasks.spawn(run_health_checks(
+            Arc::clone(&self.connection_manager),
+            self.config.health_checks.clone(),
+            self.shutdown.clone(),
+        ));
+        self.tasks.spawn(run_aggregate_flush(
+            Arc::clone(&self.connection_manager),
+            self.shutdown.clone(),
+        ));
+        self.tasks.spawn(run_delivery_group_retries(
+            Arc::clone(&self.connection_manager),
+            self.config.delivery_groups.clone(),
+            self.shutdown.clone(),
+        ));
+        if let Some(coap) = &self.config.coap {
+            let ingestor = Arc::new(Ingestor::new(
+                Arc::clone(&self.connection_manager),
+                self.message_tx.clone(),
+                coap.client_id.clone(),
+                self.config.web_ui.debug_deliveries,
+            ));
+            let coap = coap.clone();
+            let shutdown = self.shutdown.clone();
+            self.tasks.spawn(async move {
+                if let Err(e) = run_coap_bridge(coap, ingestor, shutdown).await {
+                    error!("CoAP bridge error: {:#}", e);
+                }
+            });
+        }
+        for source in build_sources(&self.config.sources)? {
+            let ingestor = Arc::new(Ingestor::new(
+                Arc::clone(&self.connection_manager),
+                self.message_tx.clone(),
+                source.name().to_string(),
+                self.config.web_ui.debug_deliveries,
+            ));
+            self.tasks
+                .spawn(run_source(source, ingestor, self.shutdown.clone()));
+        }
+        if let Some(remote) = &self.config.storage.remote {
+            self.tasks.spawn(run_config_refresh(
+                remote.clone(),
+                Arc::clone(&self.broker_storage),
+                Arc::clone(&self.settings_storage),
+                Arc::clone(&self.connection_manager),
+                self.main_broker_restart_tx.clone(),
+                self.shutdown.clone(),
+            ));
+        }
+        if let Some(span_exporter) = self.span_exporter.take() {
+            self.tasks
+                .spawn(run_span_exporter(span_exporter, self.shutdown.clone()));
+        }
+        if let Some(dead_letters) = self.dead_letters.take() {
+            self.tasks.spawn(run_dead_letter_publisher(
+                dead_letters,
+                initial_config.clone(),
+                self.shutdown.clone(),
+            ));
+        }
+
         // Main broker client restart loop
         let mut current_config = initial_config;
 
@@ -157,28 +435,29 @@ impl MqttProxy {
                 current_config.clone(),
                 Arc::clone(&self.connection_manager),
                 self.message_tx.clone(),
-                self.messages_received.clone(),
-                self.messages_forwarded.clone(),
-                self.total_latency_ns.clone(),
+                self.config.web_ui.debug_deliveries,
             )
             .await?;
 
             info!("Connecting to main broker and subscribing to topics...");
 
+            // Run in its own task so a restart can wait for the old client to disconnect
+            // before the next one subscribes (two clients would forward every message twice)
+            let mut client_task = tokio::spawn(main_client.run(shutdown_rx));
+
             tokio::select! {
-                result = main_client.run(shutdown_rx) => {
+                result = &mut client_task => {
                     error!("Main broker client stopped: {:?}", result);
-                    result?;
+                    result??;
                     break;
                 }
                 _ = self.main_broker_restart_rx.recv() => {
                     info!("Main broker restart requested, reconnecting with new settings...");
-                    // Signal shutdown to the current client
-                    let _ = shutdown_tx.send(true);
+                    Self::stop_main_client(shutdown_tx, client_task).await;
 
                     // Resolve new config from settings storage
                     current_config = Self::resolve_main_broker_config(
-                        &self.settings_storage,
+                        self.settings_storage.as_ref(),
                         &self.config.main_broker,
                     )
                     .await;
@@ -187,17 +466,44 @@ impl MqttProxy {
                         current_config.address, current_config.port
                     );
 
-                    // Small delay to let the old client shut down cleanly
-                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
                     continue;
                 }
                 _ = tokio::signal::ctrl_c() => {
                     info!("Shutting down MQTT Proxy");
+                    Self::stop_main_client(shutdown_tx, client_task).await;
+                    break;
+                }
+                _ = self.shutdown.cancelled() => {
+                    info!("Shutting down MQTT Proxy");
+                    Self::stop_main_client(shutdown_tx, client_task).await;
                     break;
                 }
             }
         }
 
+        self.shutdown.cancel();
+        self.connection_manager.write().await.shutdown().await;
+        while self.tasks.join_next().await.is_some() {}
+        // Changes may still be waiting for the storage flush interval
+        for flushed in [self.broker_storage.flush(), self.settings_storage.flush()] {
+            if let Err(e) = flushed {
+                error!("Failed to flush storage on shutdown: {:#}", e);
+            }
+        }
+        let delivery_groups = self.connection_manager.read().await.delivery_groups();
+        if let Err(e) = delivery_groups.flush_state() {
+            error!("Failed to save delivery group state on shutdown: {:#}", e);
+        }
+        if let Some(guard) = &self.replay_guard {
+            if let Err(e) = guard.flush() {
+                error!(
+                    "Failed to flush replay protection state on shutdown: {:#}",
+                    e
+                );
+            }
+        }
+        info!("MQTT Proxy stopped");
+
         Ok(())
     }
 }
@@ -0,0 +1,122 @@
+//! HAProxy PROXY protocol (v1 and v2) for the MQTT listener
+//!
+//! Behind a TCP load balancer every connection appears to come from the balancer. With
+//! the PROXY protocol enabled on both sides, the balancer sends a header with the original
+//! client address before any MQTT bytes; the listener reads it first and uses that address
+//! for logging and the clients API. Once enabled, the header is required.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header line, including CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY header from the start of a connection without consuming anything after
+/// it. Returns the original client address, or `None` for health checks from the balancer
+/// itself (v2 LOCAL, v1 UNKNOWN) and address families other than TCP over IPv4/IPv6.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // Both versions are at least 12 bytes long: the v2 signature, or "PROXY " plus more
+    let mut start = [0u8; 12];
+    stream
+        .read_exact(&mut start)
+        .await
+        .context("Connection closed before the PROXY header")?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        bail!("Missing PROXY protocol header")
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len_hi, len_lo] = header;
+    ensure!(
+        version_command >> 4 == 2,
+        "Unsupported PROXY protocol version"
+    );
+    let mut body = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    stream.read_exact(&mut body).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: the balancer's own connection; keep the socket address
+        0 => return Ok(None),
+        1 => {}
+        other => bail!("Unsupported PROXY command {}", other),
+    }
+    Ok(match family {
+        // TCP over IPv4: source address, destination address, source port, destination port
+        0x11 => {
+            ensure!(body.len() >= 12, "Truncated PROXY v2 IPv4 addresses");
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        0x21 => {
+            ensure!(body.len() >= 36, "Truncated PROXY v2 IPv6 addresses");
+            let octets: [u8; 16] = body[..16].try_into().expect("slice is 16 bytes");
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        _ => None,
+    })
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        ensure!(line.len() < V1_MAX_LEN, "PROXY v1 header too long");
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).context("Invalid PROXY v1 header")?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse().context("Invalid PROXY v1 source address")?;
+            let port: u16 = source_port
+                .parse()
+                .context("Invalid PROXY v1 source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("Invalid PROXY v1 header '{}'", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reads_v1_and_v2_headers_only() {
+        let mut v1: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 1883\r\n\x10rest";
+        assert_eq!(
+            read_header(&mut v1).await.unwrap(),
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+        assert_eq!(v1, b"\x10rest");
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        v2.extend_from_slice(&[198, 51, 100, 9, 10, 0, 0, 1, 0xc8, 0x1f, 0x07, 0x5b]);
+        v2.push(0x10);
+        let mut v2: &[u8] = &v2;
+        assert_eq!(
+            read_header(&mut v2).await.unwrap(),
+            Some("198.51.100.9:51231".parse().unwrap())
+        );
+        assert_eq!(v2, [0x10]);
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+
+        let mut plain: &[u8] = &[0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T', b'T', 4, 2, 0, 60];
+        assert!(read_header(&mut plain).await.is_err());
+    }
+}
@@ -0,0 +1,431 @@
+//! Brokers and settings managed by a central service
+//!
+//! With `[storage.remote]`, fleets of edge proxies are configured centrally: each proxy
+//! fetches its brokers and/or settings with GET from the configured URLs, in the format of
+//! the local store files (`{"brokers": [...]}`, `{"mainBroker": ..., "acls": [...]}`), and
+//! polls them every `refresh_interval_secs`. Responses are cached in the store files, so a
+//! proxy starting while the service is down runs with the last configuration it fetched.
+//! Polls send the last `ETag` in `If-None-Match`; `304 Not Modified` or an unchanged body
+//! leaves everything as it is.
+//!
+//! Centrally managed configuration is read-only on the proxy: changes through the API fail
+//! with `ManagedRemotely`.
+
+use crate::acl::{AclTable, ClientAcl};
+use crate::broker_storage::{BrokerConfig, BrokerStorage, FileBrokerStorage};
+use crate::config::RemoteStorageConfig;
+use crate::connection_manager::ConnectionManager;
+use crate::http_client::{self, HttpUrl};
+use crate::routing::RoutingTable;
+use crate::rules::Rule;
+use crate::settings_storage::{FileSettingsStorage, MainBrokerSettings, SettingsStorage};
+use crate::subscription_table::MAIN_BROKER_DEMAND;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// A change to configuration managed by the central service, the error of every write
+#[derive(Debug, thiserror::Error)]
+#[error("This configuration is managed centrally by {0} and can't be changed here")]
+pub struct ManagedRemotely(pub String);
+
+/// A JSON document polled from the management service
+struct RemoteDocument {
+    url: HttpUrl,
+    headers: BTreeMap<String, String>,
+    timeout: Duration,
+    /// The last response handed to the cache
+    applied: Mutex<Option<Fetched>>,
+}
+
+struct Fetched {
+    etag: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Fetched {
+    fn text(&self) -> Result<&str> {
+        std::str::from_utf8(&self.body).context("Response is not UTF-8")
+    }
+}
+
+impl RemoteDocument {
+    fn new(url: &str, config: &RemoteStorageConfig) -> Result<Self> {
+        Ok(Self {
+            url: HttpUrl::parse(url).with_context(|| format!("Invalid remote URL '{}'", url))?,
+            headers: config.headers.clone(),
+            timeout: Duration::from_secs(config.timeout_secs.max(1)),
+            applied: Mutex::new(None),
+        })
+    }
+
+    /// The document, or `None` if it didn't change since the last `applied`
+    async fn fetch(&self) -> Result<Option<Fetched>> {
+        let etag = self
+            .applied
+            .lock()
+            .as_ref()
+            .and_then(|applied| applied.etag.clone());
+        let headers = [("Accept", "application/json")]
+            .into_iter()
+            .chain(etag.as_deref().map(|etag| ("If-None-Match", etag)))
+            .chain(
+                self.headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str())),
+            );
+        let response = tokio::time::timeout(
+            self.timeout,
+            http_client::send("GET", &self.url, headers, None),
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "No response from {} within {:?}",
+                self.url.authority(),
+                self.timeout
+            )
+        })??;
+        if response.status == 304 {
+            return Ok(None);
+        }
+        let fetched = Fetched {
+            etag: response.header("ETag").map(str::to_string),
+            body: response.into_body()?,
+        };
+        let mut applied = self.applied.lock();
+        match applied.as_mut() {
+            Some(applied) if applied.body == fetched.body => {
+                applied.etag = fetched.etag;
+                Ok(None)
+            }
+            _ => Ok(Some(fetched)),
+        }
+    }
+
+    fn applied(&self, fetched: Fetched) {
+        *self.applied.lock() = Some(fetched);
+    }
+
+    fn managed_remotely(&self) -> anyhow::Error {
+        ManagedRemotely(self.url.authority().to_string()).into()
+    }
+}
+
+/// Brokers fetched from `brokers_url`, cached in the broker store file
+pub struct HttpBrokerStorage {
+    document: RemoteDocument,
+    cache: FileBrokerStorage,
+}
+
+impl HttpBrokerStorage {
+    /// Fetch the brokers, falling back to the cached ones if the service can't be reached
+    pub async fn open(config: &RemoteStorageConfig, cache: FileBrokerStorage) -> Result<Self> {
+        let url = config
+            .brokers_url
+            .as_deref()
+            .context("brokers_url is not set")?;
+        let storage = Self {
+            document: RemoteDocument::new(url, config)?,
+            cache,
+        };
+        match storage.refresh().await {
+            Ok(_) => info!("Fetched brokers from {}", storage.document.url.authority()),
+            Err(e) => warn!(
+                "Failed to fetch brokers from {}, using the cached ones: {:#}",
+                storage.document.url.authority(),
+                e
+            ),
+        }
+        Ok(storage)
+    }
+}
+
+#[async_trait]
+impl BrokerStorage for HttpBrokerStorage {
+    async fn list(&self) -> Vec<BrokerConfig> {
+        self.cache.list().await
+    }
+
+    async fn list_with_passwords(&self) -> Vec<BrokerConfig> {
+        self.cache.list_with_passwords().await
+    }
+
+    async fn get(&self, id: &str) -> Option<BrokerConfig> {
+        self.cache.get(id).await
+    }
+
+    async fn get_with_password(&self, id: &str) -> Option<BrokerConfig> {
+        self.cache.get_with_password(id).await
+    }
+
+    async fn add(&self, _broker: BrokerConfig) -> Result<()> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn update(&self, _id: &str, _updated: BrokerConfig) -> Result<u64> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn delete(&self, _id: &str) -> Result<()> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn toggle_enabled(&self, _id: &str, _enabled: bool) -> Result<()> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn refresh(&self) -> Result<bool> {
+        let Some(fetched) = self.document.fetch().await? else {
+            return Ok(false);
+        };
+        self.cache.replace(fetched.text()?).await?;
+        self.document.applied(fetched);
+        Ok(true)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.cache.flush()
+    }
+}
+
+/// Settings fetched from `settings_url`, cached in the settings store file
+pub struct HttpSettingsStorage {
+    document: RemoteDocument,
+    cache: FileSettingsStorage,
+}
+
+impl HttpSettingsStorage {
+    /// Fetch the settings, falling back to the cached ones if the service can't be reached
+    pub async fn open(config: &RemoteStorageConfig, cache: FileSettingsStorage) -> Result<Self> {
+        let url = config
+            .settings_url
+            .as_deref()
+            .context("settings_url is not set")?;
+        let storage = Self {
+            document: RemoteDocument::new(url, config)?,
+            cache,
+        };
+        match storage.refresh().await {
+            Ok(_) => info!("Fetched settings from {}", storage.document.url.authority()),
+            Err(e) => warn!(
+                "Failed to fetch settings from {}, using the cached ones: {:#}",
+                storage.document.url.authority(),
+                e
+            ),
+        }
+        Ok(storage)
+    }
+}
+
+#[async_trait]
+impl SettingsStorage for HttpSettingsStorage {
+    async fn get_main_broker(&self) -> Option<MainBrokerSettings> {
+        self.cache.get_main_broker().await
+    }
+
+    async fn get_main_broker_for_api(&self) -> Option<MainBrokerSettings> {
+        self.cache.get_main_broker_for_api().await
+    }
+
+    async fn set_main_broker(&self, _settings: MainBrokerSettings) -> Result<()> {
+        Err(self.document.managed_remotely())
+    }
+
+    fn acl_table(&self) -> Arc<AclTable> {
+        self.cache.acl_table()
+    }
+
+    async fn set_acl(&self, _acl: ClientAcl) -> Result<()> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn remove_acl(&self, _identity: &str) -> Result<bool> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn get_routing(&self) -> Option<RoutingTable> {
+        self.cache.get_routing().await
+    }
+
+    async fn set_routing(&self, _routing: Option<RoutingTable>) -> Result<()> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn get_main_demand(&self) -> Vec<String> {
+        self.cache.get_main_demand().await
+    }
+
+    async fn set_main_demand(&self, _filters: Vec<String>) -> Result<()> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn get_rules(&self) -> Vec<Rule> {
+        self.cache.get_rules().await
+    }
+
+    async fn set_rules(&self, _rules: Vec<Rule>) -> Result<()> {
+        Err(self.document.managed_remotely())
+    }
+
+    async fn refresh(&self) -> Result<bool> {
+        let Some(fetched) = self.document.fetch().await? else {
+            return Ok(false);
+        };
+        self.cache.replace(fetched.text()?).await?;
+        self.document.applied(fetched);
+        Ok(true)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.cache.flush()
+    }
+}
+
+/// Poll the stores every `refresh_interval_secs` until `shutdown` is cancelled and apply
+/// what changed: brokers are reconnected as needed, routing, rules and main broker demand
+/// are replaced (ACLs are replaced by the store itself), and changed main broker settings
+/// restart the main broker client
+pub async fn run_config_refresh(
+    config: RemoteStorageConfig,
+    broker_storage: Arc<dyn BrokerStorage>,
+    settings_storage: Arc<dyn SettingsStorage>,
+    connection_manager: Arc<RwLock<ConnectionManager>>,
+    main_broker_restart_tx: mpsc::Sender<()>,
+    shutdown: CancellationToken,
+) {
+    let period = Duration::from_secs(config.refresh_interval_secs.max(1));
+    info!("Refreshing the remote configuration every {:?}", period);
+    // The first tick would be now, right after the stores were opened
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        match broker_storage.refresh().await {
+            Ok(true) => {
+                info!("Brokers changed centrally, applying");
+                let brokers = broker_storage.list_with_passwords().await;
+                connection_manager.write().await.sync_brokers(brokers).await;
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Failed to refresh brokers: {:#}", e),
+        }
+
+        let main_broker = settings_storage.get_main_broker().await;
+        match settings_storage.refresh().await {
+            Ok(true) => {
+                info!("Settings changed centrally, applying");
+                let mut manager = connection_manager.write().await;
+                manager.set_routing_table(settings_storage.get_routing().await);
+                manager.set_rules(settings_storage.get_rules().await);
+                manager.client_registry().subscription_table().set(
+                    MAIN_BROKER_DEMAND,
+                    &settings_storage.get_main_demand().await,
+                );
+                drop(manager);
+                if settings_storage.get_main_broker().await != main_broker {
+                    let _ = main_broker_restart_tx.send(()).await;
+                }
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Failed to refresh settings: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves `body` with its length as ETag, answering a matching `If-None-Match` with 304
+    async fn serve(listener: TcpListener, body: Arc<Mutex<String>>) {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![0; 4096];
+            let read = stream.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..read]).to_string();
+            let body = body.lock().clone();
+            let etag = format!("\"{}\"", body.len());
+            let response = if request.contains(&format!("If-None-Match: {}", etag)) {
+                "HTTP/1.1 304 Not Modified\r\n\r\n".to_string()
+            } else {
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\n\r\n{}",
+                    etag,
+                    body.len(),
+                    body
+                )
+            };
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    fn brokers(names: &[&str]) -> String {
+        let brokers: Vec<String> = names
+            .iter()
+            .map(|name| {
+                format!(
+                    r#"{{"id":"{0}","name":"{0}","address":"10.0.0.1","port":1883,"clientIdPrefix":"proxy"}}"#,
+                    name
+                )
+            })
+            .collect();
+        format!(r#"{{"brokers":[{}]}}"#, brokers.join(","))
+    }
+
+    #[tokio::test]
+    async fn test_http_broker_storage_caches_and_refreshes() {
+        let temp_dir = TempDir::new().unwrap();
+        let store_path = temp_dir.path().join("brokers.json");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = RemoteStorageConfig {
+            brokers_url: Some(format!(
+                "http://{}/proxies/edge-1/brokers",
+                listener.local_addr().unwrap()
+            )),
+            settings_url: None,
+            headers: BTreeMap::new(),
+            refresh_interval_secs: 60,
+            timeout_secs: 5,
+        };
+        let body = Arc::new(Mutex::new(brokers(&["plant-a"])));
+        let server = tokio::spawn(serve(listener, Arc::clone(&body)));
+
+        let storage =
+            HttpBrokerStorage::open(&config, FileBrokerStorage::new(&store_path).unwrap())
+                .await
+                .unwrap();
+        assert_eq!(storage.list().await[0].name, "plant-a");
+        let err = storage.delete("plant-a").await.unwrap_err();
+        assert!(err.downcast_ref::<ManagedRemotely>().is_some());
+
+        // Unchanged (304), then changed
+        assert!(!storage.refresh().await.unwrap());
+        *body.lock() = brokers(&["plant-a", "plant-b"]);
+        assert!(storage.refresh().await.unwrap());
+        assert_eq!(storage.list().await.len(), 2);
+        storage.flush().unwrap();
+
+        // The service is gone: the cached brokers are used
+        server.abort();
+        let _ = server.await;
+        let storage =
+            HttpBrokerStorage::open(&config, FileBrokerStorage::new(&store_path).unwrap())
+                .await
+                .unwrap();
+        assert_eq!(storage.list().await.len(), 2);
+        assert!(storage.refresh().await.is_err());
+    }
+}
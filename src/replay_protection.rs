@@ -0,0 +1,291 @@
+//! Replay protection for command topics
+//!
+//! Commands that bidirectional brokers relay back towards the main broker can drive
+//! actuators, so a command that arrives twice (a broker redelivering it, or someone
+//! replaying a captured message) must not act twice. For topics matching
+//! `[replay_protection] topics`, each message's (origin, correlation ID) pair is
+//! remembered for `window_secs`; a message whose pair was already seen in that window is
+//! dropped. The origin is the ID of the broker the command came from; the correlation ID
+//! is the MQTT 5.0 correlation data, or else a field of the JSON payload. The seen pairs
+//! are kept in a file, so a restart doesn't reopen the window.
+
+use crate::config::ReplayProtectionConfig;
+use crate::connection_manager::ConnectionManager;
+use crate::debounced_write::DebouncedWriter;
+use crate::mqtt_v5::{self, PropertyValue};
+use anyhow::{Context, Result};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::warn;
+
+/// Upper bound on remembered commands; beyond it the oldest are forgotten first
+const MAX_SEEN_COMMANDS: usize = 100_000;
+
+/// What the guard decided for a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayVerdict {
+    /// Not a command topic, or a command seen for the first time
+    Accept,
+    /// The command was already seen within the window
+    Replay,
+    /// The command has no correlation ID and `require_correlation_id` is set
+    MissingId,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SeenCommand {
+    origin: String,
+    id: String,
+    seen_at: DateTime<Utc>,
+}
+
+pub struct ReplayGuard {
+    config: ReplayProtectionConfig,
+    /// (origin, correlation ID) → when it was first seen
+    seen: Mutex<HashMap<(String, String), DateTime<Utc>>>,
+    writer: DebouncedWriter,
+    /// Messages dropped as replays or for lacking a correlation ID since startup
+    dropped: AtomicU64,
+}
+
+impl ReplayGuard {
+    /// Load the commands seen within the window from `config.store_path`
+    pub fn load(config: ReplayProtectionConfig, flush_interval: Duration) -> Result<Self> {
+        let path = Path::new(&config.store_path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {:?}", parent))?;
+        }
+        let mut seen = HashMap::new();
+        match std::fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<Vec<SeenCommand>>(&contents) {
+                Ok(entries) => {
+                    let cutoff = Utc::now() - window(&config);
+                    seen.extend(
+                        entries
+                            .into_iter()
+                            .filter(|entry| entry.seen_at > cutoff)
+                            .map(|entry| ((entry.origin, entry.id), entry.seen_at)),
+                    );
+                }
+                Err(e) => warn!(
+                    "Ignoring unreadable replay protection state in {}: {}",
+                    config.store_path, e
+                ),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", config.store_path))
+            }
+        }
+        Ok(Self {
+            writer: DebouncedWriter::new(path.to_path_buf(), flush_interval),
+            config,
+            seen: Mutex::new(seen),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Check a message from broker `origin`, and remember it if it is a new command
+    pub fn check(
+        &self,
+        origin: &str,
+        topic: &str,
+        payload: &[u8],
+        properties: Option<&mqtt_v5::Properties>,
+        now: DateTime<Utc>,
+    ) -> ReplayVerdict {
+        if !self
+            .config
+            .topics
+            .iter()
+            .any(|pattern| ConnectionManager::topic_matches_pattern(pattern, topic))
+        {
+            return ReplayVerdict::Accept;
+        }
+        let Some(id) = self.correlation_id(payload, properties) else {
+            if self.config.require_correlation_id {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return ReplayVerdict::MissingId;
+            }
+            return ReplayVerdict::Accept;
+        };
+
+        let mut seen = self.seen.lock();
+        let cutoff = now - window(&self.config);
+        let key = (origin.to_string(), id);
+        if seen.get(&key).is_some_and(|seen_at| *seen_at > cutoff) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return ReplayVerdict::Replay;
+        }
+        seen.insert(key, now);
+        seen.retain(|_, seen_at| *seen_at > cutoff);
+        if seen.len() > MAX_SEEN_COMMANDS {
+            let mut by_age: Vec<DateTime<Utc>> = seen.values().copied().collect();
+            by_age.sort_unstable();
+            let oldest_kept = by_age[by_age.len() - MAX_SEEN_COMMANDS];
+            seen.retain(|_, seen_at| *seen_at >= oldest_kept);
+        }
+        self.persist(&seen);
+        ReplayVerdict::Accept
+    }
+
+    /// Messages dropped since startup
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Write pending state now (on shutdown)
+    pub fn flush(&self) -> Result<()> {
+        self.writer.flush()
+    }
+
+    /// MQTT 5.0 correlation data, or else the configured JSON field (a string or number)
+    fn correlation_id(
+        &self,
+        payload: &[u8],
+        properties: Option<&mqtt_v5::Properties>,
+    ) -> Option<String> {
+        if let Some(PropertyValue::BinaryData(data)) =
+            properties.and_then(|p| p.get(mqtt_v5::property::CORRELATION_DATA))
+        {
+            return Some(match std::str::from_utf8(data) {
+                Ok(text) => text.to_string(),
+                Err(_) => base64::engine::general_purpose::STANDARD.encode(data),
+            });
+        }
+        let Ok(Value::Object(object)) = serde_json::from_slice::<Value>(payload) else {
+            return None;
+        };
+        match object.get(&self.config.correlation_field)? {
+            Value::String(id) if !id.is_empty() => Some(id.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        }
+    }
+
+    fn persist(&self, seen: &HashMap<(String, String), DateTime<Utc>>) {
+        let entries: Vec<SeenCommand> = seen
+            .iter()
+            .map(|((origin, id), seen_at)| SeenCommand {
+                origin: origin.clone(),
+                id: id.clone(),
+                seen_at: *seen_at,
+            })
+            .collect();
+        let result = serde_json::to_string(&entries)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| self.writer.write(contents));
+        if let Err(e) = result {
+            warn!("Failed to save replay protection state: {:#}", e);
+        }
+    }
+}
+
+fn window(config: &ReplayProtectionConfig) -> chrono::Duration {
+    chrono::Duration::seconds(config.window_secs as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &tempfile::TempDir) -> ReplayProtectionConfig {
+        ReplayProtectionConfig {
+            topics: vec!["cmd/#".to_string()],
+            window_secs: 60,
+            correlation_field: "id".to_string(),
+            require_correlation_id: false,
+            store_path: dir.path().join("replay.json").to_string_lossy().to_string(),
+        }
+    }
+
+    #[test]
+    fn test_replays_within_window_are_dropped_across_restarts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let guard = ReplayGuard::load(config(&dir), Duration::ZERO).unwrap();
+        let now = Utc::now();
+        let open = br#"{"id": "c-1", "action": "open"}"#;
+
+        assert_eq!(
+            guard.check("site-a", "cmd/valve", open, None, now),
+            ReplayVerdict::Accept
+        );
+        assert_eq!(
+            guard.check("site-a", "cmd/valve", open, None, now),
+            ReplayVerdict::Replay
+        );
+        // Other origins, other topics and payloads without an ID aren't affected
+        assert_eq!(
+            guard.check("site-b", "cmd/valve", open, None, now),
+            ReplayVerdict::Accept
+        );
+        assert_eq!(
+            guard.check("site-a", "status/valve", open, None, now),
+            ReplayVerdict::Accept
+        );
+        assert_eq!(
+            guard.check("site-a", "cmd/valve", b"open", None, now),
+            ReplayVerdict::Accept
+        );
+
+        // The state survives a restart, and expires with the window
+        drop(guard);
+        let guard = ReplayGuard::load(config(&dir), Duration::ZERO).unwrap();
+        assert_eq!(
+            guard.check("site-a", "cmd/valve", open, None, now),
+            ReplayVerdict::Replay
+        );
+        let later = now + chrono::Duration::seconds(61);
+        assert_eq!(
+            guard.check("site-a", "cmd/valve", open, None, later),
+            ReplayVerdict::Accept
+        );
+        assert_eq!(guard.dropped(), 1);
+    }
+
+    #[test]
+    fn test_correlation_data_and_required_ids() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let guard = ReplayGuard::load(
+            ReplayProtectionConfig {
+                require_correlation_id: true,
+                ..config(&dir)
+            },
+            Duration::ZERO,
+        )
+        .unwrap();
+        let now = Utc::now();
+        let mut properties = mqtt_v5::Properties::default();
+        properties.push(
+            mqtt_v5::property::CORRELATION_DATA,
+            PropertyValue::BinaryData(b"req-7".to_vec()),
+        );
+
+        assert_eq!(
+            guard.check("b1", "cmd/door", b"unlock", Some(&properties), now),
+            ReplayVerdict::Accept
+        );
+        // The correlation data wins over the payload field
+        assert_eq!(
+            guard.check("b1", "cmd/door", br#"{"id": 8}"#, Some(&properties), now),
+            ReplayVerdict::Replay
+        );
+        assert_eq!(
+            guard.check("b1", "cmd/door", br#"{"id": 8}"#, None, now),
+            ReplayVerdict::Accept
+        );
+        assert_eq!(
+            guard.check("b1", "cmd/door", b"unlock", None, now),
+            ReplayVerdict::MissingId
+        );
+    }
+}
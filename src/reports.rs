@@ -0,0 +1,307 @@
+//! Periodic usage reports per tenant or site, for internal chargeback
+//!
+//! Every forwarded message is counted against its topic. At the end of each reporting
+//! period the counters are grouped by one topic level (`tenants/{tenant}/...` → level 1),
+//! kept as the previous report for the API and optionally published to the main broker.
+
+use crate::config::{MainBrokerConfig, ReportsConfig};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Distinct topics counted per period; the rest are counted under `OTHER_TOPIC`
+const MAX_TRACKED_TOPICS: usize = 10_000;
+const OTHER_TOPIC: &str = "(other)";
+/// Group of topics that have fewer levels than the grouping level
+const NO_GROUP: &str = "(none)";
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopicUsage {
+    pub topic: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupUsage {
+    pub group: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+    /// Busiest topics of the group by bytes
+    pub top_topics: Vec<TopicUsage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    /// Topic level the groups are taken from
+    pub group_level: usize,
+    #[serde(flatten)]
+    pub total: Usage,
+    /// Groups by bytes, largest first
+    pub groups: Vec<GroupUsage>,
+}
+
+struct Period {
+    started: DateTime<Utc>,
+    topics: HashMap<String, Usage>,
+}
+
+impl Period {
+    fn new() -> Self {
+        Self {
+            started: Utc::now(),
+            topics: HashMap::new(),
+        }
+    }
+}
+
+/// Message and byte counters of the current reporting period
+pub struct UsageTracker {
+    group_level: usize,
+    top_topics: usize,
+    current: Mutex<Period>,
+    previous: Mutex<Option<UsageReport>>,
+}
+
+impl Default for UsageTracker {
+    fn default() -> Self {
+        Self::new(&ReportsConfig::default())
+    }
+}
+
+impl UsageTracker {
+    pub fn new(config: &ReportsConfig) -> Self {
+        Self {
+            group_level: config.group_level,
+            top_topics: config.top_topics,
+            current: Mutex::new(Period::new()),
+            previous: Mutex::new(None),
+        }
+    }
+
+    /// Count a forwarded message (topic plus payload bytes, like `BandwidthStats`)
+    pub fn record(&self, topic: &str, payload_len: usize) {
+        let usage = Usage {
+            messages: 1,
+            bytes: (topic.len() + payload_len) as u64,
+        };
+        let mut current = self.current.lock();
+        match current.topics.get_mut(topic) {
+            Some(counters) => counters.add(usage),
+            None => {
+                let topic = if current.topics.len() < MAX_TRACKED_TOPICS {
+                    topic
+                } else {
+                    OTHER_TOPIC
+                };
+                current
+                    .topics
+                    .entry(topic.to_string())
+                    .or_default()
+                    .add(usage);
+            }
+        }
+    }
+
+    /// Report for the period in progress
+    pub fn current(&self) -> UsageReport {
+        let current = self.current.lock();
+        self.build_report(current.started, &current.topics)
+    }
+
+    /// Report for the last completed period
+    pub fn previous(&self) -> Option<UsageReport> {
+        self.previous.lock().clone()
+    }
+
+    /// Close the current period and start a new one
+    pub fn rotate(&self) -> UsageReport {
+        let period = std::mem::replace(&mut *self.current.lock(), Period::new());
+        let report = self.build_report(period.started, &period.topics);
+        *self.previous.lock() = Some(report.clone());
+        report
+    }
+
+    fn build_report(&self, started: DateTime<Utc>, topics: &HashMap<String, Usage>) -> UsageReport {
+        let mut groups: HashMap<&str, (Usage, Vec<TopicUsage>)> = HashMap::new();
+        let mut total = Usage::default();
+        for (topic, usage) in topics {
+            total.add(*usage);
+            let group = if topic == OTHER_TOPIC {
+                OTHER_TOPIC
+            } else {
+                topic.split('/').nth(self.group_level).unwrap_or(NO_GROUP)
+            };
+            let entry = groups.entry(group).or_default();
+            entry.0.add(*usage);
+            entry.1.push(TopicUsage {
+                topic: topic.clone(),
+                usage: *usage,
+            });
+        }
+
+        let mut groups: Vec<GroupUsage> = groups
+            .into_iter()
+            .map(|(group, (usage, mut top_topics))| {
+                top_topics.sort_by(|a, b| {
+                    b.usage
+                        .bytes
+                        .cmp(&a.usage.bytes)
+                        .then_with(|| a.topic.cmp(&b.topic))
+                });
+                top_topics.truncate(self.top_topics);
+                GroupUsage {
+                    group: group.to_string(),
+                    usage,
+                    top_topics,
+                }
+            })
+            .collect();
+        groups.sort_by(|a, b| {
+            b.usage
+                .bytes
+                .cmp(&a.usage.bytes)
+                .then_with(|| a.group.cmp(&b.group))
+        });
+
+        UsageReport {
+            period_start: started,
+            period_end: Utc::now(),
+            group_level: self.group_level,
+            total,
+            groups,
+        }
+    }
+}
+
+/// Close a reporting period every `interval_secs` and publish the report if configured
+pub async fn run_usage_reports(
+    tracker: Arc<UsageTracker>,
+    config: ReportsConfig,
+    main_broker: MainBrokerConfig,
+    shutdown: CancellationToken,
+) {
+    let period = Duration::from_secs(config.interval_secs.max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+    // Reports are published through a connection of their own, so a restart of the
+    // forwarding client doesn't lose them
+    let mut publisher = config.publish_topic.as_ref().map(|topic| {
+        let mut options = MqttOptions::new(
+            format!("{}-reports", main_broker.client_id),
+            &main_broker.address,
+            main_broker.port,
+        );
+        options.set_keep_alive(Duration::from_secs(60));
+        if let (Some(username), Some(password)) = (&main_broker.username, &main_broker.password) {
+            options.set_credentials(username, password);
+        }
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        info!("Publishing usage reports to '{}'", topic);
+        (topic.clone(), client, eventloop)
+    });
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = interval.tick() => {
+                let report = tracker.rotate();
+                info!(
+                    "Usage report: {} messages, {} bytes in {} groups",
+                    report.total.messages,
+                    report.total.bytes,
+                    report.groups.len()
+                );
+                if let Some((topic, client, _)) = &publisher {
+                    match serde_json::to_vec(&report) {
+                        Ok(payload) => {
+                            if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, false, payload) {
+                                warn!("Failed to publish usage report: {}", e);
+                            }
+                        }
+                        Err(e) => warn!("Failed to serialize usage report: {}", e),
+                    }
+                }
+            }
+            result = async { publisher.as_mut().unwrap().2.poll().await }, if publisher.is_some() => {
+                if let Err(e) = result {
+                    warn!("Usage report connection error: {}", e);
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some((_, client, mut eventloop)) = publisher {
+        let _ = client.try_disconnect();
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            while let Ok(event) = eventloop.poll().await {
+                if matches!(
+                    event,
+                    rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)
+                ) {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_report_groups_by_level() {
+        let tracker = UsageTracker::new(&ReportsConfig {
+            group_level: 1,
+            top_topics: 1,
+            ..ReportsConfig::default()
+        });
+        tracker.record("tenants/acme/a", 10);
+        tracker.record("tenants/acme/b", 100);
+        tracker.record("tenants/acme/b", 100);
+        tracker.record("tenants/globex/a", 5);
+        tracker.record("status", 1);
+
+        let report = tracker.rotate();
+        assert_eq!(report.total.messages, 5);
+        let groups: Vec<(&str, u64)> = report
+            .groups
+            .iter()
+            .map(|g| (g.group.as_str(), g.usage.messages))
+            .collect();
+        assert_eq!(groups, vec![("acme", 3), ("globex", 1), ("(none)", 1)]);
+        assert_eq!(report.groups[0].top_topics.len(), 1);
+        assert_eq!(report.groups[0].top_topics[0].topic, "tenants/acme/b");
+
+        // Rotating starts a fresh period and keeps the finished one
+        assert_eq!(tracker.current().total.messages, 0);
+        assert_eq!(tracker.previous().unwrap().total.messages, 5);
+    }
+}
@@ -0,0 +1,160 @@
+//! Resource profiles for small devices
+//!
+//! `profile = "low_resource"` is meant for 256 MB gateways. It shrinks the request queues
+//! of the MQTT clients and broker tasks, caps offline buffers, turns the message history
+//! off and subscribes to the main broker only on the topics some broker, rule or delivery
+//! group can take instead of `#`. Target: under 48 MB resident with 10 brokers and full
+//! buffers, checked by the ignored `test_low_resource_soak` (run it with
+//! `cargo test --release -- --ignored soak`).
+//!
+//! The profile is set once at startup and read wherever queues are created.
+
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceProfile {
+    #[default]
+    Standard,
+    LowResource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Requests an MQTT client queues before publishing blocks or fails
+    pub client_queue: usize,
+    /// Default send queue capacity per broker, and its pending subscription commands
+    pub broker_commands: usize,
+    /// Connection events queued for a broker task
+    pub broker_events: usize,
+    /// Messages queued for the Web UI stream
+    pub ui_broadcast: usize,
+    /// Upper bound for a broker's `offlineBuffer.maxMessages`
+    pub max_offline_buffer: usize,
+    /// Keep the searchable message history
+    pub message_history: bool,
+    /// Subscribe to `#` on the main broker so the UI sees every topic
+    pub monitor_all_topics: bool,
+}
+
+impl ResourceProfile {
+    pub fn limits(self) -> ResourceLimits {
+        match self {
+            ResourceProfile::Standard => ResourceLimits {
+                client_queue: 10_000,
+                broker_commands: 1000,
+                broker_events: 100,
+                ui_broadcast: 1000,
+                max_offline_buffer: usize::MAX,
+                message_history: true,
+                monitor_all_topics: true,
+            },
+            ResourceProfile::LowResource => ResourceLimits {
+                client_queue: 200,
+                broker_commands: 100,
+                broker_events: 32,
+                ui_broadcast: 64,
+                max_offline_buffer: 100,
+                message_history: false,
+                monitor_all_topics: false,
+            },
+        }
+    }
+}
+
+static ACTIVE: OnceLock<ResourceProfile> = OnceLock::new();
+
+/// Select the profile for this process; later calls with another profile are ignored
+pub fn activate(profile: ResourceProfile) {
+    if ACTIVE.set(profile).is_err() && ACTIVE.get() != Some(&profile) {
+        tracing::warn!("Resource profile already set; ignoring {:?}", profile);
+    }
+}
+
+/// Limits of the active profile (`Standard` until one is activated)
+pub fn limits() -> ResourceLimits {
+    ACTIVE.get().copied().unwrap_or_default().limits()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime_stats::resident_bytes;
+
+    /// Forward messages for a while to unreachable brokers, so every queue and buffer
+    /// fills up, and check memory stays within the low-resource target.
+    /// `SOAK_SECS` sets the duration (default 60).
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_low_resource_soak() {
+        use crate::broker_storage::BrokerConfig;
+        use crate::client_registry::ClientRegistry;
+        use crate::config::DedupConfig;
+        use crate::connection_manager::ConnectionManager;
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        const TARGET_BYTES: u64 = 48 * 1024 * 1024;
+        activate(ResourceProfile::LowResource);
+        let brokers: Vec<BrokerConfig> = (0..10)
+            .map(|i| {
+                serde_json::from_value(serde_json::json!({
+                    "id": format!("b{}", i),
+                    "name": format!("Broker {}", i),
+                    "address": "127.0.0.1",
+                    "port": 1,
+                    "clientIdPrefix": "soak",
+                    "enabled": true
+                }))
+                .unwrap()
+            })
+            .collect();
+        let manager = ConnectionManager::new(
+            brokers,
+            Arc::new(ClientRegistry::new()),
+            "127.0.0.1".to_string(),
+            1,
+            4,
+            &DedupConfig::default(),
+            0,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let secs = std::env::var("SOAK_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(60);
+        let deadline = Instant::now() + Duration::from_secs(secs);
+        let payload = bytes::Bytes::from(vec![b'x'; 1024]);
+        let mut peak = 0;
+        let mut sent: u64 = 0;
+        while Instant::now() < deadline {
+            for _ in 0..100 {
+                manager
+                    .forward_message(
+                        &format!("soak/{}", sent % 1000),
+                        payload.clone(),
+                        rumqttc::QoS::AtLeastOnce,
+                        false,
+                        None,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                sent += 1;
+            }
+            peak = peak.max(resident_bytes().unwrap_or(0));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        println!(
+            "Forwarded {} messages; peak RSS {:.1} MB",
+            sent,
+            peak as f64 / 1048576.0
+        );
+        assert!(peak < TARGET_BYTES, "peak RSS {} over target", peak);
+    }
+}
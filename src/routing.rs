@@ -0,0 +1,196 @@
+//! Routing by a topic level (site, region, tenant)
+//!
+//! Fleets often encode the site in the topic, e.g. `fleet/{site}/device/...`. Instead of a
+//! wildcard filter per broker and site, the routing table takes one topic level as the
+//! routing key and maps each key to the brokers that serve it. Adding a region is one
+//! table entry.
+//!
+//! The table also orders delivery between brokers: a dependency holds a message back
+//! from one broker until another has acknowledged it (a local historian before the
+//! cloud, say), and decides what happens when that acknowledgement doesn't come.
+
+use crate::connection_manager::ConnectionManager;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingTable {
+    /// Zero-based topic level holding the routing key (`1` for `fleet/{site}/...`)
+    pub level: usize,
+    /// Routing key → IDs of the brokers that receive messages for it
+    #[serde(default)]
+    pub routes: BTreeMap<String, Vec<String>>,
+    /// Brokers that only receive a message after another broker acknowledged it
+    #[serde(default)]
+    pub dependencies: Vec<BrokerDependency>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokerDependency {
+    /// ID of the broker that waits
+    pub broker: String,
+    /// ID of the broker that has to acknowledge the message first
+    pub after: String,
+    /// Topic patterns the ordering applies to; empty for all topics
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub on_failure: DependencyFailure,
+}
+
+/// What to do when the broker a dependency waits for doesn't acknowledge the message
+/// (it's disconnected, fails, or doesn't ack in time)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyFailure {
+    /// Don't forward to the waiting broker either
+    #[default]
+    Skip,
+    /// Forward to the waiting broker anyway
+    Forward,
+}
+
+impl RoutingTable {
+    /// The routing key of `topic`, if it has enough levels
+    pub fn routing_key<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic.split('/').nth(self.level)
+    }
+
+    /// Whether the table lets `broker_id` receive `topic`.
+    ///
+    /// Brokers that appear in the table only receive topics whose key maps to them;
+    /// brokers that don't appear are left to their own topic filters.
+    pub fn allows(&self, broker_id: &str, topic: &str) -> bool {
+        let routed = |ids: &Vec<String>| ids.iter().any(|id| id == broker_id);
+        if !self.routes.values().any(routed) {
+            return true;
+        }
+        self.routing_key(topic)
+            .and_then(|key| self.routes.get(key))
+            .is_some_and(routed)
+    }
+
+    /// Dependencies that hold back `topic` from `broker_id`
+    pub fn prerequisites<'a>(
+        &'a self,
+        broker_id: &'a str,
+        topic: &'a str,
+    ) -> impl Iterator<Item = &'a BrokerDependency> + 'a {
+        self.dependencies.iter().filter(move |dependency| {
+            dependency.broker == broker_id
+                && (dependency.topics.is_empty()
+                    || dependency
+                        .topics
+                        .iter()
+                        .any(|pattern| ConnectionManager::topic_matches_pattern(pattern, topic)))
+        })
+    }
+
+    /// Check that every broker ID is in `known` and that dependencies don't form a cycle
+    pub fn validate(&self, known: &[String]) -> Result<()> {
+        let ids = self.routes.values().flatten().chain(
+            self.dependencies
+                .iter()
+                .flat_map(|dependency| [&dependency.broker, &dependency.after]),
+        );
+        for id in ids {
+            if !known.contains(id) {
+                bail!("Unknown broker ID '{}' in routing table", id);
+            }
+        }
+        for dependency in &self.dependencies {
+            if dependency.broker == dependency.after {
+                bail!("Broker '{}' cannot depend on itself", dependency.broker);
+            }
+            // Follow the brokers `after` waits for; reaching `broker` again is a cycle
+            let mut stack = vec![dependency.after.as_str()];
+            let mut seen = Vec::new();
+            while let Some(id) = stack.pop() {
+                if id == dependency.broker {
+                    bail!(
+                        "Dependency of '{}' on '{}' forms a cycle",
+                        dependency.broker,
+                        dependency.after
+                    );
+                }
+                if !seen.contains(&id) {
+                    seen.push(id);
+                    stack.extend(
+                        self.dependencies
+                            .iter()
+                            .filter(|d| d.broker == id)
+                            .map(|d| d.after.as_str()),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_table_restricts_listed_brokers_only() {
+        let table = RoutingTable {
+            level: 1,
+            routes: BTreeMap::from([
+                ("north".to_string(), vec!["b-north".to_string()]),
+                (
+                    "south".to_string(),
+                    vec!["b-south".to_string(), "b-backup".to_string()],
+                ),
+            ]),
+            dependencies: Vec::new(),
+        };
+
+        assert_eq!(table.routing_key("fleet/north/truck-1"), Some("north"));
+        assert!(table.allows("b-north", "fleet/north/truck-1"));
+        assert!(!table.allows("b-south", "fleet/north/truck-1"));
+        assert!(table.allows("b-backup", "fleet/south/truck-9"));
+        // Unknown keys and short topics reach none of the routed brokers
+        assert!(!table.allows("b-north", "fleet/west/truck-2"));
+        assert!(!table.allows("b-north", "fleet"));
+        // Brokers outside the table are unaffected
+        assert!(table.allows("b-archive", "fleet/north/truck-1"));
+    }
+
+    #[test]
+    fn test_dependencies_filter_topics_and_reject_cycles() {
+        let dependency = |broker: &str, after: &str, topics: &[&str]| BrokerDependency {
+            broker: broker.to_string(),
+            after: after.to_string(),
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            on_failure: DependencyFailure::Skip,
+        };
+        let mut table = RoutingTable {
+            level: 0,
+            routes: BTreeMap::new(),
+            dependencies: vec![
+                dependency("cloud", "historian", &["sensors/#"]),
+                dependency("backup", "cloud", &[]),
+            ],
+        };
+        let known: Vec<String> = ["historian", "cloud", "backup"]
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        assert!(table.validate(&known).is_ok());
+
+        assert_eq!(table.prerequisites("cloud", "sensors/a").count(), 1);
+        assert_eq!(table.prerequisites("cloud", "alarms/a").count(), 0);
+        assert_eq!(table.prerequisites("backup", "alarms/a").count(), 1);
+        assert_eq!(table.prerequisites("historian", "sensors/a").count(), 0);
+
+        assert!(table.validate(&known[..2]).is_err());
+        table
+            .dependencies
+            .push(dependency("historian", "backup", &["alarms/#"]));
+        assert!(table.validate(&known).is_err());
+    }
+}
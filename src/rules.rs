@@ -0,0 +1,323 @@
+//! Rules engine for the forwarding path
+//!
+//! A rule matches messages by topic pattern and, optionally, a payload condition (the
+//! syntax of `payload_filter`), and then acts on them: forward to a fixed set of brokers
+//! instead of the normal routing, rewrite the topic, drop the message, copy it to an
+//! extra topic, transcode the payload with codecs from the `codec` registry, or hold it
+//! back to be combined with others on one topic (see `aggregation`). Every
+//! enabled rule is checked against the original topic, in order, and the actions of all
+//! matching rules combine: a drop wins, the last rewrite wins, broker sets add up, codec
+//! steps run in order and each copy is forwarded separately (through the normal routing,
+//! without rules). Rules are managed through `/api/rules` and kept in the settings store.
+
+use crate::aggregation::{AggregateFormat, AggregateStep};
+use crate::payload_filter::Condition;
+use crate::topic_rewrite::{substitute, wildcard_captures};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rule {
+    pub id: String,
+    /// Topic pattern the rule matches (`+`/`#` wildcards)
+    pub topic: String,
+    /// Payload condition such as `$.battery < 20`; non-JSON payloads never match one
+    #[serde(default)]
+    pub condition: Option<Condition>,
+    pub actions: Vec<RuleAction>,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Topics in actions may use `{n}` for the level(s) the rule pattern's n-th wildcard
+/// matched
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Forward only to these brokers (by ID), bypassing topic filters and routing
+    Forward { brokers: Vec<String> },
+    /// Forward under another topic
+    RewriteTopic { topic: String },
+    /// Don't forward at all
+    Drop,
+    /// Also forward a copy under another topic
+    Copy { topic: String },
+    /// Decode the payload with one registered codec and/or encode it with another
+    Transcode {
+        #[serde(default)]
+        decode: Option<String>,
+        #[serde(default)]
+        encode: Option<String>,
+    },
+    /// Add the proxy's `traceparent` (with `[opentelemetry]` enabled and the message
+    /// sampled) as an MQTT 5.0 user property, or as top-level `field` of JSON object payloads
+    InjectTraceparent {
+        #[serde(default)]
+        field: Option<String>,
+    },
+    /// Forward the message only combined with others under one topic (see `aggregation`)
+    #[serde(rename_all = "camelCase")]
+    Aggregate {
+        topic: String,
+        #[serde(default = "default_window_ms")]
+        window_ms: u64,
+        #[serde(default = "default_max_messages")]
+        max_messages: usize,
+        #[serde(default)]
+        format: AggregateFormat,
+    },
+}
+
+fn default_window_ms() -> u64 {
+    1000
+}
+
+fn default_max_messages() -> usize {
+    100
+}
+
+/// One `transcode` action: codec names from the `CodecRegistry`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecStep {
+    pub decode: Option<String>,
+    pub encode: Option<String>,
+}
+
+/// What the matching rules decided for a message
+#[derive(Debug, Default, PartialEq)]
+pub struct RuleOutcome {
+    pub drop: bool,
+    /// Topic to forward under instead of the original
+    pub topic: Option<String>,
+    /// Brokers to forward to instead of the routed ones
+    pub brokers: Option<Vec<String>>,
+    /// Extra topics to forward copies under
+    pub copies: Vec<String>,
+    /// Codec steps applied to the payload, in order, before it is forwarded
+    pub codec_steps: Vec<CodecStep>,
+    /// Aggregates to add the message to instead of forwarding it
+    pub aggregates: Vec<AggregateStep>,
+    /// Where the forwarded message carries the proxy's `traceparent`
+    pub trace_injection: TraceInjection,
+}
+
+/// `inject_traceparent` actions of the matching rules
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceInjection {
+    /// As a user property, even if `[opentelemetry] propagate` is off
+    pub property: bool,
+    /// Fields of JSON object payloads
+    pub fields: Vec<String>,
+}
+
+impl Rule {
+    /// Rule IDs must be unique and broker and codec names known
+    pub fn validate_all(
+        rules: &[Rule],
+        known_brokers: &[String],
+        known_codecs: &[String],
+    ) -> Result<()> {
+        for (index, rule) in rules.iter().enumerate() {
+            if rule.id.is_empty() {
+                bail!("Rule {} has no id", index + 1);
+            }
+            if rules[..index].iter().any(|other| other.id == rule.id) {
+                bail!("Duplicate rule id '{}'", rule.id);
+            }
+            if rule.topic.is_empty() {
+                bail!("Rule '{}' has no topic pattern", rule.id);
+            }
+            if rule.actions.is_empty() {
+                bail!("Rule '{}' has no actions", rule.id);
+            }
+            for action in &rule.actions {
+                match action {
+                    RuleAction::Forward { brokers } => {
+                        if let Some(unknown) = brokers.iter().find(|id| !known_brokers.contains(id))
+                        {
+                            bail!(
+                                "Rule '{}' forwards to unknown broker '{}'",
+                                rule.id,
+                                unknown
+                            );
+                        }
+                    }
+                    RuleAction::RewriteTopic { topic }
+                    | RuleAction::Copy { topic }
+                    | RuleAction::Aggregate { topic, .. } => {
+                        if topic.is_empty() || topic.contains(['+', '#']) {
+                            bail!("Rule '{}' has an invalid target topic '{}'", rule.id, topic);
+                        }
+                    }
+                    RuleAction::Drop => {}
+                    RuleAction::InjectTraceparent { field } => {
+                        if field.as_deref().is_some_and(str::is_empty) {
+                            bail!(
+                                "Rule '{}' injects the traceparent into an empty field",
+                                rule.id
+                            );
+                        }
+                    }
+                    RuleAction::Transcode { decode, encode } => {
+                        if decode.is_none() && encode.is_none() {
+                            bail!("Rule '{}' has a transcode action without codecs", rule.id);
+                        }
+                        if let Some(unknown) = [decode, encode]
+                            .into_iter()
+                            .flatten()
+                            .find(|name| !known_codecs.contains(name))
+                        {
+                            bail!("Rule '{}' uses unknown codec '{}'", rule.id, unknown);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply `rules` to a message
+pub fn evaluate(rules: &[Rule], topic: &str, payload: &[u8]) -> RuleOutcome {
+    let mut outcome = RuleOutcome::default();
+    // Parsed once, and only if a matching rule has a condition
+    let mut document: Option<Option<Value>> = None;
+    for rule in rules.iter().filter(|rule| rule.enabled) {
+        let Some(captures) = wildcard_captures(&rule.topic, topic) else {
+            continue;
+        };
+        if let Some(condition) = &rule.condition {
+            let document =
+                document.get_or_insert_with(|| serde_json::from_slice::<Value>(payload).ok());
+            if !document
+                .as_ref()
+                .is_some_and(|document| condition.matches(document))
+            {
+                continue;
+            }
+        }
+        for action in &rule.actions {
+            match action {
+                RuleAction::Forward { brokers } => {
+                    let targets = outcome.brokers.get_or_insert_with(Vec::new);
+                    for broker in brokers {
+                        if !targets.contains(broker) {
+                            targets.push(broker.clone());
+                        }
+                    }
+                }
+                RuleAction::RewriteTopic { topic } => {
+                    outcome.topic = Some(substitute(topic, &captures))
+                }
+                RuleAction::Drop => outcome.drop = true,
+                RuleAction::Copy { topic } => outcome.copies.push(substitute(topic, &captures)),
+                RuleAction::Transcode { decode, encode } => outcome.codec_steps.push(CodecStep {
+                    decode: decode.clone(),
+                    encode: encode.clone(),
+                }),
+                RuleAction::InjectTraceparent { field: None } => {
+                    outcome.trace_injection.property = true
+                }
+                RuleAction::InjectTraceparent { field: Some(field) } => {
+                    if !outcome.trace_injection.fields.contains(field) {
+                        outcome.trace_injection.fields.push(field.clone());
+                    }
+                }
+                RuleAction::Aggregate {
+                    topic,
+                    window_ms,
+                    max_messages,
+                    format,
+                } => outcome.aggregates.push(AggregateStep {
+                    topic: substitute(topic, &captures),
+                    window_ms: *window_ms,
+                    max_messages: *max_messages,
+                    format: *format,
+                }),
+            }
+        }
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(json: serde_json::Value) -> Vec<Rule> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_matching_rules_combine() {
+        let rules = rules(serde_json::json!([
+            {"id": "low-battery", "topic": "sensors/+/status", "condition": "$.battery < 20",
+             "actions": [{"type": "copy", "topic": "alerts/{1}/battery"},
+                         {"type": "forward", "brokers": ["ops"]}]},
+            {"id": "rename", "topic": "sensors/#",
+             "actions": [{"type": "rewrite_topic", "topic": "site-a/{1}"}]},
+            {"id": "debug", "topic": "debug/#", "actions": [{"type": "drop"}]},
+            {"id": "pack", "topic": "sensors/+/history", "actions": [{"type": "transcode", "encode": "gzip"}]},
+            {"id": "fan-in", "topic": "meters/+/power",
+             "actions": [{"type": "aggregate", "topic": "site-a/meters", "windowMs": 5000}]},
+            {"id": "trace", "topic": "orders/#",
+             "actions": [{"type": "inject_traceparent"},
+                         {"type": "inject_traceparent", "field": "traceparent"}]},
+            {"id": "off", "topic": "#", "enabled": false, "actions": [{"type": "drop"}]}
+        ]));
+        assert_eq!(
+            evaluate(&rules, "sensors/door/status", br#"{"battery": 5}"#),
+            RuleOutcome {
+                drop: false,
+                topic: Some("site-a/door/status".to_string()),
+                brokers: Some(vec!["ops".to_string()]),
+                copies: vec!["alerts/door/battery".to_string()],
+                codec_steps: vec![],
+                aggregates: vec![],
+                trace_injection: TraceInjection::default(),
+            }
+        );
+        assert_eq!(
+            evaluate(&rules, "sensors/door/history", b"[]").codec_steps,
+            vec![CodecStep {
+                decode: None,
+                encode: Some("gzip".to_string()),
+            }]
+        );
+        // Condition not met, or not JSON: only the topic rule applies
+        for payload in [&br#"{"battery": 80}"#[..], b"ok"] {
+            let outcome = evaluate(&rules, "sensors/door/status", payload);
+            assert_eq!(outcome.topic.as_deref(), Some("site-a/door/status"));
+            assert!(outcome.brokers.is_none() && outcome.copies.is_empty());
+        }
+        assert_eq!(
+            evaluate(&rules, "meters/m1/power", b"120").aggregates,
+            vec![AggregateStep {
+                topic: "site-a/meters".to_string(),
+                window_ms: 5000,
+                max_messages: 100,
+                format: AggregateFormat::Array,
+            }]
+        );
+        assert_eq!(
+            evaluate(&rules, "orders/42", b"{}").trace_injection,
+            TraceInjection {
+                property: true,
+                fields: vec!["traceparent".to_string()],
+            }
+        );
+        assert!(evaluate(&rules, "debug/trace", b"").drop);
+        assert_eq!(evaluate(&rules, "lights/hall", b""), RuleOutcome::default());
+
+        let codecs = ["gzip".to_string()];
+        assert!(Rule::validate_all(&rules, &["ops".to_string()], &codecs).is_ok());
+        assert!(Rule::validate_all(&rules, &[], &codecs).is_err());
+        assert!(Rule::validate_all(&rules, &["ops".to_string()], &[]).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//! Runtime introspection for `/api/debug/runtime`
+//!
+//! Slow leaks on long-running edge deployments show up as memory, task counts or queue
+//! depths that keep growing. Resident memory comes from /proc (Linux), heap usage from
+//! `CountingAllocator`, which the binary installs as its global allocator (embedders of the
+//! library may leave it out; heap usage is then not reported), and task counts from the
+//! Tokio runtime's metrics.
+
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+static HEAP_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting live heap bytes
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            HEAP_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        HEAP_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            HEAP_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            HEAP_BYTES.fetch_add(new_size, Ordering::Relaxed);
+            HEAP_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+/// Live heap bytes, if `CountingAllocator` is the global allocator
+pub fn heap_bytes() -> Option<usize> {
+    (ALLOCATIONS.load(Ordering::Relaxed) > 0).then(|| HEAP_BYTES.load(Ordering::Relaxed))
+}
+
+/// Resident set size of the process (Linux only)
+pub fn resident_bytes() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Some(pages * u64::try_from(page_size).ok()?)
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryStats {
+    pub resident_bytes: Option<u64>,
+    pub heap_bytes: Option<usize>,
+    /// Heap allocations since startup
+    pub allocations: Option<u64>,
+}
+
+impl MemoryStats {
+    pub fn current() -> Self {
+        let heap_bytes = heap_bytes();
+        Self {
+            resident_bytes: resident_bytes(),
+            heap_bytes,
+            allocations: heap_bytes.map(|_| ALLOCATIONS.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskStats {
+    pub workers: usize,
+    /// Spawned tasks not finished yet
+    pub alive: usize,
+    /// Tasks waiting in the runtime's shared queue
+    pub global_queue_depth: usize,
+}
+
+impl TaskStats {
+    /// Stats of the runtime the caller runs on
+    pub fn current() -> Self {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        Self {
+            workers: metrics.num_workers(),
+            alive: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueDepth {
+    pub queued: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokerQueues {
+    /// Messages waiting in the broker's send queue
+    pub send: QueueDepth,
+    /// Messages the full send queue refused or evicted
+    pub send_dropped: u64,
+    /// Subscription commands waiting for the broker task
+    pub commands: QueueDepth,
+    /// Messages held while disconnected
+    pub offline_buffered: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheSizes {
+    pub message_history: usize,
+    pub dead_letters: usize,
+    pub retained_messages: usize,
+    pub connected_clients: usize,
+    /// Messages waiting for delivery groups
+    pub delivery_group_pending: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeStats {
+    pub memory: MemoryStats,
+    pub tasks: TaskStats,
+    /// Messages queued for Web UI WebSocket sessions
+    pub ui_broadcast: QueueDepth,
+    pub brokers: BTreeMap<String, BrokerQueues>,
+    pub caches: CacheSizes,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_runtime_stats_are_reported() {
+        let handle = tokio::spawn(std::future::pending::<()>());
+        let tasks = TaskStats::current();
+        assert_eq!(tasks.workers, 2);
+        assert!(tasks.alive >= 1);
+        handle.abort();
+
+        // The test binary keeps the system allocator
+        let memory = MemoryStats::current();
+        assert!(memory.heap_bytes.is_none() && memory.allocations.is_none());
+        #[cfg(target_os = "linux")]
+        assert!(memory.resident_bytes.unwrap() > 0);
+    }
+}
@@ -0,0 +1,190 @@
+//! Decimation of high-volume topics towards a downstream broker
+//!
+//! Analytics brokers often only need a thinned-out stream. A sampling rule either
+//! forwards one in N messages per topic, or at most M messages per second per topic
+//! ("keep last": the newest message held back in a second is sent when the next second
+//! starts, so the broker always ends up with the latest value). Sampling only affects the
+//! broker the rule is configured on; other brokers and local subscribers see every message.
+
+use crate::connection_manager::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Topics tracked per broker; messages on further topics are forwarded unsampled
+const MAX_SAMPLED_TOPICS: usize = 10_000;
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplingRule {
+    /// Topic pattern the rule applies to (`+`/`#` wildcards)
+    pub topic: String,
+    /// Forward only every Nth message per topic
+    #[serde(default)]
+    pub every_nth: Option<u32>,
+    /// Forward at most this many messages per second per topic, keeping the last one
+    #[serde(default)]
+    pub max_per_second: Option<u32>,
+}
+
+struct TopicState<T> {
+    rule: usize,
+    seen: u64,
+    window_start: Instant,
+    sent_in_window: u32,
+    held: Option<T>,
+}
+
+/// Per-topic sampling state for one broker; `T` is the message held back for keep-last
+pub struct Sampler<T> {
+    rules: Vec<SamplingRule>,
+    topics: HashMap<String, TopicState<T>>,
+    /// Number of topics with a held-back message
+    held: usize,
+}
+
+impl<T> Sampler<T> {
+    pub fn new(rules: Vec<SamplingRule>) -> Self {
+        Self {
+            rules,
+            topics: HashMap::new(),
+            held: 0,
+        }
+    }
+
+    /// Returns the message if it should be forwarded now; otherwise it is dropped or
+    /// held back until `next_release`
+    pub fn offer(&mut self, topic: &str, message: T, now: Instant) -> Option<T> {
+        if self.rules.is_empty() {
+            return Some(message);
+        }
+        if !self.topics.contains_key(topic) {
+            let Some(rule) = self.rules.iter().position(|rule| {
+                !rule.topic.is_empty()
+                    && ConnectionManager::topic_matches_pattern(&rule.topic, topic)
+            }) else {
+                return Some(message);
+            };
+            if self.topics.len() >= MAX_SAMPLED_TOPICS {
+                return Some(message);
+            }
+            self.topics.insert(
+                topic.to_string(),
+                TopicState {
+                    rule,
+                    seen: 0,
+                    window_start: now,
+                    sent_in_window: 0,
+                    held: None,
+                },
+            );
+        }
+        let state = self.topics.get_mut(topic).expect("state was just inserted");
+        let rule = &self.rules[state.rule];
+
+        state.seen += 1;
+        if let Some(n) = rule.every_nth.filter(|n| *n > 1) {
+            if !(state.seen - 1).is_multiple_of(n as u64) {
+                return None;
+            }
+        }
+
+        let Some(max) = rule.max_per_second else {
+            return Some(message);
+        };
+        if now.duration_since(state.window_start) >= RATE_WINDOW {
+            state.window_start = now;
+            state.sent_in_window = 0;
+        }
+        if state.sent_in_window < max {
+            state.sent_in_window += 1;
+            // A newer message supersedes the held one
+            if state.held.take().is_some() {
+                self.held -= 1;
+            }
+            Some(message)
+        } else {
+            if state.held.replace(message).is_none() {
+                self.held += 1;
+            }
+            None
+        }
+    }
+
+    /// When the earliest held-back message is due
+    pub fn next_release(&self) -> Option<Instant> {
+        if self.held == 0 {
+            return None;
+        }
+        self.topics
+            .values()
+            .filter(|state| state.held.is_some())
+            .map(|state| state.window_start + RATE_WINDOW)
+            .min()
+    }
+
+    /// Held-back messages whose window has ended; each starts a new window
+    pub fn release_due(&mut self, now: Instant) -> Vec<T> {
+        let mut released = Vec::new();
+        if self.held == 0 {
+            return released;
+        }
+        for state in self.topics.values_mut() {
+            if state.held.is_some() && now.duration_since(state.window_start) >= RATE_WINDOW {
+                state.window_start = now;
+                state.sent_in_window = 1;
+                released.extend(state.held.take());
+            }
+        }
+        self.held -= released.len();
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(topic: &str, every_nth: Option<u32>, max_per_second: Option<u32>) -> SamplingRule {
+        SamplingRule {
+            topic: topic.to_string(),
+            every_nth,
+            max_per_second,
+        }
+    }
+
+    #[test]
+    fn test_every_nth_per_topic() {
+        let mut sampler = Sampler::new(vec![rule("sensors/#", Some(3), None)]);
+        let now = Instant::now();
+
+        let forwarded: Vec<u32> = (0..7)
+            .filter_map(|i| sampler.offer("sensors/a", i, now))
+            .collect();
+        assert_eq!(forwarded, vec![0, 3, 6]);
+        // Each topic is decimated on its own; unmatched topics pass through
+        assert_eq!(sampler.offer("sensors/b", 0, now), Some(0));
+        assert_eq!(sampler.offer("status", 1, now), Some(1));
+    }
+
+    #[test]
+    fn test_max_per_second_keeps_last() {
+        let mut sampler = Sampler::new(vec![rule("sensors/+", None, Some(2))]);
+        let start = Instant::now();
+
+        assert_eq!(sampler.offer("sensors/a", 1, start), Some(1));
+        assert_eq!(sampler.offer("sensors/a", 2, start), Some(2));
+        assert_eq!(sampler.offer("sensors/a", 3, start), None);
+        assert_eq!(sampler.offer("sensors/a", 4, start), None);
+        assert_eq!(sampler.next_release(), Some(start + RATE_WINDOW));
+        assert!(sampler.release_due(start).is_empty());
+
+        let later = start + RATE_WINDOW;
+        assert_eq!(sampler.release_due(later), vec![4]);
+        assert_eq!(sampler.next_release(), None);
+        // The released message counts towards the new window
+        assert_eq!(sampler.offer("sensors/a", 5, later), Some(5));
+        assert_eq!(sampler.offer("sensors/a", 6, later), None);
+    }
+}
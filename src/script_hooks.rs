@@ -0,0 +1,290 @@
+//! Script hooks for message processing
+//!
+//! Custom logic such as enrichment or suppression runs in a [Rhai](https://rhai.rs)
+//! script instead of the proxy: `[scripting] script` names a file that defines
+//! `on_client_publish` and/or `on_broker_message`, so changing the logic needs no
+//! rebuild. `on_client_publish` sees publishes from clients connected to the proxy,
+//! `on_broker_message` messages from the main broker; both run before anything is
+//! forwarded or shown in the Web UI.
+//!
+//! ```text
+//! fn on_client_publish(msg) {      // msg: #{ topic, payload, retain, client_id }
+//!     if msg.topic.starts_with("debug/") { return (); }   // () drops the message
+//!     msg.topic = "site-a/" + msg.topic;
+//!     msg                                                 // forwards it as changed
+//! }
+//! ```
+//!
+//! `payload` is a string, or a blob if the payload isn't UTF-8; the script may return
+//! either. `client_id` is `()` for broker messages. The script runs in the proxy on the
+//! calling task, concurrently for all messages; each call may take `max_operations`
+//! steps, so a runaway loop is stopped instead of holding up forwarding. A failing call
+//! forwards the message unchanged, or drops it if `fail_open` is off.
+
+use crate::config::ScriptingConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptHook {
+    OnClientPublish,
+    OnBrokerMessage,
+}
+
+impl ScriptHook {
+    const ALL: [ScriptHook; 2] = [ScriptHook::OnClientPublish, ScriptHook::OnBrokerMessage];
+
+    /// The script function implementing the hook
+    fn function(self) -> &'static str {
+        match self {
+            ScriptHook::OnClientPublish => "on_client_publish",
+            ScriptHook::OnBrokerMessage => "on_broker_message",
+        }
+    }
+}
+
+pub struct ScriptHooks {
+    engine: Engine,
+    ast: AST,
+    /// Hooks the script defines a function for
+    hooks: Vec<ScriptHook>,
+    fail_open: bool,
+}
+
+impl ScriptHooks {
+    /// Compile the script, so a missing file or syntax error shows at startup
+    pub fn start(config: ScriptingConfig) -> Result<Self> {
+        let source = std::fs::read_to_string(&config.script)
+            .with_context(|| format!("Failed to read script '{}'", config.script))?;
+        let hooks = Self::compile(&source, &config)
+            .with_context(|| format!("Failed to load script '{}'", config.script))?;
+        info!(
+            "Loaded script '{}' for hooks {:?}",
+            config.script, hooks.hooks
+        );
+        Ok(hooks)
+    }
+
+    fn compile(source: &str, config: &ScriptingConfig) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        engine.on_print(|text| info!("Script: {}", text));
+        engine.on_debug(|text, _, _| debug!("Script: {}", text));
+        let ast = engine.compile(source).map_err(|e| anyhow!("{}", e))?;
+
+        let hooks: Vec<ScriptHook> = ScriptHook::ALL
+            .into_iter()
+            .filter(|hook| {
+                ast.iter_functions()
+                    .any(|f| f.name == hook.function() && f.params.len() == 1)
+            })
+            .collect();
+        if hooks.is_empty() {
+            bail!("Script defines neither on_client_publish(msg) nor on_broker_message(msg)");
+        }
+        Ok(Self {
+            engine,
+            ast,
+            hooks,
+            fail_open: config.fail_open,
+        })
+    }
+
+    /// Pass a message through `hook`: the topic and payload to continue with, or `None`
+    /// if it is dropped
+    pub fn run(
+        &self,
+        hook: ScriptHook,
+        topic: &str,
+        payload: Bytes,
+        retain: bool,
+        client_id: Option<&str>,
+    ) -> Option<(String, Bytes)> {
+        if !self.hooks.contains(&hook) {
+            return Some((topic.to_string(), payload));
+        }
+        let mut message = Map::new();
+        message.insert("topic".into(), Dynamic::from(topic.to_string()));
+        message.insert(
+            "payload".into(),
+            match std::str::from_utf8(&payload) {
+                Ok(text) => Dynamic::from(text.to_string()),
+                Err(_) => Dynamic::from_blob(payload.to_vec()),
+            },
+        );
+        message.insert("retain".into(), Dynamic::from(retain));
+        message.insert(
+            "client_id".into(),
+            client_id.map_or(Dynamic::UNIT, |id| Dynamic::from(id.to_string())),
+        );
+
+        let reply = self
+            .engine
+            .call_fn::<Dynamic>(
+                &mut Scope::new(),
+                &self.ast,
+                hook.function(),
+                (Dynamic::from_map(message),),
+            )
+            .map_err(|e| anyhow!("{}", e))
+            .and_then(|reply| hook_result(reply, topic, &payload));
+        match reply {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Script hook failed on '{}': {:#}", topic, e);
+                self.fail_open.then(|| (topic.to_string(), payload))
+            }
+        }
+    }
+}
+
+/// What a hook function returned: `()` to drop the message, or the message to forward,
+/// where a missing `topic` or `payload` keeps the original
+fn hook_result(reply: Dynamic, topic: &str, payload: &Bytes) -> Result<Option<(String, Bytes)>> {
+    if reply.is_unit() {
+        return Ok(None);
+    }
+    let type_name = reply.type_name();
+    let Some(mut message) = reply.try_cast::<Map>() else {
+        bail!(
+            "Script returned a {} instead of the message or ()",
+            type_name
+        );
+    };
+    let new_topic = match message.remove("topic") {
+        Some(value) => value
+            .into_string()
+            .map_err(|kind| anyhow!("Script returned a {} as the topic", kind))?,
+        None => topic.to_string(),
+    };
+    if new_topic.is_empty() || new_topic.contains(['+', '#']) {
+        bail!("Script returned an invalid topic '{}'", new_topic);
+    }
+    let new_payload = match message.remove("payload") {
+        None => payload.clone(),
+        Some(value) if value.is_blob() => {
+            Bytes::from(value.into_blob().map_err(anyhow::Error::msg)?)
+        }
+        Some(value) => Bytes::from(
+            value
+                .into_string()
+                .map_err(|kind| anyhow!("Script returned a {} as the payload", kind))?,
+        ),
+    };
+    Ok(Some((new_topic, new_payload)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hooks(script: &str, fail_open: bool) -> ScriptHooks {
+        ScriptHooks::compile(
+            script,
+            &ScriptingConfig {
+                script: "test.rhai".to_string(),
+                max_operations: 10_000,
+                fail_open,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_script_drops_and_modifies_messages() {
+        let hooks = hooks(
+            r#"
+            fn on_client_publish(msg) {
+                if msg.topic.starts_with("debug/") { return (); }
+                if msg.topic.starts_with("rename/") {
+                    msg.topic = "renamed";
+                    msg.payload = "hi from " + msg.client_id;
+                }
+                msg
+            }
+            "#,
+            true,
+        );
+        let hook = ScriptHook::OnClientPublish;
+        let payload = Bytes::from_static(b"21.5");
+
+        assert_eq!(
+            hooks.run(hook, "sensors/1", payload.clone(), false, Some("dev")),
+            Some(("sensors/1".to_string(), payload.clone()))
+        );
+        assert_eq!(
+            hooks.run(hook, "debug/1", payload.clone(), false, None),
+            None
+        );
+        assert_eq!(
+            hooks.run(hook, "rename/1", payload.clone(), false, Some("dev")),
+            Some(("renamed".to_string(), Bytes::from_static(b"hi from dev")))
+        );
+        // Hooks the script doesn't define pass messages unchanged
+        assert!(hooks
+            .run(ScriptHook::OnBrokerMessage, "debug/1", payload, false, None)
+            .is_some());
+    }
+
+    #[test]
+    fn test_binary_payloads_are_blobs() {
+        let hooks = hooks(
+            r#"
+            fn on_broker_message(msg) {
+                msg.payload.push(0xff);
+                msg
+            }
+            "#,
+            true,
+        );
+        assert_eq!(
+            hooks.run(
+                ScriptHook::OnBrokerMessage,
+                "a",
+                Bytes::from_static(b"\xff\x00"),
+                false,
+                None
+            ),
+            Some(("a".to_string(), Bytes::from_static(b"\xff\x00\xff")))
+        );
+    }
+
+    #[test]
+    fn test_runaway_script_is_stopped() {
+        let script = r#"
+            fn on_client_publish(msg) {
+                loop { }
+            }
+        "#;
+        let payload = Bytes::from_static(b"1");
+        let open = hooks(script, true);
+        assert_eq!(
+            open.run(
+                ScriptHook::OnClientPublish,
+                "a",
+                payload.clone(),
+                false,
+                None
+            ),
+            Some(("a".to_string(), payload.clone()))
+        );
+        let closed = hooks(script, false);
+        assert_eq!(
+            closed.run(ScriptHook::OnClientPublish, "a", payload, false, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_script_without_hooks_is_rejected() {
+        let config = ScriptingConfig {
+            script: "test.rhai".to_string(),
+            max_operations: 10_000,
+            fail_open: true,
+        };
+        assert!(ScriptHooks::compile("fn other(msg) { msg }", &config).is_err());
+        assert!(ScriptHooks::compile("fn on_client_publish(msg) {", &config).is_err());
+    }
+}
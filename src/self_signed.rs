@@ -0,0 +1,114 @@
+//! Self-signed certificates for lab deployments
+//!
+//! With `tls_self_signed` set and no `tls_cert_path`/`tls_key_path`, the TLS listener and
+//! the HTTPS Web UI use `self-signed.crt` and `self-signed.key` in the configured directory,
+//! generating them on first start. The pair is kept, so clients that pinned or trusted the
+//! certificate keep working across restarts; delete the files to issue a new one (e.g. for
+//! other hostnames). Both servers share the pair when they use the same directory.
+
+use crate::config::SelfSignedConfig;
+use anyhow::{bail, Context, Result};
+use rcgen::{Certificate, CertificateParams, DnType};
+use std::path::Path;
+use tracing::info;
+
+const CERT_FILE: &str = "self-signed.crt";
+const KEY_FILE: &str = "self-signed.key";
+const COMMON_NAME: &str = "mqtt-proxy self-signed";
+
+/// Certificate and key paths of a TLS server: the configured ones, or the self-signed pair
+/// when neither is configured. `what` names the server in errors.
+pub fn cert_and_key(
+    cert_path: Option<&str>,
+    key_path: Option<&str>,
+    self_signed: Option<&SelfSignedConfig>,
+    what: &str,
+) -> Result<(String, String)> {
+    match (cert_path, key_path, self_signed) {
+        (Some(cert_path), Some(key_path), _) => Ok((cert_path.to_string(), key_path.to_string())),
+        (None, None, Some(self_signed)) => ensure(self_signed),
+        (None, _, _) => bail!("TLS for the {} requires tls_cert_path", what),
+        (_, None, _) => bail!("TLS for the {} requires tls_key_path", what),
+    }
+}
+
+/// Paths of the self-signed pair in `config.dir`, generated if it doesn't exist yet
+pub fn ensure(config: &SelfSignedConfig) -> Result<(String, String)> {
+    let dir = Path::new(&config.dir);
+    let cert_path = dir.join(CERT_FILE);
+    let key_path = dir.join(KEY_FILE);
+    let paths = (
+        cert_path.to_string_lossy().into_owned(),
+        key_path.to_string_lossy().into_owned(),
+    );
+    if cert_path.exists() && key_path.exists() {
+        return Ok(paths);
+    }
+
+    anyhow::ensure!(
+        !config.hostnames.is_empty(),
+        "tls_self_signed needs at least one hostname"
+    );
+    let mut params = CertificateParams::new(config.hostnames.clone());
+    params
+        .distinguished_name
+        .push(DnType::CommonName, COMMON_NAME);
+    let cert = Certificate::from_params(params).context("Failed to generate certificate")?;
+    let cert_pem = cert
+        .serialize_pem()
+        .context("Failed to encode certificate")?;
+
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory: {:?}", dir))?;
+    write_private(&key_path, cert.serialize_private_key_pem().as_bytes())?;
+    std::fs::write(&cert_path, cert_pem)
+        .with_context(|| format!("Failed to write {:?}", cert_path))?;
+    info!(
+        "Generated self-signed certificate {:?} for {}",
+        cert_path,
+        config.hostnames.join(", ")
+    );
+    Ok(paths)
+}
+
+/// Write a file only the owner can read
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    std::io::Write::write_all(&mut file, contents)
+        .with_context(|| format!("Failed to write {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener_tls::{load_certs, load_private_key, peer_common_name};
+
+    #[test]
+    fn test_self_signed_pair_is_generated_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SelfSignedConfig {
+            dir: dir.path().join("tls").to_string_lossy().into_owned(),
+            hostnames: vec!["proxy.lab".to_string(), "10.0.0.5".to_string()],
+        };
+
+        let (cert_path, key_path) = cert_and_key(None, None, Some(&config), "listener").unwrap();
+        let certs = load_certs(&cert_path).unwrap();
+        assert_eq!(peer_common_name(&certs[0]), Some(COMMON_NAME.to_string()));
+        load_private_key(&key_path).unwrap();
+
+        let (again, _) = ensure(&config).unwrap();
+        assert_eq!(load_certs(&again).unwrap(), certs);
+
+        assert_eq!(
+            cert_and_key(Some("a.crt"), Some("a.key"), Some(&config), "listener").unwrap(),
+            ("a.crt".to_string(), "a.key".to_string())
+        );
+        assert!(cert_and_key(Some("a.crt"), None, None, "listener").is_err());
+    }
+}
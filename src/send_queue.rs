@@ -0,0 +1,222 @@
+//! Bounded publish queue in front of each broker task
+//!
+//! Forwarding hands a message to each target broker's queue and moves on; the broker task
+//! drains its queue at its own pace, so a slow broker doesn't hold up the listener or the
+//! main broker client. When a broker falls behind and its queue fills up, the broker's
+//! `sendQueue.policy` decides what gives:
+//!
+//! - `drop_new`: the incoming message is refused and dead-lettered as a publish timeout
+//! - `drop_oldest`: the message that has waited longest is evicted to make room
+//! - `block`: forwarding waits for room, up to the publish timeout, which slows down the
+//!   client publishing to the proxy instead of losing messages
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    #[default]
+    DropNew,
+    DropOldest,
+    Block,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendQueueConfig {
+    /// Messages the queue holds; the resource profile's default when unset
+    #[serde(default)]
+    pub capacity: Option<usize>,
+    #[serde(default)]
+    pub policy: QueuePolicy,
+}
+
+/// Result of adding a message to the queue
+#[derive(Debug, PartialEq)]
+pub enum Pushed<T> {
+    Queued,
+    /// Queued after evicting the oldest message (`drop_oldest`)
+    Evicted(T),
+    /// The queue is full (`drop_new`, or `block` once the wait timed out)
+    Full(T),
+    /// The broker task has stopped
+    Closed(T),
+}
+
+pub struct SendQueue<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    policy: QueuePolicy,
+    /// Wakes the broker task when a message arrives
+    pushed: Notify,
+    /// Wakes blocked forwarders when a message is taken
+    popped: Notify,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+    high_water: AtomicUsize,
+}
+
+impl<T> SendQueue<T> {
+    pub fn new(capacity: usize, policy: QueuePolicy) -> Self {
+        Self {
+            items: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            policy,
+            pushed: Notify::new(),
+            popped: Notify::new(),
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> QueuePolicy {
+        self.policy
+    }
+
+    /// Add a message without waiting. Under `block` a full queue returns `Full`, and the
+    /// caller may wait with `push_wait`.
+    pub fn try_push(&self, item: T) -> Pushed<T> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Pushed::Closed(item);
+        }
+        let mut items = self.items.lock();
+        let evicted = if items.len() < self.capacity {
+            None
+        } else if self.policy == QueuePolicy::DropOldest {
+            items.pop_front()
+        } else {
+            if self.policy == QueuePolicy::DropNew {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            return Pushed::Full(item);
+        };
+        items.push_back(item);
+        self.high_water.fetch_max(items.len(), Ordering::Relaxed);
+        drop(items);
+        self.pushed.notify_one();
+        match evicted {
+            Some(evicted) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Pushed::Evicted(evicted)
+            }
+            None => Pushed::Queued,
+        }
+    }
+
+    /// Add a message, waiting up to `timeout` for room in a full queue
+    pub async fn push_wait(&self, mut item: T, timeout: Duration) -> Pushed<T> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let popped = self.popped.notified();
+            match self.try_push(item) {
+                Pushed::Full(refused) => item = refused,
+                pushed => return pushed,
+            }
+            if tokio::time::timeout_at(deadline, popped).await.is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Pushed::Full(item);
+            }
+        }
+    }
+
+    /// Take the oldest message, waiting for one if the queue is empty
+    pub async fn pop(&self) -> T {
+        loop {
+            let pushed = self.pushed.notified();
+            if let Some(item) = self.items.lock().pop_front() {
+                self.popped.notify_one();
+                return item;
+            }
+            pushed.await;
+        }
+    }
+
+    /// Refuse further messages; those already queued stay for `drain`
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.popped.notify_waiters();
+    }
+
+    /// Remove and return every queued message
+    pub fn drain(&self) -> Vec<T> {
+        let items: Vec<T> = self.items.lock().drain(..).collect();
+        self.popped.notify_waiters();
+        items
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.lock().is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Messages refused or evicted because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Deepest the queue has been
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_full_queue_policies() {
+        let queue = SendQueue::new(2, QueuePolicy::DropNew);
+        assert_eq!(queue.try_push(1), Pushed::Queued);
+        assert_eq!(queue.try_push(2), Pushed::Queued);
+        assert_eq!(queue.try_push(3), Pushed::Full(3));
+        assert_eq!((queue.len(), queue.dropped()), (2, 1));
+
+        let queue = SendQueue::new(2, QueuePolicy::DropOldest);
+        queue.try_push(1);
+        queue.try_push(2);
+        assert_eq!(queue.try_push(3), Pushed::Evicted(1));
+        assert_eq!(queue.drain(), vec![2, 3]);
+        assert_eq!((queue.dropped(), queue.high_water()), (1, 2));
+
+        queue.close();
+        assert_eq!(queue.try_push(4), Pushed::Closed(4));
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_room() {
+        let queue = Arc::new(SendQueue::new(1, QueuePolicy::Block));
+        queue.try_push(1);
+        assert_eq!(
+            queue.push_wait(2, Duration::from_millis(20)).await,
+            Pushed::Full(2)
+        );
+
+        let consumer = Arc::clone(&queue);
+        let popped = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            consumer.pop().await
+        });
+        assert_eq!(
+            queue.push_wait(3, Duration::from_secs(5)).await,
+            Pushed::Queued
+        );
+        assert_eq!(popped.await.unwrap(), 1);
+        assert_eq!(queue.pop().await, 3);
+        assert_eq!(queue.dropped(), 1);
+    }
+}
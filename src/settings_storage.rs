@@ -1,12 +1,20 @@
+use crate::acl::{AclTable, ClientAcl};
+use crate::config::StorageConfig;
 use crate::crypto::{decrypt_password, encrypt_password};
+use crate::debounced_write::DebouncedWriter;
+use crate::remote_config::HttpSettingsStorage;
+use crate::routing::RoutingTable;
+use crate::rules::Rule;
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tracing::{error, info, warn};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MainBrokerSettings {
     pub address: String,
@@ -16,6 +24,12 @@ pub struct MainBrokerSettings {
     pub username: Option<String>,
     #[serde(default)]
     pub password: Option<String>,
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u8,
+}
+
+fn default_protocol_version() -> u8 {
+    crate::broker_client::PROTOCOL_V4
 }
 
 impl MainBrokerSettings {
@@ -57,15 +71,101 @@ impl MainBrokerSettings {
 struct SettingsStore {
     #[serde(default)]
     main_broker: Option<MainBrokerSettings>,
+    /// Topic ACLs for listener clients
+    #[serde(default)]
+    acls: Vec<ClientAcl>,
+    /// Routing by topic level (see `routing`)
+    #[serde(default)]
+    routing: Option<RoutingTable>,
+    /// Forwarding rules (see `rules`)
+    #[serde(default)]
+    rules: Vec<Rule>,
+    /// Filters consumers on the main broker want from bidirectional brokers
+    #[serde(default)]
+    main_demand: Vec<String>,
 }
 
-pub struct SettingsStorage {
-    store_path: PathBuf,
+/// Where the main broker settings, ACLs, routing table, forwarding rules and main broker
+/// demand are kept: a local file (`FileSettingsStorage`) or a central management service
+/// (`HttpSettingsStorage`, see `remote_config`)
+#[async_trait]
+pub trait SettingsStorage: Send + Sync {
+    /// Returns main broker settings with decrypted password (for internal use)
+    async fn get_main_broker(&self) -> Option<MainBrokerSettings>;
+
+    /// Returns main broker settings with hidden password (for API responses)
+    async fn get_main_broker_for_api(&self) -> Option<MainBrokerSettings>;
+
+    /// Save main broker settings (encrypts password before storing)
+    async fn set_main_broker(&self, settings: MainBrokerSettings) -> Result<()>;
+
+    /// ACLs as enforced by the listener (see `MqttListenerServer::with_acl`)
+    fn acl_table(&self) -> Arc<AclTable>;
+
+    /// Create or replace the ACL for `acl.identity`
+    async fn set_acl(&self, acl: ClientAcl) -> Result<()>;
+
+    /// Remove the ACL for `identity`; returns false if there was none
+    async fn remove_acl(&self, identity: &str) -> Result<bool>;
+
+    async fn get_routing(&self) -> Option<RoutingTable>;
+
+    /// Save the routing table; `None` turns routing by topic level off
+    async fn set_routing(&self, routing: Option<RoutingTable>) -> Result<()>;
+
+    async fn get_main_demand(&self) -> Vec<String>;
+
+    /// Replace the filters declared for consumers on the main broker
+    async fn set_main_demand(&self, filters: Vec<String>) -> Result<()>;
+
+    async fn get_rules(&self) -> Vec<Rule>;
+
+    /// Replace the forwarding rules
+    async fn set_rules(&self, rules: Vec<Rule>) -> Result<()>;
+
+    /// Pick up changes made elsewhere; true if the settings changed
+    async fn refresh(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Write changes still waiting for the flush interval
+    fn flush(&self) -> Result<()>;
+}
+
+/// Create the storage selected in the configuration
+pub async fn build_settings_storage(config: &StorageConfig) -> Result<Arc<dyn SettingsStorage>> {
+    let flush_interval = Duration::from_millis(config.flush_interval_ms);
+    let file =
+        FileSettingsStorage::with_flush_interval(&config.settings_store_path, flush_interval)?;
+    match config
+        .remote
+        .as_ref()
+        .filter(|remote| remote.settings_url.is_some())
+    {
+        Some(remote) => Ok(Arc::new(HttpSettingsStorage::open(remote, file).await?)),
+        None => Ok(Arc::new(file)),
+    }
+}
+
+/// Settings kept in a JSON file, written through `debounced_write`
+pub struct FileSettingsStorage {
+    writer: DebouncedWriter,
     store: Arc<RwLock<SettingsStore>>,
+    /// Live copy of the stored ACLs, consulted by the listener on every packet
+    acl_table: Arc<AclTable>,
 }
 
-impl SettingsStorage {
+impl FileSettingsStorage {
     pub fn new<P: AsRef<Path>>(store_path: P) -> Result<Self> {
+        Self::with_flush_interval(store_path, Duration::ZERO)
+    }
+
+    /// Like `new`, but changes are written at most once per `flush_interval` (see
+    /// `debounced_write`); call `flush` before exiting
+    pub fn with_flush_interval<P: AsRef<Path>>(
+        store_path: P,
+        flush_interval: Duration,
+    ) -> Result<Self> {
         let store_path = store_path.as_ref().to_path_buf();
 
         // Create directory if it doesn't exist
@@ -89,13 +189,37 @@ impl SettingsStorage {
         };
 
         Ok(Self {
-            store_path,
+            writer: DebouncedWriter::new(store_path, flush_interval),
+            acl_table: Arc::new(AclTable::new(store.acls.clone())),
             store: Arc::new(RwLock::new(store)),
         })
     }
 
-    /// Returns main broker settings with decrypted password (for internal use)
-    pub async fn get_main_broker(&self) -> Option<MainBrokerSettings> {
+    /// Replace all settings with those in `contents`, JSON in the format of the store file
+    /// (e.g. as fetched by `HttpSettingsStorage`)
+    pub async fn replace(&self, contents: &str) -> Result<()> {
+        let mut replacement: SettingsStore =
+            serde_json::from_str(contents).context("Invalid settings")?;
+        replacement.main_broker = replacement
+            .main_broker
+            .map(|settings| settings.with_encrypted_password());
+        self.acl_table.replace_all(replacement.acls.clone());
+        *self.store.write().await = replacement;
+        self.save().await
+    }
+
+    async fn save(&self) -> Result<()> {
+        let store = self.store.read().await;
+        let json =
+            serde_json::to_string_pretty(&*store).context("Failed to serialize settings store")?;
+        drop(store);
+        self.writer.write(json)
+    }
+}
+
+#[async_trait]
+impl SettingsStorage for FileSettingsStorage {
+    async fn get_main_broker(&self) -> Option<MainBrokerSettings> {
         let store = self.store.read().await;
         store
             .main_broker
@@ -103,14 +227,12 @@ impl SettingsStorage {
             .map(|s| s.with_decrypted_password())
     }
 
-    /// Returns main broker settings with hidden password (for API responses)
-    pub async fn get_main_broker_for_api(&self) -> Option<MainBrokerSettings> {
+    async fn get_main_broker_for_api(&self) -> Option<MainBrokerSettings> {
         let store = self.store.read().await;
         store.main_broker.as_ref().map(|s| s.with_hidden_password())
     }
 
-    /// Save main broker settings (encrypts password before storing)
-    pub async fn set_main_broker(&self, settings: MainBrokerSettings) -> Result<()> {
+    async fn set_main_broker(&self, settings: MainBrokerSettings) -> Result<()> {
         let mut store = self.store.write().await;
 
         // Handle password: if placeholder, keep existing
@@ -134,19 +256,71 @@ impl SettingsStorage {
         Ok(())
     }
 
-    async fn save(&self) -> Result<()> {
-        let store = self.store.read().await;
-        let json =
-            serde_json::to_string_pretty(&*store).context("Failed to serialize settings store")?;
+    fn acl_table(&self) -> Arc<AclTable> {
+        Arc::clone(&self.acl_table)
+    }
 
-        // Write to temp file first, then rename (atomic operation)
-        let temp_path = self.store_path.with_extension("tmp");
-        std::fs::write(&temp_path, json)
-            .with_context(|| format!("Failed to write temp file: {:?}", temp_path))?;
+    async fn set_acl(&self, acl: ClientAcl) -> Result<()> {
+        let mut store = self.store.write().await;
+        store.acls.retain(|a| a.identity != acl.identity);
+        store.acls.push(acl.clone());
+        drop(store);
+
+        self.save().await?;
+        info!("ACL for '{}' saved", acl.identity);
+        self.acl_table.set(acl);
+        Ok(())
+    }
 
-        std::fs::rename(&temp_path, &self.store_path)
-            .with_context(|| format!("Failed to save settings store: {:?}", self.store_path))?;
+    async fn remove_acl(&self, identity: &str) -> Result<bool> {
+        let mut store = self.store.write().await;
+        let before = store.acls.len();
+        store.acls.retain(|a| a.identity != identity);
+        if store.acls.len() == before {
+            return Ok(false);
+        }
+        drop(store);
 
+        self.save().await?;
+        info!("ACL for '{}' removed", identity);
+        self.acl_table.remove(identity);
+        Ok(true)
+    }
+
+    async fn get_routing(&self) -> Option<RoutingTable> {
+        self.store.read().await.routing.clone()
+    }
+
+    async fn set_routing(&self, routing: Option<RoutingTable>) -> Result<()> {
+        self.store.write().await.routing = routing;
+        self.save().await?;
+        info!("Routing table saved");
         Ok(())
     }
+
+    async fn get_main_demand(&self) -> Vec<String> {
+        self.store.read().await.main_demand.clone()
+    }
+
+    async fn set_main_demand(&self, filters: Vec<String>) -> Result<()> {
+        self.store.write().await.main_demand = filters;
+        self.save().await?;
+        info!("Main broker subscription demand saved");
+        Ok(())
+    }
+
+    async fn get_rules(&self) -> Vec<Rule> {
+        self.store.read().await.rules.clone()
+    }
+
+    async fn set_rules(&self, rules: Vec<Rule>) -> Result<()> {
+        self.store.write().await.rules = rules;
+        self.save().await?;
+        info!("Forwarding rules saved");
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer.flush()
+    }
 }
@@ -0,0 +1,246 @@
+//! Sources that originate messages instead of relaying them
+//!
+//! A `MessageSource` is polled on its own interval, and each message it returns is
+//! published through the ingestion path (see `ingest::Ingestor`) with the source's name as
+//! the client ID, so rules, routing and script hooks treat it like a listener client.
+//! Sources are configured as `[[sources]]` entries; `http_json` polls an HTTP endpoint
+//! that returns JSON. Other protocols (Modbus, serial meters) implement the trait and run
+//! with `run_source`.
+
+use crate::config::{HttpJsonSourceConfig, SourceConfig};
+use crate::http_client::{self, HttpUrl};
+use crate::ingest::Ingestor;
+use crate::payload_filter::Condition;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rumqttc::QoS;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// A message produced by a source
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceMessage {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// Something the proxy polls for messages to publish
+#[async_trait]
+pub trait MessageSource: Send + Sync {
+    /// Name in logs, and the client ID its messages are published under
+    fn name(&self) -> &str;
+
+    /// Time between polls
+    fn interval(&self) -> Duration;
+
+    /// The messages of one poll; an error skips this poll
+    async fn poll(&self) -> Result<Vec<SourceMessage>>;
+}
+
+/// Create the sources of the configuration
+pub fn build_sources(configs: &[SourceConfig]) -> Result<Vec<Arc<dyn MessageSource>>> {
+    let mut sources: Vec<Arc<dyn MessageSource>> = Vec::new();
+    for config in configs {
+        let source = match config {
+            SourceConfig::HttpJson(config) => Arc::new(HttpJsonSource::new(config.clone())?),
+        };
+        if sources.iter().any(|other| other.name() == source.name()) {
+            bail!("Duplicate source name '{}'", source.name());
+        }
+        sources.push(source);
+    }
+    Ok(sources)
+}
+
+/// Poll `source` and publish its messages until `shutdown` is cancelled
+pub async fn run_source(
+    source: Arc<dyn MessageSource>,
+    ingestor: Arc<Ingestor>,
+    shutdown: CancellationToken,
+) {
+    info!(
+        "Polling source '{}' every {:?}",
+        source.name(),
+        source.interval()
+    );
+    let mut interval = tokio::time::interval(source.interval().max(Duration::from_secs(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let polled = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            polled = async {
+                interval.tick().await;
+                source.poll().await
+            } => polled,
+        };
+        let messages = match polled {
+            Ok(messages) => messages,
+            Err(e) => {
+                warn!("Polling source '{}' failed: {:#}", source.name(), e);
+                continue;
+            }
+        };
+        debug!(
+            "Source '{}' produced {} messages",
+            source.name(),
+            messages.len()
+        );
+        for message in messages {
+            if let Err(e) = ingestor
+                .publish(&message.topic, message.payload, message.qos, message.retain)
+                .await
+            {
+                warn!(
+                    "Failed to publish message of source '{}' on '{}': {}",
+                    source.name(),
+                    message.topic,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Polls a URL with GET and publishes the JSON response, or the part `select` points to
+pub struct HttpJsonSource {
+    config: HttpJsonSourceConfig,
+    url: HttpUrl,
+    select: Option<Condition>,
+    qos: QoS,
+}
+
+impl HttpJsonSource {
+    pub fn new(config: HttpJsonSourceConfig) -> Result<Self> {
+        let url = HttpUrl::parse(&config.url)
+            .with_context(|| format!("Invalid URL of source '{}'", config.name))?;
+        let select = match &config.select {
+            Some(path) => {
+                let condition = Condition::parse(path)?;
+                if !condition.is_path() {
+                    bail!("select of source '{}' must be a bare path", config.name);
+                }
+                Some(condition)
+            }
+            None => None,
+        };
+        let qos = match config.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            other => bail!("Invalid QoS {} of source '{}'", other, config.name),
+        };
+        if config.topic.is_empty() || config.topic.contains(['+', '#']) {
+            bail!(
+                "Invalid topic '{}' of source '{}'",
+                config.topic,
+                config.name
+            );
+        }
+        Ok(Self {
+            config,
+            url,
+            select,
+            qos,
+        })
+    }
+
+    async fn get(&self) -> Result<Vec<u8>> {
+        let headers = [("Accept", "application/json")].into_iter().chain(
+            self.config
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        http_client::request("GET", &self.url, headers, None).await
+    }
+}
+
+#[async_trait]
+impl MessageSource for HttpJsonSource {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.interval_secs)
+    }
+
+    async fn poll(&self) -> Result<Vec<SourceMessage>> {
+        let timeout = Duration::from_secs(self.config.timeout_secs.max(1));
+        let body = tokio::time::timeout(timeout, self.get())
+            .await
+            .with_context(|| {
+                format!("No response from {} within {:?}", self.config.url, timeout)
+            })??;
+        let document: Value = serde_json::from_slice(&body).context("Response is not JSON")?;
+        let payload = match &self.select {
+            Some(path) => {
+                let selected = path.select(&document).with_context(|| {
+                    format!(
+                        "Response has no {}",
+                        self.config.select.as_deref().unwrap_or_default()
+                    )
+                })?;
+                Bytes::from(serde_json::to_vec(selected)?)
+            }
+            None => Bytes::from(body),
+        };
+        Ok(vec![SourceMessage {
+            topic: self.config.topic.clone(),
+            payload,
+            qos: self.qos,
+            retain: self.config.retain,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_http_json_source_selects_part_of_the_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 1024];
+            let len = socket.read(&mut request).await.unwrap();
+            assert!(request[..len].starts_with(b"GET /meter HTTP/1.1\r\n"));
+            let body = r#"{"meter": {"power": 1250}, "ok": true}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let config: HttpJsonSourceConfig = serde_json::from_value(serde_json::json!({
+            "name": "meter",
+            "url": format!("http://127.0.0.1:{}/meter", port),
+            "topic": "site-a/meter",
+            "select": "$.meter",
+            "qos": 1
+        }))
+        .unwrap();
+        let source = HttpJsonSource::new(config).unwrap();
+        assert_eq!(
+            source.poll().await.unwrap(),
+            vec![SourceMessage {
+                topic: "site-a/meter".to_string(),
+                payload: Bytes::from_static(br#"{"power":1250}"#),
+                qos: QoS::AtLeastOnce,
+                retain: false,
+            }]
+        );
+    }
+}
@@ -0,0 +1,535 @@
+//! In-memory traffic statistics for the Web UI dashboard
+//!
+//! Keeps a ring of per-second buckets (message counts plus a bounded sample of forwarding
+//! latencies) so the API can serve bucketed time series without an external Prometheus.
+
+use crate::metrics::{self, Metrics};
+use chrono::{DateTime, TimeZone, Utc};
+use parking_lot::Mutex;
+use prometheus::IntCounterVec;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How long per-second buckets are kept
+const RETENTION_SECS: i64 = 3600;
+/// Maximum latency samples stored per second (reservoir sampled beyond this)
+const MAX_LATENCY_SAMPLES: usize = 100;
+
+#[derive(Default)]
+struct SecondBucket {
+    second: i64,
+    received: u64,
+    forwarded: u64,
+    dropped: u64,
+    /// Number of latency observations seen in this second (for reservoir sampling)
+    latency_count: u64,
+    latencies_us: Vec<u32>,
+}
+
+/// A single aggregated point of the time series
+#[derive(Debug, Clone, Serialize)]
+pub struct TimeseriesPoint {
+    pub timestamp: DateTime<Utc>,
+    pub received: u64,
+    pub forwarded: u64,
+    pub dropped: u64,
+    pub latency_p50_ms: Option<f64>,
+    pub latency_p95_ms: Option<f64>,
+    pub latency_p99_ms: Option<f64>,
+}
+
+/// Ring of per-second traffic samples
+pub struct TrafficStats {
+    buckets: Mutex<VecDeque<SecondBucket>>,
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrafficStats {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Maximum window that can be queried
+    pub fn retention() -> Duration {
+        Duration::from_secs(RETENTION_SECS as u64)
+    }
+
+    /// Record one processed message with its forwarding outcome counts and latency
+    pub fn record_message(&self, forwarded: u64, dropped: u64, latency: Duration) {
+        self.record_at(Utc::now().timestamp(), forwarded, dropped, latency);
+    }
+
+    fn record_at(&self, second: i64, forwarded: u64, dropped: u64, latency: Duration) {
+        let mut buckets = self.buckets.lock();
+
+        if buckets.back().map(|b| b.second) != Some(second) {
+            buckets.push_back(SecondBucket {
+                second,
+                ..Default::default()
+            });
+            while buckets
+                .front()
+                .is_some_and(|b| b.second <= second - RETENTION_SECS)
+            {
+                buckets.pop_front();
+            }
+        }
+
+        let bucket = buckets.back_mut().expect("bucket was just pushed");
+        bucket.received += 1;
+        bucket.forwarded += forwarded;
+        bucket.dropped += dropped;
+
+        let latency_us = latency.as_micros().min(u32::MAX as u128) as u32;
+        bucket.latency_count += 1;
+        if bucket.latencies_us.len() < MAX_LATENCY_SAMPLES {
+            bucket.latencies_us.push(latency_us);
+        } else {
+            let slot = rand::random::<u64>() % bucket.latency_count;
+            if (slot as usize) < MAX_LATENCY_SAMPLES {
+                bucket.latencies_us[slot as usize] = latency_us;
+            }
+        }
+    }
+
+    /// Aggregate the last `window` into points of `step` width, oldest first
+    pub fn timeseries(&self, window: Duration, step: Duration) -> Vec<TimeseriesPoint> {
+        self.timeseries_at(Utc::now().timestamp(), window, step)
+    }
+
+    fn timeseries_at(&self, now: i64, window: Duration, step: Duration) -> Vec<TimeseriesPoint> {
+        let window = (window.as_secs() as i64).clamp(1, RETENTION_SECS);
+        let step = (step.as_secs() as i64).clamp(1, window);
+        let point_count = (window + step - 1) / step;
+        let start = now - point_count * step + 1;
+
+        let buckets = self.buckets.lock();
+        (0..point_count)
+            .map(|i| {
+                let from = start + i * step;
+                let to = from + step;
+                let mut point = TimeseriesPoint {
+                    timestamp: Utc.timestamp_opt(from, 0).single().unwrap_or_default(),
+                    received: 0,
+                    forwarded: 0,
+                    dropped: 0,
+                    latency_p50_ms: None,
+                    latency_p95_ms: None,
+                    latency_p99_ms: None,
+                };
+                let mut latencies: Vec<u32> = Vec::new();
+                for bucket in buckets.iter().filter(|b| b.second >= from && b.second < to) {
+                    point.received += bucket.received;
+                    point.forwarded += bucket.forwarded;
+                    point.dropped += bucket.dropped;
+                    latencies.extend_from_slice(&bucket.latencies_us);
+                }
+                if !latencies.is_empty() {
+                    latencies.sort_unstable();
+                    point.latency_p50_ms = Some(percentile_ms(&latencies, 0.50));
+                    point.latency_p95_ms = Some(percentile_ms(&latencies, 0.95));
+                    point.latency_p99_ms = Some(percentile_ms(&latencies, 0.99));
+                }
+                point
+            })
+            .collect()
+    }
+}
+
+/// Maximum distinct first-level topics tracked for bandwidth (the rest share one bucket)
+const MAX_TOPIC_PREFIXES: usize = 1000;
+/// Bucket for first-level topics beyond `MAX_TOPIC_PREFIXES`
+const OTHER_TOPIC_PREFIX: &str = "(other)";
+
+/// Byte and message counters for one direction pair
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ByteCounters {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+}
+
+impl ByteCounters {
+    fn add(&mut self, direction: Direction, bytes: u64) {
+        match direction {
+            Direction::Sent => {
+                self.bytes_sent += bytes;
+                self.messages_sent += 1;
+            }
+            Direction::Received => {
+                self.bytes_received += bytes;
+                self.messages_received += 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    /// `direction` label of the Prometheus counter
+    fn label(self) -> &'static str {
+        match self {
+            Direction::Sent => metrics::OUTBOUND,
+            Direction::Received => metrics::INBOUND,
+        }
+    }
+}
+
+/// Bandwidth used against one downstream broker
+#[derive(Debug, Clone, Serialize)]
+pub struct BrokerBandwidth {
+    pub broker_id: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub counters: ByteCounters,
+}
+
+/// Bandwidth attributed to one first-level topic
+#[derive(Debug, Clone, Serialize)]
+pub struct TopicBandwidth {
+    pub prefix: String,
+    #[serde(flatten)]
+    pub counters: ByteCounters,
+}
+
+#[derive(Default)]
+struct BandwidthInner {
+    /// Keyed by broker id, with the last known broker name
+    brokers: HashMap<String, (String, ByteCounters)>,
+    topics: HashMap<String, ByteCounters>,
+}
+
+/// Cumulative bytes exchanged with downstream brokers, per broker and per first-level topic
+///
+/// Counts topic plus payload bytes of each PUBLISH; MQTT framing and TLS overhead are not
+/// included, so this slightly undercounts what the uplink sees. The same bytes go to the
+/// `mqtt_broker_bytes_total` Prometheus counter.
+pub struct BandwidthStats {
+    inner: Mutex<BandwidthInner>,
+    counter: IntCounterVec,
+    /// Whether the counter's `prefix` label is filled in
+    prefix_label: AtomicBool,
+}
+
+impl Default for BandwidthStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BandwidthStats {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(BandwidthInner::default()),
+            counter: Metrics::global().broker_bytes.clone(),
+            prefix_label: AtomicBool::new(false),
+        }
+    }
+
+    /// Label `mqtt_broker_bytes_total` with the first-level topic prefix too, capped like
+    /// `topics` (to apply `[topic_metrics]`)
+    pub fn set_prefix_label(&self, enabled: bool) {
+        self.prefix_label.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Record a message published to a downstream broker
+    pub fn record_sent(&self, broker_id: &str, broker_name: &str, topic: &str, payload_len: usize) {
+        self.record(Direction::Sent, broker_id, broker_name, topic, payload_len);
+    }
+
+    /// Record a message received from a downstream broker
+    pub fn record_received(
+        &self,
+        broker_id: &str,
+        broker_name: &str,
+        topic: &str,
+        payload_len: usize,
+    ) {
+        self.record(
+            Direction::Received,
+            broker_id,
+            broker_name,
+            topic,
+            payload_len,
+        );
+    }
+
+    fn record(
+        &self,
+        direction: Direction,
+        broker_id: &str,
+        broker_name: &str,
+        topic: &str,
+        payload_len: usize,
+    ) {
+        let bytes = (topic.len() + payload_len) as u64;
+        let prefix = topic.split('/').next().unwrap_or_default();
+
+        let mut inner = self.inner.lock();
+        let broker = inner
+            .brokers
+            .entry(broker_id.to_string())
+            .or_insert_with(|| (broker_name.to_string(), ByteCounters::default()));
+        if broker.0 != broker_name {
+            broker.0 = broker_name.to_string();
+        }
+        broker.1.add(direction, bytes);
+
+        let prefix = if inner.topics.contains_key(prefix) || inner.topics.len() < MAX_TOPIC_PREFIXES
+        {
+            prefix
+        } else {
+            OTHER_TOPIC_PREFIX
+        };
+        match inner.topics.get_mut(prefix) {
+            Some(counters) => counters.add(direction, bytes),
+            None => {
+                let mut counters = ByteCounters::default();
+                counters.add(direction, bytes);
+                inner.topics.insert(prefix.to_string(), counters);
+            }
+        }
+        drop(inner);
+
+        let prefix = if self.prefix_label.load(Ordering::Relaxed) {
+            prefix
+        } else {
+            ""
+        };
+        self.counter
+            .with_label_values(&[broker_name, direction.label(), prefix])
+            .inc_by(bytes);
+    }
+
+    /// Per-broker totals, sorted by broker name
+    pub fn brokers(&self) -> Vec<BrokerBandwidth> {
+        let mut brokers: Vec<_> = self
+            .inner
+            .lock()
+            .brokers
+            .iter()
+            .map(|(id, (name, counters))| BrokerBandwidth {
+                broker_id: id.clone(),
+                name: name.clone(),
+                counters: counters.clone(),
+            })
+            .collect();
+        brokers.sort_by(|a, b| a.name.cmp(&b.name));
+        brokers
+    }
+
+    /// Per first-level topic totals, largest first
+    pub fn topics(&self) -> Vec<TopicBandwidth> {
+        let mut topics: Vec<_> = self
+            .inner
+            .lock()
+            .topics
+            .iter()
+            .map(|(prefix, counters)| TopicBandwidth {
+                prefix: prefix.clone(),
+                counters: counters.clone(),
+            })
+            .collect();
+        topics
+            .sort_by_key(|t| std::cmp::Reverse(t.counters.bytes_sent + t.counters.bytes_received));
+        topics
+    }
+}
+
+/// Maximum round-trip samples kept per broker (one per keep-alive ping)
+const MAX_RTT_SAMPLES: usize = 1440;
+
+/// A single round-trip measurement against a downstream broker
+#[derive(Debug, Clone, Serialize)]
+pub struct RttSample {
+    pub timestamp: DateTime<Utc>,
+    pub rtt_ms: f64,
+}
+
+/// Bounded history of PINGREQ/PINGRESP round trips for one downstream broker
+#[derive(Default)]
+pub struct RttHistory {
+    samples: Mutex<VecDeque<RttSample>>,
+}
+
+impl RttHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a measured round trip
+    pub fn record(&self, rtt: Duration) {
+        let mut samples = self.samples.lock();
+        if samples.len() >= MAX_RTT_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(RttSample {
+            timestamp: Utc::now(),
+            rtt_ms: rtt.as_secs_f64() * 1000.0,
+        });
+    }
+
+    /// Most recent round trip in milliseconds
+    pub fn latest_ms(&self) -> Option<f64> {
+        self.samples.lock().back().map(|s| s.rtt_ms)
+    }
+
+    /// All retained samples, oldest first
+    pub fn samples(&self) -> Vec<RttSample> {
+        self.samples.lock().iter().cloned().collect()
+    }
+}
+
+/// Nearest-rank percentile of sorted microsecond samples, in milliseconds
+fn percentile_ms(sorted_us: &[u32], quantile: f64) -> f64 {
+    let rank = ((quantile * sorted_us.len() as f64).ceil() as usize).clamp(1, sorted_us.len());
+    sorted_us[rank - 1] as f64 / 1000.0
+}
+
+/// Parse a short duration like `30s`, `15m`, `1h` (a bare number is seconds)
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last()? {
+        (i, 's') => (&value[..i], 1),
+        (i, 'm') => (&value[..i], 60),
+        (i, 'h') => (&value[..i], 3600),
+        (i, 'd') => (&value[..i], 86400),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("15m"), Some(Duration::from_secs(900)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("45"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("abc"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("99999999999999999d"), None);
+    }
+
+    #[test]
+    fn test_timeseries_buckets() {
+        let stats = TrafficStats::new();
+        let now = 1_000_000;
+
+        stats.record_at(now - 70, 2, 0, Duration::from_millis(1));
+        stats.record_at(now - 5, 1, 1, Duration::from_millis(4));
+        stats.record_at(now, 3, 0, Duration::from_millis(2));
+
+        let points = stats.timeseries_at(now, Duration::from_secs(120), Duration::from_secs(60));
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].received, 1);
+        assert_eq!(points[0].forwarded, 2);
+        assert_eq!(points[1].received, 2);
+        assert_eq!(points[1].forwarded, 4);
+        assert_eq!(points[1].dropped, 1);
+        assert_eq!(points[1].latency_p50_ms, Some(2.0));
+        assert_eq!(points[1].latency_p99_ms, Some(4.0));
+    }
+
+    #[test]
+    fn test_old_buckets_expire() {
+        let stats = TrafficStats::new();
+        stats.record_at(0, 1, 0, Duration::ZERO);
+        stats.record_at(RETENTION_SECS + 10, 1, 0, Duration::ZERO);
+        assert_eq!(stats.buckets.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_bandwidth_per_broker_and_prefix() {
+        let stats = BandwidthStats::new();
+        stats.record_sent("a", "alpha", "sensors/temp", 10);
+        stats.record_sent("b", "beta", "sensors/temp", 10);
+        stats.record_received("a", "alpha", "cmd", 5);
+
+        let brokers = stats.brokers();
+        assert_eq!(brokers.len(), 2);
+        assert_eq!(brokers[0].name, "alpha");
+        assert_eq!(brokers[0].counters.bytes_sent, 22);
+        assert_eq!(brokers[0].counters.bytes_received, 8);
+        assert_eq!(brokers[0].counters.messages_received, 1);
+
+        let topics = stats.topics();
+        assert_eq!(topics[0].prefix, "sensors");
+        assert_eq!(topics[0].counters.bytes_sent, 44);
+        assert_eq!(topics[0].counters.messages_sent, 2);
+        assert_eq!(topics[1].prefix, "cmd");
+    }
+
+    #[test]
+    fn test_bandwidth_topic_prefixes_are_capped() {
+        let stats = BandwidthStats::new();
+        for i in 0..(MAX_TOPIC_PREFIXES + 10) {
+            stats.record_sent("a", "alpha", &format!("t{}/x", i), 1);
+        }
+        let topics = stats.topics();
+        assert_eq!(topics.len(), MAX_TOPIC_PREFIXES + 1);
+        let other = topics
+            .iter()
+            .find(|t| t.prefix == OTHER_TOPIC_PREFIX)
+            .unwrap();
+        assert_eq!(other.counters.messages_sent, 10);
+    }
+
+    #[test]
+    fn test_bandwidth_feeds_prometheus_counter() {
+        let stats = BandwidthStats::new();
+        let bytes = |direction: &str, prefix: &str| {
+            Metrics::global()
+                .broker_bytes
+                .with_label_values(&["bandwidth-test", direction, prefix])
+                .get()
+        };
+        stats.record_sent("bw", "bandwidth-test", "sensors/temp", 10);
+        stats.record_received("bw", "bandwidth-test", "cmd", 5);
+        assert_eq!(bytes(metrics::OUTBOUND, ""), 22);
+        assert_eq!(bytes(metrics::INBOUND, ""), 8);
+
+        stats.set_prefix_label(true);
+        stats.record_sent("bw", "bandwidth-test", "sensors/temp", 10);
+        assert_eq!(bytes(metrics::OUTBOUND, "sensors"), 22);
+        assert_eq!(bytes(metrics::OUTBOUND, ""), 22);
+    }
+
+    #[test]
+    fn test_rtt_history_is_bounded() {
+        let history = RttHistory::new();
+        assert_eq!(history.latest_ms(), None);
+
+        for ms in 0..(MAX_RTT_SAMPLES as u64 + 5) {
+            history.record(Duration::from_millis(ms));
+        }
+        let samples = history.samples();
+        assert_eq!(samples.len(), MAX_RTT_SAMPLES);
+        assert_eq!(samples[0].rtt_ms, 5.0);
+        assert_eq!(history.latest_ms(), Some((MAX_RTT_SAMPLES + 4) as f64));
+    }
+}
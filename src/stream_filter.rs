@@ -0,0 +1,181 @@
+//! Server-side filters for the `/ws/messages` stream
+//!
+//! A WebSocket client narrows the live feed with query parameters when it connects
+//! (`?topics=sensors/#,alarms/+&client_ids=plc-1&max_payload=256`) or later with a
+//! `subscribe` control message, which replaces the whole filter:
+//!
+//! ```json
+//! {"type": "subscribe", "topics": ["sensors/#"], "client_ids": [], "max_payload": 256}
+//! ```
+//!
+//! Messages are dropped on the server, so busy systems don't flood the browser. Payloads
+//! longer than `max_payload` bytes are cut, with the full size in `payload_size`. A client
+//! too slow for the stream gets a `lagged` notice with the number of messages it missed.
+
+use crate::acl::is_valid_filter;
+use crate::connection_manager::ConnectionManager;
+use crate::web_server::MqttMessage;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Which messages a WebSocket client receives; empty lists don't filter
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StreamFilter {
+    /// Topic filters (supports `+` and `#`)
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// Publishing client IDs
+    #[serde(default)]
+    pub client_ids: Vec<String>,
+    /// Payload bytes sent per message
+    #[serde(default)]
+    pub max_payload: Option<usize>,
+}
+
+/// Query parameters of `/ws/messages`, lists comma-separated
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamQuery {
+    pub topics: Option<String>,
+    pub client_ids: Option<String>,
+    pub max_payload: Option<usize>,
+}
+
+/// Messages a WebSocket client may send
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    Subscribe(StreamFilter),
+}
+
+/// Notices sent between the streamed messages: replies to control messages, and lost
+/// messages
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlReply {
+    Subscribed {
+        filter: StreamFilter,
+    },
+    Error {
+        message: String,
+    },
+    /// The client fell behind the broadcast buffer and missed `dropped` messages (before
+    /// filtering); `total_dropped` counts them since it connected
+    Lagged {
+        dropped: u64,
+        total_dropped: u64,
+    },
+}
+
+/// A streamed message, with its payload cut to `max_payload`
+#[derive(Debug, Serialize)]
+pub struct StreamedMessage<'a> {
+    #[serde(flatten)]
+    pub message: Cow<'a, MqttMessage>,
+    /// Size of the full payload, when it was cut
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_size: Option<usize>,
+}
+
+impl StreamFilter {
+    pub fn from_query(query: StreamQuery) -> Result<Self> {
+        let list = |value: Option<String>| -> Vec<String> {
+            value
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let filter = Self {
+            topics: list(query.topics),
+            client_ids: list(query.client_ids),
+            max_payload: query.max_payload,
+        };
+        filter.validate()?;
+        Ok(filter)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(topic) = self.topics.iter().find(|topic| !is_valid_filter(topic)) {
+            bail!("Invalid topic filter '{}'", topic);
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, message: &MqttMessage) -> bool {
+        (self.client_ids.is_empty() || self.client_ids.contains(&message.client_id))
+            && (self.topics.is_empty()
+                || self.topics.iter().any(|pattern| {
+                    ConnectionManager::topic_matches_pattern(pattern, &message.topic)
+                }))
+    }
+
+    /// The message as this client receives it, or `None` if it is filtered out
+    pub fn apply<'a>(&self, message: &'a MqttMessage) -> Option<StreamedMessage<'a>> {
+        if !self.matches(message) {
+            return None;
+        }
+        match self.max_payload {
+            Some(max) if message.payload.len() > max => {
+                let mut cut = message.clone();
+                cut.payload.truncate(max);
+                Some(StreamedMessage {
+                    message: Cow::Owned(cut),
+                    payload_size: Some(message.payload.len()),
+                })
+            }
+            _ => Some(StreamedMessage {
+                message: Cow::Borrowed(message),
+                payload_size: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn message(client_id: &str, topic: &str, payload: &[u8]) -> MqttMessage {
+        MqttMessage {
+            timestamp: Utc::now(),
+            client_id: client_id.to_string(),
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos: 0,
+            retain: false,
+            deliveries: None,
+        }
+    }
+
+    #[test]
+    fn test_filter_from_query_and_control_message() {
+        let filter = StreamFilter::from_query(StreamQuery {
+            topics: Some("sensors/#, alarms/+".to_string()),
+            client_ids: None,
+            max_payload: Some(4),
+        })
+        .unwrap();
+        assert!(filter.apply(&message("a", "other/x", b"")).is_none());
+        let long = message("a", "sensors/kitchen/temp", b"21.55");
+        let cut = filter.apply(&long).unwrap();
+        let json = serde_json::to_value(&cut).unwrap();
+        assert_eq!(json["payload"], serde_json::json!(b"21.5"));
+        assert_eq!(json["payload_size"], 5);
+        assert_eq!(json["topic"], "sensors/kitchen/temp");
+
+        let ControlMessage::Subscribe(filter) =
+            serde_json::from_str(r#"{"type": "subscribe", "client_ids": ["plc-1"]}"#).unwrap();
+        assert!(filter.matches(&message("plc-1", "any/topic", b"")));
+        assert!(!filter.matches(&message("plc-2", "any/topic", b"")));
+
+        assert!(StreamFilter::from_query(StreamQuery {
+            topics: Some("sensors/#/temp".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
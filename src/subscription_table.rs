@@ -0,0 +1,187 @@
+//! Reference-counted table of local client subscriptions
+//!
+//! Clients of the MQTT listener subscribe to filters that bidirectional brokers have to
+//! deliver. The table maps each filter to the clients that want it; the client registry
+//! keeps it up to date as clients subscribe, unsubscribe and disconnect, and each
+//! bidirectional broker task follows it: it subscribes to a filter once the first client
+//! wants it and unsubscribes when the last one is gone, and after a reconnect it subscribes
+//! to whatever is wanted at that moment.
+//!
+//! Consumers on the main broker can't be seen by the proxy, so their demand is declared
+//! through the API and kept in the table under `MAIN_BROKER_DEMAND`. Brokers in
+//! `on_demand` subscription mode subscribe to nothing else.
+
+use crate::acl::filter_covers;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashSet};
+use tokio::sync::watch;
+
+/// Owner of the filters wanted by consumers on the main broker
+pub const MAIN_BROKER_DEMAND: &str = "$main";
+
+pub struct SubscriptionTable {
+    filters: Mutex<BTreeMap<String, HashSet<String>>>,
+    /// Bumped on every change in demand; broker tasks wait on it
+    version: watch::Sender<u64>,
+}
+
+impl Default for SubscriptionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SubscriptionTable {
+    pub fn new() -> Self {
+        Self {
+            filters: Mutex::new(BTreeMap::new()),
+            version: watch::channel(0).0,
+        }
+    }
+
+    /// Record that `client_id` wants `filters`; returns the filters nobody wanted before
+    pub fn add(&self, client_id: &str, filters: &[String]) -> Vec<String> {
+        let mut table = self.filters.lock();
+        let added: Vec<String> = filters
+            .iter()
+            .filter(|filter| {
+                let clients = table.entry(filter.to_string()).or_default();
+                clients.insert(client_id.to_string()) && clients.len() == 1
+            })
+            .cloned()
+            .collect();
+        drop(table);
+        self.changed(!added.is_empty());
+        added
+    }
+
+    /// Drop `client_id`'s interest in `filters`; returns the filters nobody wants anymore
+    pub fn remove(&self, client_id: &str, filters: &[String]) -> Vec<String> {
+        let mut table = self.filters.lock();
+        let mut removed = Vec::new();
+        for filter in filters {
+            if let Some(clients) = table.get_mut(filter) {
+                if clients.remove(client_id) && clients.is_empty() {
+                    table.remove(filter);
+                    removed.push(filter.clone());
+                }
+            }
+        }
+        drop(table);
+        self.changed(!removed.is_empty());
+        removed
+    }
+
+    /// Replace the filters `client_id` wants
+    pub fn set(&self, client_id: &str, filters: &[String]) {
+        let current: Vec<String> = self
+            .filters
+            .lock()
+            .iter()
+            .filter(|(filter, clients)| clients.contains(client_id) && !filters.contains(filter))
+            .map(|(filter, _)| filter.clone())
+            .collect();
+        self.remove(client_id, &current);
+        self.add(client_id, filters);
+    }
+
+    /// Filters `client_id` wants, sorted
+    pub fn filters_of(&self, client_id: &str) -> Vec<String> {
+        self.filters
+            .lock()
+            .iter()
+            .filter(|(_, clients)| clients.contains(client_id))
+            .map(|(filter, _)| filter.clone())
+            .collect()
+    }
+
+    /// Filters wanted by at least one client, sorted
+    pub fn filters(&self) -> Vec<String> {
+        self.filters.lock().keys().cloned().collect()
+    }
+
+    /// Number of clients that want `filter`
+    pub fn count(&self, filter: &str) -> usize {
+        self.filters.lock().get(filter).map_or(0, HashSet::len)
+    }
+
+    /// Changes whenever a filter gains its first or loses its last client
+    pub fn watch(&self) -> watch::Receiver<u64> {
+        self.version.subscribe()
+    }
+
+    fn changed(&self, changed: bool) {
+        if changed {
+            self.version.send_modify(|version| *version += 1);
+        }
+    }
+}
+
+/// The part of `filter` that falls within `allowed`: `filter` itself where an allowed
+/// filter covers it, or the allowed filters it covers. Partial overlaps (`a/+/c` and
+/// `a/b/#`) yield nothing, since subscribing either would exceed the other.
+pub fn restrict(filter: &str, allowed: &[String]) -> Vec<String> {
+    if allowed.iter().any(|allowed| filter_covers(allowed, filter)) {
+        return vec![filter.to_string()];
+    }
+    allowed
+        .iter()
+        .filter(|allowed| filter_covers(filter, allowed))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_are_reference_counted() {
+        let table = SubscriptionTable::new();
+        let mut changes = table.watch();
+        let filters = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            table.add("a", &filters(&["x/#", "y"])),
+            filters(&["x/#", "y"])
+        );
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        // A second client for the same filter doesn't change the demand
+        assert!(table.add("b", &filters(&["x/#"])).is_empty());
+        assert!(!changes.has_changed().unwrap());
+        assert_eq!(table.count("x/#"), 2);
+
+        assert!(table.remove("a", &filters(&["x/#"])).is_empty());
+        assert_eq!(table.remove("a", &filters(&["y"])), filters(&["y"]));
+        assert_eq!(table.remove("b", &filters(&["x/#"])), filters(&["x/#"]));
+        assert!(changes.has_changed().unwrap());
+        assert!(table.filters().is_empty());
+    }
+
+    #[test]
+    fn test_set_replaces_an_owners_filters() {
+        let table = SubscriptionTable::new();
+        table.add("client", &["x/#".to_string()]);
+        table.set(MAIN_BROKER_DEMAND, &["x/#".to_string(), "y".to_string()]);
+        table.set(MAIN_BROKER_DEMAND, &["z".to_string()]);
+
+        assert_eq!(table.filters_of(MAIN_BROKER_DEMAND), vec!["z".to_string()]);
+        assert_eq!(table.filters(), vec!["x/#".to_string(), "z".to_string()]);
+    }
+
+    #[test]
+    fn test_restrict_to_allowed_filters() {
+        let allowed = vec!["sensors/#".to_string(), "alarms/+".to_string()];
+
+        assert_eq!(restrict("sensors/+/temp", &allowed), vec!["sensors/+/temp"]);
+        assert_eq!(restrict("#", &allowed), allowed);
+        assert!(restrict("commands/#", &allowed).is_empty());
+        assert_eq!(
+            restrict("alarms/#", &["alarms/+/x".to_string()]),
+            vec!["alarms/+/x"]
+        );
+        assert!(restrict("+/b/c", &["a/#".to_string()]).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+//! Suppression of unchanged state republished by chatty sensors
+//!
+//! Many devices republish their full state on a timer even when nothing changed. For
+//! topics matching a rule, a message whose payload is identical to the last one forwarded
+//! on the same topic within the rule's window is not forwarded again. A changed payload is
+//! always forwarded, and an unchanged one goes through once the window has passed, so
+//! downstream brokers still see the state at least once per window. This is independent
+//! of echo detection on bidirectional brokers.
+
+use crate::config::DuplicateRule;
+use crate::connection_manager::ConnectionManager;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Topics remembered at once; further topics are forwarded without suppression
+const MAX_TRACKED_TOPICS: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleCounters {
+    pub topic: String,
+    pub window_secs: u64,
+    /// Messages suppressed by this rule since startup
+    pub suppressed: u64,
+}
+
+/// Last forwarded payload per topic and suppression counters per rule
+#[derive(Default)]
+pub struct DuplicateSuppressor {
+    rules: Vec<(DuplicateRule, AtomicU64)>,
+    /// Topic → (rule index, payload hash, time forwarded)
+    last: Mutex<HashMap<String, (usize, u64, Instant)>>,
+}
+
+impl DuplicateSuppressor {
+    pub fn new(rules: Vec<DuplicateRule>) -> Self {
+        Self {
+            rules: rules
+                .into_iter()
+                .filter(|rule| !rule.topic.is_empty())
+                .map(|rule| (rule, AtomicU64::new(0)))
+                .collect(),
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the message repeats the last payload forwarded on its topic within the
+    /// window; otherwise it is remembered as the last forwarded payload
+    pub fn is_duplicate(&self, topic: &str, payload: &[u8]) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        let now = Instant::now();
+        let mut last = self.last.lock();
+        let hash = payload_hash(payload);
+        let rule = match last.get_mut(topic) {
+            Some((rule, last_hash, forwarded)) => {
+                let window = Duration::from_secs(self.rules[*rule].0.window_secs);
+                if *last_hash == hash && now.duration_since(*forwarded) < window {
+                    self.rules[*rule].1.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+                *last_hash = hash;
+                *forwarded = now;
+                return false;
+            }
+            None => self
+                .rules
+                .iter()
+                .position(|(rule, _)| ConnectionManager::topic_matches_pattern(&rule.topic, topic)),
+        };
+        if let Some(rule) = rule {
+            if last.len() < MAX_TRACKED_TOPICS {
+                last.insert(topic.to_string(), (rule, hash, now));
+            }
+        }
+        false
+    }
+
+    pub fn counters(&self) -> Vec<RuleCounters> {
+        self.rules
+            .iter()
+            .map(|(rule, suppressed)| RuleCounters {
+                topic: rule.topic.clone(),
+                window_secs: rule.window_secs,
+                suppressed: suppressed.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+fn payload_hash(payload: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suppresses_identical_consecutive_payloads() {
+        let suppressor = DuplicateSuppressor::new(vec![DuplicateRule {
+            topic: "sensors/#".to_string(),
+            window_secs: 60,
+        }]);
+
+        assert!(!suppressor.is_duplicate("sensors/a", b"21.5"));
+        assert!(suppressor.is_duplicate("sensors/a", b"21.5"));
+        assert!(suppressor.is_duplicate("sensors/a", b"21.5"));
+        // A change is forwarded and becomes the new reference
+        assert!(!suppressor.is_duplicate("sensors/a", b"21.6"));
+        assert!(!suppressor.is_duplicate("sensors/a", b"21.5"));
+        // Topics are tracked separately; unmatched topics are never suppressed
+        assert!(!suppressor.is_duplicate("sensors/b", b"21.5"));
+        assert!(!suppressor.is_duplicate("status", b"ok"));
+        assert!(!suppressor.is_duplicate("status", b"ok"));
+
+        assert_eq!(suppressor.counters()[0].suppressed, 2);
+    }
+
+    #[test]
+    fn test_window_expiry_forwards_unchanged_state() {
+        let suppressor = DuplicateSuppressor::new(vec![DuplicateRule {
+            topic: "sensors/+".to_string(),
+            window_secs: 0,
+        }]);
+
+        assert!(!suppressor.is_duplicate("sensors/a", b"on"));
+        assert!(!suppressor.is_duplicate("sensors/a", b"on"));
+    }
+}
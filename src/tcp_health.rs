@@ -0,0 +1,87 @@
+//! Plain TCP health port for monitoring systems that can't speak HTTP
+//!
+//! Every connection gets one status line and is closed:
+//! `UP <connected>/<brokers>` when every downstream broker is connected,
+//! `DEGRADED <connected>/<brokers>` when some are and `DOWN <connected>/<brokers>` when
+//! none is. A check only has to compare the first byte (`U`, `D`) or the first word.
+
+use crate::connection_manager::ConnectionManager;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+/// How long a slow monitoring client gets to take the status line
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The status line for `connected` out of `total` downstream brokers
+pub fn status_line(connected: usize, total: usize) -> String {
+    let status = if connected == total {
+        "UP"
+    } else if connected > 0 {
+        "DEGRADED"
+    } else {
+        "DOWN"
+    };
+    format!("{} {}/{}\n", status, connected, total)
+}
+
+/// Answer health checks on `port` until `shutdown` is cancelled
+pub async fn run_tcp_health(
+    port: u16,
+    connection_manager: Arc<RwLock<ConnectionManager>>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
+        .await
+        .with_context(|| format!("Failed to bind TCP health port {}", port))?;
+    info!("TCP health port listening on {}", port);
+
+    loop {
+        let (mut socket, peer) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("TCP health port accept failed: {}", e);
+                    continue;
+                }
+            },
+        };
+        let line = {
+            let manager = connection_manager.read().await;
+            let brokers = manager.get_broker_status();
+            let connected = brokers.iter().filter(|broker| broker.connected).count();
+            status_line(connected, brokers.len())
+        };
+        tokio::spawn(async move {
+            let written = tokio::time::timeout(WRITE_TIMEOUT, async {
+                socket.write_all(line.as_bytes()).await?;
+                socket.shutdown().await
+            })
+            .await;
+            if !matches!(written, Ok(Ok(()))) {
+                debug!("Health check from {} disconnected early", peer);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_line() {
+        assert_eq!(status_line(3, 3), "UP 3/3\n");
+        assert_eq!(status_line(1, 3), "DEGRADED 1/3\n");
+        assert_eq!(status_line(0, 3), "DOWN 0/3\n");
+        // Nothing to forward to is nothing missing
+        assert_eq!(status_line(0, 0), "UP 0/0\n");
+    }
+}
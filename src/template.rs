@@ -0,0 +1,165 @@
+//! Handlebars-style templates for outgoing message bodies
+//!
+//! Non-MQTT sinks (webhooks, Kafka) usually expect a body in their own format rather than
+//! the raw device payload. A template builds that body from the message:
+//!
+//! - `{{topic}}` - the full topic; `{{topic.1}}` - the second topic level
+//! - `{{payload}}` - the raw payload as text; `{{payload.battery.level}}` - a JSON field
+//! - `{{timestamp}}` - the current time (RFC 3339)
+//! - `{{json payload.state}}` - the value as JSON (strings quoted, objects kept), for
+//!   building JSON bodies; missing values render as `null`
+//!
+//! Plain expressions render strings without quotes and other values as JSON; missing
+//! values render as an empty string.
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Topic,
+    TopicLevel(usize),
+    Payload,
+    PayloadField(Vec<String>),
+    Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Expr { source: Source, json: bool },
+}
+
+/// A parsed template; parse once and render per message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find("}}") else {
+                bail!("Unclosed '{{{{' in template");
+            };
+            segments.push(parse_expr(rest[start + 2..start + end].trim())?);
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn render(&self, topic: &str, payload: &[u8]) -> String {
+        let mut parsed: Option<Option<Value>> = None;
+        let mut out = String::new();
+        for segment in &self.segments {
+            let (source, json) = match segment {
+                Segment::Literal(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Segment::Expr { source, json } => (source, *json),
+            };
+            let value = match source {
+                Source::Topic => Some(Value::String(topic.to_string())),
+                Source::TopicLevel(level) => topic
+                    .split('/')
+                    .nth(*level)
+                    .map(|level| Value::String(level.to_string())),
+                Source::Payload => match std::str::from_utf8(payload) {
+                    // `json payload` embeds a JSON payload as is
+                    Ok(text) if json => Some(
+                        serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.into())),
+                    ),
+                    Ok(text) => Some(Value::String(text.to_string())),
+                    Err(_) => None,
+                },
+                Source::PayloadField(path) => parsed
+                    .get_or_insert_with(|| serde_json::from_slice(payload).ok())
+                    .as_ref()
+                    .and_then(|value| lookup(value, path))
+                    .cloned(),
+                Source::Timestamp => Some(Value::String(chrono::Utc::now().to_rfc3339())),
+            };
+            match (value, json) {
+                (Some(value), true) => out.push_str(&value.to_string()),
+                (None, true) => out.push_str("null"),
+                (Some(Value::String(text)), false) => out.push_str(&text),
+                (Some(value), false) => out.push_str(&value.to_string()),
+                (None, false) => {}
+            }
+        }
+        out
+    }
+}
+
+fn parse_expr(expr: &str) -> Result<Segment> {
+    let (json, path) = match expr.strip_prefix("json ") {
+        Some(path) => (true, path.trim()),
+        None => (false, expr),
+    };
+    let mut parts = path.split('.');
+    let source = match (parts.next(), parts.clone().next()) {
+        (Some("topic"), None) => Source::Topic,
+        (Some("topic"), Some(level)) if parts.clone().count() == 1 => match level.parse() {
+            Ok(level) => Source::TopicLevel(level),
+            Err(_) => bail!("Invalid topic level '{}' in template", level),
+        },
+        (Some("payload"), None) => Source::Payload,
+        (Some("payload"), Some(_)) => {
+            let path: Vec<String> = parts.map(str::to_string).collect();
+            if path.iter().any(String::is_empty) {
+                bail!("Invalid payload field '{}' in template", expr);
+            }
+            Source::PayloadField(path)
+        }
+        (Some("timestamp"), None) => Source::Timestamp,
+        _ => bail!("Unknown template expression '{{{{{}}}}}'", expr),
+    };
+    Ok(Segment::Expr { source, json })
+}
+
+/// Value at a field path; numeric parts index into arrays
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, part| match value {
+        Value::Object(object) => object.get(part),
+        Value::Array(items) => items.get(part.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_topic_levels_and_payload_fields() {
+        let template = Template::parse(
+            r#"{"site":"{{topic.1}}","temp":{{json payload.temp}},"tags":{{json payload.tags}},"missing":{{json payload.nope}},"first":"{{payload.tags.0}}"}"#,
+        )
+        .unwrap();
+        let body = template.render("fleet/north/truck-1", br#"{"temp":21.5,"tags":["a","b"]}"#);
+        assert_eq!(
+            body,
+            r#"{"site":"north","temp":21.5,"tags":["a","b"],"missing":null,"first":"a"}"#
+        );
+
+        let raw = Template::parse("{{topic}}: {{payload}}").unwrap();
+        assert_eq!(raw.render("a/b", b"on"), "a/b: on");
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_expressions() {
+        assert!(Template::parse("{{nope}}").is_err());
+        assert!(Template::parse("{{topic.x}}").is_err());
+        assert!(Template::parse("{{payload..a}}").is_err());
+        assert!(Template::parse("{{topic").is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Sanity checks on device timestamps
+//!
+//! A device with a dead RTC battery reports times in 1970 or far in the future, which
+//! pollutes downstream time-series databases. For topics matching a rule, the timestamp
+//! field of JSON object payloads is compared with the proxy's clock; a message off by
+//! more than the allowed skew (or with an unreadable timestamp) is either tagged with a
+//! flag field or dropped. Payloads without the field, or that aren't JSON objects, are
+//! forwarded unchanged.
+
+use crate::config::{TimestampAction, TimestampRule};
+use crate::connection_manager::ConnectionManager;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Epoch values above this are taken as milliseconds (in seconds, it's the year 5138)
+const MILLIS_THRESHOLD: f64 = 1e11;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TimestampCounters {
+    pub topic: String,
+    pub max_skew_secs: u64,
+    pub action: TimestampAction,
+    /// Messages with an out-of-range or unreadable timestamp since startup
+    pub invalid: u64,
+}
+
+/// Configured timestamp rules and how many messages each caught
+#[derive(Default)]
+pub struct TimestampChecker {
+    rules: Vec<(TimestampRule, AtomicU64)>,
+}
+
+impl TimestampChecker {
+    pub fn new(rules: Vec<TimestampRule>) -> Self {
+        Self {
+            rules: rules
+                .into_iter()
+                .filter(|rule| !rule.topic.is_empty())
+                .map(|rule| (rule, AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    /// The payload to forward (tagged if its timestamp is off), or `None` to drop it
+    pub fn check(&self, topic: &str, payload: Bytes, now: DateTime<Utc>) -> Option<Bytes> {
+        let Some((rule, invalid)) = self
+            .rules
+            .iter()
+            .find(|(rule, _)| ConnectionManager::topic_matches_pattern(&rule.topic, topic))
+        else {
+            return Some(payload);
+        };
+        let Ok(Value::Object(mut object)) = serde_json::from_slice::<Value>(&payload) else {
+            return Some(payload);
+        };
+        let Some(value) = lookup(&object, &rule.field) else {
+            return Some(payload);
+        };
+        let plausible = parse_timestamp(value)
+            .is_some_and(|time| (time - now).num_seconds().unsigned_abs() <= rule.max_skew_secs);
+        if plausible {
+            return Some(payload);
+        }
+
+        invalid.fetch_add(1, Ordering::Relaxed);
+        match rule.action {
+            TimestampAction::Drop => None,
+            TimestampAction::Tag => {
+                object.insert(rule.tag_field.clone(), Value::Bool(true));
+                Some(
+                    serde_json::to_vec(&Value::Object(object))
+                        .map(Bytes::from)
+                        .unwrap_or(payload),
+                )
+            }
+        }
+    }
+
+    pub fn counters(&self) -> Vec<TimestampCounters> {
+        self.rules
+            .iter()
+            .map(|(rule, invalid)| TimestampCounters {
+                topic: rule.topic.clone(),
+                max_skew_secs: rule.max_skew_secs,
+                action: rule.action,
+                invalid: invalid.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Value at a dotted path (`meta.ts`)
+fn lookup<'a>(object: &'a serde_json::Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let mut parts = path.split('.');
+    let mut value = object.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_object()?.get(part)?;
+    }
+    Some(value)
+}
+
+/// Epoch seconds or milliseconds, or an RFC 3339 string
+fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::Number(number) => {
+            let epoch = number.as_f64()?;
+            let millis = if epoch.abs() >= MILLIS_THRESHOLD {
+                epoch
+            } else {
+                epoch * 1000.0
+            };
+            DateTime::from_timestamp_millis(millis as i64)
+        }
+        Value::String(text) => DateTime::parse_from_rfc3339(text)
+            .ok()
+            .map(|time| time.with_timezone(&Utc)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(topic: &str, action: TimestampAction) -> TimestampRule {
+        TimestampRule {
+            topic: topic.to_string(),
+            field: "ts".to_string(),
+            max_skew_secs: 60,
+            action,
+            tag_field: "ts_invalid".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_tags_or_drops_skewed_timestamps() {
+        let checker = TimestampChecker::new(vec![
+            rule("sensors/#", TimestampAction::Tag),
+            rule("meters/#", TimestampAction::Drop),
+        ]);
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let check = |topic: &str, payload: &str| {
+            checker
+                .check(topic, Bytes::from(payload.to_string()), now)
+                .map(|p| serde_json::from_slice::<Value>(&p).unwrap())
+        };
+
+        // Seconds, milliseconds and RFC 3339 within the skew pass unchanged
+        for ts in [
+            "1714564790",
+            "1714564830000",
+            r#""2024-05-01T12:00:30+00:00""#,
+        ] {
+            let payload = format!(r#"{{"ts":{},"v":1}}"#, ts);
+            assert_eq!(
+                check("sensors/a", &payload),
+                Some(serde_json::from_str(&payload).unwrap())
+            );
+        }
+        // A dead RTC reports 1970
+        assert_eq!(
+            check("sensors/a", r#"{"ts":3600,"v":1}"#),
+            Some(serde_json::json!({"ts": 3600, "v": 1, "ts_invalid": true}))
+        );
+        assert_eq!(check("meters/a", r#"{"ts":"garbage"}"#), None);
+        // No timestamp field, or an unmatched topic
+        assert!(check("meters/a", r#"{"v":1}"#).is_some());
+        assert!(check("other", r#"{"ts":0}"#).is_some());
+
+        let invalid: Vec<u64> = checker.counters().iter().map(|c| c.invalid).collect();
+        assert_eq!(invalid, vec![1, 1]);
+    }
+}
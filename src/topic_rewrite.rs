@@ -0,0 +1,153 @@
+//! Topic rewriting per broker
+//!
+//! A broker's `topicRewrites` change the topic a message is published under on that
+//! broker, e.g. so `sensors/#` lands on a shared cloud broker as `site-a/sensors/#`. The
+//! first rule whose `topic` pattern matches applies: `replace` builds a new topic from the
+//! levels the pattern's wildcards matched (`{1}`, `{2}`, ... in order), then `stripPrefix`
+//! is removed and `addPrefix` prepended. Topics no rule matches are published unchanged.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopicRewrite {
+    /// Topic pattern the rule applies to (`+`/`#` wildcards)
+    pub topic: String,
+    /// New topic; `{n}` is the level(s) matched by the pattern's n-th wildcard
+    #[serde(default)]
+    pub replace: Option<String>,
+    /// Removed from the start of the topic when present
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+    /// Prepended to the topic
+    #[serde(default)]
+    pub add_prefix: Option<String>,
+}
+
+/// The topic `topic` is published under according to the first matching rule
+pub fn apply<'a>(rules: &[TopicRewrite], topic: &'a str) -> Cow<'a, str> {
+    let Some((rule, captures)) = rules
+        .iter()
+        .find_map(|rule| wildcard_captures(&rule.topic, topic).map(|captures| (rule, captures)))
+    else {
+        return Cow::Borrowed(topic);
+    };
+    let mut rewritten = match &rule.replace {
+        Some(template) => substitute(template, &captures),
+        None => topic.to_string(),
+    };
+    if let Some(prefix) = rule.strip_prefix.as_deref().filter(|p| !p.is_empty()) {
+        if let Some(rest) = rewritten.strip_prefix(prefix) {
+            rewritten = rest.to_string();
+        }
+    }
+    if let Some(prefix) = &rule.add_prefix {
+        rewritten.insert_str(0, prefix);
+    }
+    Cow::Owned(rewritten)
+}
+
+/// The levels matched by each wildcard of `pattern`, or `None` if `topic` doesn't match
+pub(crate) fn wildcard_captures<'a>(pattern: &str, topic: &'a str) -> Option<Vec<&'a str>> {
+    if pattern.is_empty() {
+        return None;
+    }
+    let mut captures = Vec::new();
+    let mut rest = Some(topic);
+    for level in pattern.split('/') {
+        if level == "#" {
+            // Also matches the parent level itself ("a/#" matches "a")
+            captures.push(rest.unwrap_or(""));
+            return Some(captures);
+        }
+        let current = rest?;
+        let (head, tail) = match current.split_once('/') {
+            Some((head, tail)) => (head, Some(tail)),
+            None => (current, None),
+        };
+        if level == "+" {
+            captures.push(head);
+        } else if level != head {
+            return None;
+        }
+        rest = tail;
+    }
+    rest.is_none().then_some(captures)
+}
+
+/// Fill `{n}` placeholders; an empty capture also takes the `/` before it
+pub(crate) fn substitute(template: &str, captures: &[&str]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let index = rest[start + 1..start + len]
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_sub(1));
+        result.push_str(&rest[..start]);
+        match index {
+            Some(index) => {
+                let capture = captures.get(index).copied().unwrap_or("");
+                if capture.is_empty() && result.ends_with('/') {
+                    result.pop();
+                }
+                result.push_str(capture);
+            }
+            None => result.push_str(&rest[start..=start + len]),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(topic: &str) -> TopicRewrite {
+        TopicRewrite {
+            topic: topic.to_string(),
+            replace: None,
+            strip_prefix: None,
+            add_prefix: None,
+        }
+    }
+
+    #[test]
+    fn test_rewrite_rules() {
+        let rules = vec![
+            TopicRewrite {
+                add_prefix: Some("site-a/".to_string()),
+                ..rule("sensors/#")
+            },
+            TopicRewrite {
+                strip_prefix: Some("local/".to_string()),
+                ..rule("local/#")
+            },
+            TopicRewrite {
+                replace: Some("devices/{1}/{2}".to_string()),
+                ..rule("zigbee2mqtt/+/#")
+            },
+        ];
+        assert_eq!(
+            apply(&rules, "sensors/kitchen/temp"),
+            "site-a/sensors/kitchen/temp"
+        );
+        assert_eq!(apply(&rules, "local/alarm"), "alarm");
+        assert_eq!(
+            apply(&rules, "zigbee2mqtt/lamp/set/state"),
+            "devices/lamp/set/state"
+        );
+        // "#" also matches its parent level; the empty capture drops its separator
+        assert_eq!(apply(&rules, "zigbee2mqtt/lamp"), "devices/lamp");
+        assert!(matches!(
+            apply(&rules, "other/topic"),
+            Cow::Borrowed("other/topic")
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+//! Live topology graph for the web UI
+//!
+//! Nodes are the main broker, the proxy, the downstream brokers and the clients of the
+//! MQTT listener; edges point in the direction messages flow. Edge rates are derived from
+//! the cumulative message counters over the last `RATE_WINDOW`, sampled whenever the graph
+//! is requested.
+
+use crate::client_registry::ConnectedClient;
+use crate::stats::BrokerBandwidth;
+use crate::web_server::BrokerStatus;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Rates are averaged over at most this long
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+/// Counter samples closer together than this are not kept
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAIN_NODE: &str = "main";
+const PROXY_NODE: &str = "proxy";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    MainBroker,
+    Proxy,
+    Broker,
+    Client,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopologyNode {
+    pub id: String,
+    pub kind: NodeKind,
+    pub label: String,
+    /// Connection state, where the proxy tracks one
+    pub connected: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopologyEdge {
+    pub from: String,
+    pub to: String,
+    /// `None` until two samples of the counter exist, or where messages aren't counted
+    pub messages_per_sec: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Topology {
+    pub nodes: Vec<TopologyNode>,
+    pub edges: Vec<TopologyEdge>,
+}
+
+/// Turns cumulative counters into rates across requests
+#[derive(Default)]
+pub struct RateMeter {
+    samples: Mutex<HashMap<String, VecDeque<(Instant, u64)>>>,
+}
+
+impl RateMeter {
+    /// Record `count` for `key` and return its rate over the window
+    pub fn rate(&self, key: &str, count: u64, now: Instant) -> Option<f64> {
+        let mut samples = self.samples.lock();
+        let history = samples.entry(key.to_string()).or_default();
+        if history
+            .back()
+            .is_none_or(|(at, _)| now.duration_since(*at) >= SAMPLE_INTERVAL)
+        {
+            history.push_back((now, count));
+        }
+        while history.len() > 2
+            && history
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            history.pop_front();
+        }
+        let (since, base) = *history.front()?;
+        let elapsed = now.duration_since(since).as_secs_f64();
+        (elapsed > 0.0).then(|| count.saturating_sub(base) as f64 / elapsed)
+    }
+}
+
+fn broker_node(id: &str) -> String {
+    format!("broker:{}", id)
+}
+
+/// Assemble the graph from the current broker, client and counter state
+pub fn build(
+    main_broker: &str,
+    brokers: &[BrokerStatus],
+    bandwidth: &[BrokerBandwidth],
+    clients: &[ConnectedClient],
+    messages_received: u64,
+    rates: &RateMeter,
+    now: Instant,
+) -> Topology {
+    let mut nodes = vec![
+        TopologyNode {
+            id: MAIN_NODE.to_string(),
+            kind: NodeKind::MainBroker,
+            label: main_broker.to_string(),
+            connected: None,
+        },
+        TopologyNode {
+            id: PROXY_NODE.to_string(),
+            kind: NodeKind::Proxy,
+            label: "mqtt-proxy".to_string(),
+            connected: None,
+        },
+    ];
+    let mut edges = vec![TopologyEdge {
+        from: MAIN_NODE.to_string(),
+        to: PROXY_NODE.to_string(),
+        messages_per_sec: rates.rate("main>proxy", messages_received, now),
+    }];
+
+    let mut relayed = 0;
+    let mut relaying = false;
+    for broker in brokers {
+        let counters = bandwidth
+            .iter()
+            .find(|b| b.broker_id == broker.id)
+            .map(|b| b.counters.clone())
+            .unwrap_or_default();
+        let node = broker_node(&broker.id);
+        nodes.push(TopologyNode {
+            id: node.clone(),
+            kind: NodeKind::Broker,
+            label: broker.name.clone(),
+            connected: Some(broker.connected),
+        });
+        edges.push(TopologyEdge {
+            from: PROXY_NODE.to_string(),
+            to: node.clone(),
+            messages_per_sec: rates.rate(&format!("proxy>{}", node), counters.messages_sent, now),
+        });
+        if broker.bidirectional {
+            relaying = true;
+            relayed += counters.messages_received;
+            edges.push(TopologyEdge {
+                from: node.clone(),
+                to: PROXY_NODE.to_string(),
+                messages_per_sec: rates.rate(
+                    &format!("{}>proxy", node),
+                    counters.messages_received,
+                    now,
+                ),
+            });
+        }
+    }
+    // Messages from bidirectional brokers continue to the main broker
+    if relaying {
+        edges.push(TopologyEdge {
+            from: PROXY_NODE.to_string(),
+            to: MAIN_NODE.to_string(),
+            messages_per_sec: rates.rate("proxy>main", relayed, now),
+        });
+    }
+
+    for client in clients {
+        let node = format!("client:{}", client.client_id);
+        nodes.push(TopologyNode {
+            id: node.clone(),
+            kind: NodeKind::Client,
+            label: client.client_id.clone(),
+            connected: Some(true),
+        });
+        edges.push(TopologyEdge {
+            from: node,
+            to: PROXY_NODE.to_string(),
+            messages_per_sec: None,
+        });
+    }
+
+    Topology { nodes, edges }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::ByteCounters;
+
+    fn broker(id: &str, bidirectional: bool) -> BrokerStatus {
+        BrokerStatus {
+            id: id.to_string(),
+            name: id.to_uppercase(),
+            address: "localhost".to_string(),
+            port: 1883,
+            connected: true,
+            enabled: true,
+            bidirectional,
+            topics: vec![],
+            subscription_topics: vec![],
+            rtt_ms: None,
+            flapping: false,
+            buffered_messages: 0,
+            watchdog_restarts: 0,
+            degraded: false,
+            probe_latency_ms: None,
+        }
+    }
+
+    fn sent(id: &str, messages_sent: u64, messages_received: u64) -> BrokerBandwidth {
+        BrokerBandwidth {
+            broker_id: id.to_string(),
+            name: id.to_uppercase(),
+            counters: ByteCounters {
+                messages_sent,
+                messages_received,
+                ..ByteCounters::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_topology_edges_carry_rates() {
+        let rates = RateMeter::default();
+        let brokers = [broker("cloud", false), broker("edge", true)];
+        let start = Instant::now();
+        let first = build(
+            "mosquitto:1883",
+            &brokers,
+            &[sent("cloud", 100, 0), sent("edge", 100, 50)],
+            &[],
+            200,
+            &rates,
+            start,
+        );
+        assert_eq!(first.nodes.len(), 4);
+        // main > proxy, proxy > cloud, proxy > edge, edge > proxy, proxy > main
+        assert_eq!(first.edges.len(), 5);
+        assert!(first.edges.iter().all(|e| e.messages_per_sec.is_none()));
+
+        let later = build(
+            "mosquitto:1883",
+            &brokers,
+            &[sent("cloud", 120, 0), sent("edge", 100, 60)],
+            &[],
+            240,
+            &rates,
+            start + Duration::from_secs(2),
+        );
+        let rate = |from: &str, to: &str| {
+            later
+                .edges
+                .iter()
+                .find(|e| e.from == from && e.to == to)
+                .and_then(|e| e.messages_per_sec)
+        };
+        assert_eq!(rate("main", "proxy"), Some(20.0));
+        assert_eq!(rate("proxy", "broker:cloud"), Some(10.0));
+        assert_eq!(rate("proxy", "broker:edge"), Some(0.0));
+        assert_eq!(rate("broker:edge", "proxy"), Some(5.0));
+        assert_eq!(rate("proxy", "main"), Some(5.0));
+    }
+}
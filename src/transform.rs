@@ -0,0 +1,135 @@
+//! Declarative JSON payload transformations per broker
+//!
+//! Downstream systems often expect a slightly different schema than devices publish. A
+//! transform rule adapts JSON object payloads on matching topics without scripting: fields
+//! are renamed, numeric fields scaled (unit conversion) and static fields such as a site ID
+//! added, in that order. Payloads that aren't JSON objects are forwarded unchanged.
+
+use crate::connection_manager::ConnectionManager;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PayloadTransform {
+    /// Topic pattern the rule applies to (`+`/`#` wildcards)
+    pub topic: String,
+    /// Old field path → new field path (dotted paths address nested objects)
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+    /// Field path → factor the numeric value is multiplied by (after renaming)
+    #[serde(default)]
+    pub scale: BTreeMap<String, f64>,
+    /// Fields set to fixed values, overwriting existing ones
+    #[serde(default)]
+    pub set: BTreeMap<String, Value>,
+}
+
+/// Apply the first rule matching `topic`; the payload is returned as is if none matches
+/// or it isn't a JSON object
+pub fn apply(rules: &[PayloadTransform], topic: &str, payload: Bytes) -> Bytes {
+    let Some(rule) = rules.iter().find(|rule| {
+        !rule.topic.is_empty() && ConnectionManager::topic_matches_pattern(&rule.topic, topic)
+    }) else {
+        return payload;
+    };
+    let Ok(Value::Object(mut object)) = serde_json::from_slice::<Value>(&payload) else {
+        return payload;
+    };
+
+    for (from, to) in &rule.rename {
+        if let Some(value) = take(&mut object, from) {
+            insert(&mut object, to, value);
+        }
+    }
+    for (path, factor) in &rule.scale {
+        if let Some(value) = get_mut(&mut object, path) {
+            if let Some(scaled) = value
+                .as_f64()
+                .and_then(|n| serde_json::Number::from_f64(n * factor))
+            {
+                *value = Value::Number(scaled);
+            }
+        }
+    }
+    for (path, value) in &rule.set {
+        insert(&mut object, path, value.clone());
+    }
+
+    match serde_json::to_vec(&Value::Object(object)) {
+        Ok(transformed) => Bytes::from(transformed),
+        Err(_) => payload,
+    }
+}
+
+fn get_mut<'a>(object: &'a mut Map<String, Value>, path: &str) -> Option<&'a mut Value> {
+    let mut parts = path.split('.');
+    let mut value = object.get_mut(parts.next()?)?;
+    for part in parts {
+        value = value.as_object_mut()?.get_mut(part)?;
+    }
+    Some(value)
+}
+
+fn take(object: &mut Map<String, Value>, path: &str) -> Option<Value> {
+    match path.rsplit_once('.') {
+        Some((parent, key)) => get_mut(object, parent)?.as_object_mut()?.remove(key),
+        None => object.remove(path),
+    }
+}
+
+/// Set a value at a dotted path, creating intermediate objects as needed
+fn insert(object: &mut Map<String, Value>, path: &str, value: Value) {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (Some(parent), key),
+        None => (None, path),
+    };
+    let mut target = object;
+    for part in parent.into_iter().flat_map(|p| p.split('.')) {
+        let entry = target
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !entry.is_object() {
+            *entry = Value::Object(Map::new());
+        }
+        target = entry
+            .as_object_mut()
+            .expect("entry was just made an object");
+    }
+    target.insert(key.to_string(), value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_rename_scale_and_set() {
+        let rules = vec![PayloadTransform {
+            topic: "sensors/+/state".to_string(),
+            rename: BTreeMap::from([
+                ("temp_f".to_string(), "temperature".to_string()),
+                ("bat".to_string(), "battery.level".to_string()),
+            ]),
+            scale: BTreeMap::from([("battery.level".to_string(), 0.01)]),
+            set: BTreeMap::from([("site".to_string(), json!("plant-1"))]),
+        }];
+
+        let payload = Bytes::from_static(br#"{"temp_f":70.5,"bat":87,"status":"ok"}"#);
+        let transformed: Value =
+            serde_json::from_slice(&apply(&rules, "sensors/a/state", payload)).unwrap();
+        assert_eq!(
+            transformed,
+            json!({"temperature": 70.5, "battery": {"level": 0.87}, "status": "ok", "site": "plant-1"})
+        );
+
+        // Other topics and non-JSON payloads are left alone
+        let raw = Bytes::from_static(br#"{"temp_f":70.5}"#);
+        assert_eq!(apply(&rules, "other", raw.clone()), raw);
+        let text = Bytes::from_static(b"offline");
+        assert_eq!(apply(&rules, "sensors/a/state", text.clone()), text);
+    }
+}
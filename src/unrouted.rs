@@ -0,0 +1,183 @@
+//! Handling of messages that no downstream broker's filters match
+//!
+//! Such messages are always counted. Depending on `[unrouted] action` they are then
+//! dropped quietly (`ignore`), dropped with a warning (`warn`), sent to a catch-all broker
+//! (`broker`) or republished on the main broker under the dead-letter topic prefix
+//! (`dead_letter`). Messages already under the dead-letter prefix, which the proxy
+//! receives back through its `#` subscription, are never dead-lettered again.
+
+use crate::config::{MainBrokerConfig, UnroutedAction, UnroutedConfig};
+use bytes::Bytes;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Dead-letter messages waiting for the publisher; further ones are dropped
+pub const DEAD_LETTER_QUEUE_SIZE: usize = 1000;
+
+/// A message to republish under the dead-letter topic prefix
+#[derive(Debug)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+}
+
+/// What `forward_message` should do with an unrouted message
+#[derive(Debug, PartialEq)]
+pub enum UnroutedOutcome<'a> {
+    Dropped,
+    /// Send it to the broker with this ID
+    Broker(&'a str),
+}
+
+#[derive(Default)]
+pub struct UnroutedHandler {
+    config: UnroutedConfig,
+    count: AtomicU64,
+    dead_letters: Option<mpsc::Sender<DeadLetter>>,
+}
+
+impl UnroutedHandler {
+    /// `dead_letters` feeds `run_dead_letter_publisher` for `action = "dead_letter"`
+    pub fn new(config: UnroutedConfig, dead_letters: Option<mpsc::Sender<DeadLetter>>) -> Self {
+        Self {
+            config,
+            count: AtomicU64::new(0),
+            dead_letters,
+        }
+    }
+
+    /// Messages that matched no broker since startup
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn handle(&self, topic: &str, payload: &Bytes, qos: QoS) -> UnroutedOutcome<'_> {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        match self.config.action {
+            UnroutedAction::Ignore => debug!("No broker matches '{}'; not forwarded", topic),
+            UnroutedAction::Warn => warn!("No broker matches '{}'; message dropped", topic),
+            UnroutedAction::Broker => match &self.config.broker {
+                Some(broker) => return UnroutedOutcome::Broker(broker),
+                None => warn!(
+                    "No broker matches '{}' and [unrouted] broker isn't set",
+                    topic
+                ),
+            },
+            UnroutedAction::DeadLetter => {
+                let prefix = self.config.dead_letter_topic.trim_end_matches('/');
+                if topic == prefix || topic.starts_with(&format!("{}/", prefix)) {
+                    return UnroutedOutcome::Dropped;
+                }
+                let dead_letter = DeadLetter {
+                    topic: format!("{}/{}", prefix, topic),
+                    payload: payload.clone(),
+                    qos,
+                };
+                if let Some(Err(e)) = self
+                    .dead_letters
+                    .as_ref()
+                    .map(|tx| tx.try_send(dead_letter))
+                {
+                    warn!("Dropping unrouted message on '{}': {}", topic, e);
+                }
+            }
+        }
+        UnroutedOutcome::Dropped
+    }
+}
+
+/// Publish dead-lettered messages to the main broker until `shutdown` is cancelled.
+/// Like usage reports, they go through a connection of their own.
+pub async fn run_dead_letter_publisher(
+    mut dead_letters: mpsc::Receiver<DeadLetter>,
+    main_broker: MainBrokerConfig,
+    shutdown: CancellationToken,
+) {
+    let mut options = MqttOptions::new(
+        format!("{}-deadletter", main_broker.client_id),
+        &main_broker.address,
+        main_broker.port,
+    );
+    options.set_keep_alive(Duration::from_secs(60));
+    if let (Some(username), Some(password)) = (&main_broker.username, &main_broker.password) {
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, DEAD_LETTER_QUEUE_SIZE);
+    info!("Publishing unrouted messages to the main broker");
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            Some(dead_letter) = dead_letters.recv() => {
+                if let Err(e) = client.try_publish(
+                    &dead_letter.topic,
+                    dead_letter.qos,
+                    false,
+                    dead_letter.payload.to_vec(),
+                ) {
+                    warn!("Failed to publish dead letter on '{}': {}", dead_letter.topic, e);
+                }
+            }
+            result = eventloop.poll() => {
+                if let Err(e) = result {
+                    warn!("Dead-letter connection error: {}", e);
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                    }
+                }
+            }
+        }
+    }
+    let _ = client.try_disconnect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_letters_skip_their_own_prefix() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let handler = UnroutedHandler::new(
+            UnroutedConfig {
+                action: UnroutedAction::DeadLetter,
+                broker: None,
+                dead_letter_topic: "proxy/unrouted/".to_string(),
+            },
+            Some(tx),
+        );
+
+        let payload = Bytes::from_static(b"21.5");
+        assert_eq!(
+            handler.handle("sensors/a", &payload, QoS::AtLeastOnce),
+            UnroutedOutcome::Dropped
+        );
+        let dead_letter = rx.try_recv().unwrap();
+        assert_eq!(dead_letter.topic, "proxy/unrouted/sensors/a");
+        assert_eq!(dead_letter.payload, payload);
+
+        // The republished message comes back through the main broker subscription
+        handler.handle("proxy/unrouted/sensors/a", &payload, QoS::AtLeastOnce);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(handler.count(), 2);
+
+        let catch_all = UnroutedHandler::new(
+            UnroutedConfig {
+                action: UnroutedAction::Broker,
+                broker: Some("cloud".to_string()),
+                ..UnroutedConfig::default()
+            },
+            None,
+        );
+        assert_eq!(
+            catch_all.handle("sensors/a", &payload, QoS::AtMostOnce),
+            UnroutedOutcome::Broker("cloud")
+        );
+    }
+}
@@ -1,27 +1,73 @@
-use crate::broker_storage::{BrokerConfig, BrokerStorage};
+use crate::acl::{is_valid_filter, ClientAcl};
+use crate::acme::Acme;
+use crate::audit::{self, Actor, AuditAction, AuditEntry, AuditLog};
+use crate::batching::BatchRule;
+use crate::broker_client::{PROTOCOL_V4, PROTOCOL_V5};
+use crate::broker_diff::{self, UpdatePreview};
+use crate::broker_probe::{self, ProbeReport, ProbeRequest};
+use crate::broker_storage::{
+    BrokerConfig, BrokerStorage, DuplicateName, RetainPolicy, RevisionMismatch, SubscriptionMode,
+    MAX_CONNECTION_COUNT,
+};
+use crate::client_registry::ConnectedClient;
+use crate::config::WebUiConfig;
 use crate::connection_manager::ConnectionManager;
+use crate::dead_letters::FailedForward;
+use crate::dedup::DedupOverride;
+use crate::delivery_groups::GroupCounters;
+use crate::ingest::{take_lines, IngestRecord, IngestSummary, Ingestor, MAX_LINE_BYTES};
+use crate::message_history::{HistoryQuery, MessageHistory};
+use crate::metrics::{self, Metrics};
+use crate::offline_buffer::OfflineBufferConfig;
+use crate::payload_filter::PayloadFilter;
+use crate::remote_config::ManagedRemotely;
+use crate::reports::UsageReport;
+use crate::resource_profile;
+use crate::routing::RoutingTable;
+use crate::rules::Rule;
+use crate::runtime_stats::{CacheSizes, MemoryStats, QueueDepth, RuntimeStats, TaskStats};
+use crate::sampling::SamplingRule;
+use crate::send_queue::SendQueueConfig;
 use crate::settings_storage::{MainBrokerSettings, SettingsStorage};
+use crate::stats::{
+    parse_duration, BrokerBandwidth, RttSample, TimeseriesPoint, TopicBandwidth, TrafficStats,
+};
+use crate::stream_filter::{ControlMessage, ControlReply, StreamFilter, StreamQuery};
+use crate::subscription_table::MAIN_BROKER_DEMAND;
+use crate::suppression::RuleCounters;
+use crate::timestamp_check::TimestampCounters;
+use crate::topic_rewrite::TopicRewrite;
+use crate::topology::{self, RateMeter, Topology};
+use crate::transform::PayloadTransform;
+use crate::web_tls;
 use axum::{
+    async_trait,
+    body::{Body, HttpBody},
     extract::{
         ws::{Message, WebSocket},
-        Path, State, WebSocketUpgrade,
+        ConnectInfo, FromRequestParts, Path, Query, State, WebSocketUpgrade,
     },
-    http::StatusCode,
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Json},
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 use chrono::{DateTime, Utc};
 use rumqttc::{Event, Incoming, MqttOptions};
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
 use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use tower_http::services::ServeDir;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 // Message structure for real-time updates
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MqttMessage {
     pub timestamp: DateTime<Utc>,
     pub client_id: String,
@@ -29,113 +75,215 @@ pub struct MqttMessage {
     pub payload: Vec<u8>,
     pub qos: u8,
     pub retain: bool,
+    /// Per-broker forwarding results, only populated when `debug_deliveries` is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deliveries: Option<Vec<DeliveryResult>>,
+}
+
+/// Outcome of forwarding a single message to one downstream broker
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeliveryResult {
+    pub broker: String,
+    pub outcome: DeliveryOutcome,
+    pub latency_ms: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOutcome {
+    Delivered,
+    Failed,
+    Timeout,
 }
 
 pub struct WebServer {
-    port: u16,
+    config: WebUiConfig,
     connection_manager: Arc<RwLock<ConnectionManager>>,
-    broker_storage: Arc<BrokerStorage>,
-    settings_storage: Arc<SettingsStorage>,
+    broker_storage: Arc<dyn BrokerStorage>,
+    settings_storage: Arc<dyn SettingsStorage>,
     main_broker_restart_tx: mpsc::Sender<()>,
     message_tx: broadcast::Sender<MqttMessage>,
-    messages_received: Arc<AtomicU64>,
-    messages_forwarded: Arc<AtomicU64>,
-    total_latency_ns: Arc<AtomicU64>,
+    message_history: Arc<MessageHistory>,
 }
 
 impl WebServer {
     pub fn new(
-        port: u16,
+        config: WebUiConfig,
         connection_manager: Arc<RwLock<ConnectionManager>>,
-        broker_storage: Arc<BrokerStorage>,
-        settings_storage: Arc<SettingsStorage>,
+        broker_storage: Arc<dyn BrokerStorage>,
+        settings_storage: Arc<dyn SettingsStorage>,
         main_broker_restart_tx: mpsc::Sender<()>,
-    ) -> (
-        Self,
-        broadcast::Sender<MqttMessage>,
-        Arc<AtomicU64>,
-        Arc<AtomicU64>,
-        Arc<AtomicU64>,
-    ) {
-        let (message_tx, _) = broadcast::channel(1000); // Buffer 1000 messages
+        message_history: Arc<MessageHistory>,
+    ) -> (Self, broadcast::Sender<MqttMessage>) {
+        let (message_tx, _) = broadcast::channel(resource_profile::limits().ui_broadcast);
         let tx_clone = message_tx.clone();
-        let messages_received = Arc::new(AtomicU64::new(0));
-        let messages_forwarded = Arc::new(AtomicU64::new(0));
-        let total_latency_ns = Arc::new(AtomicU64::new(0));
-        let received_clone = Arc::clone(&messages_received);
-        let forwarded_clone = Arc::clone(&messages_forwarded);
-        let latency_clone = Arc::clone(&total_latency_ns);
 
         (
             Self {
-                port,
+                config,
                 connection_manager,
                 broker_storage,
                 settings_storage,
                 main_broker_restart_tx,
                 message_tx,
-                messages_received,
-                messages_forwarded,
-                total_latency_ns,
+                message_history,
             },
             tx_clone,
-            received_clone,
-            forwarded_clone,
-            latency_clone,
         )
     }
 
-    pub async fn run(self) -> anyhow::Result<()> {
+    /// Serve the API and Web UI until `shutdown` is cancelled, then wait for the
+    /// history writer to flush
+    pub async fn run(self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind(format!(
+            "{}:{}",
+            self.config.bind_address, self.config.port
+        ))
+        .await?;
+        let acme = match &self.config.acme {
+            Some(acme_config) => Some(Arc::new(Acme::new(acme_config.clone())?)),
+            None => None,
+        };
+        let tls = match &acme {
+            Some(acme) => Some(acme.acceptor()),
+            None => web_tls::build_web_tls_acceptor(&self.config)?,
+        };
+        // Child token, so a server error stops the history writer but not the whole proxy
+        let shutdown = shutdown.child_token();
+        let acme_task = acme
+            .map(|acme| tokio::spawn(acme.run(self.config.bind_address.clone(), shutdown.clone())));
+
+        // Record broadcast messages into the searchable history buffer
+        let history = Arc::clone(&self.message_history);
+        let mut history_rx = self.message_tx.subscribe();
+        let history_shutdown = shutdown.clone();
+        let history_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = history_shutdown.cancelled() => break,
+                    result = history_rx.recv() => match result {
+                        Ok(msg) => history.push(msg),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!("Message history lagged, skipped {} messages", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                }
+            }
+            let _ = tokio::task::spawn_blocking(move || history.flush()).await;
+        });
+
+        let ingestor = Arc::new(Ingestor::new(
+            Arc::clone(&self.connection_manager),
+            Some(self.message_tx.clone()),
+            self.config.ingest.client_id.clone(),
+            self.config.debug_deliveries,
+        ));
+        let audit_log = Arc::new(AuditLog::open(&self.config.audit_log_path)?);
         let app_state = AppState {
             connection_manager: self.connection_manager,
             broker_storage: self.broker_storage,
             settings_storage: self.settings_storage,
             main_broker_restart_tx: self.main_broker_restart_tx,
             message_tx: self.message_tx.clone(),
-            messages_received: self.messages_received,
-            messages_forwarded: self.messages_forwarded,
-            total_latency_ns: self.total_latency_ns,
+            metrics: Metrics::global(),
+            message_history: self.message_history,
+            topology_rates: Arc::new(RateMeter::default()),
+            shutdown: shutdown.clone(),
+            ingestor,
+            ingest_max_batch: self.config.ingest.max_batch,
+            audit_log,
         };
 
-        let app = Router::new()
-            .route("/health", get(health_check))
-            .route("/api/brokers", get(list_brokers).post(add_broker))
-            .route(
-                "/api/brokers/:id",
-                get(get_broker).put(update_broker).delete(delete_broker),
-            )
-            .route("/api/brokers/:id/toggle", post(toggle_broker))
-            .route("/api/status", get(get_status))
-            .route(
-                "/api/settings/main-broker",
-                get(get_main_broker_settings).put(update_main_broker_settings),
-            )
-            .route(
-                "/api/settings/main-broker/test",
-                post(test_main_broker_connection),
-            )
-            .route("/ws/messages", get(websocket_handler))
-            .nest_service("/", ServeDir::new("web-ui/dist"))
-            .with_state(app_state);
-
-        let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
-        info!("Web UI listening on http://0.0.0.0:{}", self.port);
+        if self.config.ingest.enabled {
+            info!(
+                "HTTP ingestion enabled as client '{}'",
+                self.config.ingest.client_id
+            );
+        }
+        let app = api_routes(self.config.ingest.enabled);
+        let ui_dir = self.config.serve_ui.then_some(self.config.ui_dir);
+        let (app, ui_app) = with_ui(app, ui_dir, self.config.ui_port, self.config.port);
 
-        axum::serve(listener, app).await?;
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        info!(
+            "{} listening on {}://{}:{}",
+            if ui_app.is_some() { "API" } else { "Web UI" },
+            scheme,
+            self.config.bind_address,
+            self.config.port
+        );
+        let api_server = serve(
+            listener,
+            app.with_state(app_state),
+            tls.clone(),
+            shutdown.clone(),
+        );
+        let served = match (ui_app, self.config.ui_port) {
+            (Some(ui_app), Some(ui_port)) => {
+                let address = format!("{}:{}", self.config.ui_bind_address, ui_port);
+                match tokio::net::TcpListener::bind(&address).await {
+                    Ok(ui_listener) => {
+                        info!("Web UI listening on {}://{}", scheme, address);
+                        let ui_server = serve(ui_listener, ui_app, tls, shutdown.clone());
+                        tokio::try_join!(api_server, ui_server).map(|_| ())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            _ => api_server.await,
+        };
+        // Stop the history writer even if the server failed
+        shutdown.cancel();
+        let _ = history_task.await;
+        if let Some(acme_task) = acme_task {
+            let _ = acme_task.await;
+        }
+        info!("Web UI stopped");
+        served?;
         Ok(())
     }
 }
 
+/// Serve `app` on `listener` until `shutdown` is cancelled, over TLS with an acceptor
+async fn serve(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    tls: Option<TlsAcceptor>,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    match tls {
+        Some(acceptor) => web_tls::serve(listener, acceptor, app, shutdown).await,
+        // The peer address goes into the audit log
+        None => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+        }
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     connection_manager: Arc<RwLock<ConnectionManager>>,
-    broker_storage: Arc<BrokerStorage>,
-    settings_storage: Arc<SettingsStorage>,
+    broker_storage: Arc<dyn BrokerStorage>,
+    settings_storage: Arc<dyn SettingsStorage>,
     main_broker_restart_tx: mpsc::Sender<()>,
     message_tx: broadcast::Sender<MqttMessage>,
-    messages_received: Arc<AtomicU64>,
-    messages_forwarded: Arc<AtomicU64>,
-    total_latency_ns: Arc<AtomicU64>,
+    metrics: Arc<Metrics>,
+    message_history: Arc<MessageHistory>,
+    /// Counter samples behind the edge rates of `/api/topology`
+    topology_rates: Arc<RateMeter>,
+    /// Cancelled when the proxy shuts down; closes open WebSocket sessions
+    shutdown: CancellationToken,
+    /// Publishes records from `/api/ingest` (`[web_ui.ingest]`)
+    ingestor: Arc<Ingestor>,
+    ingest_max_batch: usize,
+    /// Changes made through the API (see `audit`)
+    audit_log: Arc<AuditLog>,
 }
 
 // Health check endpoint
@@ -143,6 +291,209 @@ async fn health_check() -> &'static str {
     "OK"
 }
 
+// Prometheus scrape endpoint
+async fn get_metrics() -> impl IntoResponse {
+    let (content_type, body) = metrics::render();
+    ([(header::CONTENT_TYPE, content_type)], body)
+}
+
+/// The API's routes; `/api/ingest` only with ingestion enabled
+fn api_routes(ingest: bool) -> Router<AppState> {
+    let app = Router::new()
+        .route("/health", get(health_check))
+        .route("/metrics", get(get_metrics))
+        .route("/api/brokers", get(list_brokers).post(add_broker))
+        .route(
+            "/api/brokers/:id",
+            get(get_broker).put(update_broker).delete(delete_broker),
+        )
+        .route("/api/brokers/probe", post(probe_broker))
+        .route("/api/brokers/:id/preview", post(preview_broker_update))
+        .route("/api/brokers/:id/toggle", post(toggle_broker))
+        .route("/api/brokers/:id/latency", get(get_broker_latency))
+        .route("/api/brokers/:id/resubscribe", post(resubscribe_broker))
+        .route("/api/resubscribe", post(resubscribe_all))
+        .route("/api/subscriptions", get(get_subscription_demand))
+        .route(
+            "/api/subscriptions/main",
+            get(get_main_demand).put(set_main_demand),
+        )
+        .route("/api/status", get(get_status))
+        .route("/api/stats/timeseries", get(get_timeseries))
+        .route("/api/stats/bandwidth", get(get_bandwidth))
+        .route("/api/stats/duplicates", get(get_duplicates))
+        .route("/api/stats/timestamps", get(get_timestamps))
+        .route("/api/stats/delivery-groups", get(get_delivery_groups))
+        .route("/api/clients", get(list_clients))
+        .route("/api/topology", get(get_topology))
+        .route("/api/messages", get(search_messages))
+        .route("/api/reports/usage", get(get_usage_report))
+        .route("/api/audit", get(get_audit_log))
+        .route(
+            "/api/settings/main-broker",
+            get(get_main_broker_settings).put(update_main_broker_settings),
+        )
+        .route(
+            "/api/settings/main-broker/test",
+            post(test_main_broker_connection),
+        )
+        .route(
+            "/api/routing",
+            get(get_routing).put(set_routing).delete(delete_routing),
+        )
+        .route("/api/rules", get(get_rules).put(set_rules).post(add_rule))
+        .route("/api/rules/:id", delete(delete_rule))
+        .route(
+            "/api/deadletter",
+            get(list_dead_letters).delete(purge_dead_letters),
+        )
+        .route("/api/deadletter/redrive", post(redrive_dead_letters))
+        .route("/api/deadletter/:id", delete(delete_dead_letter))
+        .route("/api/debug/runtime", get(get_runtime_stats))
+        .route("/api/acls", get(list_acls))
+        .route("/api/acls/:identity", put(set_acl).delete(delete_acl))
+        .route("/ws/messages", get(websocket_handler));
+    if ingest {
+        app.route("/api/ingest", post(ingest_batch))
+            .route("/api/ingest/stream", post(ingest_stream))
+    } else {
+        app
+    }
+}
+
+/// Add the Web UI to the API's routes. With a separate UI port, only the UI's files are
+/// served there, returned as the second router; the UI calls the API on `api_port`,
+/// across origins
+fn with_ui(
+    app: Router<AppState>,
+    ui_dir: Option<String>,
+    ui_port: Option<u16>,
+    api_port: u16,
+) -> (Router<AppState>, Option<Router>) {
+    match (ui_files(ui_dir), ui_port) {
+        (UiFiles::Found(dir), None) => (app.nest_service("/", ServeDir::new(dir)), None),
+        (UiFiles::Found(dir), Some(ui_port)) => (
+            api_only(app, UiFiles::OtherPort(ui_port)).layer(ui_cors(ui_port)),
+            Some(ui_only(dir, api_port)),
+        ),
+        (ui, _) => (api_only(app, ui), None),
+    }
+}
+
+/// Whether the Web UI's static files are served
+#[derive(Clone)]
+enum UiFiles {
+    Found(String),
+    Missing(String),
+    Disabled,
+    /// Served on `ui_port`
+    OtherPort(u16),
+}
+
+/// The API with a landing page at / instead of the Web UI
+fn api_only(app: Router<AppState>, ui: UiFiles) -> Router<AppState> {
+    app.route("/", get(move || api_landing(ui.clone())))
+        .fallback(|| async { AppError::NotFound })
+}
+
+/// The Web UI's files for `ui_port`, without the API; `/ui-config.json` tells the UI
+/// which port the API is on
+fn ui_only(dir: String, api_port: u16) -> Router {
+    let ui_config = serde_json::json!({ "apiPort": api_port });
+    Router::new()
+        .route(
+            "/ui-config.json",
+            get(move || async move { Json(ui_config) }),
+        )
+        .nest_service("/", ServeDir::new(dir))
+}
+
+/// Lets the Web UI on `ui_port` call the API: requests from that port of the host the
+/// API was reached on are allowed, other origins get no CORS headers
+fn ui_cors(ui_port: u16) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, parts| {
+            let host = parts
+                .headers
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(host_name);
+            origin_host_port(origin)
+                .is_some_and(|(origin_host, port)| port == ui_port && host == Some(origin_host))
+        }))
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .expose_headers([header::ETAG])
+}
+
+/// The host and port of an `Origin` header value, with the scheme's default port
+fn origin_host_port(origin: &HeaderValue) -> Option<(&str, u16)> {
+    let (scheme, authority) = origin.to_str().ok()?.split_once("://")?;
+    let host = host_name(authority);
+    let port = match authority[host.len()..].strip_prefix(':') {
+        Some(port) => port.parse().ok()?,
+        None if scheme == "https" => 443,
+        None => 80,
+    };
+    Some((host, port))
+}
+
+/// `authority` without its port; IPv6 addresses keep their brackets
+fn host_name(authority: &str) -> &str {
+    match authority.rfind(':') {
+        Some(colon) if !authority[colon..].contains(']') => &authority[..colon],
+        _ => authority,
+    }
+}
+
+fn ui_files(dir: Option<String>) -> UiFiles {
+    match dir {
+        Some(dir) if std::path::Path::new(&dir).join("index.html").is_file() => UiFiles::Found(dir),
+        Some(dir) => {
+            warn!(
+                "Web UI not found in '{}' (build it with `npm run build` in web-ui); serving the API only",
+                dir
+            );
+            UiFiles::Missing(dir)
+        }
+        None => UiFiles::Disabled,
+    }
+}
+
+// Landing page at / when the Web UI isn't served
+async fn api_landing(ui: UiFiles) -> Json<serde_json::Value> {
+    let (status, message) = match ui {
+        UiFiles::Missing(dir) => (
+            "missing",
+            format!("Web UI files not found in '{}'; the API is available", dir),
+        ),
+        UiFiles::OtherPort(port) => (
+            "separate_port",
+            format!(
+                "Web UI is served on port {}; this port serves the API only",
+                port
+            ),
+        ),
+        _ => (
+            "disabled",
+            "Web UI is disabled (web_ui.serve_ui = false); the API is available".to_string(),
+        ),
+    };
+    Json(serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "ui": status,
+        "message": message,
+        "links": {
+            "health": "/health",
+            "metrics": "/metrics",
+            "status": "/api/status",
+            "brokers": "/api/brokers",
+            "messages": "/ws/messages",
+        },
+    }))
+}
+
 // List all brokers
 async fn list_brokers(
     State(state): State<AppState>,
@@ -155,18 +506,46 @@ async fn list_brokers(
 async fn get_broker(
     State(state): State<AppState>,
     Path(id): Path<String>,
-) -> Result<Json<BrokerConfig>, AppError> {
+) -> Result<impl IntoResponse, AppError> {
     let broker = state
         .broker_storage
         .get(&id)
         .await
         .ok_or(AppError::NotFound)?;
-    Ok(Json(broker))
+    Ok(([(header::ETAG, etag(broker.revision))], Json(broker)))
+}
+
+/// Entity tag of a broker revision
+fn etag(revision: u64) -> String {
+    format!("\"{}\"", revision)
+}
+
+/// The revision an update is based on, from its `If-Match` header (`*` stands for the
+/// current one). Updates without the header are refused, so concurrent edits can't
+/// silently overwrite each other.
+fn if_match(headers: &HeaderMap, current: &BrokerConfig) -> Result<u64, AppError> {
+    let value = headers
+        .get(header::IF_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(AppError::PreconditionRequired)?;
+    let matches = value.split(',').map(str::trim).any(|tag| {
+        tag == "*"
+            || tag
+                .strip_prefix('"')
+                .and_then(|tag| tag.strip_suffix('"'))
+                .and_then(|revision| revision.parse::<u64>().ok())
+                == Some(current.revision)
+    });
+    if !matches {
+        return Err(AppError::RevisionMismatch(Box::new(current.clone())));
+    }
+    Ok(current.revision)
 }
 
 // Add new broker
 async fn add_broker(
     State(state): State<AppState>,
+    actor: Actor,
     Json(payload): Json<AddBrokerRequest>,
 ) -> Result<Json<BrokerConfig>, AppError> {
     // Generate unique ID
@@ -178,6 +557,8 @@ async fn add_broker(
         address: payload.address,
         port: payload.port,
         client_id_prefix: payload.client_id_prefix,
+        client_id: payload.client_id.filter(|id| !id.is_empty()),
+        clean_session: payload.clean_session.unwrap_or(true),
         username: if payload.username.is_empty() {
             None
         } else {
@@ -191,42 +572,357 @@ async fn add_broker(
         enabled: payload.enabled.unwrap_or(true),
         use_tls: payload.use_tls.unwrap_or(false),
         insecure_skip_verify: payload.insecure_skip_verify.unwrap_or(false),
+        skip_hostname_verification: payload.skip_hostname_verification.unwrap_or(false),
         ca_cert_path: payload.ca_cert_path,
+        client_cert_path: payload.client_cert_path.filter(|p| !p.is_empty()),
+        client_key_path: payload.client_key_path.filter(|p| !p.is_empty()),
+        tls_verify_hostname: payload.tls_verify_hostname.filter(|h| !h.is_empty()),
+        alpn_protocols: payload.alpn_protocols.unwrap_or_default(),
+        protocol_version: validate_protocol_version(
+            payload.protocol_version.unwrap_or(PROTOCOL_V4),
+        )?,
         bidirectional: payload.bidirectional.unwrap_or(false),
         topics: payload.topics.unwrap_or_default(),
         subscription_topics: payload.subscription_topics.unwrap_or_default(),
+        subscription_mode: payload.subscription_mode.unwrap_or_default(),
+        subscription_qos: validate_subscription_qos(payload.subscription_qos)?,
+        encrypt_topics: payload.encrypt_topics.unwrap_or_default(),
+        sign_topics: payload.sign_topics.unwrap_or_default(),
+        sampling: validate_sampling(payload.sampling.unwrap_or_default())?,
+        batching: validate_batching(payload.batching.unwrap_or_default())?,
+        transforms: validate_transforms(payload.transforms.unwrap_or_default())?,
+        compress_topics: payload.compress_topics.unwrap_or_default(),
+        offline_buffer: payload.offline_buffer.unwrap_or_default(),
+        send_queue: validate_send_queue(payload.send_queue.unwrap_or_default())?,
+        connection_count: validate_connection_count(payload.connection_count.unwrap_or(1))?,
+        topic_rewrites: payload.topic_rewrites.unwrap_or_default(),
+        reverse_retain: payload.reverse_retain.unwrap_or_default(),
+        forward_properties: payload.forward_properties.unwrap_or(true),
+        payload_filters: validate_payload_filters(payload.payload_filters.unwrap_or_default())?,
+        priority: payload.priority.unwrap_or_default(),
+        is_default: payload.is_default.unwrap_or_default(),
+        dedup: payload.dedup.unwrap_or_default(),
+        revision: 0,
     };
+    ensure_single_default(&state, &broker).await?;
 
     state.broker_storage.add(broker.clone()).await?;
 
     // Notify connection manager to establish connection (uses plaintext password)
     let mut manager = state.connection_manager.write().await;
-    manager.add_broker(broker.clone()).await?;
+    manager
+        .add_broker(broker.clone())
+        .await
+        .map_err(AppError::Unreachable)?;
 
     info!("Broker '{}' added via API", broker.name);
+    let broker = broker.with_hidden_password();
+    state.audit_log.record(
+        actor,
+        AuditAction::BrokerAdded,
+        &broker.id,
+        Some(&broker.name),
+        audit::changes(None, Some(&broker), false),
+    );
     // Return config with hidden password
-    Ok(Json(broker.with_hidden_password()))
+    Ok(Json(broker))
+}
+
+/// Only one broker can be the catch-all for unmatched messages
+async fn ensure_single_default(state: &AppState, broker: &BrokerConfig) -> Result<(), AppError> {
+    if !broker.is_default {
+        return Ok(());
+    }
+    match state
+        .broker_storage
+        .list()
+        .await
+        .into_iter()
+        .find(|other| other.is_default && other.id != broker.id)
+    {
+        Some(other) => Err(AppError::Conflict {
+            code: ErrorCode::DefaultBrokerExists,
+            message: format!("Broker '{}' is already the default broker", other.name),
+            field: Some("default"),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Delivery groups only deliver while all their members are enabled (see
+/// `DeliveryGroups::validate`), so a member can't be disabled or deleted
+async fn ensure_group_members(state: &AppState, id: &str, enabled: bool) -> Result<(), AppError> {
+    let enabled_brokers: Vec<String> = state
+        .broker_storage
+        .list()
+        .await
+        .into_iter()
+        .filter(|broker| {
+            if broker.id == id {
+                enabled
+            } else {
+                broker.enabled
+            }
+        })
+        .map(|broker| broker.id)
+        .collect();
+    let delivery_groups = state.connection_manager.read().await.delivery_groups();
+    delivery_groups
+        .validate(&enabled_brokers)
+        .map_err(|e| AppError::Conflict {
+            code: ErrorCode::DeliveryGroupMember,
+            message: e.to_string(),
+            field: None,
+        })
+}
+
+fn validate_subscription_qos(qos: Option<u8>) -> Result<Option<u8>, AppError> {
+    match qos {
+        Some(qos) if qos > 2 => Err(AppError::field(
+            ErrorCode::InvalidValue,
+            "subscriptionQos",
+            format!("Unsupported subscriptionQos {} (expected 0, 1 or 2)", qos),
+        )),
+        qos => Ok(qos),
+    }
+}
+
+fn validate_protocol_version(version: u8) -> Result<u8, AppError> {
+    match version {
+        PROTOCOL_V4 | PROTOCOL_V5 => Ok(version),
+        other => Err(AppError::field(
+            ErrorCode::InvalidValue,
+            "protocolVersion",
+            format!("Unsupported protocolVersion {} (expected 4 or 5)", other),
+        )),
+    }
+}
+
+fn validate_sampling(rules: Vec<SamplingRule>) -> Result<Vec<SamplingRule>, AppError> {
+    for rule in &rules {
+        if !is_valid_filter(&rule.topic) {
+            return Err(AppError::field(
+                ErrorCode::InvalidTopicFilter,
+                "sampling",
+                format!("Invalid sampling topic '{}'", rule.topic),
+            ));
+        }
+        if rule.every_nth.is_none() && rule.max_per_second.is_none() {
+            return Err(AppError::field(
+                ErrorCode::InvalidValue,
+                "sampling",
+                format!(
+                    "Sampling rule for '{}' needs everyNth or maxPerSecond",
+                    rule.topic
+                ),
+            ));
+        }
+        if rule.every_nth == Some(0) || rule.max_per_second == Some(0) {
+            return Err(AppError::field(
+                ErrorCode::InvalidValue,
+                "sampling",
+                format!(
+                    "Sampling rule for '{}' must use values of at least 1",
+                    rule.topic
+                ),
+            ));
+        }
+    }
+    Ok(rules)
+}
+
+fn validate_batching(rules: Vec<BatchRule>) -> Result<Vec<BatchRule>, AppError> {
+    for rule in &rules {
+        if !is_valid_filter(&rule.topic) {
+            return Err(AppError::field(
+                ErrorCode::InvalidTopicFilter,
+                "batching",
+                format!("Invalid batching topic '{}'", rule.topic),
+            ));
+        }
+        if rule.max_messages == 0 || rule.max_delay_ms == 0 {
+            return Err(AppError::field(
+                ErrorCode::InvalidValue,
+                "batching",
+                format!(
+                    "Batching rule for '{}' needs maxMessages and maxDelayMs of at least 1",
+                    rule.topic
+                ),
+            ));
+        }
+    }
+    Ok(rules)
+}
+
+fn validate_send_queue(config: SendQueueConfig) -> Result<SendQueueConfig, AppError> {
+    if config.capacity == Some(0) {
+        return Err(AppError::field(
+            ErrorCode::InvalidValue,
+            "sendQueue",
+            "sendQueue.capacity must be at least 1",
+        ));
+    }
+    Ok(config)
+}
+
+fn validate_connection_count(count: usize) -> Result<usize, AppError> {
+    if !(1..=MAX_CONNECTION_COUNT).contains(&count) {
+        return Err(AppError::field(
+            ErrorCode::InvalidValue,
+            "connectionCount",
+            format!(
+                "connectionCount must be between 1 and {}",
+                MAX_CONNECTION_COUNT
+            ),
+        ));
+    }
+    Ok(count)
+}
+
+fn validate_transforms(
+    transforms: Vec<PayloadTransform>,
+) -> Result<Vec<PayloadTransform>, AppError> {
+    for transform in &transforms {
+        if !is_valid_filter(&transform.topic) {
+            return Err(AppError::field(
+                ErrorCode::InvalidTopicFilter,
+                "transforms",
+                format!("Invalid transform topic '{}'", transform.topic),
+            ));
+        }
+        let paths = transform
+            .rename
+            .iter()
+            .flat_map(|(from, to)| [from, to])
+            .chain(transform.scale.keys())
+            .chain(transform.set.keys());
+        for path in paths {
+            if path.split('.').any(str::is_empty) {
+                return Err(AppError::field(
+                    ErrorCode::InvalidValue,
+                    "transforms",
+                    format!(
+                        "Invalid field path '{}' in transform for '{}'",
+                        path, transform.topic
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(transforms)
+}
+
+fn validate_payload_filters(filters: Vec<PayloadFilter>) -> Result<Vec<PayloadFilter>, AppError> {
+    for filter in &filters {
+        if !is_valid_filter(&filter.topic) {
+            return Err(AppError::field(
+                ErrorCode::InvalidTopicFilter,
+                "payloadFilters",
+                format!("Invalid payload filter topic '{}'", filter.topic),
+            ));
+        }
+    }
+    Ok(filters)
 }
 
 // Update existing broker
 async fn update_broker(
     State(state): State<AppState>,
+    actor: Actor,
     Path(id): Path<String>,
+    headers: HeaderMap,
     Json(payload): Json<UpdateBrokerRequest>,
-) -> Result<Json<BrokerConfig>, AppError> {
+) -> Result<impl IntoResponse, AppError> {
     // Get existing broker to preserve credentials if not provided
     let existing = state
         .broker_storage
         .get(&id)
         .await
         .ok_or(AppError::NotFound)?;
+    let revision = if_match(&headers, &existing)?;
+    let mut updated = merge_update(&id, existing.clone(), payload)?;
+    updated.revision = revision;
+    ensure_single_default(&state, &updated).await?;
+    ensure_group_members(&state, &id, updated.enabled).await?;
 
-    let updated = BrokerConfig {
-        id: id.clone(),
+    // The store checks the revision again, in case another update got in since
+    updated.revision = state.broker_storage.update(&id, updated.clone()).await?;
+
+    // Saving an unchanged config leaves the connection (and its offline buffer) alone
+    let changes = broker_diff::changes(&existing, &updated);
+    if !changes.is_empty() {
+        // Update connection manager (need decrypted password for connections)
+        let broker_with_password = state
+            .broker_storage
+            .get_with_password(&id)
+            .await
+            .ok_or(AppError::NotFound)?;
+        let mut manager = state.connection_manager.write().await;
+        manager
+            .update_broker(broker_with_password)
+            .await
+            .map_err(AppError::Unreachable)?;
+    }
+
+    info!("Broker '{}' updated via API", updated.name);
+    if !changes.is_empty() {
+        state.audit_log.record(
+            actor,
+            AuditAction::BrokerUpdated,
+            &id,
+            Some(&updated.name),
+            changes,
+        );
+    }
+    // Return config with hidden password
+    Ok((
+        [(header::ETAG, etag(updated.revision))],
+        Json(updated.with_hidden_password()),
+    ))
+}
+
+// What an update would change, without saving it
+async fn preview_broker_update(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<UpdateBrokerRequest>,
+) -> Result<Json<UpdatePreview>, AppError> {
+    let existing = state
+        .broker_storage
+        .get(&id)
+        .await
+        .ok_or(AppError::NotFound)?;
+    let updated = merge_update(&id, existing.clone(), payload)?;
+    let buffered = state
+        .connection_manager
+        .read()
+        .await
+        .get_broker_status()
+        .into_iter()
+        .find(|status| status.id == id)
+        .map_or(0, |status| status.buffered_messages);
+    Ok(Json(broker_diff::preview(&existing, &updated, buffered)))
+}
+
+/// Apply an update request to the stored config of broker `id`
+fn merge_update(
+    id: &str,
+    existing: BrokerConfig,
+    payload: UpdateBrokerRequest,
+) -> Result<BrokerConfig, AppError> {
+    Ok(BrokerConfig {
+        id: id.to_string(),
         name: payload.name,
         address: payload.address,
         port: payload.port,
         client_id_prefix: payload.client_id_prefix,
+        // Fields the Web UI form doesn't send are kept as-is when omitted
+        client_id: match payload.client_id {
+            Some(id) if !id.is_empty() => Some(id),
+            Some(_) => None,
+            None => existing.client_id,
+        },
+        clean_session: payload.clean_session.unwrap_or(existing.clean_session),
         // If username not provided or empty, keep existing; otherwise use new value
         username: match payload.username {
             Some(u) if !u.is_empty() => Some(u),
@@ -243,32 +939,75 @@ async fn update_broker(
         enabled: payload.enabled,
         use_tls: payload.use_tls,
         insecure_skip_verify: payload.insecure_skip_verify,
+        skip_hostname_verification: payload
+            .skip_hostname_verification
+            .unwrap_or(existing.skip_hostname_verification),
         ca_cert_path: payload.ca_cert_path,
+        client_cert_path: match payload.client_cert_path {
+            Some(p) if !p.is_empty() => Some(p),
+            Some(_) => None,
+            None => existing.client_cert_path,
+        },
+        client_key_path: match payload.client_key_path {
+            Some(p) if !p.is_empty() => Some(p),
+            Some(_) => None,
+            None => existing.client_key_path,
+        },
+        tls_verify_hostname: match payload.tls_verify_hostname {
+            Some(h) if !h.is_empty() => Some(h),
+            Some(_) => None,
+            None => existing.tls_verify_hostname,
+        },
+        alpn_protocols: payload.alpn_protocols.unwrap_or(existing.alpn_protocols),
+        protocol_version: validate_protocol_version(
+            payload
+                .protocol_version
+                .unwrap_or(existing.protocol_version),
+        )?,
         topics: payload.topics,
         subscription_topics: payload.subscription_topics,
-    };
-
-    state.broker_storage.update(&id, updated.clone()).await?;
-
-    // Update connection manager (need decrypted password for connections)
-    let broker_with_password = state
-        .broker_storage
-        .get_with_password(&id)
-        .await
-        .ok_or(AppError::NotFound)?;
-    let mut manager = state.connection_manager.write().await;
-    manager.update_broker(broker_with_password).await?;
-
-    info!("Broker '{}' updated via API", updated.name);
-    // Return config with hidden password
-    Ok(Json(updated.with_hidden_password()))
+        subscription_mode: payload
+            .subscription_mode
+            .unwrap_or(existing.subscription_mode),
+        subscription_qos: validate_subscription_qos(
+            payload.subscription_qos.or(existing.subscription_qos),
+        )?,
+        encrypt_topics: payload.encrypt_topics.unwrap_or(existing.encrypt_topics),
+        sign_topics: payload.sign_topics.unwrap_or(existing.sign_topics),
+        sampling: validate_sampling(payload.sampling.unwrap_or(existing.sampling))?,
+        batching: validate_batching(payload.batching.unwrap_or(existing.batching))?,
+        transforms: validate_transforms(payload.transforms.unwrap_or(existing.transforms))?,
+        compress_topics: payload.compress_topics.unwrap_or(existing.compress_topics),
+        offline_buffer: payload.offline_buffer.unwrap_or(existing.offline_buffer),
+        send_queue: validate_send_queue(payload.send_queue.unwrap_or(existing.send_queue))?,
+        connection_count: validate_connection_count(
+            payload
+                .connection_count
+                .unwrap_or(existing.connection_count),
+        )?,
+        topic_rewrites: payload.topic_rewrites.unwrap_or(existing.topic_rewrites),
+        reverse_retain: payload.reverse_retain.unwrap_or(existing.reverse_retain),
+        forward_properties: payload
+            .forward_properties
+            .unwrap_or(existing.forward_properties),
+        payload_filters: validate_payload_filters(
+            payload.payload_filters.unwrap_or(existing.payload_filters),
+        )?,
+        priority: payload.priority.unwrap_or(existing.priority),
+        is_default: payload.is_default.unwrap_or(existing.is_default),
+        dedup: payload.dedup.unwrap_or(existing.dedup),
+        revision: existing.revision,
+    })
 }
 
 // Delete broker
 async fn delete_broker(
     State(state): State<AppState>,
+    actor: Actor,
     Path(id): Path<String>,
 ) -> Result<StatusCode, AppError> {
+    let existing = state.broker_storage.get(&id).await;
+    ensure_group_members(&state, &id, false).await?;
     state.broker_storage.delete(&id).await?;
 
     // Remove from connection manager
@@ -276,15 +1015,25 @@ async fn delete_broker(
     manager.remove_broker(&id).await?;
 
     info!("Broker '{}' deleted via API", id);
+    state.audit_log.record(
+        actor,
+        AuditAction::BrokerDeleted,
+        &id,
+        existing.as_ref().map(|broker| broker.name.as_str()),
+        audit::changes(existing.as_ref(), None, false),
+    );
     Ok(StatusCode::NO_CONTENT)
 }
 
 // Toggle broker enabled/disabled
 async fn toggle_broker(
     State(state): State<AppState>,
+    actor: Actor,
     Path(id): Path<String>,
     Json(payload): Json<ToggleBrokerRequest>,
 ) -> Result<StatusCode, AppError> {
+    let existing = state.broker_storage.get(&id).await;
+    ensure_group_members(&state, &id, payload.enabled).await?;
     state
         .broker_storage
         .toggle_enabled(&id, payload.enabled)
@@ -298,37 +1047,323 @@ async fn toggle_broker(
             .get_with_password(&id)
             .await
             .ok_or(AppError::NotFound)?;
-        manager.enable_broker(broker).await?;
+        manager
+            .enable_broker(broker)
+            .await
+            .map_err(AppError::Unreachable)?;
     } else {
         manager.disable_broker(&id).await?;
     }
 
+    if let Some(existing) = existing.filter(|broker| broker.enabled != payload.enabled) {
+        let toggled = BrokerConfig {
+            enabled: payload.enabled,
+            ..existing.clone()
+        };
+        state.audit_log.record(
+            actor,
+            AuditAction::BrokerToggled,
+            &id,
+            Some(&existing.name),
+            broker_diff::changes(&existing, &toggled),
+        );
+    }
     Ok(StatusCode::OK)
 }
 
+// Replay the subscriptions of one downstream broker
+async fn resubscribe_broker(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ResubscribeResponse>, AppError> {
+    let manager = state.connection_manager.read().await;
+    let broker = manager
+        .get_all_brokers()
+        .into_iter()
+        .find(|broker| broker.id == id)
+        .ok_or(AppError::NotFound)?;
+    if !broker.bidirectional {
+        return Err(AppError::bad_request(
+            ErrorCode::NotBidirectional,
+            format!(
+                "Broker '{}' is not bidirectional and has no subscriptions",
+                broker.name
+            ),
+        ));
+    }
+    Ok(Json(ResubscribeResponse {
+        brokers: manager.resubscribe(Some(&id)).await,
+        main_broker: false,
+    }))
+}
+
+// Replay the subscriptions of all downstream brokers and the main broker
+async fn resubscribe_all(State(state): State<AppState>) -> Json<ResubscribeResponse> {
+    let manager = state.connection_manager.read().await;
+    let brokers = manager.resubscribe(None).await;
+    manager.resubscribe_main();
+    info!(
+        "Resubscribing on the main broker and {} downstream brokers via API",
+        brokers.len()
+    );
+    Json(ResubscribeResponse {
+        brokers,
+        main_broker: true,
+    })
+}
+
+// Get keep-alive round-trip history for a connected broker
+async fn get_broker_latency(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<BrokerLatencyResponse>, AppError> {
+    let manager = state.connection_manager.read().await;
+    let samples = manager.get_broker_rtt(&id).ok_or(AppError::NotFound)?;
+
+    Ok(Json(BrokerLatencyResponse {
+        broker_id: id,
+        samples,
+    }))
+}
+
 // Get overall system status
 async fn get_status(State(state): State<AppState>) -> Result<Json<SystemStatus>, AppError> {
     let manager = state.connection_manager.read().await;
     let broker_statuses = manager.get_broker_status();
 
-    let messages_received = state.messages_received.load(Ordering::Relaxed);
-    let total_latency_ns = state.total_latency_ns.load(Ordering::Relaxed);
+    Ok(Json(SystemStatus {
+        brokers: broker_statuses,
+        total_messages_received: state.metrics.messages_received.get(),
+        total_messages_forwarded: state.metrics.messages_forwarded.get(),
+        unrouted_messages: manager.unrouted_messages(),
+        avg_latency_ms: state.metrics.average_latency_ms(),
+        ui_paused: manager.load_shedder().is_shedding(),
+        ui_shed_messages: manager.load_shedder().shed_messages(),
+    }))
+}
+
+// Memory, task counts, queue depths and cache sizes, for spotting slow leaks
+async fn get_runtime_stats(State(state): State<AppState>) -> Json<RuntimeStats> {
+    let manager = state.connection_manager.read().await;
+    let delivery_group_pending = manager
+        .delivery_groups()
+        .counters()
+        .await
+        .iter()
+        .map(|group| group.pending)
+        .sum();
+    let client_registry = manager.client_registry();
+    Json(RuntimeStats {
+        memory: MemoryStats::current(),
+        tasks: TaskStats::current(),
+        ui_broadcast: QueueDepth {
+            queued: state.message_tx.len(),
+            capacity: resource_profile::limits().ui_broadcast,
+        },
+        brokers: manager.broker_queues(),
+        caches: CacheSizes {
+            message_history: state.message_history.len(),
+            dead_letters: manager.dead_letter_store().len(),
+            retained_messages: client_registry.retained_count(),
+            connected_clients: client_registry.clients().await.len(),
+            delivery_group_pending,
+        },
+    })
+}
 
-    // Calculate average latency in milliseconds
-    let avg_latency_ms = if messages_received > 0 {
-        (total_latency_ns as f64 / messages_received as f64) / 1_000_000.0 // Convert ns to ms
-    } else {
-        0.0
+// Get bucketed traffic counts and latency percentiles
+async fn get_timeseries(
+    State(state): State<AppState>,
+    Query(query): Query<TimeseriesQuery>,
+) -> Result<Json<TimeseriesResponse>, AppError> {
+    let window = match query.window.as_deref() {
+        Some(w) => parse_duration(w).ok_or_else(|| {
+            AppError::field(
+                ErrorCode::InvalidValue,
+                "window",
+                format!("Invalid window '{}'", w),
+            )
+        })?,
+        None => std::time::Duration::from_secs(3600),
+    };
+    let step = match query.step.as_deref() {
+        Some(s) => parse_duration(s).ok_or_else(|| {
+            AppError::field(
+                ErrorCode::InvalidValue,
+                "step",
+                format!("Invalid step '{}'", s),
+            )
+        })?,
+        None => std::time::Duration::from_secs(60),
     };
+    let window = window.min(TrafficStats::retention());
+    let step = step.min(window);
 
-    Ok(Json(SystemStatus {
-        brokers: broker_statuses,
-        total_messages_received: messages_received,
-        total_messages_forwarded: state.messages_forwarded.load(Ordering::Relaxed),
-        avg_latency_ms,
+    let stats = state.connection_manager.read().await.traffic_stats();
+    Ok(Json(TimeseriesResponse {
+        window_secs: window.as_secs(),
+        step_secs: step.as_secs(),
+        points: stats.timeseries(window, step),
     }))
 }
 
+// Get bytes exchanged with downstream brokers since startup
+async fn get_bandwidth(State(state): State<AppState>) -> Json<BandwidthResponse> {
+    let stats = state.connection_manager.read().await.bandwidth_stats();
+    Json(BandwidthResponse {
+        brokers: stats.brokers(),
+        topics: stats.topics(),
+    })
+}
+
+// Messages suppressed as unchanged repeats, per configured rule
+async fn get_duplicates(State(state): State<AppState>) -> Json<DuplicatesResponse> {
+    let rules = state
+        .connection_manager
+        .read()
+        .await
+        .duplicate_suppressor()
+        .counters();
+    Json(DuplicatesResponse {
+        total_suppressed: rules.iter().map(|rule| rule.suppressed).sum(),
+        rules,
+    })
+}
+
+// Clients connected to the MQTT listener
+async fn list_clients(State(state): State<AppState>) -> Json<Vec<ConnectedClient>> {
+    let registry = state.connection_manager.read().await.client_registry();
+    Json(registry.clients().await)
+}
+
+// Graph of the main broker, proxy, downstream brokers and listener clients
+async fn get_topology(State(state): State<AppState>) -> Json<Topology> {
+    let manager = state.connection_manager.read().await;
+    let brokers = manager.get_broker_status();
+    let bandwidth = manager.bandwidth_stats().brokers();
+    let main_broker = manager.main_broker_address();
+    let registry = manager.client_registry();
+    drop(manager);
+    let clients = registry.clients().await;
+    Json(topology::build(
+        &main_broker,
+        &brokers,
+        &bandwidth,
+        &clients,
+        state.metrics.messages_received.get(),
+        &state.topology_rates,
+        std::time::Instant::now(),
+    ))
+}
+
+// Messages caught with implausible timestamps, per configured rule
+async fn get_timestamps(State(state): State<AppState>) -> Json<TimestampsResponse> {
+    let rules = state
+        .connection_manager
+        .read()
+        .await
+        .timestamp_checker()
+        .counters();
+    Json(TimestampsResponse {
+        total_invalid: rules.iter().map(|rule| rule.invalid).sum(),
+        rules,
+    })
+}
+
+// Messages waiting for and delivered to each delivery group
+async fn get_delivery_groups(State(state): State<AppState>) -> Json<DeliveryGroupsResponse> {
+    let delivery_groups = state.connection_manager.read().await.delivery_groups();
+    let groups = delivery_groups.counters().await;
+    Json(DeliveryGroupsResponse {
+        total_pending: groups.iter().map(|group| group.pending).sum(),
+        groups,
+    })
+}
+
+// Usage of the current and the last completed reporting period
+async fn get_usage_report(State(state): State<AppState>) -> Json<UsageReportResponse> {
+    let usage = state.connection_manager.read().await.usage_tracker();
+    Json(UsageReportResponse {
+        current: usage.current(),
+        previous: usage.previous(),
+    })
+}
+
+// Search the recent message history
+async fn search_messages(
+    State(state): State<AppState>,
+    Query(query): Query<MessageSearchQuery>,
+) -> Result<Json<MessageSearchResponse>, AppError> {
+    let payload_regex = match query.regex.as_deref() {
+        Some(pattern) => Some(regex::Regex::new(pattern).map_err(|e| {
+            AppError::field(
+                ErrorCode::InvalidValue,
+                "regex",
+                format!("Invalid regex: {}", e),
+            )
+        })?),
+        None => None,
+    };
+
+    let history_query = HistoryQuery {
+        topic: query.topic.filter(|t| !t.is_empty()),
+        payload_contains: query.contains.filter(|c| !c.is_empty()),
+        payload_regex,
+        client_id: query.client_id.filter(|c| !c.is_empty()),
+        since: query
+            .since
+            .as_deref()
+            .map(|since| parse_time("since", since))
+            .transpose()?,
+        until: query
+            .until
+            .as_deref()
+            .map(|until| parse_time("until", until))
+            .transpose()?,
+        limit: query.limit.unwrap_or(100).min(1000),
+    };
+
+    // Searching on-disk segments reads files, keep it off the async workers
+    let history = Arc::clone(&state.message_history);
+    let messages = tokio::task::spawn_blocking(move || history.search(&history_query))
+        .await
+        .map_err(|e| anyhow::anyhow!("History search failed: {}", e))?;
+
+    Ok(Json(MessageSearchResponse { messages }))
+}
+
+// Latest configuration changes, newest first
+async fn get_audit_log(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditResponse>, AppError> {
+    let audit_log = Arc::clone(&state.audit_log);
+    let limit = query.limit.unwrap_or(100).min(1000);
+    let entries = tokio::task::spawn_blocking(move || {
+        audit_log.latest(limit, query.target.as_deref().filter(|t| !t.is_empty()))
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!("Reading the audit log failed: {}", e))??;
+    Ok(Json(AuditResponse { entries }))
+}
+
+/// Parse an RFC 3339 timestamp or a relative duration (`10m` = ten minutes ago)
+fn parse_time(field: &'static str, value: &str) -> Result<DateTime<Utc>, AppError> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    parse_duration(value)
+        .and_then(|ago| chrono::Duration::from_std(ago).ok())
+        .and_then(|ago| Utc::now().checked_sub_signed(ago))
+        .ok_or_else(|| {
+            AppError::field(
+                ErrorCode::InvalidValue,
+                field,
+                format!("Invalid time '{}'", value),
+            )
+        })
+}
+
 // Request/Response types
 #[derive(Debug, Serialize)]
 struct ListBrokersResponse {
@@ -343,7 +1378,11 @@ struct AddBrokerRequest {
     port: u16,
     client_id_prefix: String,
     #[serde(default)]
-    username: String,
+    client_id: Option<String>,
+    #[serde(default)]
+    clean_session: Option<bool>,
+    #[serde(default)]
+    username: String,
     #[serde(default)]
     password: String,
     #[serde(default)]
@@ -353,13 +1392,61 @@ struct AddBrokerRequest {
     #[serde(default)]
     insecure_skip_verify: Option<bool>,
     #[serde(default)]
+    skip_hostname_verification: Option<bool>,
+    #[serde(default)]
     ca_cert_path: Option<String>,
     #[serde(default)]
+    client_cert_path: Option<String>,
+    #[serde(default)]
+    client_key_path: Option<String>,
+    #[serde(default)]
+    tls_verify_hostname: Option<String>,
+    #[serde(default)]
+    alpn_protocols: Option<Vec<String>>,
+    #[serde(default)]
+    protocol_version: Option<u8>,
+    #[serde(default)]
     bidirectional: Option<bool>,
     #[serde(default)]
     topics: Option<Vec<String>>,
     #[serde(default)]
     subscription_topics: Option<Vec<String>>,
+    #[serde(default)]
+    subscription_mode: Option<SubscriptionMode>,
+    #[serde(default)]
+    subscription_qos: Option<u8>,
+    #[serde(default)]
+    encrypt_topics: Option<Vec<String>>,
+    #[serde(default)]
+    sign_topics: Option<Vec<String>>,
+    #[serde(default)]
+    sampling: Option<Vec<SamplingRule>>,
+    #[serde(default)]
+    batching: Option<Vec<BatchRule>>,
+    #[serde(default)]
+    transforms: Option<Vec<PayloadTransform>>,
+    #[serde(default)]
+    compress_topics: Option<Vec<String>>,
+    #[serde(default)]
+    offline_buffer: Option<OfflineBufferConfig>,
+    #[serde(default)]
+    send_queue: Option<SendQueueConfig>,
+    #[serde(default)]
+    connection_count: Option<usize>,
+    #[serde(default)]
+    topic_rewrites: Option<Vec<TopicRewrite>>,
+    #[serde(default)]
+    reverse_retain: Option<RetainPolicy>,
+    #[serde(default)]
+    forward_properties: Option<bool>,
+    #[serde(default)]
+    payload_filters: Option<Vec<PayloadFilter>>,
+    #[serde(default)]
+    priority: Option<i32>,
+    #[serde(default, rename = "default")]
+    is_default: Option<bool>,
+    #[serde(default)]
+    dedup: Option<DedupOverride>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -370,6 +1457,10 @@ struct UpdateBrokerRequest {
     port: u16,
     client_id_prefix: String,
     #[serde(default)]
+    client_id: Option<String>,
+    #[serde(default)]
+    clean_session: Option<bool>,
+    #[serde(default)]
     username: Option<String>,
     #[serde(default)]
     password: Option<String>,
@@ -377,13 +1468,69 @@ struct UpdateBrokerRequest {
     use_tls: bool,
     insecure_skip_verify: bool,
     #[serde(default)]
+    skip_hostname_verification: Option<bool>,
+    #[serde(default)]
     ca_cert_path: Option<String>,
     #[serde(default)]
+    client_cert_path: Option<String>,
+    #[serde(default)]
+    client_key_path: Option<String>,
+    #[serde(default)]
+    tls_verify_hostname: Option<String>,
+    #[serde(default)]
+    alpn_protocols: Option<Vec<String>>,
+    #[serde(default)]
+    protocol_version: Option<u8>,
+    #[serde(default)]
     bidirectional: bool,
     #[serde(default)]
     topics: Vec<String>,
     #[serde(default)]
     subscription_topics: Vec<String>,
+    #[serde(default)]
+    subscription_mode: Option<SubscriptionMode>,
+    #[serde(default)]
+    subscription_qos: Option<u8>,
+    #[serde(default)]
+    encrypt_topics: Option<Vec<String>>,
+    #[serde(default)]
+    sign_topics: Option<Vec<String>>,
+    #[serde(default)]
+    sampling: Option<Vec<SamplingRule>>,
+    #[serde(default)]
+    batching: Option<Vec<BatchRule>>,
+    #[serde(default)]
+    transforms: Option<Vec<PayloadTransform>>,
+    #[serde(default)]
+    compress_topics: Option<Vec<String>>,
+    #[serde(default)]
+    offline_buffer: Option<OfflineBufferConfig>,
+    #[serde(default)]
+    send_queue: Option<SendQueueConfig>,
+    #[serde(default)]
+    connection_count: Option<usize>,
+    #[serde(default)]
+    topic_rewrites: Option<Vec<TopicRewrite>>,
+    #[serde(default)]
+    reverse_retain: Option<RetainPolicy>,
+    #[serde(default)]
+    forward_properties: Option<bool>,
+    #[serde(default)]
+    payload_filters: Option<Vec<PayloadFilter>>,
+    #[serde(default)]
+    priority: Option<i32>,
+    #[serde(default, rename = "default")]
+    is_default: Option<bool>,
+    #[serde(default)]
+    dedup: Option<DedupOverride>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetAclRequest {
+    #[serde(default)]
+    publish: Vec<String>,
+    #[serde(default)]
+    subscribe: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -391,12 +1538,146 @@ struct ToggleBrokerRequest {
     enabled: bool,
 }
 
+#[derive(Debug, Deserialize)]
+struct DeadLetterQuery {
+    broker: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RedriveRequest {
+    /// Only this broker's entries
+    #[serde(default)]
+    broker: Option<String>,
+    /// Only these entries
+    #[serde(default)]
+    ids: Option<Vec<u64>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FilterDemand {
+    filter: String,
+    /// Local clients subscribed to the filter, plus one if main broker consumers want it
+    subscribers: usize,
+    main_broker: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MainDemandRequest {
+    filters: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResubscribeResponse {
+    /// Downstream brokers the subscriptions were sent to
+    brokers: Vec<String>,
+    main_broker: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RedriveResponse {
+    redriven: usize,
+    failed: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct DeadLetterResponse {
+    total: usize,
+    /// Entries dropped because the store was full
+    evicted: u64,
+    messages: Vec<FailedForward>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TimeseriesQuery {
+    window: Option<String>,
+    step: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageSearchQuery {
+    topic: Option<String>,
+    contains: Option<String>,
+    regex: Option<String>,
+    client_id: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct MessageSearchResponse {
+    messages: Vec<MqttMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditQuery {
+    /// Only changes of this broker ID, or `main-broker`
+    target: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct AuditResponse {
+    entries: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct TimeseriesResponse {
+    window_secs: u64,
+    step_secs: u64,
+    points: Vec<TimeseriesPoint>,
+}
+
+#[derive(Debug, Serialize)]
+struct UsageReportResponse {
+    current: UsageReport,
+    previous: Option<UsageReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicatesResponse {
+    total_suppressed: u64,
+    rules: Vec<RuleCounters>,
+}
+
+#[derive(Debug, Serialize)]
+struct TimestampsResponse {
+    total_invalid: u64,
+    rules: Vec<TimestampCounters>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeliveryGroupsResponse {
+    total_pending: usize,
+    groups: Vec<GroupCounters>,
+}
+
+#[derive(Debug, Serialize)]
+struct BandwidthResponse {
+    brokers: Vec<BrokerBandwidth>,
+    topics: Vec<TopicBandwidth>,
+}
+
+#[derive(Debug, Serialize)]
+struct BrokerLatencyResponse {
+    broker_id: String,
+    samples: Vec<RttSample>,
+}
+
 #[derive(Debug, Serialize)]
 struct SystemStatus {
     brokers: Vec<BrokerStatus>,
     total_messages_received: u64,
     total_messages_forwarded: u64,
+    /// Messages no broker's filters matched
+    unrouted_messages: u64,
     avg_latency_ms: f64,
+    /// The live message stream (and history) is paused because forwarding is under load
+    ui_paused: bool,
+    /// Messages not shown in the UI because of load shedding
+    ui_shed_messages: u64,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -410,34 +1691,539 @@ pub struct BrokerStatus {
     pub bidirectional: bool,
     pub topics: Vec<String>,
     pub subscription_topics: Vec<String>,
+    /// Most recent keep-alive round trip to the broker
+    pub rtt_ms: Option<f64>,
+    /// Connection keeps dropping right after connecting (likely a duplicate client ID)
+    pub flapping: bool,
+    /// Messages held for the broker while it is disconnected
+    pub buffered_messages: usize,
+    /// Times the watchdog recreated the connection since startup
+    pub watchdog_restarts: u64,
+    /// Health-check probes fail or are slow (see `[health_checks]`)
+    pub degraded: bool,
+    /// Round trip of the last acknowledged health-check probe
+    pub probe_latency_ms: Option<f64>,
+}
+
+/// Machine-readable error codes of API errors. Clients branch on (and translate) the code;
+/// the message is English text for logs and may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorCode {
+    Internal,
+    NotFound,
+    InvalidValue,
+    InvalidTopicFilter,
+    InvalidRoutingTable,
+    InvalidRule,
+    DuplicateName,
+    DefaultBrokerExists,
+    DeliveryGroupMember,
+    PreconditionRequired,
+    RevisionMismatch,
+    ManagedRemotely,
+    NotBidirectional,
+    BatchTooLarge,
+    BrokerUnreachable,
+}
+
+/// Body of every error response
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+    /// Request field (JSON name or query parameter) the error is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'static str>,
+    /// The saved broker config, when an update was based on an older revision
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<Box<BrokerConfig>>,
 }
 
 // Error handling
 enum AppError {
     Internal(anyhow::Error),
     NotFound,
+    /// The request can't be carried out as sent (400)
+    BadRequest {
+        code: ErrorCode,
+        message: String,
+        field: Option<&'static str>,
+    },
+    /// The request conflicts with the saved configuration (409)
+    Conflict {
+        code: ErrorCode,
+        message: String,
+        field: Option<&'static str>,
+    },
+    /// A broker connection couldn't be started (502)
+    Unreachable(anyhow::Error),
+    /// A broker update without `If-Match` (428)
+    PreconditionRequired,
+    /// A broker update based on an older revision than this current config (409)
+    RevisionMismatch(Box<BrokerConfig>),
+}
+
+impl AppError {
+    fn bad_request(code: ErrorCode, message: impl Into<String>) -> Self {
+        AppError::BadRequest {
+            code,
+            message: message.into(),
+            field: None,
+        }
+    }
+
+    /// A bad request about one field of the request
+    fn field(code: ErrorCode, field: &'static str, message: impl Into<String>) -> Self {
+        AppError::BadRequest {
+            code,
+            message: message.into(),
+            field: Some(field),
+        }
+    }
+}
+
+/// Who made a request, for the audit log
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| *peer);
+        Ok(Actor::from_request(&parts.headers, peer))
+    }
 }
 
 impl From<anyhow::Error> for AppError {
     fn from(err: anyhow::Error) -> Self {
-        AppError::Internal(err)
+        let err = match err.downcast::<RevisionMismatch>() {
+            Ok(RevisionMismatch(current)) => return AppError::RevisionMismatch(current),
+            Err(err) => err,
+        };
+        if let Some(duplicate) = err.downcast_ref::<DuplicateName>() {
+            return AppError::Conflict {
+                code: ErrorCode::DuplicateName,
+                message: duplicate.to_string(),
+                field: Some("name"),
+            };
+        }
+        match err.downcast_ref::<ManagedRemotely>() {
+            Some(managed) => AppError::Conflict {
+                code: ErrorCode::ManagedRemotely,
+                message: managed.to_string(),
+                field: None,
+            },
+            None => AppError::Internal(err),
+        }
     }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        let (status, message) = match self {
+        let (status, code, message, field) = match self {
             AppError::Internal(err) => {
                 error!("Internal error: {}", err);
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::Internal,
                     format!("Internal error: {}", err),
+                    None,
                 )
             }
-            AppError::NotFound => (StatusCode::NOT_FOUND, "Broker not found".to_string()),
+            AppError::NotFound => (
+                StatusCode::NOT_FOUND,
+                ErrorCode::NotFound,
+                "Not found".to_string(),
+                None,
+            ),
+            AppError::BadRequest {
+                code,
+                message,
+                field,
+            } => (StatusCode::BAD_REQUEST, code, message, field),
+            AppError::Conflict {
+                code,
+                message,
+                field,
+            } => (StatusCode::CONFLICT, code, message, field),
+            AppError::Unreachable(err) => (
+                StatusCode::BAD_GATEWAY,
+                ErrorCode::BrokerUnreachable,
+                format!("Broker connection failed: {}", err),
+                None,
+            ),
+            AppError::PreconditionRequired => (
+                StatusCode::PRECONDITION_REQUIRED,
+                ErrorCode::PreconditionRequired,
+                "Updates need an If-Match header with the broker's ETag".to_string(),
+                None,
+            ),
+            AppError::RevisionMismatch(current) => {
+                let headers = [(header::ETAG, etag(current.revision))];
+                let body = ErrorBody {
+                    code: ErrorCode::RevisionMismatch,
+                    message: format!(
+                        "Broker '{}' was changed in the meantime (now revision {})",
+                        current.name, current.revision
+                    ),
+                    field: None,
+                    current: Some(current),
+                };
+                return (StatusCode::CONFLICT, headers, Json(body)).into_response();
+            }
         };
 
-        (status, Json(serde_json::json!({ "error": message }))).into_response()
+        let body = ErrorBody {
+            code,
+            message,
+            field,
+            current: None,
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+// Routing table endpoints
+async fn get_routing(State(state): State<AppState>) -> Result<Json<RoutingTable>, AppError> {
+    state
+        .settings_storage
+        .get_routing()
+        .await
+        .map(Json)
+        .ok_or(AppError::NotFound)
+}
+
+async fn set_routing(
+    State(state): State<AppState>,
+    Json(routing): Json<RoutingTable>,
+) -> Result<Json<RoutingTable>, AppError> {
+    let known: Vec<String> = state
+        .broker_storage
+        .list()
+        .await
+        .into_iter()
+        .map(|broker| broker.id)
+        .collect();
+    routing
+        .validate(&known)
+        .map_err(|e| AppError::bad_request(ErrorCode::InvalidRoutingTable, e.to_string()))?;
+
+    state
+        .settings_storage
+        .set_routing(Some(routing.clone()))
+        .await?;
+    state
+        .connection_manager
+        .write()
+        .await
+        .set_routing_table(Some(routing.clone()));
+    info!(
+        "Routing table updated via API ({} keys)",
+        routing.routes.len()
+    );
+    Ok(Json(routing))
+}
+
+async fn get_rules(State(state): State<AppState>) -> Json<Vec<Rule>> {
+    Json(state.settings_storage.get_rules().await)
+}
+
+/// Validate, store and apply a new rule list
+async fn save_rules(state: &AppState, rules: Vec<Rule>) -> Result<(), AppError> {
+    let known: Vec<String> = state
+        .broker_storage
+        .list()
+        .await
+        .into_iter()
+        .map(|broker| broker.id)
+        .collect();
+    let codecs = state.connection_manager.read().await.codecs().names();
+    Rule::validate_all(&rules, &known, &codecs)
+        .map_err(|e| AppError::bad_request(ErrorCode::InvalidRule, e.to_string()))?;
+
+    state.settings_storage.set_rules(rules.clone()).await?;
+    info!("Forwarding rules updated via API ({} rules)", rules.len());
+    state.connection_manager.write().await.set_rules(rules);
+    Ok(())
+}
+
+async fn set_rules(
+    State(state): State<AppState>,
+    Json(rules): Json<Vec<Rule>>,
+) -> Result<Json<Vec<Rule>>, AppError> {
+    save_rules(&state, rules.clone()).await?;
+    Ok(Json(rules))
+}
+
+async fn add_rule(
+    State(state): State<AppState>,
+    Json(rule): Json<Rule>,
+) -> Result<(StatusCode, Json<Rule>), AppError> {
+    let mut rules = state.settings_storage.get_rules().await;
+    rules.push(rule.clone());
+    save_rules(&state, rules).await?;
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+async fn delete_rule(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let mut rules = state.settings_storage.get_rules().await;
+    let count = rules.len();
+    rules.retain(|rule| rule.id != id);
+    if rules.len() == count {
+        return Err(AppError::NotFound);
+    }
+    save_rules(&state, rules).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_routing(State(state): State<AppState>) -> Result<StatusCode, AppError> {
+    state.settings_storage.set_routing(None).await?;
+    state
+        .connection_manager
+        .write()
+        .await
+        .set_routing_table(None);
+    info!("Routing table removed via API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Subscription demand followed by bidirectional brokers
+async fn get_subscription_demand(State(state): State<AppState>) -> Json<Vec<FilterDemand>> {
+    let table = state
+        .connection_manager
+        .read()
+        .await
+        .client_registry()
+        .subscription_table();
+    let main = table.filters_of(MAIN_BROKER_DEMAND);
+    Json(
+        table
+            .filters()
+            .into_iter()
+            .map(|filter| FilterDemand {
+                subscribers: table.count(&filter),
+                main_broker: main.contains(&filter),
+                filter,
+            })
+            .collect(),
+    )
+}
+
+async fn get_main_demand(State(state): State<AppState>) -> Json<MainDemandRequest> {
+    Json(MainDemandRequest {
+        filters: state.settings_storage.get_main_demand().await,
+    })
+}
+
+async fn set_main_demand(
+    State(state): State<AppState>,
+    Json(payload): Json<MainDemandRequest>,
+) -> Result<Json<MainDemandRequest>, AppError> {
+    if let Some(filter) = payload.filters.iter().find(|f| !is_valid_filter(f)) {
+        return Err(AppError::field(
+            ErrorCode::InvalidTopicFilter,
+            "filters",
+            format!("Invalid topic filter '{}'", filter),
+        ));
+    }
+    let mut filters = payload.filters;
+    filters.sort();
+    filters.dedup();
+
+    state
+        .settings_storage
+        .set_main_demand(filters.clone())
+        .await?;
+    state
+        .connection_manager
+        .read()
+        .await
+        .client_registry()
+        .subscription_table()
+        .set(MAIN_BROKER_DEMAND, &filters);
+    info!(
+        "Main broker subscription demand updated via API ({} filters)",
+        filters.len()
+    );
+    Ok(Json(MainDemandRequest { filters }))
+}
+
+// Listener client ACL endpoints
+async fn list_acls(State(state): State<AppState>) -> Json<Vec<ClientAcl>> {
+    Json(state.settings_storage.acl_table().list())
+}
+
+async fn set_acl(
+    State(state): State<AppState>,
+    Path(identity): Path<String>,
+    Json(payload): Json<SetAclRequest>,
+) -> Result<Json<ClientAcl>, AppError> {
+    for (field, filters) in [
+        ("publish", &payload.publish),
+        ("subscribe", &payload.subscribe),
+    ] {
+        if let Some(filter) = filters.iter().find(|filter| !is_valid_filter(filter)) {
+            return Err(AppError::field(
+                ErrorCode::InvalidTopicFilter,
+                field,
+                format!("Invalid topic filter '{}'", filter),
+            ));
+        }
+    }
+
+    let acl = ClientAcl {
+        identity,
+        publish: payload.publish,
+        subscribe: payload.subscribe,
+    };
+    state.settings_storage.set_acl(acl.clone()).await?;
+    Ok(Json(acl))
+}
+
+async fn delete_acl(
+    State(state): State<AppState>,
+    Path(identity): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if state.settings_storage.remove_acl(&identity).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound)
+    }
+}
+
+// Dead-letter endpoints for forwards that failed for good
+async fn list_dead_letters(
+    State(state): State<AppState>,
+    Query(query): Query<DeadLetterQuery>,
+) -> Json<DeadLetterResponse> {
+    let store = state.connection_manager.read().await.dead_letter_store();
+    let messages = store.list(query.broker.as_deref());
+    Json(DeadLetterResponse {
+        total: messages.len(),
+        evicted: store.evicted(),
+        messages,
+    })
+}
+
+async fn redrive_dead_letters(
+    State(state): State<AppState>,
+    Json(selection): Json<RedriveRequest>,
+) -> Json<RedriveResponse> {
+    let manager = state.connection_manager.read().await;
+    let entries = manager
+        .dead_letter_store()
+        .take(selection.broker.as_deref(), selection.ids.as_deref());
+    let selected = entries.len();
+    let redriven = manager.redrive(entries).await;
+    info!(
+        "Re-drove {}/{} dead-lettered messages via API",
+        redriven, selected
+    );
+    Json(RedriveResponse {
+        redriven,
+        failed: selected - redriven,
+    })
+}
+
+// HTTP ingestion endpoints
+async fn ingest_batch(
+    State(state): State<AppState>,
+    Json(records): Json<Vec<IngestRecord>>,
+) -> Result<Json<IngestSummary>, AppError> {
+    if records.len() > state.ingest_max_batch {
+        return Err(AppError::bad_request(
+            ErrorCode::BatchTooLarge,
+            format!("At most {} records per batch", state.ingest_max_batch),
+        ));
+    }
+    let mut summary = IngestSummary::default();
+    for (index, record) in records.iter().enumerate() {
+        summary.record(index, state.ingestor.publish_record(record).await);
+    }
+    debug!(
+        "Ingested {}/{} records via API",
+        summary.accepted,
+        records.len()
+    );
+    Ok(Json(summary))
+}
+
+/// Newline-delimited records, published as each line arrives. A broken body or an
+/// overlong line ends the stream; the records before it stay published.
+async fn ingest_stream(State(state): State<AppState>, mut body: Body) -> Json<IngestSummary> {
+    let mut summary = IngestSummary::default();
+    let mut buffer = Vec::new();
+    let mut index = 0;
+    loop {
+        let done = match std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+            Some(Ok(frame)) => {
+                if let Ok(data) = frame.into_data() {
+                    buffer.extend_from_slice(&data);
+                }
+                false
+            }
+            Some(Err(e)) => {
+                summary.record(index, Err(anyhow::anyhow!("Failed to read body: {}", e)));
+                break;
+            }
+            None => {
+                // The last line may lack its newline
+                buffer.push(b'\n');
+                true
+            }
+        };
+        for line in take_lines(&mut buffer) {
+            let result = match serde_json::from_slice::<IngestRecord>(&line) {
+                Ok(record) => state.ingestor.publish_record(&record).await,
+                Err(e) => Err(anyhow::anyhow!("Invalid record: {}", e)),
+            };
+            summary.record(index, result);
+            index += 1;
+        }
+        if buffer.len() > MAX_LINE_BYTES {
+            summary.record(
+                index,
+                Err(anyhow::anyhow!("Line longer than {} bytes", MAX_LINE_BYTES)),
+            );
+            break;
+        }
+        if done {
+            break;
+        }
+    }
+    debug!(
+        "Ingested {}/{} streamed records via API",
+        summary.accepted,
+        summary.accepted + summary.rejected
+    );
+    Json(summary)
+}
+
+async fn purge_dead_letters(
+    State(state): State<AppState>,
+    Query(query): Query<DeadLetterQuery>,
+) -> StatusCode {
+    let store = state.connection_manager.read().await.dead_letter_store();
+    let purged = store.take(query.broker.as_deref(), None).len();
+    info!("Purged {} dead-lettered messages via API", purged);
+    StatusCode::NO_CONTENT
+}
+
+async fn delete_dead_letter(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AppError> {
+    let store = state.connection_manager.read().await.dead_letter_store();
+    if store.take(None, Some(&[id])).is_empty() {
+        Err(AppError::NotFound)
+    } else {
+        Ok(StatusCode::NO_CONTENT)
     }
 }
 
@@ -451,6 +2237,7 @@ async fn get_main_broker_settings(
 
 async fn update_main_broker_settings(
     State(state): State<AppState>,
+    actor: Actor,
     Json(payload): Json<UpdateMainBrokerRequest>,
 ) -> Result<Json<MainBrokerSettingsResponse>, AppError> {
     let settings = MainBrokerSettings {
@@ -467,23 +2254,52 @@ async fn update_main_broker_settings(
         } else {
             payload.password
         },
+        protocol_version: validate_protocol_version(
+            payload.protocol_version.unwrap_or(PROTOCOL_V4),
+        )?,
     };
+    let protocol_version = settings.protocol_version;
+    // Only a password other than the placeholder replaces the stored one
+    let password_set = settings
+        .password
+        .as_deref()
+        .is_some_and(|password| password != "********");
+    let previous = state.settings_storage.get_main_broker_for_api().await;
 
     state.settings_storage.set_main_broker(settings).await?;
 
     // Update connection manager with new main broker address for reverse connections
     {
         let mut manager = state.connection_manager.write().await;
-        manager.update_main_broker_config(payload.address, payload.port);
+        manager.update_main_broker_config(payload.address, payload.port, protocol_version);
     }
 
     // Signal the proxy to restart the main broker client
     let _ = state.main_broker_restart_tx.send(()).await;
 
     let saved = state.settings_storage.get_main_broker_for_api().await;
+    state.audit_log.record(
+        actor,
+        AuditAction::MainBrokerUpdated,
+        audit::MAIN_BROKER_TARGET,
+        None,
+        audit::changes(previous.as_ref(), saved.as_ref(), password_set),
+    );
     Ok(Json(MainBrokerSettingsResponse { settings: saved }))
 }
 
+/// Connect to a broker that isn't saved yet and report what it supports
+async fn probe_broker(Json(payload): Json<ProbeRequest>) -> Result<Json<ProbeReport>, AppError> {
+    if payload.address.trim().is_empty() {
+        return Err(AppError::field(
+            ErrorCode::InvalidValue,
+            "address",
+            "Address is required",
+        ));
+    }
+    Ok(Json(broker_probe::probe(&payload).await))
+}
+
 async fn test_main_broker_connection(
     Json(payload): Json<TestConnectionRequest>,
 ) -> Result<Json<TestConnectionResponse>, AppError> {
@@ -527,12 +2343,14 @@ async fn test_main_broker_connection(
                     payload.address, payload.port
                 ),
                 latency_ms: Some(latency_ms),
+                code: None,
             }))
         }
         Ok(Err(e)) => Ok(Json(TestConnectionResponse {
             success: false,
             message: format!("Connection failed: {}", e),
             latency_ms: None,
+            code: Some(ErrorCode::BrokerUnreachable),
         })),
         Err(_) => Ok(Json(TestConnectionResponse {
             success: false,
@@ -541,6 +2359,7 @@ async fn test_main_broker_connection(
                 payload.address, payload.port
             ),
             latency_ms: None,
+            code: Some(ErrorCode::BrokerUnreachable),
         })),
     }
 }
@@ -561,6 +2380,8 @@ struct UpdateMainBrokerRequest {
     username: Option<String>,
     #[serde(default)]
     password: Option<String>,
+    #[serde(default)]
+    protocol_version: Option<u8>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -581,25 +2402,327 @@ struct TestConnectionResponse {
     success: bool,
     message: String,
     latency_ms: Option<u64>,
+    /// Set when the connection failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
 }
 
 // WebSocket handler for real-time MQTT messages
 async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    Query(query): Query<StreamQuery>,
+) -> Result<axum::response::Response, AppError> {
+    let filter = StreamFilter::from_query(query)
+        .map_err(|e| AppError::field(ErrorCode::InvalidTopicFilter, "topics", e.to_string()))?;
+    Ok(ws.on_upgrade(|socket| handle_socket(socket, state, filter)))
 }
 
-async fn handle_socket(mut socket: WebSocket, state: AppState) {
+async fn handle_socket(mut socket: WebSocket, state: AppState, mut filter: StreamFilter) {
     info!("New WebSocket client connected");
     let mut rx = state.message_tx.subscribe();
+    let mut total_dropped = 0;
 
-    while let Ok(msg) = rx.recv().await {
-        let json = serde_json::to_string(&msg).unwrap_or_default();
-        if socket.send(Message::Text(json)).await.is_err() {
+    loop {
+        let reply = tokio::select! {
+            _ = state.shutdown.cancelled() => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+            result = rx.recv() => match result {
+                Ok(msg) => match filter.apply(&msg) {
+                    Some(streamed) => serde_json::to_string(&streamed).unwrap_or_default(),
+                    None => continue,
+                },
+                Err(broadcast::error::RecvError::Lagged(dropped)) => {
+                    total_dropped += dropped;
+                    state.metrics.websocket_dropped.inc_by(dropped);
+                    debug!("WebSocket client lagged, dropped {} messages", dropped);
+                    serde_json::to_string(&ControlReply::Lagged {
+                        dropped,
+                        total_dropped,
+                    })
+                    .unwrap_or_default()
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = match serde_json::from_str::<ControlMessage>(&text) {
+                        Ok(ControlMessage::Subscribe(new_filter)) => match new_filter.validate() {
+                            Ok(()) => {
+                                filter = new_filter;
+                                ControlReply::Subscribed {
+                                    filter: filter.clone(),
+                                }
+                            }
+                            Err(e) => ControlReply::Error {
+                                message: e.to_string(),
+                            },
+                        },
+                        Err(e) => ControlReply::Error {
+                            message: format!("Invalid control message: {}", e),
+                        },
+                    };
+                    serde_json::to_string(&reply).unwrap_or_default()
+                }
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => {
+                    debug!("WebSocket client disconnected");
+                    break;
+                }
+                Some(Ok(_)) => continue,
+            },
+        };
+        if socket.send(Message::Text(reply)).await.is_err() {
             debug!("WebSocket client disconnected");
             break;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker_storage::FileBrokerStorage;
+    use crate::client_registry::ClientRegistry;
+    use crate::config::DedupConfig;
+    use crate::settings_storage::FileSettingsStorage;
+    use axum::response::Response;
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    async fn test_state(dir: &TempDir) -> AppState {
+        let connection_manager = Arc::new(RwLock::new(
+            ConnectionManager::new(
+                vec![],
+                Arc::new(ClientRegistry::new()),
+                "127.0.0.1".to_string(),
+                1,
+                4,
+                &DedupConfig::default(),
+                0,
+                None,
+                None,
+            )
+            .await
+            .unwrap(),
+        ));
+        let (message_tx, _) = broadcast::channel(16);
+        AppState {
+            ingestor: Arc::new(Ingestor::new(
+                Arc::clone(&connection_manager),
+                Some(message_tx.clone()),
+                "ingest".to_string(),
+                false,
+            )),
+            connection_manager,
+            broker_storage: Arc::new(
+                FileBrokerStorage::new(dir.path().join("brokers.json")).unwrap(),
+            ),
+            settings_storage: Arc::new(
+                FileSettingsStorage::new(dir.path().join("settings.json")).unwrap(),
+            ),
+            main_broker_restart_tx: mpsc::channel(1).0,
+            message_tx,
+            metrics: Metrics::global(),
+            message_history: Arc::new(MessageHistory::new(0)),
+            topology_rates: Arc::new(RateMeter::default()),
+            shutdown: CancellationToken::new(),
+            ingest_max_batch: 100,
+            audit_log: Arc::new(AuditLog::open(dir.path().join("audit.jsonl")).unwrap()),
+        }
+    }
+
+    /// A disabled broker as the Web UI form sends it, so no connection is started
+    fn broker(name: &str) -> Value {
+        json!({
+            "name": name,
+            "address": "localhost",
+            "port": 1883,
+            "clientIdPrefix": "test",
+            "enabled": false,
+            "useTls": false,
+            "insecureSkipVerify": false,
+        })
+    }
+
+    async fn add(state: &AppState, request: Value) -> Response {
+        add_broker(
+            State(state.clone()),
+            Actor::default(),
+            Json(serde_json::from_value(request).unwrap()),
+        )
+        .await
+        .into_response()
+    }
+
+    async fn update(state: &AppState, id: &str, tag: Option<&str>, request: Value) -> Response {
+        let mut headers = HeaderMap::new();
+        if let Some(tag) = tag {
+            headers.insert(header::IF_MATCH, HeaderValue::from_str(tag).unwrap());
+        }
+        update_broker(
+            State(state.clone()),
+            Actor::default(),
+            Path(id.to_string()),
+            headers,
+            Json(serde_json::from_value(request).unwrap()),
+        )
+        .await
+        .into_response()
+    }
+
+    /// Status, ETag and JSON body of a response
+    async fn parts(response: Response) -> (StatusCode, Option<String>, Value) {
+        let status = response.status();
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .map(|tag| tag.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, etag, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// Check an error response is exactly `{code, message, field}`
+    async fn assert_error(response: Response, status: StatusCode, code: &str, field: Option<&str>) {
+        let (actual, _, body) = parts(response).await;
+        assert_eq!(actual, status);
+        let body = body.as_object().unwrap();
+        assert_eq!(body["code"], code);
+        assert!(body["message"].is_string());
+        assert_eq!(body.get("field").and_then(Value::as_str), field);
+        assert_eq!(body.len(), 2 + usize::from(field.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_broker_updates_need_the_current_etag() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir).await;
+        let (_, _, added) = parts(add(&state, broker("Edge")).await).await;
+        let id = added["id"].as_str().unwrap();
+        let response = get_broker(State(state.clone()), Path(id.to_string()))
+            .await
+            .into_response();
+        let (_, first, _) = parts(response).await;
+        let first = first.unwrap();
+
+        let (status, _, body) = parts(update(&state, id, None, broker("Edge 2")).await).await;
+        assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+        assert_eq!(body["code"], "precondition-required");
+
+        let (status, second, body) =
+            parts(update(&state, id, Some(&first), broker("Edge 2")).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "Edge 2");
+        let second = second.unwrap();
+        assert_ne!(second, first);
+
+        // An update based on the first revision would undo the rename
+        let (status, tag, body) =
+            parts(update(&state, id, Some(&first), broker("Edge 3")).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "revision-mismatch");
+        assert_eq!(body["current"]["name"], "Edge 2");
+        assert_eq!(tag.as_deref(), Some(second.as_str()));
+        assert_eq!(state.broker_storage.get(id).await.unwrap().name, "Edge 2");
+
+        let (status, third, body) =
+            parts(update(&state, id, Some("*"), broker("Edge 3")).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "Edge 3");
+        assert_ne!(third.unwrap(), second);
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir).await;
+        let (status, _, _) = parts(add(&state, broker("Edge")).await).await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert_error(
+            add(&state, broker("Edge")).await,
+            StatusCode::CONFLICT,
+            "duplicate-name",
+            Some("name"),
+        )
+        .await;
+
+        let mut request = broker("Sampled");
+        request["sampling"] = json!([{ "topic": "sensors/#/temp", "everyNth": 2 }]);
+        assert_error(
+            add(&state, request).await,
+            StatusCode::BAD_REQUEST,
+            "invalid-topic-filter",
+            Some("sampling"),
+        )
+        .await;
+
+        let response = get_broker(State(state.clone()), Path("missing".to_string()))
+            .await
+            .into_response();
+        assert_error(response, StatusCode::NOT_FOUND, "not-found", None).await;
+
+        // The CA file is read when the connection starts
+        let mut request = broker("Remote");
+        request["enabled"] = json!(true);
+        request["useTls"] = json!(true);
+        request["caCertPath"] = json!(dir.path().join("missing-ca.pem"));
+        assert_error(
+            add(&state, request).await,
+            StatusCode::BAD_GATEWAY,
+            "broker-unreachable",
+            None,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_ui_port_serves_only_the_ui() {
+        use tower::ServiceExt;
+
+        let dir = TempDir::new().unwrap();
+        let ui_dir = dir.path().join("ui");
+        std::fs::create_dir(&ui_dir).unwrap();
+        std::fs::write(ui_dir.join("index.html"), "<html>MQTT proxy</html>").unwrap();
+        let (api, ui) = with_ui(
+            api_routes(false),
+            Some(ui_dir.to_string_lossy().into_owned()),
+            Some(3001),
+            3000,
+        );
+        let api = api.with_state(test_state(&dir).await);
+        let ui = ui.unwrap();
+        let get = |uri: &str| {
+            axum::http::Request::get(uri)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        let response = ui.clone().oneshot(get("/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"<html>MQTT proxy</html>");
+        let (status, _, body) =
+            parts(ui.clone().oneshot(get("/ui-config.json")).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"apiPort": 3000}));
+        for uri in ["/api/status", "/api/brokers", "/health", "/metrics"] {
+            let response = ui.clone().oneshot(get(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+
+        let (status, _, body) = parts(api.clone().oneshot(get("/api/status")).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["brokers"].is_array());
+        let (status, _, body) = parts(api.clone().oneshot(get("/")).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ui"], "separate_port");
+        let response = api.oneshot(get("/index.html")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,107 @@
+//! HTTPS for the Web UI and API
+//!
+//! `axum::serve` only speaks plain HTTP, so with `web_ui.use_tls` each accepted connection
+//! goes through a TLS handshake and is then served by hyper (HTTP/1.1 with upgrades for
+//! the WebSocket stream, or HTTP/2 when the client negotiates it via ALPN). The peer
+//! address is attached as `ConnectInfo` like `axum::serve` does for the audit log.
+
+use crate::config::WebUiConfig;
+use crate::listener_tls::{load_certs, load_private_key};
+use crate::self_signed;
+use anyhow::{Context, Result};
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tower::Service;
+use tracing::debug;
+
+/// Time a client has to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build the acceptor for the Web UI ports, or `None` when `use_tls` is off
+pub fn build_web_tls_acceptor(config: &WebUiConfig) -> Result<Option<TlsAcceptor>> {
+    if !config.use_tls {
+        return Ok(None);
+    }
+    let (cert_path, key_path) = self_signed::cert_and_key(
+        config.tls_cert_path.as_deref(),
+        config.tls_key_path.as_deref(),
+        config.tls_self_signed.as_ref(),
+        "Web UI",
+    )?;
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(&cert_path)?, load_private_key(&key_path)?)
+        .context("Invalid Web UI certificate or key")?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// Serve `app` over TLS until `shutdown` is cancelled, then wait for open connections to
+/// finish their requests
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    app: Router,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("Failed to accept Web UI connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", peer);
+                        return;
+                    }
+                };
+            let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(peer));
+                app.clone().call(request)
+            });
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            let served = tokio::select! {
+                served = connection.as_mut() => served,
+                _ = shutdown.cancelled() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = served {
+                debug!("Web UI connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
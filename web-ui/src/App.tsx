@@ -4,6 +4,7 @@ import MetricsDashboard from './components/MetricsDashboard'
 import AddBrokerForm from './components/AddBrokerForm'
 import MessageViewer from './components/MessageViewer'
 import MainBrokerSettings from './components/MainBrokerSettings'
+import { apiFetch } from './api'
 import './App.css'
 
 interface Broker {
@@ -21,6 +22,7 @@ interface Broker {
   bidirectional: boolean
   topics: string[]
   subscriptionTopics: string[]
+  revision: number
 }
 
 interface BrokerStatus {
@@ -58,11 +60,11 @@ function App() {
   const fetchBrokers = async () => {
     try {
       // Fetch full broker configs from /api/brokers
-      const brokersResponse = await fetch('/api/brokers')
+      const brokersResponse = await apiFetch('/api/brokers')
       const brokersData = await brokersResponse.json()
 
       // Fetch status from /api/status to get connected state
-      const statusResponse = await fetch('/api/status')
+      const statusResponse = await apiFetch('/api/status')
       const statusData = await statusResponse.json()
 
       // Merge the data - add connected state from status to broker configs
@@ -86,7 +88,7 @@ function App() {
 
   const handleAddBroker = async (brokerData: BrokerFormData) => {
     try {
-      const response = await fetch('/api/brokers', {
+      const response = await apiFetch('/api/brokers', {
         method: 'POST',
         headers: { 'Content-Type': 'application/json' },
         body: JSON.stringify(brokerData),
@@ -97,7 +99,7 @@ function App() {
         fetchBrokers() // Refresh the list
       } else {
         const error = await response.json()
-        alert(`Failed to add broker: ${error.error}`)
+        alert(`Failed to add broker: ${error.message}`)
       }
     } catch (error) {
       console.error('Error adding broker:', error)
@@ -111,7 +113,7 @@ function App() {
     }
 
     try {
-      const response = await fetch(`/api/brokers/${id}`, {
+      const response = await apiFetch(`/api/brokers/${id}`, {
         method: 'DELETE',
       })
 
@@ -127,7 +129,7 @@ function App() {
 
   const handleToggleBroker = async (id: string, enabled: boolean) => {
     try {
-      const response = await fetch(`/api/brokers/${id}/toggle`, {
+      const response = await apiFetch(`/api/brokers/${id}/toggle`, {
         method: 'POST',
         headers: { 'Content-Type': 'application/json' },
         body: JSON.stringify({ enabled }),
@@ -160,6 +162,7 @@ function App() {
       subscriptionTopics: broker.subscriptionTopics || [],
       connected: broker.connected,
       enabled: broker.enabled,
+      revision: broker.revision,
     })
   }
 
@@ -189,18 +192,31 @@ function App() {
         updateData.password = brokerData.password
       }
 
-      const response = await fetch(`/api/brokers/${editingBroker.id}`, {
+      const response = await apiFetch(`/api/brokers/${editingBroker.id}`, {
         method: 'PUT',
-        headers: { 'Content-Type': 'application/json' },
+        headers: {
+          'Content-Type': 'application/json',
+          // Refused with 409 if someone else saved the broker since it was loaded
+          'If-Match': `"${editingBroker.revision}"`,
+        },
         body: JSON.stringify(updateData),
       })
 
       if (response.ok) {
         setEditingBroker(null)
         fetchBrokers()
+      } else if (response.status === 409) {
+        const error = await response.json()
+        if (error.code === 'revision-mismatch') {
+          alert('This broker was changed by someone else in the meantime. Reopen it to see the current settings.')
+          setEditingBroker(null)
+          fetchBrokers()
+        } else {
+          alert(`Failed to update broker: ${error.message}`)
+        }
       } else {
         const error = await response.json()
-        alert(`Failed to update broker: ${error.error}`)
+        alert(`Failed to update broker: ${error.message}`)
       }
     } catch (error) {
       console.error('Error updating broker:', error)
@@ -0,0 +1,24 @@
+// With `web_ui.ui_port` set, this UI is served on its own port and the API is on another
+// port of the same host, named by /ui-config.json. Otherwise the API is on this origin.
+let apiOrigin: Promise<string> | null = null
+
+function resolveApiOrigin(): Promise<string> {
+  apiOrigin ??= fetch('/ui-config.json')
+    .then(response => (response.ok ? response.json() : null))
+    .then(config =>
+      config?.apiPort
+        ? `${window.location.protocol}//${window.location.hostname}:${config.apiPort}`
+        : '',
+    )
+    .catch(() => '')
+  return apiOrigin
+}
+
+export async function apiFetch(path: string, init?: RequestInit): Promise<Response> {
+  return fetch(`${await resolveApiOrigin()}${path}`, init)
+}
+
+export async function apiWebSocketUrl(path: string): Promise<string> {
+  const origin = (await resolveApiOrigin()) || window.location.origin
+  return origin.replace(/^http/, 'ws') + path
+}
@@ -1,4 +1,5 @@
 import { useEffect, useState } from 'react'
+import { apiFetch } from '../api'
 
 interface MainBrokerFormData {
   address: string
@@ -35,7 +36,7 @@ export default function MainBrokerSettings() {
 
   const fetchSettings = async () => {
     try {
-      const response = await fetch('/api/settings/main-broker')
+      const response = await apiFetch('/api/settings/main-broker')
       const data = await response.json()
       if (data.settings) {
         const s = data.settings
@@ -74,7 +75,7 @@ export default function MainBrokerSettings() {
       if (formData.username) payload.username = formData.username
       if (!keepPassword && formData.password) payload.password = formData.password
 
-      const response = await fetch('/api/settings/main-broker/test', {
+      const response = await apiFetch('/api/settings/main-broker/test', {
         method: 'POST',
         headers: { 'Content-Type': 'application/json' },
         body: JSON.stringify(payload),
@@ -107,7 +108,7 @@ export default function MainBrokerSettings() {
         payload.password = formData.password
       }
 
-      const response = await fetch('/api/settings/main-broker', {
+      const response = await apiFetch('/api/settings/main-broker', {
         method: 'PUT',
         headers: { 'Content-Type': 'application/json' },
         body: JSON.stringify(payload),
@@ -124,7 +125,7 @@ export default function MainBrokerSettings() {
         const error = await response.json()
         setTestResult({
           success: false,
-          message: `Failed to save: ${error.error}`,
+          message: `Failed to save: ${error.message}`,
         })
       }
     } catch (error) {
@@ -1,5 +1,6 @@
 import { useEffect, useState, useCallback } from 'react'
 import './MessageViewer.css'
+import { apiWebSocketUrl } from '../api'
 
 interface MqttMessage {
   timestamp: string
@@ -24,16 +25,17 @@ function MessageViewer() {
   const [selectedTopic, setSelectedTopic] = useState<string | null>(null)
   const [connected, setConnected] = useState(false)
   const [lastMessageTime, setLastMessageTime] = useState<string | null>(null)
+  const [droppedCount, setDroppedCount] = useState(0)
   const [maxMessages] = useState(1000) // Keep last 1000 messages
 
   useEffect(() => {
-    const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:'
-    const wsUrl = `${protocol}//${window.location.host}/ws/messages`
-
     let ws: WebSocket
     let reconnectTimeout: ReturnType<typeof setTimeout>
+    let closed = false
 
-    const connect = () => {
+    const connect = async () => {
+      const wsUrl = await apiWebSocketUrl('/ws/messages')
+      if (closed) return
       ws = new WebSocket(wsUrl)
 
       ws.onopen = () => {
@@ -43,7 +45,16 @@ function MessageViewer() {
 
       ws.onmessage = (event) => {
         try {
-          const msg: MqttMessage = JSON.parse(event.data)
+          const data = JSON.parse(event.data)
+
+          // Notices from the server; the viewer is too slow when it gets `lagged`
+          if (data.type === 'lagged') {
+            setDroppedCount(prev => prev + data.dropped)
+            return
+          }
+          if (data.type) return
+
+          const msg: MqttMessage = data
 
           // Update last message timestamp (only if newer)
           setLastMessageTime(prev => {
@@ -74,7 +85,7 @@ function MessageViewer() {
         console.log('WebSocket disconnected')
         setConnected(false)
         // Reconnect after 3 seconds
-        reconnectTimeout = setTimeout(connect, 3000)
+        if (!closed) reconnectTimeout = setTimeout(connect, 3000)
       }
 
       ws.onerror = (error) => {
@@ -85,6 +96,7 @@ function MessageViewer() {
     connect()
 
     return () => {
+      closed = true
       if (ws) ws.close()
       if (reconnectTimeout) clearTimeout(reconnectTimeout)
     }
@@ -254,6 +266,11 @@ function MessageViewer() {
               Last: {formatTimestamp(lastMessageTime)}
             </div>
           )}
+          {droppedCount > 0 && (
+            <div className="last-update">
+              {droppedCount} messages dropped (viewer too slow)
+            </div>
+          )}
         </div>
         <div className={`connection-status ${connected ? 'connected' : 'disconnected'}`}>
           {connected ? '🟢 Connected' : '🔴 Disconnected'}
@@ -1,4 +1,5 @@
 import { useEffect, useState } from 'react'
+import { apiFetch } from '../api'
 
 interface BrokerStatus {
   connected: boolean
@@ -10,6 +11,8 @@ interface Metrics {
   messagesForwarded: number
   avgLatencyMs: number
   activeConnections: number
+  uiPaused: boolean
+  uiShedMessages: number
 }
 
 export default function MetricsDashboard() {
@@ -18,6 +21,8 @@ export default function MetricsDashboard() {
     messagesForwarded: 0,
     avgLatencyMs: 0,
     activeConnections: 0,
+    uiPaused: false,
+    uiShedMessages: 0,
   })
 
   useEffect(() => {
@@ -28,7 +33,7 @@ export default function MetricsDashboard() {
 
   const fetchMetrics = async () => {
     try {
-      const response = await fetch('/api/status')
+      const response = await apiFetch('/api/status')
       const data = await response.json()
 
       setMetrics({
@@ -36,6 +41,8 @@ export default function MetricsDashboard() {
         messagesForwarded: data.total_messages_forwarded || 0,
         avgLatencyMs: data.avg_latency_ms || 0,
         activeConnections: data.brokers?.filter((b: BrokerStatus) => b.connected && b.enabled).length || 0,
+        uiPaused: data.ui_paused || false,
+        uiShedMessages: data.ui_shed_messages || 0,
       })
     } catch (error) {
       console.error('Failed to fetch metrics:', error)
@@ -64,6 +71,13 @@ export default function MetricsDashboard() {
         <p className="metric-value">{metrics.activeConnections}</p>
         <p className="metric-label">Connected and enabled</p>
       </div>
+      {metrics.uiPaused && (
+        <div className="metric-card">
+          <h4>Live View Paused</h4>
+          <p className="metric-value warning-text">{metrics.uiShedMessages.toLocaleString()}</p>
+          <p className="metric-label">Messages not shown while forwarding is under load</p>
+        </div>
+      )}
     </div>
   )
 }